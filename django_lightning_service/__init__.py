@@ -1,2 +1,5 @@
 from .django_lightning_service import *
+from .django_models import describe_model, register_django_model, register_django_persistence
+from .django_transaction import flush_on_commit
+from .proxies import EntityProxy, entity_class
 __AUTHOR__ = "darius"
@@ -1,31 +1,525 @@
-from typing import Any
+from typing import Any, Callable, Iterator
 
 
-class PyAttribute:
+class EntityNotFound(LookupError): ...
+class AttributeNotFound(KeyError): ...
+class UnpersistedEntity(ValueError): ...
+class ProtectedError(Exception): ...
+class DependencyCycleError(Exception): ...
+class StaleEntityError(Exception): ...
+class IntegrityError(Exception): ...
+class ValidationError(ValueError): ...
+CustomError = EntityNotFound
+
+
+class PyEntityStore:
+    def __iter__(self) -> Iterator[PyEntity]: ...
+    def __len__(self) -> int: ...
+    def __contains__(self, item: PyEntity | PyEntityIdentifier) -> bool: ...
+    def connect(self, event: str, callback: Callable[[PyEntity, list[str]], None]) -> None:
+        """call *callback* on *event* with the entity and the list of the names of the concerned
+        attributes: "created" when an entity is added to the store (all its attributes),
+        "changed" when an attribute value is set, "deleted" when an entity is deleted (none) and
+        "flushed" for each entity saved by flush() (all the attributes of the inserted ones, the
+        modified ones of the updated ones, none for the deleted ones)"""
+    def subscribe(self, event: str, callback: Callable[[dict[str, Any]], None]) -> None:
+        """call *callback* on the lifecycle *event* with a dict describing it, its name as "event":
+        "entity_created" with the "identifier", "entity" and "values" of an entity added to the
+        store, "attribute_changed" with the "identifier", "entity", "attribute", "previous" and
+        "value" of a value set, at "epoch", "entity_deleted" with the "identifier" and "entity" of
+        a deleted entity, at "epoch", and "epoch_advanced" with the "previous" and new "epoch" when
+        the current epoch moves. disconnect() stops it"""
+    def disconnect(self, event: str, callback: Callable[[PyEntity, list[str]], None]) -> bool:
+        """stop calling *callback* on *event*, return whether it was connected"""
+    def watch(self, identifier: PyEntityIdentifier, attribute: str, callback: Callable[[PyEntity, str, Any, Any], None]) -> None:
+        """call *callback* with the entity, the attribute and the previous and new values each time
+        the current value of the *attribute* of the entity *identifier* changes, whether it is set
+        or the current epoch moves, by a rollback or an undo. unwatch() stops it"""
+    def unwatch(self, identifier: PyEntityIdentifier, attribute: str, callback: Callable[[PyEntity, str, Any, Any], None]) -> bool:
+        """stop calling *callback* on the changes of the *attribute* of the entity *identifier*,
+        return whether it was watching them"""
+    def get(self, identifier: PyEntityIdentifier) -> PyEntity: ...
+    def count(self, model: str) -> int:
+        """the number of entities of *model* not deleted, without evaluating a filter"""
+    def query(self, model: str, **kwargs) -> PyQuery:
+        """the entities of *model* matching the keyword arguments, as a query evaluated on each use"""
+    def filter(self, model: str, **kwargs) -> list[PyEntity]:
+        """the entities of *model* matching the keyword arguments, exact values or django style
+        lookups: `filter("User", name="darius", age__gt=30)`"""
+    def columns(self, model: str, attributes: list[str] | None=None, **kwargs) -> dict[str, list[Any]]:
+        """the *attributes* of the entities of *model* matching the keyword arguments, as a dict of
+        columns, ready for `pandas.DataFrame()`. "pk" is the pk of the entities and foreign keys
+        hold the pk they reference. the attributes default to "pk" and the registered ones, or the
+        ones of the first entity"""
+    def load_csv(self, model: str, path: str, column_mapping: dict[str, str] | None=None, pk: str='id') -> int:
+        """instantiate an entity of *model* per record of the CSV file at *path*: persisted ones
+        for the records with a *pk* column value, new ones otherwise. *column_mapping* gives the
+        attribute of each column to load, all columns are loaded as is if None. empty cells are
+        None, integers numbers and anything else strings. return the number of loaded records"""
+    def to_csv(self, model: str, path: str, attributes: list[str] | None=None, **kwargs) -> int:
+        """write the columns() of the entities of *model* matching the keyword arguments to the CSV
+        file at *path*. return the number of written records"""
+    def to_arrow(self, model: str, attributes: list[str] | None=None, **kwargs) -> Any:
+        """the columns() as a pyarrow RecordBatch"""
+    def latest(self, model: str, attribute: str) -> PyEntity | None:
+        """return the entity of *model* with the greatest *attribute* value, if any"""
+    def earliest(self, model: str, attribute: str) -> PyEntity | None:
+        """return the entity of *model* with the smallest *attribute* value, if any"""
+    def all(self, model: str | None=None) -> list[PyEntity]:
+        """return all entities of *model*, or of every model if none is given"""
+    def changes_since(self, epoch: int) -> list[tuple[PyEntity, bool, list[str]]]:
+        """return the `(entity, created, changed_attributes)` of the entities created or modified since *epoch*"""
+    def in_bulk(self, model: str, pks: list[int]) -> dict[int, PyEntity]:
+        """return a dict of pk -> entity for the entities of *model* having one of the given *pks*"""
+    def filter_related(self, model: str, attribute: str, related_model: str, related_attribute: str, value: Any) -> list[PyEntity]:
+        """return the entities of *model* whose *attribute* holds the pk of a *related_model*
+        entity having *related_attribute* equal to *value*"""
+    def instantiate_entity(self, identifier: PyEntityIdentifier, attributes: list[PyAttributeDescriptor | tuple[str, str, Any]]) -> PyEntity: ...
+    def snapshot(self) -> PyEntityStore: ...
+    def apply_pk(self, identifier: PyEntityIdentifier, pk: int) -> None:
+        """set the pk of a persisted entity, making it reachable by model+pk"""
+    def stats(self) -> dict[str, Any]:
+        """return counters about the store content, as a dict"""
+    def memory_report(self) -> dict[str, dict[str, int]]:
+        """estimate the bytes used by each model, as a dict of dicts by model with the number of
+        "entities" and the bytes of the "entities" themselves ("entity_bytes"), of the histories of
+        their values ("history_bytes") and of their index entries ("index_bytes"), and the "total" of the three"""
+    def dump_debug(self) -> str:
+        """a human readable report of the store: its epochs, every entity with its state and
+        its values, the initial one of the modified values, then the changes the next flush would save"""
+    def metrics(self) -> dict[str, Any]:
+        """the counters of the work done by the store since it was created or reset_metrics(), as a
+        dict: the executed "filters", the entities they "scanned" and the "scanned_per_filter"
+        histogram as (upper bound, count) buckets, the last bound None, the lookups by identifier
+        finding the entity in the index ("index_hits") or not ("index_misses"), the related
+        entities found in the store ("cache_hits") or to load ("cache_misses") by get_related() and
+        prefetch(), and the "history_entries" of the attributes"""
+    def reset_metrics(self) -> None: ...
+    def enable_query_log(self, capacity: int=1000) -> None:
+        """record the last *capacity* filters and counts, like django's connection.queries, see
+        queries(). a capacity of 0 stops the recording"""
+    def queries(self) -> list[dict[str, Any]]:
+        """the recorded filters and counts, oldest first, as dicts with the "operation", the "model",
+        the normalized "expression", the number of "results" and the "duration" in seconds"""
+    def clear_query_log(self) -> None: ...
+    def check_integrity(self) -> dict[str, list[tuple]]:
+        """check the relations between the entities, returning a dict with the "dangling" foreign keys
+        and "deleted_memberships" as (entity, attribute) lists, and the "one_to_one_duplicates" as
+        (attribute, referenced entity, referencing entities) list. all lists are empty if consistent"""
+    def at(self, epoch: int) -> PyEntityStoreView:
+        """return a read-only view of the store as it was at *epoch*"""
+    def at_epoch(self, epoch: int) -> PyEpochScope:
+        """return a context manager reading the values as they were at *epoch* in the enclosed block,
+        through the entities of the store. nothing can be written meanwhile"""
+    def transaction(self) -> PyTransaction:
+        """return a context manager running the enclosed block in a transaction,
+        committed on success and rolled back on exception"""
+    def begin(self) -> int:
+        """start a transaction and return the epoch its values must be written at"""
+    def commit(self) -> None: ...
+    def rollback(self) -> None:
+        """discard the values and entities written since begin()"""
+    def undo(self) -> bool:
+        """go back to before the last committed transaction, return False if there is nothing to undo"""
+    def redo(self) -> bool:
+        """go forward to after the last undone transaction, return False if there is nothing to redo"""
+    def savepoint(self) -> int:
+        """mark the state of the running transaction and return a token for rollback_to()"""
+    def rollback_to(self, token: int) -> None:
+        """discard the values and entities written since the savepoint *token*"""
+    def checkpoint(self, label: str) -> int:
+        """label the current state with *label* and return its epoch"""
+    def checkpoint_epoch(self, label: str) -> int:
+        """the epoch labelled with *label*"""
+    def rollback_to_checkpoint(self, label: str) -> None:
+        """discard the values and entities written since the checkpoint *label*"""
+    def changes_since_checkpoint(self, label: str) -> list[tuple[PyEntity, bool, list[str]]]:
+        """same as changes_since(), from the checkpoint *label*"""
+    def get_related(self, entity: PyEntity, attribute: str) -> PyEntity | None:
+        """the entity referenced by the foreign key *attribute* of *entity*, None if it's empty.
+        if it's missing from the store, it's fetched with the loader and instantiated"""
+    def set_loader(self, loader: Callable[[str, int], list[PyAttributeDescriptor | tuple[str, str, Any]] | None] | None, batch_loader: Callable[[str, list[int]], dict[int, list[PyAttributeDescriptor | tuple[str, str, Any]]]] | None=None) -> None:
+        """set the callable fetching the related entities missing from the store: called with the
+        model and pk, it returns the attributes as given to instantiate_entity(), or None.
+        *batch_loader*, if given, is called instead by prefetch() with the model and a list of pks,
+        and returns a dict of pk -> attributes"""
+    def register_persistence(self, model: str, insert: Callable[[PyEntity], int] | None=None, update: Callable[[PyEntity, list[str]], int | None] | None=None, delete: Callable[[PyEntity], None] | None=None, bulk_insert: Callable[[list[PyEntity]], list[int]] | None=None, batch_size: int=100) -> None:
+        """set the callables persisting the entities of *model* on flush(): *insert* is called with
+        each new entity and returns its pk, *update* with each modified entity and the names of
+        its modified attributes, *delete* with each deleted entity. *bulk_insert*, if given, is
+        called instead of *insert* with lists of at most *batch_size* new entities, like django's
+        bulk_create(), and returns their pks in the same order"""
+    def set_on_commit(self, on_commit: Callable[[Callable[[], dict[str, int]]], None] | None) -> None:
+        """defer the flushes to the commit of the enclosing database transaction: flush() passes
+        flush_now() to *on_commit*, like django's `transaction.on_commit`, instead of saving the
+        changes, so nothing is written if the transaction rolls back. None flushes immediately again"""
+    def flush(self) -> dict[str, int] | None:
+        """flush_now(), or queue it with the on_commit callable if set, see set_on_commit(). return
+        the counts of flush_now(), None if queued"""
+    def flush_now(self) -> dict[str, int]:
+        """save the changes made since the last flush with the persistence callbacks: the new entities
+        are inserted and given the returned pks, the referenced ones first, then the modified ones
+        updated and the deleted ones deleted, the referencing ones first. if a callback fails, the
+        flush can be retried. return the number of entities inserted, updated and deleted.
+        versioned entities are updated with their version incremented, see set_version_attribute()"""
+    def register_table(self, model: str, table: str, columns: dict[str, str], pk_column: str='id') -> None:
+        """map *model* to the database *table*, *columns* giving the column of each attribute to write
+        in order. names are written as is in the statements, quoted if needed"""
+    def to_json(self, indent: bool=False) -> str:
+        """the store as a JSON document: the entities with their identifiers and attribute
+        histories, and the epoch pointers. *indent* pretty prints it"""
+    @staticmethod
+    def from_json(document: str) -> PyEntityStore:
+        """the store described by a document returned by to_json(). the callbacks and table
+        mappings aren't serialized, they must be registered again"""
+    def to_msgpack(self) -> bytes:
+        """the store as to_json() document encoded with MessagePack"""
+    @staticmethod
+    def from_msgpack(bytes: bytes) -> PyEntityStore:
+        """the store encoded by to_msgpack(). like with from_json(), the callbacks and table
+        mappings must be registered again"""
+    def dump(self, path: str) -> None:
+        """write a versioned binary snapshot of the store, histories included, to the file at
+        *path*. the file is replaced once fully written, so a crash never leaves it truncated"""
+    @staticmethod
+    def restore(path: str) -> PyEntityStore:
+        """the store written by dump() to the file at *path*. like with from_json(), the callbacks
+        and table mappings must be registered again"""
+    def attach(self, entity: PyEntity) -> PyEntity:
+        """the entity of the store with the identifier of *entity*, like an unpickled one. it's
+        fetched with the loader if it's missing"""
+    def __deepcopy__(self, memo: dict) -> PyEntityStore: ...
+    def __reduce__(self) -> tuple[Callable[[bytes], PyEntityStore], tuple[bytes]]: ...
+    def sql_statements(self) -> list[tuple[str, list[list[Any]]]]:
+        """the changes made since the last flush as `(sql, params)` statements, to run with
+        cursor.executemany() before calling mark_flushed(). the new entities referenced by others
+        must be given a pk first"""
+    def mark_flushed(self) -> int:
+        """record the current state as persisted, following flushes only save the changes made from now on"""
+    def prefetch(self, entities: list[PyEntity], lookups: list[str]) -> None:
+        """resolve the foreign keys listed in *lookups* ("author", "author__publisher") for all
+        *entities* level by level, loading the missing related entities in batch"""
+    def register_model(self, model: str, attributes: list[tuple[str, str, Any]], unique: list[list[str]]=[]) -> None:
+        """declare the attributes of *model*, given like to instantiate_entity() with their default
+        value, and the sets of attributes whose values must be *unique* together"""
+    def model_schema(self, model: str) -> dict[str, Any]:
+        """the schema of *model* as a dict with the `(name, kind, default)` "attributes", the
+        "unique" attribute sets and the "field_types" of the typed attributes by name"""
+    def instantiate_model(self, model: str, pk: int | None=None, **values) -> PyEntity:
+        """instantiate an entity of the registered *model* with the keyword arguments as values,
+        the other attributes taking their default value. it's persisted if *pk* is given"""
+    def register_many_to_many(self, model: str, name: str, through: str, source_attribute: str, target_model: str, target_attribute: str) -> None:
+        """declare the many to many relation *name* of *model*, realized by the *through* model
+        entities holding the *source_attribute* and *target_attribute* foreign keys"""
+    def set_constraint_check(self, model: str, constraint: str, attributes: list[str], check: str) -> None:
+        """check the *constraint* ("unique", "foreign_key" or "check") of *model* on the given
+        *attributes*, or the check constraint named by the only one, on each write of their values
+        ("immediate") or on flush only ("deferred", the default), letting imports go through
+        inconsistent intermediate states. violations raise IntegrityError"""
+    def validate(self, model: str | None=None) -> dict[PyEntityIdentifier, dict[str, list[str]]]:
+        """the messages of the alive entities of *model*, or of every model, that aren't valid, by
+        identifier: `{identifier: {attribute: [message, ...]}}`, see PyEntity.full_clean()"""
+    def add_entity_validator(self, model: str, validator: Callable[[dict[str, Any]], None]) -> None:
+        """validate the entities of *model* as a whole, for the rules over several attributes:
+        *validator* is called with the dict of their values on flush and by validate(), raising
+        an exception to refuse them. its message is given under "__all__", unless it's a
+        ValidationError carrying the messages by attribute as `message_dict`"""
+    def enable_audit_log(self) -> None:
+        """record the values set from now on in an append only audit log, see audit_log()"""
+    def set_audit_context(self, who: str | None=None, when: Any=None) -> None:
+        """give *who* and *when*, a datetime, to the writes recorded from now on"""
+    def audit_log(self, identifier: PyEntityIdentifier | None=None, attribute: str | None=None) -> list[dict[str, Any]]:
+        """the recorded writes in order, of the entity *identifier* and its *attribute* if given, as
+        dicts with the "identifier", "attribute", "previous" and new "value", the "epoch" of the
+        write, and "who" made it "when", see set_audit_context()"""
+    def export_audit_log(self, path: str) -> int:
+        """write the audit log as CSV to *path*, with the model, pk and uuid of the written entities,
+        return the number of written records"""
+    def add_check_constraint(self, model: str, name: str, **kwargs) -> None:
+        """require the entities of *model* to match the keyword arguments, exact values or django
+        style lookups like filter(), checked like the other constraints under *name*:
+        `add_check_constraint("Book", "positive_pages", pages__gt=0)`. replace the check
+        constraint of this *name*"""
+    def set_version_attribute(self, model: str, attribute: str) -> None:
+        """lock the updates of *model* optimistically with its *attribute* version: flush()
+        increments it and the update callback must only update the row still holding the
+        previous version, returning the number of updated rows. StaleEntityError is raised if none"""
+    def set_related_ordering(self, model: str, attribute: str, ordering: str) -> None:
+        """order the entities of *model* referencing an entity with *attribute* by their *ordering*
+        attribute, descending if prefixed with "-". many to many relations are ordered by the
+        attribute of their through model, given a position when missing"""
+    def add_index(self, model: str, attribute: str, sorted: bool=False) -> None:
+        """index the values of *attribute* of the entities of *model*, so the filters on its exact
+        value or on a list of values (`__in`) look the entities up instead of scanning the model.
+        *sorted* indexes serve the range lookups (`__gt`, `__lte`...) and PyQuery.order_by() too"""
+    def add_membership(self, source: PyEntity, name: str, target: PyEntity, **attributes) -> PyEntity:
+        """link *source* to *target* through the relation *name*, the keyword arguments are the
+        attributes of the created through entity"""
+    def remove_membership(self, source: PyEntity, name: str, target: PyEntity) -> int:
+        """delete the through entities linking *source* and *target*, return their number"""
+    def memberships(self, source: PyEntity, name: str) -> list[PyEntity]:
+        """the through entities of the relation *name* of *source*"""
+    def many_to_many(self, source: PyEntity, name: str, **kwargs) -> list[PyEntity]:
+        """the entities related to *source* through the relation *name*, the keyword arguments
+        filter on the attributes of the through entities"""
+    def related(self, entity: PyEntity, model: str, attribute: str) -> list[PyEntity]:
+        """the entities of *model* whose foreign key *attribute* references *entity*"""
+    def delete(self, entity: PyEntity) -> int:
+        """delete *entity* at the current epoch, with the entities referencing it according to the
+        on_delete behavior of their foreign keys. return the number of deleted entities"""
+    def collect_garbage(self) -> int:
+        """remove the deleted entities no undo, checkpoint, branch or transaction can observe anymore,
+        return their number"""
+    def fork(self) -> PyBranch:
+        """fork the epoch timeline, see PyBranch"""
+    def compact(self, before_epoch: int) -> int:
+        """collapse the values written before *before_epoch* (initial values excepted),
+        return the number of dropped history entries"""
+    def current_epoch(self) -> int:
+        """the epoch values must be written at to be read back"""
+    def in_transaction(self) -> bool: ...
+    def clear(self) -> None:
+        """drop all the entities and reset the epochs, so the store can be reused"""
+    def merge(self, other: PyEntityStore, policy: str='fail', attributes_policy: dict[str, str] | None=None) -> None:
+        """import the entities of *other*, resolving differing attribute values with *policy*
+        ("ours", "theirs" or "fail"), optionally overridden per attribute name"""
+    def upsert(self, model: str, natural_key: list[str], values: dict[str, Any]) -> tuple[PyEntity, bool]:
+        """update the entity of *model* matching the *natural_key* attributes of *values*, like
+        PyEntity.update(), or create it, like instantiate_model() for the registered models. return
+        the entity and whether it was created"""
+    def child(self) -> PyEntityStoreOverlay:
+        """return a child store reading through this one, whose changes are applied on commit()"""
+    def hydrate(self, model: str, rows: list[dict[str, Any]], related: dict[str, str] | None=None) -> list[PyEntity]:
+        """instantiate the entities of *model* loaded from the database as *rows*, along with the
+        related entities given as nested dicts. *related* maps the foreign key lookups
+        ("author", "author__publisher") to their related model"""
+    def load(self, model: str, rows: list[dict[str, Any]], pk: str='id') -> list[PyEntity]:
+        """instantiate the persisted entities of *model* from the database *rows*, like the
+        QuerySet.values() ones, their *pk* key giving the identifier. registered models get their
+        declared attributes, see register_model()"""
+    def instantiate_many(self, model: str, rows: list[dict[str, Any]]) -> list[PyEntity]: ...
+
+
+class PyEntityStoreOverlay:
+    def get(self, identifier: PyEntityIdentifier) -> PyEntity: ...
+    def instantiate_entity(self, identifier: PyEntityIdentifier, attributes: list[PyAttributeDescriptor | tuple[str, str, Any]]) -> PyEntity: ...
+    def commit(self) -> None: ...
+    def discard(self) -> None: ...
+
+
+class PyTransaction:
+    def __enter__(self) -> int: ...
+    def __exit__(self, exc_type, exc_value, traceback) -> bool: ...
+
+
+class PyEpochScope:
+    """the block reading the store at an epoch, see PyEntityStore.at_epoch()"""
+    def __enter__(self) -> int: ...
+    def __exit__(self, exc_type, exc_value, traceback) -> bool: ...
+
+
+class PyBranch:
+    """a fork of the store epoch timeline: values set through it are only visible through it
+    until merge() writes them to the store or discard() drops them"""
+    @property
+    def epoch(self) -> int: ...
+    def get(self, entity: PyEntity, attribute: str) -> Any: ...
+    def set(self, entity: PyEntity, attribute: str, value: Any) -> None: ...
+    def merge(self) -> None: ...
+    def discard(self) -> None: ...
+
+
+class PyEntityStoreView:
+    """a read-only view of a store, answering with the values as they were at its epoch"""
+    @property
+    def epoch(self) -> int: ...
+    def get(self, identifier: PyEntityIdentifier) -> PyEntityView: ...
+    def filter(self, model: str, **kwargs) -> list[PyEntityView]: ...
+
+
+class PyEntityView:
+    def __hash__(self) -> int: ...
+    def get(self, attr_name: str) -> PyAttributeView: ...
+
+
+class PyAttributeView:
+    @property
+    def initial(self): ...
     @property
     def value(self): ...
+
+
+class PyAttribute:
     @property
     def initial(self): ...
+    @property
+    def value(self): ...
+    def set_value(self, value, epoch: int | None=None):
+        """set the value at *epoch*, or at the current epoch of the store if not given.
+        fail in a PyEntityStore.at_epoch() block, with TypeError if it isn't of the field type of
+        the attribute, and with IntegrityError, the value left unchanged, if it violates a
+        constraint checked immediately, see PyEntityStore.set_constraint_check()"""
+    def history(self) -> list[tuple[int, Any]]:
+        """return the `(epoch, value)` pairs this attribute went through, ordered by epoch"""
+    def value_at(self, epoch: int) -> Any:
+        """return the value of this attribute as it was at *epoch*"""
+
+
+class PyEntity:
+    """an entity of a store. it can be subclassed in python to add domain methods, the instances
+    being created from the store and the identifier of the entity:
+
+        class User(PyEntity):
+            def full_name(self):
+                return f"{self['first_name']} {self['last_name']}"
+
+        User(store, PyEntityIdentifier("User", 1)).full_name()"""
+    def __init__(self, entity_store: PyEntityStore, identifier: PyEntityIdentifier): ...
+    def __hash__(self) -> int: ...
+    def __getitem__(self, name: str) -> Any: ...
+    def __setitem__(self, name: str, value: Any) -> None: ...
+    def get(self, attr_name: str) -> PyAttribute: ...
+    def get_identifier(self) -> PyEntityIdentifier: ...
+    def full_clean(self) -> None:
+        """run the validators of the attributes and check the constraints of the entity, whatever
+        when they are checked, raise a ValidationError with the messages of every offending
+        attribute as `message_dict`, the constraints over several attributes under "__all__".
+        see PyEntityStore.validate()"""
+    def __reduce__(self) -> tuple[Callable[[bytes, PyEntityIdentifier], PyEntity], tuple[bytes, PyEntityIdentifier]]: ...
+    def __copy__(self) -> PyEntity: ...
+    def __deepcopy__(self, memo: dict) -> PyEntity: ...
+    @staticmethod
+    def detached(bytes: bytes, identifier: PyEntityIdentifier) -> PyEntity:
+        """the read-only copy of the entity *identifier* pickled in *bytes*, in a store of its own"""
+    def to_dict(self, epoch: int | None=None) -> dict[str, Any]:
+        """the values of the attributes by name, at *epoch* or the current ones, ordered by name"""
+    def update(self, values: dict[str, Any]) -> None:
+        """set the current values of the attributes given by name in *values*, like
+        PyAttribute.set_value(). nothing is set if one of them is unknown"""
+    def is_dirty(self) -> bool:
+        """true if any attribute was modified since the initial epoch"""
+    def changed_fields(self) -> list[str]:
+        """names of the attributes modified since the initial epoch"""
+    def revert_to(self, epoch: int) -> None:
+        """restore the values the attributes had at *epoch*"""
+    def rel(self, name: str, attribute: str | None=None) -> PyRelatedManager:
+        """the manager of the entities related through the many to many relation *name*, or, if
+        *attribute* is given, of the entities of the model *name* referencing this one with it"""
+
 
-    def set_value(self, value, epoch: int): ...
+class PyRelatedManager:
+    """the entities related to an entity through a many to many relation or a reverse foreign key,
+    with the surface of django's RelatedManager. see PyEntity.rel()"""
+    def all(self) -> list[PyEntity]: ...
+    def add(self, *entities: PyEntity, through_defaults: dict[str, Any] | None=None) -> None:
+        """relate the *entities* not related yet. *through_defaults* are the attributes of the
+        created through entities"""
+    def remove(self, *entities: PyEntity) -> None:
+        """unrelate the *entities*, by deleting their through entities or emptying their foreign key"""
+    def set(self, entities: list[PyEntity], through_defaults: dict[str, Any] | None=None) -> None:
+        """relate exactly the *entities*, see add() and remove()"""
+    def clear(self) -> None:
+        """unrelate all the entities"""
+
+
+class PyQuery:
+    """the entities of a model matching a filter, like a django QuerySet. it's evaluated again on
+    each use, see PyEntityStore.query()"""
+    def __iter__(self) -> Iterator[PyEntity]: ...
+    def __len__(self) -> int: ...
+    def __contains__(self, item: PyEntity) -> bool: ...
+    def all(self) -> list[PyEntity]: ...
+    def filter(self, **kwargs) -> PyQuery:
+        """the entities of this query also matching the keyword arguments"""
+    def order_by(self, ordering: str) -> PyQuery:
+        """the entities of this query ordered by their *ordering* attribute, descending if prefixed
+        with "-", the entities without value last"""
+
+
+class PyEntityIterator:
+    """the iterator over the entities of a store or a query"""
+    def __iter__(self) -> PyEntityIterator: ...
+    def __next__(self) -> PyEntity: ...
 
 
 class PyEntityIdentifier:
+    def __init__(self, model: str, pk: int | None=None): ...
+    def __hash__(self) -> int: ...
     def has_applied_pk(self) -> bool: ...
     def get_uuid(self) -> str: ...
     def get_model(self) -> str: ...
-    def get_applied_pk(self) -> int: ...
+    def get_applied_pk(self) -> int:
+        """the pk, fail with UnpersistedEntity if the entity has none yet"""
+    @staticmethod
+    def restore(model: str, pk: int | None, uuid: str) -> PyEntityIdentifier:
+        """the identifier with the given *uuid*, as pickled"""
+    def __reduce__(self) -> tuple[Callable[[str, int | None, str], PyEntityIdentifier], tuple[str, int | None, str]]: ...
 
 
-class PyEntity:
+class PyAttributeDescriptor:
+    """an attribute of the entities given to instantiate_entity(). its *field_type* ("int", "str",
+    "bool", "datetime" or "date") converts the values set and got through PyAttribute, any value
+    is stored as given by default. the values set are checked by the *validators*, called with
+    the value and raising an exception to refuse it, must be one of the *choices* if given and
+    must not be None unless *null*, nor strings longer than *max_length*, nor numbers out of the
+    *min_value* and *max_value* bounds, a ValidationError is raised otherwise. the entities
+    missing a value for a *null* False attribute can't be flushed"""
+    def __init__(self, name: str, kind: str, initial: Any, field_type: str='any', validators: list[Callable[[Any], None]]=[], choices: list[Any] | None=None, null: bool=True, max_length: int | None=None, min_value: int | None=None, max_value: int | None=None): ...
+    @property
+    def name(self) -> str: ...
 
-    def get(self, attr_name: str) -> PyAttribute:...
 
-class PyEntityStore:
-    def    get( self, identifier: PyEntityIdentifier) -> PyEntity: ...
-    def    filter( self, model: str, **kwargs) -> list[PyEntity]: ...
-    def    instantiate_entity(self, identifier: PyEntityIdentifier) -> PyEntity: ...
+class PySyncEntityStore:
+    """a store usable from several threads, like the thread pools of a django server. a limited key/value
+    facade: unlike PyEntityStore, it answers with identifiers and values rather than entities, the values
+    being given and returned as stored, whatever their field type, and it has no relations, transactions,
+    epochs nor hooks. the calls are serialized, without the GIL"""
+    def __len__(self) -> int: ...
+    def register_model(self, model: str, attributes: list[tuple[str, str, Any]], unique: list[list[str]]=[]) -> None:
+        """declare the attributes of *model*, see PyEntityStore.register_model()"""
+    def instantiate_model(self, model: str, pk: int | None=None, **values) -> PyEntityIdentifier:
+        """instantiate an entity of the registered *model* with the given values, return its identifier"""
+    def get(self, identifier: PyEntityIdentifier, attribute: str) -> Any:
+        """the current value of *attribute*"""
+    def set(self, identifier: PyEntityIdentifier, attribute: str, value: Any) -> None:
+        """set the current value of *attribute*"""
+    def values(self, identifier: PyEntityIdentifier) -> dict[str, Any]:
+        """the current values of the attributes of an entity, by name"""
+    def filter(self, model: str, **kwargs) -> list[PyEntityIdentifier]:
+        """the identifiers of the entities of *model* matching the keyword arguments, see
+        PyEntityStore.filter()"""
+    def delete(self, identifier: PyEntityIdentifier) -> int:
+        """delete an entity with the ones referencing it, return the number of deleted entities"""
+    def count(self, model: str) -> int:
+        """the number of alive entities of *model*"""
+
+
+class DatabaseValue:
+    """a value as stored by the attributes, built with Number(), String() or Null(). it's accepted
+    anywhere a value is, like the int, str and None it stands for"""
+    @property
+    def kind(self) -> str:
+        """the kind of the value: number, string, null or foreign_key"""
+    @property
+    def value(self) -> int | str | PyEntityIdentifier | None:
+        """the python value: an int, a str, None or an identifier"""
+    def __hash__(self) -> int: ...
 
 
-def create_database_value(t: str) -> Any: ...
-def repr_database_value(t: Any) -> str: ...
\ No newline at end of file
+def Number(value: int) -> DatabaseValue:
+    """the number *value*, see DatabaseValue"""
+def String(value: str) -> DatabaseValue:
+    """the string *value*, see DatabaseValue"""
+def Null() -> DatabaseValue:
+    """the missing value, see DatabaseValue"""
+def union(left: list[PyEntity], right: list[PyEntity]) -> list[PyEntity]:
+    """entities present in *left* or *right*, without duplicates"""
+def intersection(left: list[PyEntity], right: list[PyEntity]) -> list[PyEntity]:
+    """entities present in both *left* and *right*, without duplicates"""
+def difference(left: list[PyEntity], right: list[PyEntity]) -> list[PyEntity]:
+    """entities present in *left* but not in *right*, without duplicates"""
+def setup_logging(filter: str | None=None) -> None:
+    """forward the logs of the store to python's logging, to the loggers named after the rust
+    modules like "django_lightning_service.entity_store". *filter* selects them with the syntax
+    of RUST_LOG, like "django_lightning_service=debug", and defaults to RUST_LOG, then "warn".
+    the filter can be changed by calling it again"""
@@ -0,0 +1,129 @@
+"""
+register django models in a PyEntityStore, from the model classes or from their description
+as plain dicts:
+
+    {
+        "name": "Book",
+        "fields": [
+            {"name": "title", "kind": "physical", "default": "", "unique": True, "type": "str"},
+            {"name": "author", "kind": "foreign_key:Author:CASCADE", "default": None},
+        ],
+        "unique_together": [["title", "author"]],
+    }
+"""
+
+# django on_delete handlers the store doesn't support are approximated
+ON_DELETE = {
+    "CASCADE": "CASCADE",
+    "SET_NULL": "SET_NULL",
+    "PROTECT": "PROTECT",
+    "RESTRICT": "PROTECT",
+    "DO_NOTHING": "DO_NOTHING",
+}
+
+# the field types of the django fields the store converts the values of, see PyAttributeDescriptor
+FIELD_TYPES = {
+    "AutoField": "int",
+    "BigAutoField": "int",
+    "BigIntegerField": "int",
+    "IntegerField": "int",
+    "PositiveBigIntegerField": "int",
+    "PositiveIntegerField": "int",
+    "PositiveSmallIntegerField": "int",
+    "SmallIntegerField": "int",
+    "BooleanField": "bool",
+    "CharField": "str",
+    "EmailField": "str",
+    "SlugField": "str",
+    "TextField": "str",
+    "URLField": "str",
+    "DateTimeField": "datetime",
+    "DateField": "date",
+}
+
+
+def describe_model(model):
+    """the plain dict description of the django *model* class concrete fields, the pk excepted"""
+    meta = model._meta
+    fields = []
+    for field in meta.concrete_fields:
+        if field.primary_key:
+            continue
+        fields.append({
+            "name": field.name,
+            "kind": _field_kind(field),
+            "default": _field_default(field),
+            "unique": field.unique,
+            "type": "any" if field.is_relation else FIELD_TYPES.get(field.get_internal_type(), "any"),
+        })
+    unique_together = [list(fields_set) for fields_set in meta.unique_together]
+    for constraint in getattr(meta, "constraints", []):
+        # conditional and expression constraints aren't enforceable by attribute values
+        if getattr(constraint, "fields", None) and getattr(constraint, "condition", None) is None and type(constraint).__name__ == "UniqueConstraint":
+            unique_together.append(list(constraint.fields))
+    return {"name": meta.object_name, "fields": fields, "unique_together": unique_together}
+
+
+def register_django_model(store, model):
+    """register in *store* the attributes, defaults and uniqueness of the django *model* class,
+    or of its description as returned by describe_model(). return the model name"""
+    description = model if isinstance(model, dict) else describe_model(model)
+    fields = description["fields"]
+    attributes = [(field["name"], field.get("kind", "physical"), field.get("default"), field.get("type", "any")) for field in fields]
+    unique = [[field["name"]] for field in fields if field.get("unique")]
+    unique.extend(list(fields_set) for fields_set in description.get("unique_together", []))
+    store.register_model(description["name"], attributes, unique)
+    return description["name"]
+
+
+def register_django_persistence(store, model):
+    """persist the entities of the django *model* class on store.flush(): the new ones are
+    saved, the modified ones saved with update_fields limited to their modified attributes and
+    the deleted ones deleted. versioned models need their own update callback, which must
+    check the version, see PyEntityStore.set_version_attribute()"""
+    description = describe_model(model)
+    relations = {field["name"] for field in description["fields"] if field["kind"] != "physical"}
+
+    def values(entity, names):
+        result = {}
+        for name in names:
+            value = entity.get(name).value
+            if name in relations:
+                result[f"{name}_id"] = value.get_applied_pk() if value is not None else None
+            else:
+                result[name] = value
+        return result
+
+    def insert(entity):
+        instance = model(**values(entity, [field["name"] for field in description["fields"]]))
+        instance.save(force_insert=True)
+        return instance.pk
+
+    def update(entity, fields):
+        model(pk=entity.get_identifier().get_applied_pk(), **values(entity, fields)).save(update_fields=fields)
+
+    def delete(entity):
+        model(pk=entity.get_identifier().get_applied_pk()).delete()
+
+    store.register_persistence(description["name"], insert=insert, update=update, delete=delete)
+    return description["name"]
+
+
+def _field_kind(field):
+    if not field.is_relation:
+        return "physical"
+    related = field.related_model._meta.object_name
+    on_delete = ON_DELETE.get(field.remote_field.on_delete.__name__, "DO_NOTHING")
+    if field.one_to_one:
+        return f"one_to_one:{related}:{on_delete}"
+    return f"foreign_key:{related}:{on_delete}"
+
+
+def _field_default(field):
+    if not field.has_default():
+        return None
+    default = field.get_default()
+    # the values the store can't hold yet are left empty
+    if default is None or isinstance(default, (int, str)):
+        return default
+    return None
@@ -0,0 +1,12 @@
+"""
+flush a PyEntityStore along with the django database transactions
+"""
+
+
+def flush_on_commit(store, using=None, robust=False):
+    """defer the flushes of *store* to the commit of the enclosing transaction of the *using*
+    database: nothing is written if it rolls back, see PyEntityStore.set_on_commit().
+    outside of a transaction, django runs the flushes immediately"""
+    from django.db import transaction
+
+    store.set_on_commit(lambda flush: transaction.on_commit(flush, using=using, robust=robust))
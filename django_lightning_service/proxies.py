@@ -0,0 +1,118 @@
+"""
+classes giving an attribute access to the entities of a registered model, generated from its
+schema, see PyEntityStore.register_model():
+
+    Book = entity_class(store, "Book")
+    book = Book.create(title="dune")
+    book.pages = 412
+    [book] = Book.filter(title="dune")
+"""
+import datetime
+
+from .django_lightning_service import DatabaseValue, PyEntity, PyEntityIdentifier
+
+# the python types of the field types, see PyAttributeDescriptor
+FIELD_TYPES = {"int": int, "str": str, "bool": bool, "datetime": datetime.datetime, "date": datetime.date}
+
+
+def entity_class(store, model):
+    """a class wrapping the entities of the registered *model* of *store*, with a property per
+    attribute. the values are checked before being set: None or the type of the default value of
+    physical attributes, None, an entity or an identifier of the related model for foreign keys.
+    the typed attributes are checked by the store itself. the attributes may not be named like
+    the members of EntityProxy"""
+    schema = store.model_schema(model)
+    attributes = {name: (kind, default, schema["field_types"].get(name)) for name, kind, default in schema["attributes"]}
+    namespace = {"__slots__": ("entity",), "__doc__": f"the {model} entities of the store", "store": store, "model": model, "attributes": attributes}
+    annotations = {}
+    for name, (kind, default, field_type) in attributes.items():
+        if name == "entity" or hasattr(EntityProxy, name):
+            raise ValueError(f"the {name} attribute of {model} clashes with the entity class members")
+        namespace[name] = _attribute_property(name)
+        if field_type is not None:
+            annotations[name] = FIELD_TYPES[field_type]
+        else:
+            annotations[name] = type(default) if isinstance(default, (int, str)) else object
+    namespace["__annotations__"] = annotations
+    return type(model, (EntityProxy,), namespace)
+
+
+class EntityProxy:
+    """the base of the entity_class() classes"""
+
+    __slots__ = ()
+
+    def __init__(self, entity):
+        self.entity = entity
+
+    @classmethod
+    def create(cls, pk=None, **values):
+        """instantiate an entity with the given values, the other attributes taking their default"""
+        values = {name: cls.check(name, value) for name, value in values.items()}
+        return cls(cls.store.instantiate_model(cls.model, pk, **values))
+
+    @classmethod
+    def check(cls, name, value):
+        """the *value* to give to the attribute *name*, raise a TypeError if it doesn't fit"""
+        if name not in cls.attributes:
+            raise AttributeError(f"{cls.model} has no {name} attribute")
+        if isinstance(value, EntityProxy):
+            value = value.entity
+        elif isinstance(value, DatabaseValue):
+            value = value.value
+        kind, default, field_type = cls.attributes[name]
+        if field_type is None:
+            _check_value(cls.model, name, kind, default, value)
+        return value
+
+    @classmethod
+    def get(cls, pk):
+        return cls(cls.store.get(PyEntityIdentifier(cls.model, pk)))
+
+    @classmethod
+    def filter(cls, **kwargs):
+        return [cls(entity) for entity in cls.store.query(cls.model, **kwargs)]
+
+    @property
+    def pk(self):
+        identifier = self.entity.get_identifier()
+        return identifier.get_applied_pk() if identifier.has_applied_pk() else None
+
+    def __eq__(self, other):
+        if not isinstance(other, EntityProxy):
+            return NotImplemented
+        return self.entity == other.entity
+
+    def __hash__(self):
+        return hash(self.entity)
+
+    def __repr__(self):
+        return f"<{type(self).__name__} {self.entity!r}>"
+
+
+def _attribute_property(name):
+    def get(proxy):
+        return proxy.entity.get(name).value
+
+    def set(proxy, value):
+        proxy.entity.get(name).set_value(proxy.check(name, value))
+
+    return property(get, set, doc=f"the {name} attribute")
+
+
+def _check_value(model, name, kind, default, value):
+    related_model = kind.split(":")[1] if kind.startswith(("foreign_key:", "one_to_one:")) else None
+    if value is None:
+        return
+    if kind == "generic_foreign_key":
+        if not isinstance(value, (PyEntity, PyEntityIdentifier)):
+            raise TypeError(f"{model}.{name} must reference an entity, not {value!r}")
+    elif related_model is not None:
+        if isinstance(value, PyEntity):
+            value = value.get_identifier()
+        if not isinstance(value, PyEntityIdentifier) or value.get_model() != related_model:
+            raise TypeError(f"{model}.{name} must reference a {related_model} entity, not {value!r}")
+    elif kind == "physical" and isinstance(default, (int, str)) and type(value) is not type(default):
+        raise TypeError(f"{model}.{name} must be a {type(default).__name__}, not {value!r}")
+    elif not isinstance(value, (int, str)):
+        raise TypeError(f"{model}.{name} must be an int or a str, not {value!r}")
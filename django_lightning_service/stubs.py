@@ -0,0 +1,112 @@
+"""
+generate the type stubs of the extension module from its introspection: the exceptions,
+classes, with their methods and properties, and functions it defines, with their docstrings.
+the annotations of the existing stubs are kept, the members they miss are added untyped:
+
+    python -m django_lightning_service.stubs > django_lightning_service/django_lightning_service.pyi
+"""
+import ast
+import pathlib
+import sys
+
+from . import django_lightning_service as extension
+
+STUBS_PATH = pathlib.Path(__file__).with_name("django_lightning_service.pyi")
+
+# the special methods worth describing, the comparison and repr ones are left to object
+SPECIAL_METHODS = {"__init__", "__enter__", "__exit__", "__iter__", "__next__", "__len__", "__contains__", "__getitem__", "__setitem__", "__hash__", "__reduce__", "__copy__", "__deepcopy__"}
+
+
+def generate_stubs(module=extension, stubs_path=STUBS_PATH):
+    """the stubs of the extension *module*, reusing the annotations of the stubs at *stubs_path*"""
+    annotated = _annotated_definitions(stubs_path)
+    members = [(name, value) for name, value in vars(module).items() if not name.startswith("_")]
+    exceptions = [(name, value) for name, value in members if isinstance(value, type) and issubclass(value, BaseException)]
+    classes = [(name, value) for name, value in members if isinstance(value, type) and not issubclass(value, BaseException)]
+    functions = [(name, value) for name, value in members if callable(value) and not isinstance(value, type)]
+
+    lines = ["from typing import Any, Callable, Iterator", "", ""]
+    # the exceptions registered under another name than their own are aliases
+    lines.extend(f"class {name}({value.__base__.__name__}): ..." for name, value in exceptions if name == value.__name__)
+    lines.extend(f"{name} = {value.__name__}" for name, value in exceptions if name != value.__name__)
+    for name, value in classes:
+        lines.extend(["", ""])
+        lines.extend(_class_stub(name, value, annotated))
+    lines.extend(["", ""])
+    for name, value in functions:
+        lines.extend(_function_stub(name, value, annotated.get((None, name)), ""))
+    return "\n".join(lines) + "\n"
+
+
+def _class_stub(name, cls, annotated):
+    lines = [f"class {name}:"]
+    if cls.__doc__:
+        lines.append(_docstring(cls.__doc__, "    "))
+    if cls.__text_signature__ not in (None, "()"):
+        signature = cls.__text_signature__.replace("(", "(self, ", 1) if cls.__text_signature__ != "($self)" else "(self)"
+        lines.append(_definition("__init__", signature, annotated.get((name, "__init__")), "    "))
+    # the properties come in no particular order
+    properties = sorted((member_name, member) for member_name, member in vars(cls).items() if type(member).__name__ == "getset_descriptor")
+    for member_name, member in properties:
+        lines.append("    @property")
+        lines.append(_definition(member_name, "(self)", annotated.get((name, member_name)), "    ", member.__doc__))
+    for member_name, member in vars(cls).items():
+        if member_name.startswith("__") and member_name not in SPECIAL_METHODS:
+            continue
+        definition = annotated.get((name, member_name))
+        if type(member).__name__ == "getset_descriptor":
+            continue
+        elif isinstance(member, staticmethod):
+            lines.append("    @staticmethod")
+            lines.extend(_function_stub(member_name, member.__func__, definition, "    "))
+        elif member_name.startswith("__"):
+            # the docstrings of the slots are the generic ones of object
+            lines.append(_definition(member_name, "(self, *args, **kwargs)", definition, "    "))
+        elif callable(member):
+            lines.extend(_function_stub(member_name, member, definition, "    "))
+    if len(lines) == 1:
+        lines[0] += " ..."
+    return lines
+
+
+def _function_stub(name, function, definition, indent):
+    signature = (getattr(function, "__text_signature__", None) or "($self, *args, **kwargs)").replace("$self", "self").replace("$cls", "cls")
+    return [_definition(name, signature, definition, indent, function.__doc__)]
+
+
+def _definition(name, signature, definition, indent, doc=None):
+    """the stub of the function *name*, with the arguments and return annotation of the annotated
+    *definition* if any, its text *signature* otherwise"""
+    if definition is not None:
+        signature = f"({ast.unparse(definition.args)})"
+        if definition.returns is not None:
+            signature += f" -> {ast.unparse(definition.returns)}"
+    if doc:
+        return f"{indent}def {name}{signature}:\n{_docstring(doc, indent + '    ')}"
+    return f"{indent}def {name}{signature}: ..."
+
+
+def _docstring(doc, indent):
+    lines = doc.strip().splitlines()
+    return "\n".join([f'{indent}"""{lines[0]}'] + [f"{indent}{line}" if line else "" for line in lines[1:]]) + '"""'
+
+
+def _annotated_definitions(stubs_path):
+    """the functions defined by the stubs at *stubs_path*, by (class name or None, name)"""
+    try:
+        tree = ast.parse(pathlib.Path(stubs_path).read_text())
+    except FileNotFoundError:
+        return {}
+    definitions = {}
+    for node in tree.body:
+        if isinstance(node, ast.FunctionDef):
+            definitions[(None, node.name)] = node
+        elif isinstance(node, ast.ClassDef):
+            for member in node.body:
+                if isinstance(member, ast.FunctionDef):
+                    definitions[(node.name, member.name)] = member
+    return definitions
+
+
+if __name__ == "__main__":
+    sys.stdout.write(generate_stubs())
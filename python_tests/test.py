@@ -1,7 +1,16 @@
 
+import concurrent.futures
+import copy
+import datetime
+import json
+import logging
+import pickle
+import sys
+import weakref
+
 import pytest
 
-from django_lightning_service import PyEntityIdentifier, PyEntityStore, create_database_value, repr_database_value
+from django_lightning_service import stubs, AttributeNotFound, CustomError, DependencyCycleError, EntityNotFound, UnpersistedEntity, describe_model, entity_class, flush_on_commit, IntegrityError, register_django_model, register_django_persistence, ProtectedError, StaleEntityError, ValidationError, PyAttributeDescriptor, PyEntity, PyEntityIdentifier, PyEntityStore, PySyncEntityStore, DatabaseValue, Null, Number, String, union, intersection, difference, setup_logging
 
 
 @pytest.fixture()
@@ -11,7 +20,7 @@ def entity_store():
 
 def test_instantiate_then_get(entity_store):
     ident = PyEntityIdentifier("Model")
-    entity = entity_store.instantiate_entity(ident)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "default name"), ("age", "physical", 0)])
 
     entity.get('name').set_value('darius', 1)
     print(entity.get("name"))
@@ -22,12 +31,1786 @@ def test_instantiate_then_get(entity_store):
     assert entity == other_entity
 
 
+def test_to_dict(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30), ("group", "foreign_key:Group", ("Group", 7))])
+    entity.update({"name": "johnny", "age": 31})
+    assert entity.to_dict()["name"] == "johnny"
+    assert list(entity.to_dict()) == ["age", "group", "name"]
+    assert entity.to_dict()["group"].get_applied_pk() == 7
+    assert {name: value for name, value in entity.to_dict(0).items() if name != "group"} == {"age": 30, "name": "john"}
+
+    with pytest.raises(Exception):
+        entity.update({"age": 32, "email": "john@example.com"})
+    assert entity.get("age").value == 31
+
+
+def test_hash(entity_store):
+    ident = PyEntityIdentifier("User")
+    john = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+    doe = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "doe")])
+    assert len({john, entity_store.get(ident), doe}) == 2
+    assert {doe: "doe"}[entity_store.get(PyEntityIdentifier("User", 2))] == "doe"
+    assert PyEntityIdentifier("User", 2) in {PyEntityIdentifier("User", 2)}
+    assert ident == ident and ident != PyEntityIdentifier("User")
+    # the pk reaches the identifiers held before it's applied
+    entity_store.apply_pk(ident, 3)
+    assert ident == PyEntityIdentifier("User", 3) and john.get_identifier() == ident
+    assert PyEntityIdentifier("User", 3) in {ident, doe}
+
+
+def test_compare(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    doe = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "doe")])
+    assert john != doe
+    assert not john != entity_store.get(PyEntityIdentifier("User", 1))
+    assert john != "john" and not john == 1
+    assert john in [doe, john]
+    with pytest.raises(TypeError):
+        john < doe
+    view = entity_store.at(entity_store.current_epoch())
+    assert view.get(PyEntityIdentifier("User", 1)) != view.get(PyEntityIdentifier("User", 2))
+
+
+def test_repr(entity_store):
+    assert repr(entity_store) == "<PyEntityStore epoch 1: empty>"
+    group = PyEntityIdentifier("Group")
+    assert repr(group) == f"<PyEntityIdentifier Group uuid={group.get_uuid()}>"
+    assert str(group) == "Group[not persisted]"
+
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30), ("group", "foreign_key:Group", ("Group", 7))])
+    entity_store.instantiate_entity(group, [("name", "physical", "admins")])
+    assert repr(john) == '<PyEntity User pk=1: age=30, group=Group[pk=7], name="john">'
+    john.get("age").set_value(31)
+    assert repr(john) == '<PyEntity User pk=1 dirty: age=31, group=Group[pk=7], name="john">'
+    assert str(john) == "User[pk=1]"
+    assert repr(entity_store) == "<PyEntityStore epoch 1: Group=1, User=1>"
+
+
+def test_stubs_up_to_date():
+    # regenerate them with `make stubs`
+    assert stubs.generate_stubs() == stubs.STUBS_PATH.read_text()
+    generated = stubs.generate_stubs(stubs_path="missing.pyi")
+    assert "    def load_csv(self, model, path, column_mapping=None, pk=\"id\"):" in generated
+
+
+def test_iteration(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30)])
+    doe = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "doe"), ("age", "physical", 30)])
+    admins = entity_store.instantiate_entity(PyEntityIdentifier("Group", 1), [("name", "physical", "admins")])
+    assert len(entity_store) == 3
+    assert set(entity_store) == {john, doe, admins}
+    assert john in entity_store and PyEntityIdentifier("Group", 1) in entity_store
+    assert PyEntityIdentifier("Group", 2) not in entity_store and "john" not in entity_store
+
+    assert entity_store.count("User") == 2 and entity_store.count("Tag") == 0
+    query = entity_store.query("User", age=30)
+    assert len(query) == 2
+    assert [entity.get("name").value for entity in query.filter(name="doe")] == ["doe"]
+    entity_store.delete(doe)
+    assert len(entity_store) == 2 and entity_store.count("User") == 1
+    assert list(query) == [john] and doe not in query and john in query
+
+
+def test_filter_lookups(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30), ("email", "physical", None)])
+    doe = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "Doe"), ("age", "physical", 42), ("email", "physical", "doe@example.com")])
+    assert entity_store.filter("User", name="john") == [john]
+    assert entity_store.filter("User", age__gt=30) == [doe]
+    assert set(entity_store.filter("User", age__gte=30, age__lte=42)) == {john, doe}
+    assert entity_store.filter("User", name__exact="Doe", age__lt=50) == [doe]
+    assert entity_store.filter("User", age__in=[1, 30]) == [john]
+    assert entity_store.filter("User", email__isnull=True) == [john]
+    assert entity_store.filter("User", name__icontains="do") == [doe]
+    assert entity_store.filter("User", name__contains="do") == []
+    assert entity_store.filter("User", name__startswith="jo", name__endswith="hn") == [john]
+    assert list(entity_store.query("User").filter(age__gt=40)) == [doe]
+    with pytest.raises(Exception):
+        entity_store.filter("User", age__around=30)
+
+
+def test_entity_class(entity_store):
+    entity_store.register_model("Group", [("name", "physical", "")])
+    entity_store.register_model("User", [("name", "physical", ""), ("age", "physical", 0), ("group", "foreign_key:Group", None)])
+    Group = entity_class(entity_store, "Group")
+    User = entity_class(entity_store, "User")
+    assert User.__name__ == "User" and User.__annotations__ == {"name": str, "age": int, "group": object}
+
+    admins = Group.create(1, name="admins")
+    john = User.create(1, name="john", group=admins)
+    assert john.name == "john" and john.age == 0 and john.group == PyEntityIdentifier("Group", 1)
+    assert john.pk == 1 and User.get(1) == john and User.filter(name="john") == [john]
+    john.age = 31
+    assert john.entity.get("age").value == 31 and john.entity.is_dirty()
+
+    with pytest.raises(TypeError):
+        john.age = "31"
+    with pytest.raises(TypeError):
+        john.group = john
+    with pytest.raises(TypeError):
+        User.create(name=1)
+    with pytest.raises(AttributeError):
+        john.nickname = "jo"
+    with pytest.raises(AttributeError):
+        User.create(nickname="jo")
+    assert john.age == 31
+
+    entity_store.register_model("Event", [("at", "physical", None, "datetime")])
+    event = entity_class(entity_store, "Event").create(at=datetime.datetime(2024, 1, 1))
+    assert event.at == datetime.datetime(2024, 1, 1) and type(event).__annotations__ == {"at": datetime.datetime}
+    with pytest.raises(TypeError):
+        event.at = "2024-01-01"
+
+    entity_store.register_model("Proxy", [("entity", "physical", None)])
+    with pytest.raises(ValueError):
+        entity_class(entity_store, "Proxy")
+
+
+def test_instantiate_with_descriptors(entity_store):
+    ident = PyEntityIdentifier("Book")
+    entity = entity_store.instantiate_entity(ident, [
+        PyAttributeDescriptor("title", "physical", "untitled"),
+        ("pages", "physical", 12),
+    ])
+
+    assert entity.get("title").initial == "untitled"
+    assert entity.get("pages").value == 12
+    with pytest.raises(Exception):
+        entity.get("name")
+
+    with pytest.raises(ValueError):
+        PyAttributeDescriptor("title", "oops", "untitled")
+
+
+def test_field_types(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [
+        PyAttributeDescriptor("joined", "physical", datetime.datetime(2024, 1, 2, 3, 4), "datetime"),
+        ("birthday", "physical", None, "date"),
+        ("active", "physical", True, "bool"),
+        ("age", "physical", 30, "int"),
+        ("name", "physical", "john"),
+    ])
+    assert entity.get("joined").value == datetime.datetime(2024, 1, 2, 3, 4)
+    assert entity.get("active").initial is True
+    entity.get("birthday").set_value(datetime.date(1990, 5, 6))
+    entity.get("active").set_value(False)
+    assert entity.get("birthday").value == datetime.date(1990, 5, 6)
+    assert entity.get("active").value is False
+    assert entity.to_dict()["birthday"] == datetime.date(1990, 5, 6)
+    assert entity.get("active").history() == [(0, True), (1, False)]
+
+    with pytest.raises(TypeError):
+        entity.get("age").set_value("31")
+    with pytest.raises(TypeError):
+        entity.get("age").set_value(True)
+    with pytest.raises(TypeError):
+        entity.get("birthday").set_value(datetime.datetime(1990, 5, 6))
+    with pytest.raises(TypeError):
+        entity.update({"active": 1})
+    entity.get("age").set_value(None)
+    entity.get("name").set_value(12)
+    with pytest.raises(ValueError):
+        PyAttributeDescriptor("age", "physical", 0, "float")
+
+    entity_store.register_model("Event", [("at", "physical", None, "datetime")])
+    assert entity_store.model_schema("Event")["field_types"] == {"at": "datetime"}
+    event = entity_store.instantiate_model("Event", at=datetime.datetime(2024, 1, 1))
+    assert event.get("at").value == datetime.datetime(2024, 1, 1)
+    with pytest.raises(TypeError):
+        entity_store.instantiate_model("Event", at="2024-01-01")
+
+
+def test_at_epoch(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    with entity_store.transaction():
+        entity.get("name").set_value("doe")
+    current = entity_store.current_epoch()
+
+    with entity_store.at_epoch(1) as epoch:
+        assert epoch == 1
+        assert entity.get("name").value == "john"
+        assert entity_store.filter("User", name="john") == [entity]
+        with pytest.raises(Exception):
+            entity.get("name").set_value("jane")
+    assert entity_store.current_epoch() == current
+    assert entity.get("name").value == "doe"
+
+    with pytest.raises(KeyError):
+        with entity_store.at_epoch(1):
+            raise KeyError("name")
+    assert entity_store.current_epoch() == current
+    entity.get("name").set_value("jane")
+    assert entity.get("name").value == "jane"
+
+
+def test_entity_subclass(entity_store):
+    class User(PyEntity):
+        def full_name(self):
+            return f"{self['first_name']} {self['last_name']}"
+
+        def rename(self, last_name):
+            self["last_name"] = last_name
+
+    identifier = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(identifier, [("first_name", "physical", "john"), ("last_name", "physical", "doe")])
+    user = User(entity_store, identifier)
+    assert isinstance(user, PyEntity) and user == entity and hash(user) == hash(entity)
+    assert user.full_name() == "john doe"
+    user.rename("smith")
+    assert entity.get("last_name").value == "smith" and entity.is_dirty()
+    assert repr(user).startswith("<User User pk=1 dirty:")
+    with pytest.raises(Exception):
+        User(entity_store, PyEntityIdentifier("User", 2))
+
+
+def test_value_setter(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30, "int")])
+    entity.get("name").value = "doe"
+    assert entity.get("name").value == "doe" and entity.get("name").history() == [(0, "john"), (1, "doe")]
+
+    with entity_store.transaction():
+        epoch = entity_store.current_epoch()
+        entity.get("name").value = "jane"
+    assert entity.get("name").history() == [(0, "john"), (1, "doe"), (epoch, "jane")]
+
+    with pytest.raises(TypeError):
+        entity.get("age").value = "31"
+    with pytest.raises(AttributeError):
+        del entity.get("age").value
+    assert entity.get("age").value == 30
+
+
+def test_exceptions(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "john")])
+    with pytest.raises(AttributeNotFound, match="no attribute age"):
+        entity.get("age")
+    with pytest.raises(KeyError):
+        entity["age"]
+    with pytest.raises(EntityNotFound):
+        entity_store.get(PyEntityIdentifier("User", 2))
+    assert CustomError is EntityNotFound and issubclass(EntityNotFound, LookupError)
+    with pytest.raises(UnpersistedEntity):
+        entity.get_identifier().get_applied_pk()
+    with pytest.raises(RuntimeError):
+        entity_store.commit()
+    with pytest.raises(ValueError, match="Book isn't registered"):
+        entity_store.instantiate_model("Book")
+
+
+def test_attribute_history(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    entity.get("name").set_value("doe", 1)
+    entity.get("name").set_value("jane", 3)
+
+    assert entity.get("name").history() == [(0, "john"), (1, "doe"), (3, "jane")]
+    assert entity.get("name").value_at(0) == "john"
+    assert entity.get("name").value_at(2) == "doe"
+
+
+def test_compact(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    for epoch in range(1, 5):
+        entity.get("name").set_value(f"name {epoch}", epoch)
+
+    assert entity_store.compact(4) == 2
+    assert entity.get("name").history() == [(0, "john"), (3, "name 3"), (4, "name 4")]
+
+
+def test_instantiate_many(entity_store):
+    entities = entity_store.instantiate_many("User", [{"name": f"user {i}", "age": i} for i in range(50)])
+
+    assert len(entities) == 50
+    assert entities[12].get("age").value == 12
+    assert entities[12].get("name").initial == "user 12"
+
+
+def test_hydrate(entity_store):
+    publisher = {"pk": 5, "name": "allen & unwin"}
+    books = entity_store.hydrate("Book", [
+        {"pk": 1, "title": "the hobbit", "author": {"pk": 3, "name": "tolkien", "publisher": publisher}},
+        {"pk": 2, "title": "silmarillion", "author": {"pk": 3, "name": "tolkien", "publisher": 5}},
+        {"pk": 4, "title": "anonymous", "author": None},
+    ], related={"author": "Author", "author__publisher": "Publisher"})
+
+    author = entity_store.get(PyEntityIdentifier("Author", 3))
+    assert entity_store.get_related(books[0], "author") == author
+    assert entity_store.get_related(books[1], "author") == author
+    assert entity_store.get_related(books[2], "author") is None
+    assert entity_store.get_related(author, "publisher").get("name").value == "allen & unwin"
+    assert entity_store.get(PyEntityIdentifier("Book", 2)).get("title").value == "silmarillion"
+
+
+def test_apply_pk(entity_store):
+    ident = PyEntityIdentifier("User")
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+
+    entity_store.apply_pk(ident, 42)
+    assert entity_store.get(PyEntityIdentifier("User", 42)) == entity
+
+
+def test_upsert(entity_store):
+    john, created = entity_store.upsert("User", ["email"], {"email": "john@example.com", "name": "john"})
+    assert created
+    same, created = entity_store.upsert("User", ["email"], {"email": "john@example.com", "name": "johnny"})
+    assert not created
+    assert same == john
+    assert john.get("name").value == "johnny"
+
+    events = []
+    entity_store.subscribe("attribute_changed", events.append)
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    book, created = entity_store.upsert("Book", ["title"], {"title": "dune"})
+    assert created and book["pages"] == 0
+    entity_store.upsert("Book", ["title"], {"title": "dune", "pages": 412})
+    assert sorted((event["attribute"], event["previous"], event["value"]) for event in events) == [("pages", 0, 412), ("title", "dune", "dune")]
+    with pytest.raises(AttributeNotFound):
+        entity_store.upsert("Book", ["title"], {"title": "dune", "pages": 7, "isbn": "x"})
+    assert book["pages"] == 412
+
+
+def test_in_bulk(entity_store):
+    users = [entity_store.instantiate_entity(PyEntityIdentifier("User", pk), [("name", "physical", "john")]) for pk in (1, 2, 3)]
+
+    result = entity_store.in_bulk("User", [1, 3, 4])
+    assert result == {1: users[0], 3: users[2]}
+
+
+def test_filter_related(entity_store):
+    entity_store.instantiate_entity(PyEntityIdentifier("Customer", 1), [("country", "physical", "FR")])
+    entity_store.instantiate_entity(PyEntityIdentifier("Customer", 2), [("country", "physical", "US")])
+    orders = entity_store.instantiate_many("Order", [{"customer": 1}, {"customer": 2}, {"customer": 1}])
+
+    assert entity_store.filter_related("Order", "customer", "Customer", "country", "FR") == [orders[0], orders[2]]
+
+
+def test_latest_earliest(entity_store):
+    users = entity_store.instantiate_many("User", [{"age": 5}, {"age": 12}, {"age": 3}])
+
+    assert entity_store.latest("User", "age") == users[1]
+    assert entity_store.earliest("User", "age") == users[2]
+    assert entity_store.latest("Book", "age") is None
+
+
+def test_all(entity_store):
+    users = entity_store.instantiate_many("User", [{"name": "john"}, {"name": "doe"}])
+    book = entity_store.instantiate_entity(PyEntityIdentifier("Book"), [("title", "physical", "dune")])
+
+    assert entity_store.all("User") == users
+    assert entity_store.all("Book") == [book]
+    assert entity_store.all("Publisher") == []
+    assert len(entity_store.all()) == 3
+
+
+def test_stats(entity_store):
+    entity_store.instantiate_many("User", [{"name": "john"}, {"name": "doe"}])
+
+    stats = entity_store.stats()
+    assert stats["entities_per_model"] == {"User": 2}
+    assert stats["history_entries"] == 2
+    assert stats["uuid_index_size"] == 2
+    assert stats["pk_index_size"] == 0
+    assert stats["approximate_memory"] > 0
+
+
+def test_set_operations(entity_store):
+    users = entity_store.instantiate_many("User", [{"age": age} for age in range(4)])
+
+    left = [users[0], users[1], users[2]]
+    right = [users[2], users[3]]
+    assert union(left, right) == users
+    assert intersection(left, right) == [users[2]]
+    assert difference(left, right) == [users[0], users[1]]
+
+
+def test_transaction(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+
+    epoch = entity_store.begin()
+    assert entity_store.in_transaction()
+    entity.get("name").set_value("doe", epoch)
+    entity_store.commit()
+    assert not entity_store.in_transaction()
+    assert entity.get("name").value == "doe"
+
+    epoch = entity_store.begin()
+    entity.get("name").set_value("jane", epoch)
+    created = PyEntityIdentifier("User", 2)
+    entity_store.instantiate_entity(created, [("name", "physical", "max")])
+    entity_store.rollback()
+    assert entity.get("name").value == "doe"
+    with pytest.raises(Exception):
+        entity_store.get(created)
+    with pytest.raises(Exception):
+        entity_store.rollback()
+
+
+def test_transaction_context_manager(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+
+    with entity_store.transaction() as epoch:
+        entity.get("name").set_value("doe", epoch)
+    assert not entity_store.in_transaction()
+    assert entity.get("name").value == "doe"
+
+    with pytest.raises(KeyError):
+        with entity_store.transaction() as epoch:
+            entity.get("name").set_value("jane", epoch)
+            raise KeyError("oops")
+    assert not entity_store.in_transaction()
+    assert entity.get("name").value == "doe"
+
+
+def test_undo_redo(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    with entity_store.transaction() as epoch:
+        entity.get("name").set_value("doe", epoch)
+
+    assert entity_store.undo()
+    assert entity.get("name").value == "john"
+    assert not entity_store.undo()
+    assert entity_store.redo()
+    assert entity.get("name").value == "doe"
+    assert not entity_store.redo()
+
+
+def test_set_value_at_current_epoch(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+
+    with entity_store.transaction():
+        entity.get("name").set_value("doe")
+        assert entity.get("name").value == "doe"
+    assert entity.get("name").initial == "john"
+
+    with pytest.raises(KeyError):
+        with entity_store.transaction():
+            entity.get("name").set_value("jane")
+            raise KeyError("oops")
+    assert entity.get("name").value == "doe"
+
+
+def test_store_view_at_epoch(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+    with entity_store.transaction():
+        entity.get("name").set_value("doe")
+
+    view = entity_store.at(0)
+    assert view.epoch == 0
+    assert view.get(ident).get("name").value == "john"
+    assert len(view.filter("User", name="john")) == 1
+    assert view.filter("User", name="doe") == []
+    assert entity.get("name").value == "doe"
+    assert len(entity_store.at(entity_store.current_epoch()).filter("User", name="doe")) == 1
+
+
+def test_changes_since(entity_store):
+    loaded = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 20)])
+    with entity_store.transaction():
+        loaded.get("age").set_value(21)
+        created = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "doe")])
+
+    changes = entity_store.changes_since(0)
+    assert changes == [(loaded, False, ["age"]), (created, True, ["name"])]
+
+
+def test_dirty_tracking(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 20)])
+    assert not entity.is_dirty()
+    assert entity.changed_fields() == []
+
+    entity.get("age").set_value(21)
+    assert entity.is_dirty()
+    assert entity.changed_fields() == ["age"]
+
+
+def test_revert_to(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 20)])
+    other = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "jane")])
+    with entity_store.transaction():
+        entity.get("age").set_value(21)
+        entity.get("name").set_value("doe")
+        other.get("name").set_value("doe")
+        entity.revert_to(0)
+
+    assert entity.get("age").value == 20
+    assert entity.get("name").value == "john"
+    assert other.get("name").value == "doe"
+
+
+def test_checkpoints(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    epoch = entity_store.checkpoint("loaded")
+    assert entity_store.checkpoint_epoch("loaded") == epoch
+
+    entity.get("name").set_value("doe")
+    assert entity_store.changes_since_checkpoint("loaded") == [(entity, False, ["name"])]
+
+    entity_store.rollback_to_checkpoint("loaded")
+    assert entity.get("name").value == "john"
+    with pytest.raises(Exception):
+        entity_store.checkpoint_epoch("oops")
+
+
+def test_none_values(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", None)])
+    assert entity.get("name").value is None
+    entity.get("name").set_value("john")
+    assert entity.get("name").value == "john"
+    entity.get("name").set_value(None)
+    assert entity.get("name").value is None
+
+
+def test_branches(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    first = entity_store.fork()
+    second = entity_store.fork()
+    first.set(entity, "name", "first")
+    second.set(entity, "name", "second")
+
+    assert first.get(entity, "name") == "first"
+    assert second.get(entity, "name") == "second"
+    assert entity.get("name").value == "john"
+
+    second.discard()
+    with pytest.raises(Exception):
+        second.get(entity, "name")
+    first.merge()
+    assert entity.get("name").value == "first"
+
+
+def test_delete(entity_store):
+    identifier = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(identifier, [("name", "physical", "john")])
+    epoch = entity_store.current_epoch()
+    with entity_store.transaction():
+        entity_store.delete(entity)
+
+    with pytest.raises(Exception):
+        entity_store.get(identifier)
+    assert entity_store.all("User") == []
+    assert entity_store.at(epoch).get(identifier).get("name").value == "john"
+    assert entity_store.collect_garbage() == 0
+
+    entity_store.undo()
+    assert entity_store.get(identifier) == entity
+
+
+def test_foreign_key(entity_store):
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group", 7), [("name", "physical", "admins")])
+    by_pk = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", 7)])
+    by_entity = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", group)])
+    empty = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", None)])
+
+    assert entity_store.get_related(by_pk, "group") == group
+    assert entity_store.get_related(by_entity, "group") == group
+    assert entity_store.get_related(empty, "group") is None
+    assert by_entity.get("group").value.get_model() == "Group"
+
+    empty.get("group").set_value(group)
+    assert entity_store.get_related(empty, "group") == group
+
+
+def test_related_loader(entity_store):
+    calls = []
+
+    def loader(model, pk):
+        calls.append((model, pk))
+        if pk == 7:
+            return [("name", "physical", "admins")]
+        return None
+
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", 7)])
+    jane = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", 8)])
+    with pytest.raises(Exception):
+        entity_store.get_related(john, "group")
+
+    entity_store.set_loader(loader)
+    group = entity_store.get_related(john, "group")
+    assert group.get("name").value == "admins"
+    assert entity_store.get(PyEntityIdentifier("Group", 7)) == group
+    assert entity_store.get_related(john, "group") == group
+    with pytest.raises(Exception):
+        entity_store.get_related(jane, "group")
+    assert calls == [("Group", 7), ("Group", 8)]
+
+
+def test_prefetch(entity_store):
+    calls = []
+
+    def batch_loader(model, pks):
+        calls.append((model, pks))
+        if model == "Book":
+            return {pk: [("author", "foreign_key:Author", pk * 10)] for pk in pks}
+        return {pk: [("name", "physical", f"author {pk}")] for pk in pks if pk != 30}
+
+    entity_store.set_loader(None, batch_loader)
+    reviews = [
+        entity_store.instantiate_entity(PyEntityIdentifier("Review"), [("book", "foreign_key:Book", pk)])
+        for pk in (1, 2, 1, 3)
+    ]
+    entity_store.prefetch(reviews, ["book__author"])
+    assert calls == [("Book", [1, 2, 3]), ("Author", [10, 20, 30])]
+    assert entity_store.get(PyEntityIdentifier("Author", 20)).get("name").value == "author 20"
+
+    entity_store.prefetch(reviews, ["book"])
+    assert len(calls) == 2
+
+
+def test_on_delete(entity_store):
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group", 1), [("name", "physical", "admins")])
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group:CASCADE", 1)])
+    invite = entity_store.instantiate_entity(PyEntityIdentifier("Invite"), [("group", "foreign_key:Group:SET_NULL", group)])
+    badge = entity_store.instantiate_entity(PyEntityIdentifier("Badge"), [("user", "foreign_key:User:PROTECT", john)])
+
+    with pytest.raises(ProtectedError):
+        entity_store.delete(group)
+    assert entity_store.delete(badge) == 1
+    assert entity_store.delete(group) == 2
+    assert entity_store.all("User") == []
+    assert invite.get("group").value is None
+
+
+def test_many_to_many_through(entity_store):
+    entity_store.register_many_to_many("Group", "members", "Membership", "group", "User", "user")
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group", 1), [("name", "physical", "admins")])
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    jane = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "jane")])
+    membership = entity_store.add_membership(group, "members", john, role="owner")
+    entity_store.add_membership(group, "members", jane, role="member")
+
+    assert membership.get("role").value == "owner"
+    assert entity_store.many_to_many(group, "members") == [john, jane]
+    assert entity_store.many_to_many(group, "members", role="owner") == [john]
+    assert entity_store.memberships(group, "members")[0] == membership
+
+    assert entity_store.remove_membership(group, "members", john) == 1
+    assert entity_store.many_to_many(group, "members") == [jane]
+
+
+def test_generic_foreign_key(entity_store):
+    post = entity_store.instantiate_entity(PyEntityIdentifier("Post", 1), [("title", "physical", "hello")])
+    photo = entity_store.instantiate_entity(PyEntityIdentifier("Photo", 1), [("title", "physical", "hello")])
+    on_post = entity_store.instantiate_entity(PyEntityIdentifier("Comment"), [("content_object", "generic_foreign_key", ("Post", 1))])
+    on_photo = entity_store.instantiate_entity(PyEntityIdentifier("Comment"), [("content_object", "generic_foreign_key", photo)])
+
+    assert entity_store.get_related(on_post, "content_object") == post
+    assert entity_store.get_related(on_photo, "content_object") == photo
+    assert entity_store.related(post, "Comment", "content_object") == [on_post]
+    assert entity_store.filter_related("Comment", "content_object", "Photo", "title", "hello") == [on_photo]
+
+
+def test_related_manager(entity_store):
+    entity_store.register_many_to_many("Post", "tags", "PostTag", "post", "Tag", "tag")
+    post = entity_store.instantiate_entity(PyEntityIdentifier("Post", 1), [("title", "physical", "hello")])
+    rust, python, django = [entity_store.instantiate_entity(PyEntityIdentifier("Tag", pk), [("name", "physical", name)]) for pk, name in enumerate(["rust", "python", "django"])]
+    tags = post.rel("tags")
+
+    tags.add(rust, python, through_defaults={"weight": 2})
+    tags.add(rust)
+    assert tags.all() == [rust, python]
+    assert entity_store.memberships(post, "tags")[0].get("weight").value == 2
+    tags.remove(rust)
+    assert tags.all() == [python]
+    tags.set([python, django])
+    assert tags.all() == [python, django]
+    tags.clear()
+    assert tags.all() == []
+    with pytest.raises(Exception):
+        post.rel("unknown")
+
+    comment = entity_store.instantiate_entity(PyEntityIdentifier("Comment"), [("post", "foreign_key:Post", None)])
+    comments = post.rel("Comment", "post")
+    comments.add(comment)
+    assert comments.all() == [comment]
+    assert entity_store.get_related(comment, "post") == post
+    comments.clear()
+    assert comment.get("post").value is None
+
+
+def test_related_ordering(entity_store):
+    entity_store.register_many_to_many("Post", "tags", "PostTag", "post", "Tag", "tag")
+    entity_store.set_related_ordering("PostTag", "post", "position")
+    post = entity_store.instantiate_entity(PyEntityIdentifier("Post", 1), [("title", "physical", "hello")])
+    rust, python, django = [entity_store.instantiate_entity(PyEntityIdentifier("Tag", pk), [("name", "physical", name)]) for pk, name in enumerate(["rust", "python", "django"])]
+    tags = post.rel("tags")
+    tags.add(rust, python)
+    tags.add(django, through_defaults={"position": -1})
+    assert tags.all() == [django, rust, python]
+
+    entity_store.set_related_ordering("Comment", "post", "-rank")
+    low = entity_store.instantiate_entity(PyEntityIdentifier("Comment"), [("post", "foreign_key:Post", post), ("rank", "physical", 1)])
+    high = entity_store.instantiate_entity(PyEntityIdentifier("Comment"), [("post", "foreign_key:Post", post), ("rank", "physical", 5)])
+    assert post.rel("Comment", "post").all() == [high, low]
+
+
+def test_flush(entity_store):
+    calls = []
+
+    def insert(entity):
+        calls.append(("insert", entity.get("name").value))
+        return 10 + len(calls)
+
+    entity_store.register_persistence(
+        "User",
+        insert=insert,
+        update=lambda entity, fields: calls.append(("update", fields)),
+        delete=lambda entity: calls.append(("delete", entity.get("name").value)),
+    )
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    jane = entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "jane")])
+    created = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "doe")])
+    john.get("name").set_value("johnny")
+    entity_store.delete(jane)
+
+    assert entity_store.flush() == {"inserted": 1, "updated": 1, "deleted": 1}
+    assert calls == [("insert", "doe"), ("update", ["name"]), ("delete", "jane")]
+    assert entity_store.get(PyEntityIdentifier("User", 11)) == created
+    assert entity_store.flush() == {"inserted": 0, "updated": 0, "deleted": 0}
+
+    entity_store.instantiate_entity(PyEntityIdentifier("Group"), [("name", "physical", "admins")])
+    with pytest.raises(Exception):
+        entity_store.flush()
+
+
+def test_flush_dependency_order(entity_store):
+    inserted = []
+
+    def insert(entity):
+        inserted.append(entity)
+        return len(inserted)
+
+    for model in ["Company", "Customer"]:
+        entity_store.register_persistence(model, insert=insert)
+    customer = entity_store.instantiate_entity(PyEntityIdentifier("Customer"), [("company", "foreign_key:Company", None)])
+    company = entity_store.instantiate_entity(PyEntityIdentifier("Company"), [("owner", "foreign_key:Customer", None)])
+    customer.get("company").set_value(company)
+    entity_store.flush()
+    assert inserted == [company, customer]
+    assert customer.get("company").value.get_applied_pk() == 1
+    assert entity_store.get(PyEntityIdentifier("Company", 1)) == company
+
+    other = entity_store.instantiate_entity(PyEntityIdentifier("Customer"), [("company", "foreign_key:Company", None)])
+    owned = entity_store.instantiate_entity(PyEntityIdentifier("Company"), [("owner", "foreign_key:Customer", other)])
+    other.get("company").set_value(owned)
+    with pytest.raises(DependencyCycleError):
+        entity_store.flush()
+
+
+def test_flush_version(entity_store):
+    rows = {1: {"name": "john", "version": 1}}
+
+    def update(entity, fields):
+        row = rows[1]
+        if row["version"] != entity.get("version").value - 1:
+            return 0
+        row.update({field: entity.get(field).value for field in fields})
+        return 1
+
+    entity_store.register_persistence("User", update=update)
+    entity_store.set_version_attribute("User", "version")
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("version", "physical", 1)])
+    john.get("name").set_value("johnny")
+    entity_store.flush()
+    assert rows[1] == {"name": "johnny", "version": 2}
+    assert john.get("version").value == 2
+
+    rows[1]["version"] = 3
+    john.get("name").set_value("jo")
+    with pytest.raises(StaleEntityError):
+        entity_store.flush()
+    assert rows[1] == {"name": "johnny", "version": 3}
+    assert john.get("version").value == 2
+
+
+def test_flush_on_commit(entity_store):
+    callbacks = []
+    inserted = []
+    entity_store.register_persistence("User", insert=lambda entity: inserted.append(entity) or len(inserted))
+    entity_store.set_on_commit(callbacks.append)
+
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "john")])
+    assert entity_store.flush() is None
+    assert inserted == []
+    # the transaction rolled back, its callbacks are dropped
+    callbacks.clear()
+    assert entity_store.flush() is None
+    assert [callback() for callback in callbacks] == [{"inserted": 1, "updated": 0, "deleted": 0}]
+    assert inserted == [john]
+
+    entity_store.set_on_commit(None)
+    assert entity_store.flush() == {"inserted": 0, "updated": 0, "deleted": 0}
+
+
+def test_flush_on_commit_django(entity_store):
+    committed = []
+    transaction = type(sys)("django.db.transaction")
+    transaction.on_commit = lambda callback, using=None, robust=False: committed.append((using, callback()))
+    sys.modules["django"] = type(sys)("django")
+    sys.modules["django.db"] = type(sys)("django.db")
+    sys.modules["django.db"].transaction = transaction
+    try:
+        flush_on_commit(entity_store, using="default")
+    finally:
+        del sys.modules["django"], sys.modules["django.db"]
+
+    entity_store.register_persistence("User", insert=lambda entity: 1)
+    entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "john")])
+    assert entity_store.flush() is None
+    assert committed == [("default", {"inserted": 1, "updated": 0, "deleted": 0})]
+
+def test_hooks(entity_store):
+    calls = []
+
+    def hook(event):
+        def callback(entity, fields):
+            calls.append((event, entity.get("name").value, fields))
+        return callback
+
+    for event in ("created", "changed", "deleted", "flushed"):
+        entity_store.connect(event, hook(event))
+    with pytest.raises(ValueError):
+        entity_store.connect("saved", hook("saved"))
+    entity_store.register_persistence("User", insert=lambda entity: 1, update=lambda entity, fields: None, delete=lambda entity: None)
+
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "john"), ("age", "physical", 30)])
+    assert calls == [("created", "john", ["age", "name"])]
+    entity_store.flush()
+    john.get("name").set_value("johnny")
+    entity_store.flush()
+    entity_store.delete(john)
+    entity_store.flush()
+    assert calls[1:] == [
+        ("flushed", "john", ["age", "name"]),
+        ("changed", "johnny", ["name"]),
+        ("flushed", "johnny", ["name"]),
+        ("deleted", "johnny", []),
+        ("flushed", "johnny", []),
+    ]
+
+    changed = hook("changed")
+    entity_store.connect("changed", changed)
+    assert entity_store.disconnect("changed", changed)
+    assert not entity_store.disconnect("changed", changed)
+
+
+def test_flush_bulk_insert(entity_store):
+    batches = []
+
+    def bulk_insert(model):
+        def insert(entities):
+            batches.append((model, len(entities)))
+            return list(range(100 * len(batches), 100 * len(batches) + len(entities)))
+        return insert
+
+    entity_store.register_persistence("Company", bulk_insert=bulk_insert("Company"), batch_size=2)
+    entity_store.register_persistence("Customer", bulk_insert=bulk_insert("Customer"), batch_size=2)
+    for i in range(3):
+        company = entity_store.instantiate_entity(PyEntityIdentifier("Company"), [("name", "physical", str(i))])
+        entity_store.instantiate_entity(PyEntityIdentifier("Customer"), [("company", "foreign_key:Company", company)])
+
+    assert entity_store.flush()["inserted"] == 6
+    assert batches == [("Company", 2), ("Company", 1), ("Customer", 2), ("Customer", 1)]
+    assert entity_store.get(PyEntityIdentifier("Customer", 300)).get("company").value.get_model() == "Company"
+
+    entity_store.register_persistence("Company", bulk_insert=lambda entities: [])
+    entity_store.instantiate_entity(PyEntityIdentifier("Company"), [("name", "physical", "acme")])
+    with pytest.raises(ValueError):
+        entity_store.flush()
+
+
+def test_sql_statements(entity_store):
+    entity_store.register_table("User", "auth_user", {"name": "username", "group": "group_id"})
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("group", "foreign_key:Group", None)])
+    entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "doe"), ("group", "foreign_key:Group", ("Group", 7))])
+    john.get("name").set_value("johnny")
+
+    assert entity_store.sql_statements() == [
+        ("INSERT INTO auth_user (username, group_id) VALUES (%s, %s)", [["doe", 7]]),
+        ("UPDATE auth_user SET username = %s WHERE id = %s", [["johnny", 1]]),
+    ]
+    entity_store.instantiate_entity(PyEntityIdentifier("Group"), [("name", "physical", "admins")])
+    with pytest.raises(Exception):
+        entity_store.sql_statements()
+
+
+def test_register_django_model(entity_store):
+    register_django_model(entity_store, {
+        "name": "Book",
+        "fields": [
+            {"name": "title", "kind": "physical", "default": "untitled", "unique": True},
+            {"name": "pages", "default": 0, "type": "int"},
+            {"name": "author", "kind": "foreign_key:Author:CASCADE", "default": None},
+        ],
+        "unique_together": [["title", "author"]],
+    })
+    assert entity_store.model_schema("Book") == {
+        "attributes": [("title", "physical", "untitled"), ("pages", "physical", 0), ("author", "foreign_key:Author:CASCADE", None)],
+        "unique": [["title"], ["title", "author"]],
+        "field_types": {"pages": "int"},
+    }
+
+    book = entity_store.instantiate_model("Book", 1, pages=42)
+    assert book.get("title").value == "untitled"
+    assert book.get("pages").value == 42
+    with pytest.raises(Exception):
+        entity_store.instantiate_model("Book", isbn="0")
+    with pytest.raises(Exception):
+        entity_store.instantiate_model("Author")
+
+
+def test_constraint_check(entity_store):
+    entity_store.register_model("Book", [("title", "physical", None)], [["title"]])
+    entity_store.register_persistence("Book", insert=lambda entity: 1)
+    dune = entity_store.instantiate_model("Book", title="dune")
+    other = entity_store.instantiate_model("Book", title="dune")
+    with pytest.raises(IntegrityError):
+        entity_store.flush()
+    with pytest.raises(IntegrityError):
+        entity_store.sql_statements()
+
+    entity_store.set_constraint_check("Book", "unique", ["title"], "immediate")
+    other.get("title").set_value("dune messiah")
+    with pytest.raises(IntegrityError):
+        other.get("title").set_value("dune")
+    assert other.get("title").value == "dune messiah"
+    with pytest.raises(ValueError):
+        entity_store.set_constraint_check("Book", "unique", ["title"], "later")
+    with pytest.raises(ValueError):
+        entity_store.set_constraint_check("Book", "foreign_key", ["title", "author"], "deferred")
+    assert dune.get("title").value == "dune"
+
+
+class Field:
+    """a fake django model field, see describe_model()"""
+
+    def __init__(self, name, default=None, related_model=None, on_delete=None, **options):
+        self.name = name
+        self.default = default
+        self.related_model = related_model
+        self.is_relation = related_model is not None
+        self.remote_field = type("Rel", (), {"on_delete": on_delete})
+        self.primary_key = options.get("primary_key", False)
+        self.unique = options.get("unique", False)
+        self.one_to_one = options.get("one_to_one", False)
+        self.internal_type = options.get("internal_type", "CharField")
+
+    def get_internal_type(self):
+        return self.internal_type
+
+    def has_default(self):
+        return self.default is not None
+
+    def get_default(self):
+        return self.default
+
+
+def test_describe_model():
+    def CASCADE(): ...
+    def RESTRICT(): ...
+
+    class Author:
+        _meta = type("Meta", (), {"object_name": "Author"})
+
+    class Book:
+        _meta = type("Meta", (), {
+            "object_name": "Book",
+            "concrete_fields": [
+                Field("id", primary_key=True, unique=True),
+                Field("title", default="untitled", unique=True),
+                Field("tags", default=list),
+                Field("published", internal_type="DateTimeField"),
+                Field("author", related_model=Author, on_delete=CASCADE),
+                Field("cover", related_model=Author, on_delete=RESTRICT, one_to_one=True, unique=True),
+            ],
+            "unique_together": (("title", "author"),),
+        })
+
+    description = describe_model(Book)
+    assert description["name"] == "Book"
+    assert [(field["name"], field["kind"], field["default"]) for field in description["fields"]] == [
+        ("title", "physical", "untitled"),
+        ("tags", "physical", None),
+        ("published", "physical", None),
+        ("author", "foreign_key:Author:CASCADE", None),
+        ("cover", "one_to_one:Author:PROTECT", None),
+    ]
+    assert [field["type"] for field in description["fields"]] == ["str", "str", "datetime", "any", "any"]
+    assert description["unique_together"] == [["title", "author"]]
+
+
+def test_register_django_persistence(entity_store):
+    saved = []
+
+    def CASCADE(): ...
+
+    class Author:
+        _meta = type("Meta", (), {"object_name": "Author"})
+
+    class Book:
+        _meta = type("Meta", (), {
+            "object_name": "Book",
+            "concrete_fields": [Field("id", primary_key=True), Field("title"), Field("author", related_model=Author, on_delete=CASCADE)],
+            "unique_together": (),
+        })
+
+        def __init__(self, **values):
+            self.pk = values.pop("pk", None)
+            self.values = values
+
+        def save(self, force_insert=False, update_fields=None):
+            self.pk = self.pk or 10
+            saved.append(("save", self.pk, self.values, update_fields))
+
+        def delete(self):
+            saved.append(("delete", self.pk))
+
+    assert register_django_persistence(entity_store, Book) == "Book"
+    book = entity_store.instantiate_entity(PyEntityIdentifier("Book"), [("title", "physical", "dune"), ("author", "foreign_key:Author", ("Author", 7))])
+    entity_store.flush()
+    assert book.get_identifier().get_applied_pk() == 10
+    book.get("title").set_value("dune messiah")
+    entity_store.flush()
+    entity_store.delete(book)
+    entity_store.flush()
+    assert saved == [
+        ("save", 10, {"title": "dune", "author_id": 7}, None),
+        ("save", 10, {"title": "dune messiah"}, ["title"]),
+        ("delete", 10),
+    ]
+
+
+def test_to_json(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("group", "foreign_key:Group:CASCADE", ("Group", 7))])
+    john.get("name").set_value("johnny")
+
+    document = json.loads(entity_store.to_json())
+    assert (document["initial_epoch"], document["current_epoch"], document["flushed_epoch"]) == (0, 1, 0)
+    [user] = document["entities"]
+    assert (user["model"], user["pk"], user["deleted_epoch"]) == ("User", 1, None)
+    assert user["attributes"]["name"] == {"kind": "physical", "history": [[0, "john"], [1, "johnny"]]}
+    assert user["attributes"]["group"]["kind"] == "foreign_key"
+    assert user["attributes"]["group"]["history"][0][1]["pk"] == 7
+    assert json.loads(entity_store.to_json(indent=True)) == document
+
+
+def test_from_json(entity_store):
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group"), [("name", "physical", "admins")])
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("group", "foreign_key:Group:CASCADE", group)])
+    john.get("name").set_value("johnny")
+
+    restored = PyEntityStore.from_json(entity_store.to_json())
+    assert json.loads(restored.to_json()) == json.loads(entity_store.to_json())
+    restored_john = restored.get(PyEntityIdentifier("User", 1))
+    assert restored_john.get("name").value == "johnny"
+    assert restored_john.get("name").initial == "john"
+    assert restored.get_related(restored_john, "group").get("name").value == "admins"
+    with pytest.raises(ValueError):
+        PyEntityStore.from_json('{"entities": []}')
+
+
+def test_pickle(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    john.get("name").set_value("johnny")
+
+    restored = PyEntityStore.from_msgpack(entity_store.to_msgpack())
+    assert json.loads(restored.to_json()) == json.loads(entity_store.to_json())
+    restored = pickle.loads(pickle.dumps(entity_store))
+    assert restored.get(PyEntityIdentifier("User", 1)).get("name").value == "johnny"
+    with pytest.raises(ValueError):
+        PyEntityStore.from_msgpack(b"")
+
+
+def test_pickle_entity(entity_store):
+    identifier = PyEntityIdentifier("User")
+    assert pickle.loads(pickle.dumps(identifier)) == identifier
+    assert pickle.loads(pickle.dumps(PyEntityIdentifier("User", 1))).get_applied_pk() == 1
+
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    john.get("name").set_value("johnny")
+    copy = pickle.loads(pickle.dumps(john))
+    assert copy == john and copy.get("name").value == "johnny" and copy.get("name").initial == "john"
+    with pytest.raises(Exception):
+        copy.get("name").set_value("doe")
+    assert entity_store.attach(copy).get("name").value == "johnny"
+
+    loaded = []
+    store = PyEntityStore()
+    store.set_loader(lambda model, pk: loaded.append(pk) or [("name", "physical", "loaded")])
+    assert store.attach(copy).get("name").value == "loaded" and loaded == [1]
+    with pytest.raises(Exception):
+        PyEntityStore().attach(copy)
+
+
+def test_dump(entity_store, tmp_path):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    john.get("name").set_value("johnny")
+    path = str(tmp_path / "store.bin")
+
+    entity_store.dump(path)
+    restored = PyEntityStore.restore(path)
+    assert json.loads(restored.to_json()) == json.loads(entity_store.to_json())
+    assert restored.get(PyEntityIdentifier("User", 1)).get("name").initial == "john"
+    (tmp_path / "other.bin").write_bytes(b"garbage")
+    with pytest.raises(ValueError):
+        PyEntityStore.restore(str(tmp_path / "other.bin"))
+
+
+def test_columns(entity_store):
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group", 7), [("name", "physical", "admins")])
+    entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john"), ("age", "physical", 30), ("group", "foreign_key:Group", group)])
+    entity_store.instantiate_entity(PyEntityIdentifier("User"), [("name", "physical", "doe"), ("age", "physical", 30), ("group", "foreign_key:Group", None)])
+    entity_store.instantiate_entity(PyEntityIdentifier("User", 2), [("name", "physical", "jane"), ("age", "physical", 40), ("group", "foreign_key:Group", None)])
+
+    assert entity_store.columns("User", age=30) == {"pk": [1, None], "age": [30, 30], "group": [7, None], "name": ["john", "doe"]}
+    assert entity_store.columns("User", ["name"]) == {"name": ["john", "doe", "jane"]}
+
+    class RecordBatch:
+        @staticmethod
+        def from_pydict(columns):
+            return ("batch", columns)
+
+    sys.modules["pyarrow"] = type(sys)("pyarrow")
+    sys.modules["pyarrow"].RecordBatch = RecordBatch
+    try:
+        assert entity_store.to_arrow("User", ["name"], age=40) == ("batch", {"name": ["jane"]})
+    finally:
+        del sys.modules["pyarrow"]
+
+
+def test_csv(entity_store, tmp_path):
+    path = tmp_path / "users.csv"
+    path.write_text("id,username,age\n1,john,30\n,doe,40\n")
+
+    assert entity_store.load_csv("User", str(path), {"username": "name", "age": "age"}) == 2
+    john = entity_store.get(PyEntityIdentifier("User", 1))
+    assert (john.get("name").value, john.get("age").value) == ("john", 30)
+
+    output = tmp_path / "export.csv"
+    assert entity_store.to_csv("User", str(output), ["pk", "name"], age=40) == 1
+    assert output.read_text() == "pk,name\n,doe\n"
+    with pytest.raises(OSError):
+        entity_store.load_csv("User", str(tmp_path / "missing.csv"))
+
+
+def test_load(entity_store):
+    register_django_model(entity_store, {
+        "name": "Book",
+        "fields": [{"name": "title", "default": "untitled"}, {"name": "author", "kind": "foreign_key:Author:CASCADE"}],
+    })
+    [john, jane] = entity_store.load("Author", [{"id": 1, "name": "john"}, {"id": 2, "name": "jane"}])
+    [book] = entity_store.load("Book", [{"id": 1, "author_id": 2}])
+    assert book.get("title").value == "untitled"
+    assert entity_store.get_related(book, "author") == jane
+    assert entity_store.load("Author", [{"pk": 1}], pk="pk") == [john]
+    assert entity_store.flush() == {"inserted": 0, "updated": 0, "deleted": 0}
+    with pytest.raises(ValueError):
+        entity_store.load("Author", [{"name": "doe"}])
+
+
+def test_check_integrity(entity_store):
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    first = entity_store.instantiate_entity(PyEntityIdentifier("Profile"), [("user", "one_to_one:User:CASCADE", john)])
+    second = entity_store.instantiate_entity(PyEntityIdentifier("Profile"), [("user", "one_to_one:User:CASCADE", john)])
+    order = entity_store.instantiate_entity(PyEntityIdentifier("Order"), [("customer", "foreign_key:User", PyEntityIdentifier("User"))])
+
+    report = entity_store.check_integrity()
+    assert report["dangling"] == [(order, "customer")]
+    assert report["deleted_memberships"] == []
+    assert report["one_to_one_duplicates"] == [("user", john, [first, second])]
+
+    entity_store.delete(order)
+    entity_store.delete(second)
+    assert not any(entity_store.check_integrity().values())
+
+
+def test_related(entity_store):
+    group = entity_store.instantiate_entity(PyEntityIdentifier("Group", 7), [("name", "physical", "admins")])
+    john = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", 7)])
+    jane = entity_store.instantiate_entity(PyEntityIdentifier("User"), [("group", "foreign_key:Group", group)])
+    assert entity_store.related(group, "User", "group") == [john, jane]
+
+    john.get("group").set_value(None)
+    assert entity_store.related(group, "User", "group") == [jane]
+
+
+def test_savepoints(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+
+    entity_store.begin()
+    entity.get("name").set_value("doe")
+    token = entity_store.savepoint()
+    entity.get("name").set_value("jane")
+    assert entity.get("name").value == "jane"
+
+    entity_store.rollback_to(token)
+    assert entity.get("name").value == "doe"
+    entity_store.commit()
+    assert entity.get("name").value == "doe"
+
+
+def test_snapshot(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+
+    snapshot = entity_store.snapshot()
+    snapshot.get(ident).get("name").set_value("doe", 1)
+
+    assert snapshot.get(ident).get("name").value == "doe"
+    assert entity.get("name").value == "john"
+
+
+def test_child_store(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+
+    child = entity_store.child()
+    child.get(ident).get("name").set_value("doe", 1)
+    assert entity.get("name").value == "john"
+    child.commit()
+    assert entity.get("name").value == "doe"
+    with pytest.raises(Exception):
+        child.commit()
+
+    child = entity_store.child()
+    child.get(ident).get("name").set_value("jane", 1)
+    child.discard()
+    assert entity.get("name").value == "doe"
+
+
+def test_merge(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity = entity_store.instantiate_entity(ident, [("name", "physical", "john"), ("age", "physical", 20)])
+    other = PyEntityStore()
+    other.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "doe"), ("age", "physical", 30)])
+    new_ident = PyEntityIdentifier("User", 2)
+    other.instantiate_entity(new_ident, [("name", "physical", "jane"), ("age", "physical", 40)])
+
+    with pytest.raises(Exception):
+        entity_store.merge(other)
+
+    entity_store.merge(other, "ours", {"age": "theirs"})
+    assert entity.get("name").value == "john"
+    assert entity.get("age").value == 30
+    assert entity_store.get(new_ident).get("name").value == "jane"
+
+
+def test_clear(entity_store):
+    ident = PyEntityIdentifier("User", 1)
+    entity_store.instantiate_entity(ident, [("name", "physical", "john")])
+
+    entity_store.clear()
+    with pytest.raises(Exception):
+        entity_store.get(ident)
+
 
 def test_database_value_into_python():
-    assert create_database_value("String") == "world"
-    assert create_database_value("Number") == 42
+    assert Number(5).value == 5
+    assert String("x").value == "x"
+    assert Null().value is None
+    assert [Number(5).kind, String("x").kind, Null().kind] == ["number", "string", "null"]
+    assert isinstance(Null(), DatabaseValue)
+
+
+def test_python_todatabase_value(entity_store):
+    assert repr(Number(5)) == "Number(5)"
+    assert repr(String("x")) == 'String("x")'
+    assert repr(Null()) == "Null()"
+    assert Number(5) == Number(5) and Number(5) != String("5")
+    assert hash(String("x")) == hash(String("x"))
+
+    entity_store.register_model("Book", [("title", "physical", String("untitled")), ("pages", "physical", Number(0))])
+    book = entity_store.instantiate_model("Book", 1, title=String("dune"))
+    assert book.get("title").value == "dune"
+    book.get("pages").set_value(Number(412))
+    assert book.get("pages").value == 412
+    book.get("title").value = Null()
+    assert book.get("title").value is None
+    assert [entity.get("pages").value for entity in entity_store.filter("Book", pages=Number(412))] == [412]
+    assert entity_class(entity_store, "Book").create(pages=Number(7)).pages == 7
+
+
+def test_sync_entity_store():
+    entity_store = PySyncEntityStore()
+    entity_store.register_model("User", [("name", "physical", ""), ("age", "physical", 0)])
+
+    def create(pk):
+        identifier = entity_store.instantiate_model("User", pk, name=f"user {pk}")
+        entity_store.set(identifier, "age", pk * 10)
+        return identifier
+
+    with concurrent.futures.ThreadPoolExecutor(max_workers=4) as executor:
+        identifiers = list(executor.map(create, range(20)))
+        ages = list(executor.map(lambda identifier: entity_store.get(identifier, "age"), identifiers))
+
+    assert ages == [pk * 10 for pk in range(20)]
+    assert len(entity_store) == entity_store.count("User") == 20
+    assert entity_store.values(identifiers[3]) == {"name": "user 3", "age": 30}
+    assert sorted(identifier.get_applied_pk() for identifier in entity_store.filter("User", age__gte=180)) == [18, 19]
+    assert entity_store.delete(identifiers[0]) == 1
+    assert entity_store.count("User") == 19
+    with pytest.raises(AttributeNotFound):
+        entity_store.get(identifiers[1], "email")
+
+
+def test_sync_entity_store_scope():
+    entity_store = PySyncEntityStore()
+    assert {name for name in dir(entity_store) if not name.startswith("_")} == {
+        "register_model", "instantiate_model", "get", "set", "values", "filter", "delete", "count",
+    }
+    entity_store.register_model("User", [("name", "physical", ""), ("group", "foreign_key:Group", None)])
+    identifier = entity_store.instantiate_model("User", 1, name="john", group=("Group", 7))
+    assert entity_store.get(identifier, "group") == PyEntityIdentifier("Group", 7)
+    entity_store.set(identifier, "name", 12)
+    assert entity_store.values(identifier) == {"name": 12, "group": PyEntityIdentifier("Group", 7)}
+    with pytest.raises(EntityNotFound):
+        entity_store.set(PyEntityIdentifier("User", 2), "name", "doe")
+
+
+def test_weakref():
+    entity_store = PyEntityStore()
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "darius")])
+    cache = weakref.WeakValueDictionary({"store": entity_store, "user": entity})
+    assert weakref.ref(entity_store)() is entity_store
+    assert cache["user"] is entity
+
+    del entity
+    assert "user" not in cache
+    del entity_store
+    assert "store" not in cache
+
+
+def test_copy(entity_store):
+    entity = entity_store.instantiate_entity(PyEntityIdentifier("User", 1), [("name", "physical", "john")])
+    entity["name"] = "doe"
+
+    handle = copy.copy(entity)
+    assert handle == entity and handle is not entity
+    handle["name"] = "jane"
+    assert entity["name"] == "jane"
+
+    clone = copy.deepcopy(entity)
+    assert clone.get_identifier() == entity.get_identifier()
+    clone["name"] = "darius"
+    assert entity["name"] == "jane"
+    assert clone.get("name").initial == "john"
+
+    store_copy = copy.deepcopy(entity_store)
+    store_copy.get(PyEntityIdentifier("User", 1))["name"] = "darius"
+    assert entity["name"] == "jane"
+    assert len(store_copy) == 1
+
+
+def test_validators(entity_store):
+    def even(value):
+        if value % 2:
+            raise ValueError(f"{value} is odd")
+
+    entity_store.register_model("Book", [
+        PyAttributeDescriptor("pages", "physical", 0, "int", validators=[even]),
+        PyAttributeDescriptor("status", "physical", "draft", choices=["draft", "published"]),
+    ])
+    book = entity_store.instantiate_model("Book", 1, pages=10)
+    book["pages"] = 12
+    book["status"] = "published"
+
+    with pytest.raises(ValidationError) as error:
+        book["pages"] = 13
+    assert (error.value.attribute, error.value.message) == ("pages", "13 is odd")
+    assert str(error.value) == "pages: 13 is odd"
+    assert book["pages"] == 12
+    with pytest.raises(ValidationError, match="status"):
+        book.get("status").value = "lost"
+    assert book["status"] == "published"
+    with pytest.raises(ValidationError):
+        entity_store.instantiate_model("Book", 2, pages=3)
+    assert entity_store.count("Book") == 1
+
+
+def test_not_null(entity_store):
+    entity_store.register_model("Book", [
+        PyAttributeDescriptor("title", "physical", None, null=False),
+        PyAttributeDescriptor("isbn", "physical", None, null=False),
+        PyAttributeDescriptor("notes", "physical", None),
+    ])
+    entity_store.register_persistence("Book", insert=lambda entity: 1)
+    book = entity_store.instantiate_model("Book")
+    book["notes"] = None
+
+    with pytest.raises(ValidationError) as error:
+        entity_store.flush()
+    assert error.value.attributes == ["isbn", "title"]
+    assert "has no value for isbn, title" in str(error.value)
+
+    book["title"] = "dune"
+    with pytest.raises(ValidationError) as error:
+        book["title"] = None
+    assert (error.value.attribute, error.value.message) == ("title", "this field cannot be null")
+    book["isbn"] = "978-0441013593"
+    assert entity_store.flush()["inserted"] == 1
+
+
+def test_max_length(entity_store):
+    entity_store.register_model("Book", [PyAttributeDescriptor("title", "physical", "", "str", max_length=4)])
+    book = entity_store.instantiate_model("Book", 1, title="dune")
+
+    with pytest.raises(ValidationError) as error:
+        book["title"] = "dunes"
+    assert error.value.message == "ensure this value has at most 4 characters (it has 5)"
+    assert book["title"] == "dune"
+    with pytest.raises(ValidationError):
+        entity_store.instantiate_model("Book", 2, title="children of dune")
+
+
+def test_min_max_value(entity_store):
+    entity_store.register_model("Review", [PyAttributeDescriptor("stars", "physical", 1, "int", min_value=1, max_value=5)])
+    review = entity_store.instantiate_model("Review", 1, stars=5)
+
+    with pytest.raises(ValidationError, match="less than or equal to 5"):
+        review["stars"] = 6
+    with pytest.raises(ValidationError, match="greater than or equal to 1"):
+        review["stars"] = 0
+    assert review["stars"] == 5
+    review["stars"] = 3
+    assert review["stars"] == 3
+
+
+def test_unique_together(entity_store):
+    entity_store.register_model("Edition", [("title", "physical", None), ("year", "physical", None)], [["title", "year"]])
+    entity_store.set_constraint_check("Edition", "unique", ["title", "year"], "immediate")
+    first = entity_store.instantiate_model("Edition", 1, title="dune", year=1965)
+    entity_store.instantiate_model("Edition", 2, title="dune", year=1984)
+    other = entity_store.instantiate_model("Edition", 3, title="dune messiah", year=1965)
+
+    with pytest.raises(IntegrityError) as error:
+        other.get("title").set_value("dune")
+    assert error.value.conflicting == first.get_identifier()
+    assert "has the same title, year as Edition pk=1" in str(error.value)
+    other.get("title").set_value(None)
+    assert other.get("title").value is None
+
+
+def test_check_constraint(entity_store):
+    entity_store.register_model("Book", [("pages", "physical", 1), ("status", "physical", "draft")])
+    entity_store.register_persistence("Book", insert=lambda entity: 1)
+    entity_store.add_check_constraint("Book", "positive_pages", pages__gt=0)
+    book = entity_store.instantiate_model("Book", pages=0)
+
+    with pytest.raises(IntegrityError, match="violates the check constraint positive_pages"):
+        entity_store.flush()
+    book["pages"] = 12
+    entity_store.set_constraint_check("Book", "check", ["positive_pages"], "immediate")
+    with pytest.raises(IntegrityError):
+        book["pages"] = -1
+    assert book["pages"] == 12
+    with pytest.raises(ValueError):
+        entity_store.set_constraint_check("Book", "check", ["positive_pages", "status"], "immediate")
+    assert entity_store.flush()["inserted"] == 1
+
+
+def test_full_clean(entity_store):
+    entity_store.register_model("Book", [
+        PyAttributeDescriptor("title", "physical", None, "str", null=False, max_length=4),
+        PyAttributeDescriptor("pages", "physical", 1, "int"),
+    ], [["title"]])
+    entity_store.add_check_constraint("Book", "positive_pages", pages__gt=0)
+    dune = entity_store.instantiate_model("Book", 1, title="dune")
+    other = entity_store.instantiate_model("Book", 2, pages=0)
+    dune.full_clean()
+
+    with pytest.raises(ValidationError) as error:
+        other.full_clean()
+    assert error.value.message_dict == {
+        "__all__": ["the check constraint positive_pages is violated"],
+        "title": ["this field cannot be null"],
+    }
+    other["title"] = "dune"
+    assert entity_store.validate("Book") == {
+        dune.get_identifier(): {"title": ["Book with this title already exists"]},
+        other.get_identifier(): {
+            "__all__": ["the check constraint positive_pages is violated"],
+            "title": ["Book with this title already exists"],
+        },
+    }
+    other["pages"] = 12
+    other["title"] = "ix"
+    assert entity_store.validate() == {}
+
+
+def test_entity_validator(entity_store):
+    entity_store.register_model("Event", [("start", "physical", 0), ("end", "physical", 0), ("title", "physical", "")])
+    entity_store.register_persistence("Event", insert=lambda entity: 1)
+
+    def check_dates(values):
+        if values["start"] > values["end"]:
+            error = ValidationError("the event ends before its start")
+            error.message_dict = {"end": ["ends before its start"]}
+            raise error
+
+    def check_title(values):
+        if not values["title"]:
+            raise ValueError("an event needs a title")
+
+    entity_store.add_entity_validator("Event", check_dates)
+    entity_store.add_entity_validator("Event", check_title)
+    event = entity_store.instantiate_model("Event", start=3, end=2)
+
+    with pytest.raises(ValidationError) as error:
+        entity_store.flush()
+    assert error.value.message_dict == {"__all__": ["an event needs a title"], "end": ["ends before its start"]}
+    assert entity_store.validate() == {event.get_identifier(): error.value.message_dict}
+    event.update({"end": 4, "title": "dune"})
+    event.full_clean()
+    assert entity_store.flush()["inserted"] == 1
+
+
+def test_subscribe(entity_store):
+    events = []
+    for event in ["entity_created", "attribute_changed", "entity_deleted", "epoch_advanced"]:
+        entity_store.subscribe(event, events.append)
+    with pytest.raises(ValueError, match="unknown event"):
+        entity_store.subscribe("created", events.append)
+
+    entity_store.register_model("Book", [("title", "physical", "")])
+    book = entity_store.instantiate_model("Book", 1, title="dune")
+    [created] = events
+    assert created["event"] == "entity_created"
+    assert created["identifier"] == book.get_identifier()
+    assert created["values"] == {"title": "dune"}
+    events.clear()
+
+    with entity_store.transaction():
+        book["title"] = "dune messiah"
+    entity_store.undo()
+    [begun, changed, undone] = events
+    assert begun["event"] == undone["event"] == "epoch_advanced"
+    assert begun["epoch"] == changed["epoch"] == undone["previous"]
+    assert undone["epoch"] == begun["previous"] == entity_store.current_epoch()
+    assert {key: changed[key] for key in ("event", "attribute", "previous", "value")} == {
+        "event": "attribute_changed", "attribute": "title", "previous": "dune", "value": "dune messiah",
+    }
+    events.clear()
+
+    entity_store.delete(book)
+    [deleted] = events
+    assert deleted["event"] == "entity_deleted"
+    assert deleted["entity"] == book
+    assert entity_store.disconnect("entity_deleted", events.append)
+
+
+def test_audit_log(entity_store, tmp_path):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    book = entity_store.instantiate_model("Book", 1, title="dune")
+    book["pages"] = 412
+    assert entity_store.audit_log() == []
+
+    entity_store.enable_audit_log()
+    entity_store.set_audit_context("admin", datetime.datetime(2024, 1, 1, 12, 0))
+    book["title"] = "dune messiah"
+    entity_store.set_audit_context("editor")
+    book.update({"pages": 256})
+    [retitled, repaged] = entity_store.audit_log()
+    assert retitled == {
+        "identifier": book.get_identifier(), "attribute": "title", "previous": "dune", "value": "dune messiah",
+        "epoch": entity_store.current_epoch(), "who": "admin", "when": datetime.datetime(2024, 1, 1, 12, 0),
+    }
+    assert (repaged["previous"], repaged["value"], repaged["who"], repaged["when"]) == (412, 256, "editor", None)
+    assert entity_store.audit_log(book.get_identifier(), "pages") == [repaged]
+
+    path = tmp_path / "audit.csv"
+    assert entity_store.export_audit_log(str(path)) == 2
+    assert path.read_text().splitlines()[2].startswith("Book,1,")
+
+
+def test_setup_logging(entity_store):
+    class Records(logging.Handler):
+        def __init__(self):
+            super().__init__(logging.DEBUG)
+            self.records = []
+
+        def emit(self, record):
+            self.records.append(record)
+
+    handler = Records()
+    logger = logging.getLogger("django_lightning_service")
+    logger.addHandler(handler)
+    logger.setLevel(logging.DEBUG)
+    try:
+        setup_logging("django_lightning_service=debug")
+        entity_store.register_model("Book", [("title", "physical", "")])
+        entity_store.register_persistence("Book", insert=lambda entity: 1)
+        entity_store.instantiate_model("Book", title="dune")
+        entity_store.filter("Book", title="dune")
+        entity_store.flush()
+        messages = [(record.name, record.levelno, record.getMessage()) for record in handler.records]
+        assert ("django_lightning_service.entity_store", logging.DEBUG, 'filter model="Book" matched=1') in messages
+        assert any(name == "django_lightning_service" and message.startswith("flush inserted=1 updated=0 deleted=0") for name, _, message in messages)
+        with pytest.raises(ValueError):
+            setup_logging("django_lightning_service=loud")
+    finally:
+        setup_logging("off")
+        logger.removeHandler(handler)
+
+
+def test_dump_debug(entity_store):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    entity_store.register_model("Author", [("name", "physical", "")])
+    book = entity_store.instantiate_model("Book", 1, title="dune", pages=412)
+    author = entity_store.instantiate_model("Author", 3, name="frank")
+    entity_store.mark_flushed()
+    assert entity_store.dump_debug().endswith("pending changes: none")
+
+    book["pages"] = 256
+    entity_store.delete(author)
+    draft = entity_store.instantiate_model("Book", title="messiah")
+    uuid = draft.get_identifier().get_uuid()
+    assert entity_store.dump_debug().splitlines()[1:] == [
+        "Author pk=3, deleted",
+        '    name: "frank"',
+        "Book pk=1, modified",
+        "    pages: 412 -> 256",
+        '    title: "dune"',
+        f"Book uuid={uuid}, new",
+        "    pages: 0",
+        '    title: "messiah"',
+        "pending changes:",
+        f"    insert Book uuid={uuid}",
+        "    update Book pk=1: pages",
+        "    delete Author pk=3",
+    ]
+    assert entity_store.dump_debug().startswith(f"store at epoch {entity_store.current_epoch()} (initial ")
+
+
+def test_metrics(entity_store):
+    entity_store.register_model("Author", [("name", "physical", "")])
+    entity_store.register_model("Book", [("title", "physical", ""), ("author", "foreign_key:Author", None)])
+    entity_store.set_loader(lambda model, pk: [("name", "physical", f"author {pk}")])
+    frank = entity_store.instantiate_model("Author", 1, name="frank")
+    dune = entity_store.instantiate_model("Book", 1, title="dune", author=frank)
+    other = entity_store.instantiate_model("Book", 2, title="other", author=PyEntityIdentifier("Author", 2))
+    entity_store.reset_metrics()
+
+    assert len(entity_store.filter("Book", title="dune")) == 1
+    entity_store.get(dune.get_identifier())
+    assert entity_store.get_related(dune, "author") == frank
+    assert entity_store.get_related(other, "author")["name"] == "author 2"
+    metrics = entity_store.metrics()
+    assert (metrics["filters"], metrics["scanned"], metrics["cache_hits"], metrics["cache_misses"]) == (1, 2, 1, 1)
+    assert metrics["scanned_per_filter"] == [(10, 1), (100, 0), (1000, 0), (10000, 0), (None, 0)]
+    assert metrics["index_hits"] >= 2
+    assert metrics["history_entries"] == entity_store.stats()["history_entries"]
+
+
+def test_query_log(entity_store):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    entity_store.instantiate_model("Book", 1, title="dune", pages=412)
+    entity_store.instantiate_model("Book", 2, title="other", pages=0)
+    entity_store.filter("Book", title="dune")
+    assert entity_store.queries() == []
+
+    entity_store.enable_query_log(capacity=2)
+    entity_store.filter("Book", title="dune", pages__gt=0)
+    assert entity_store.count("Book") == 2
+    entity_store.filter("Book", pages__gt=0, title="dune")
+    queries = entity_store.queries()
+    assert [(query["operation"], query["model"], query["expression"], query["results"]) for query in queries] == [
+        ("count", "Book", "", 2),
+        ("filter", "Book", 'pages__gt=0 AND title="dune"', 1),
+    ]
+    assert all(query["duration"] >= 0 for query in queries)
+    entity_store.clear_query_log()
+    assert entity_store.queries() == []
+
+
+def test_memory_report(entity_store):
+    entity_store.register_model("Author", [("name", "physical", "")])
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    entity_store.instantiate_model("Author", 1, name="frank")
+    dune = entity_store.instantiate_model("Book", 1, title="dune", pages=412)
+    entity_store.instantiate_model("Book", 2, title="other")
+    report = entity_store.memory_report()
+    assert sorted(report) == ["Author", "Book"]
+    assert report["Book"]["entities"] == 2
+    assert report["Book"]["total"] == sum(report["Book"][key] for key in ("entity_bytes", "history_bytes", "index_bytes"))
+    assert report["Book"]["total"] > report["Author"]["total"]
+
+    history_bytes = report["Book"]["history_bytes"]
+    for pages in range(10):
+        entity_store.begin()
+        dune.get("pages").set_value(pages)
+        entity_store.commit()
+    assert entity_store.memory_report()["Book"]["history_bytes"] > history_bytes
+
+
+def test_watch(entity_store):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    dune = entity_store.instantiate_model("Book", 1, title="dune", pages=412)
+    other = entity_store.instantiate_model("Book", 2, title="other")
+    changes = []
+    callback = lambda entity, attribute, previous, value: changes.append((entity, attribute, previous, value))
+    entity_store.watch(dune.get_identifier(), "pages", callback)
+
+    dune.get("pages").value = 500
+    dune.get("pages").value = 500
+    dune.get("title").value = "dune messiah"
+    other.get("pages").value = 10
+    assert changes == [(dune, "pages", 412, 500)]
+
+    entity_store.begin()
+    dune.get("pages").value = 600
+    entity_store.rollback()
+    assert changes[1:] == [(dune, "pages", 500, 600), (dune, "pages", 600, 500)]
+
+    with pytest.raises(AttributeNotFound):
+        entity_store.watch(dune.get_identifier(), "author", callback)
+    assert entity_store.unwatch(dune.get_identifier(), "pages", callback)
+    assert not entity_store.unwatch(dune.get_identifier(), "pages", callback)
+    dune.get("pages").value = 700
+    assert len(changes) == 3
+
+
+def test_add_index(entity_store):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    books = [entity_store.instantiate_model("Book", pk, title=f"book {pk % 4}", pages=pk) for pk in range(20)]
+    entity_store.add_index("Book", "title")
+    entity_store.reset_metrics()
+
+    assert entity_store.filter("Book", title="book 1") == books[1::4]
+    assert entity_store.filter("Book", title__in=["book 2", "book 3"], pages__gt=10) == [books[11], books[14], books[15], books[18], books[19]]
+    assert entity_store.metrics()["scanned"] == 15
+    with entity_store.transaction():
+        books[1].get("title").value = "book 0"
+        assert books[1] not in entity_store.filter("Book", title="book 1")
+    assert entity_store.filter("Book", title="book 0") == [books[0], books[1], books[4], books[8], books[12], books[16]]
+
+
+def test_sorted_index(entity_store):
+    entity_store.register_model("Book", [("title", "physical", ""), ("pages", "physical", 0)])
+    books = [entity_store.instantiate_model("Book", pk, title=f"book {pk}", pages=pages) for pk, pages in enumerate([300, 100, None, 200, 100])]
+    assert list(entity_store.query("Book").order_by("pages")) == [books[1], books[4], books[3], books[0], books[2]]
+
+    entity_store.add_index("Book", "pages", sorted=True)
+    entity_store.reset_metrics()
+    assert entity_store.filter("Book", pages__lt=250) == [books[1], books[3], books[4]]
+    assert entity_store.metrics()["scanned"] == 3
+    assert list(entity_store.query("Book").order_by("pages")) == [books[1], books[4], books[3], books[0], books[2]]
+    assert list(entity_store.query("Book", pages__gt=100).order_by("-pages")) == [books[0], books[3]]
+    assert list(entity_store.query("Book").order_by("-pages").filter(title="book 4")) == [books[4]]
+
 
+def test_related_manager_writes(entity_store):
+    entity_store.register_model("Comment", [("post", "foreign_key:Post", None)], [["post"]])
+    entity_store.set_constraint_check("Comment", "unique", ["post"], "immediate")
+    post = entity_store.instantiate_entity(PyEntityIdentifier("Post", 1), [("title", "physical", "hello")])
+    first, second = entity_store.instantiate_model("Comment"), entity_store.instantiate_model("Comment")
+    events = []
+    entity_store.subscribe("attribute_changed", events.append)
 
-def test_python_todatabase_value():
-    assert repr_database_value("world") == "String(world)"
-    assert repr_database_value(42) == "Number(42)"
\ No newline at end of file
+    comments = post.rel("Comment", "post")
+    comments.add(first)
+    assert [(event["attribute"], event["previous"], event["value"]) for event in events] == [("post", None, post.get_identifier())]
+    with pytest.raises(IntegrityError):
+        comments.add(second)
+    assert second.get("post").value is None
+    assert comments.all() == [first]
+    comments.clear()
+    assert len(events) == 2 and first.get("post").value is None
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use crate::entity::{DatabaseValue, Entity, EntityIdentifier, Model};
+use crate::entity_store::{AuditEntry, EntityStore};
+use crate::errors::EntityError;
+use crate::query::columns;
+
+/// instantiate an entity of *model* per record of the CSV *reader*, read one at a time. the
+/// records with a value in the *pk_column* are persisted entities, the others new ones.
+/// *column_mapping* gives the attribute of each column to load, all of them are loaded under
+/// their name if None. empty cells are None, integers numbers and anything else strings.
+/// return the number of loaded records
+pub fn load_csv(store: &mut EntityStore, model: &Model, reader: impl Read, column_mapping: Option<&HashMap<String, String>>, pk_column: &str) -> Result<usize, EntityError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().map_err(csv_error)?.clone();
+    let mut count = 0;
+    for record in reader.records() {
+        let record = record.map_err(csv_error)?;
+        let mut pk = None;
+        let mut values = HashMap::new();
+        for (column, cell) in headers.iter().zip(record.iter()) {
+            let value = parse_cell(cell);
+            if column == pk_column {
+                pk = match value {
+                    DatabaseValue::Number(pk) => Some(pk),
+                    DatabaseValue::None => None,
+                    _ => return Err(EntityError::Csv(format!("line {}: the pk must be an integer", count + 2))),
+                };
+                continue;
+            }
+            let attribute = match column_mapping {
+                Some(column_mapping) => match column_mapping.get(column) {
+                    Some(attribute) => attribute.clone(),
+                    None => continue,
+                },
+                None => column.to_string(),
+            };
+            values.insert(attribute, value);
+        }
+        let identifier = match pk {
+            Some(pk) => EntityIdentifier::new_persisted(model.clone(), pk),
+            None => EntityIdentifier::new(model.clone()),
+        };
+        let attributes = store.model_attributes(model, values)?;
+        store.instantiate_entity(identifier, attributes);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// write the *attributes* of *entities* as CSV to *writer*, with a header line, like
+/// query::columns(). None is written as an empty cell. return the number of written records
+pub fn write_csv(store: &EntityStore, entities: &[Rc<Entity>], attributes: &[String], writer: impl Write) -> Result<usize, EntityError> {
+    let columns = columns(store, entities, attributes)?;
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(attributes).map_err(csv_error)?;
+    for row in 0..entities.len() {
+        writer.write_record(columns.iter().map(|column| format_cell(&column[row]))).map_err(csv_error)?;
+    }
+    writer.flush().map_err(|error| EntityError::Csv(error.to_string()))?;
+    Ok(entities.len())
+}
+
+fn parse_cell(cell: &str) -> DatabaseValue {
+    if cell.is_empty() {
+        DatabaseValue::None
+    } else if let Ok(number) = cell.parse() {
+        DatabaseValue::Number(number)
+    } else {
+        DatabaseValue::String(cell.to_string())
+    }
+}
+
+/// write the audit log *entries* as CSV to *writer*, with a header line. the written entities and
+/// the referenced ones are given by pk once persisted, by uuid before. return the number of
+/// written records
+pub fn write_audit_log(entries: &[AuditEntry], writer: impl Write) -> Result<usize, EntityError> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["model", "pk", "uuid", "attribute", "previous", "value", "epoch", "who", "when"]).map_err(csv_error)?;
+    for entry in entries {
+        writer.write_record([
+            entry.identifier.get_model().to_string(),
+            entry.identifier.get_applied_pk().map(|pk| pk.to_string()).unwrap_or_default(),
+            entry.identifier.get_uuid().to_string(),
+            entry.attribute.clone(),
+            format_audited(&entry.previous),
+            format_audited(&entry.value),
+            entry.epoch.to_string(),
+            entry.who.clone().unwrap_or_default(),
+            entry.when.clone().unwrap_or_default(),
+        ]).map_err(csv_error)?;
+    }
+    writer.flush().map_err(|error| EntityError::Csv(error.to_string()))?;
+    Ok(entries.len())
+}
+
+/// like format_cell(), the referenced entities given by pk once persisted, by uuid before
+fn format_audited(value: &DatabaseValue) -> String {
+    match value {
+        DatabaseValue::ForeignKey(identifier) => match identifier.get_applied_pk() {
+            Ok(pk) => pk.to_string(),
+            Err(_) => identifier.get_uuid().to_string(),
+        },
+        value => format_cell(value),
+    }
+}
+
+fn format_cell(value: &DatabaseValue) -> String {
+    match value {
+        DatabaseValue::String(value) => value.clone(),
+        DatabaseValue::Number(value) => value.to_string(),
+        // foreign keys are given as pks by columns()
+        DatabaseValue::None | DatabaseValue::ForeignKey(_) => String::new(),
+    }
+}
+
+fn csv_error(error: csv::Error) -> EntityError {
+    EntityError::Csv(error.to_string())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::csv_io::{load_csv, write_audit_log, write_csv};
+    use crate::entity::{BaseEntityAttribute, DatabaseValue, EntityIdentifier};
+    use crate::entity_store::{AuditEntry, EntityStore};
+    use crate::errors::EntityError;
+
+    #[test]
+    fn test_csv() {
+        let mut entity_store = EntityStore::new();
+        let model = "User".to_string();
+        let file = "id,username,age,notes\n1,john,30,\n,doe,40,\"a, b\"\n";
+        let mapping = HashMap::from([("username".to_string(), "name".to_string()), ("age".to_string(), "age".to_string())]);
+        assert_eq!(load_csv(&mut entity_store, &model, file.as_bytes(), Some(&mapping), "id"), Ok(2));
+
+        let john = entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 1)).unwrap();
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert_eq!(john.get("age").unwrap().get_value(), DatabaseValue::Number(30));
+        assert!(john.get("notes").is_err());
+        assert_eq!(entity_store.changeset().unwrap().created.len(), 1);
+
+        let mut output = vec![];
+        let attributes = vec!["pk".to_string(), "name".to_string(), "age".to_string()];
+        assert_eq!(write_csv(&entity_store, &entity_store.all(&model), &attributes, &mut output), Ok(2));
+        assert_eq!(String::from_utf8(output).unwrap(), "pk,name,age\n1,john,30\n,doe,40\n");
+
+        let mut other = EntityStore::new();
+        assert_eq!(load_csv(&mut other, &model, file.as_bytes(), None, "id"), Ok(2));
+        assert_eq!(other.all(&model)[1].get("notes").unwrap().get_value(), DatabaseValue::String("a, b".to_string()));
+        assert_eq!(load_csv(&mut other, &model, "id,name\njohn,john\n".as_bytes(), None, "id"), Err(EntityError::Csv("line 2: the pk must be an integer".to_string())));
+    }
+
+    #[test]
+    fn test_write_audit_log() {
+        let user = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let group = EntityIdentifier::new("Group".to_string());
+        let entries = vec![AuditEntry {
+            identifier: user.clone(),
+            attribute: "group".to_string(),
+            previous: DatabaseValue::None,
+            value: DatabaseValue::ForeignKey(group.clone()),
+            epoch: 2,
+            who: Some("admin".to_string()),
+            when: None,
+        }];
+        let mut output = vec![];
+        assert_eq!(write_audit_log(&entries, &mut output), Ok(1));
+        assert_eq!(String::from_utf8(output).unwrap(), format!(
+            "model,pk,uuid,attribute,previous,value,epoch,who,when\nUser,1,{},group,,{},2,admin,\n", user.get_uuid(), group.get_uuid(),
+        ));
+    }
+}
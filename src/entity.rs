@@ -1,12 +1,20 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, Ref, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
+use crate::entity_store::{EntityStore, IndexLink, ReverseIndex, ReverseLink, ValueIndex};
 use crate::errors::EntityError;
+use crate::symbol::{Symbol, Symbols};
 
 pub type Epoch = i64;
 pub type Model = String;
+/// messages by attribute name, like the message_dict of a django ValidationError
+pub type MessageDict = BTreeMap<String, Vec<String>>;
 
 #[derive(Debug)]
 pub struct EpochPtr {
@@ -26,6 +34,7 @@ impl EpochPtr {
             epoch: RefCell::new(epoch)
         }
     }
+    #[allow(dead_code)]
     pub fn slide(&self, epoch: Epoch) {
         let mut ep = self.epoch.borrow_mut();
         *ep = epoch;
@@ -36,10 +45,39 @@ impl EpochPtr {
     }
 }
 
-#[derive(Debug)]
+/// hand out monotonically increasing epochs, so each write epoch is never reused
+#[derive(Debug, Clone)]
+pub struct EpochManager {
+    last_epoch: Epoch,
+}
+
+impl EpochManager {
+    pub fn new(last_epoch: Epoch) -> Self {
+        EpochManager { last_epoch }
+    }
+
+    /// return a new epoch, greater than all the previously known ones
+    pub fn allocate(&mut self) -> Epoch {
+        self.last_epoch += 1;
+        self.last_epoch
+    }
+
+    /// make sure the next allocated epochs are greater than *epoch*
+    pub fn advance_to(&mut self, epoch: Epoch) {
+        self.last_epoch = self.last_epoch.max(epoch);
+    }
+
+    pub fn get_last_epoch(&self) -> Epoch {
+        self.last_epoch
+    }
+}
+
+#[derive(Debug, Clone)]
 struct AttributeValue<T> {
     epoch: Epoch,
     value: T,
+    // written in the branch forked at *epoch*, invisible from the main timeline
+    branched: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -47,8 +85,27 @@ struct AttributeValue<T> {
 pub enum DatabaseValue {
     String(String),
     Number(i64),
-    #[allow(dead_code)]
     None,
+    /// reference to another entity, held by foreign key attributes
+    ForeignKey(EntityIdentifier),
+}
+
+impl DatabaseValue {
+    /// approximate number of bytes used by this value, heap included
+    pub fn approximate_size(&self) -> usize {
+        match self {
+            DatabaseValue::String(val) => size_of::<DatabaseValue>() + val.capacity(),
+            _ => size_of::<DatabaseValue>(),
+        }
+    }
+
+    /// a copy referencing a detached() identifier, for the copies of the stores
+    pub fn detached(&self) -> DatabaseValue {
+        match self {
+            DatabaseValue::ForeignKey(identifier) => DatabaseValue::ForeignKey(identifier.detached()),
+            value => value.clone(),
+        }
+    }
 }
 
 impl Display for DatabaseValue {
@@ -57,6 +114,7 @@ impl Display for DatabaseValue {
             DatabaseValue::String(val) => write!(f, "String({})", val),
             DatabaseValue::Number(val) => write!(f, "Number({})", val),
             DatabaseValue::None => f.write_str("None"),
+            DatabaseValue::ForeignKey(identifier) => write!(f, "ForeignKey({})", identifier),
         }
     }
 }
@@ -69,33 +127,51 @@ impl PartialEq for DatabaseValue {
             (None, None) => true,
             (None, _) => false,
             (_, None) => false,
-            (&String(ref a), &String(ref b)) => a == b,
-            (&Number(ref a), &Number(ref b)) => a == b,
+            (String(a), String(b)) => a == b,
+            (Number(a), Number(b)) => a == b,
+            (ForeignKey(a), ForeignKey(b)) => a == b,
             _ => false,
         }
     }
 }
 
+impl PartialOrd for DatabaseValue {
+    /// values are only ordered against values of the same type
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        use DatabaseValue::*;
+
+        match (self, other) {
+            (String(a), String(b)) => a.partial_cmp(b),
+            (Number(a), Number(b)) => a.partial_cmp(b),
+            _ => Option::None,
+        }
+    }
+}
+
 pub trait BaseEntityAttribute {
     fn get_initial(&self) -> DatabaseValue;
 
     fn get_value(&self) -> DatabaseValue;
 
     fn set_value(&self, value: DatabaseValue, epoch: Epoch);
-}
-
-pub trait EntityAttribute: Debug + BaseEntityAttribute {}
 
-impl<T: Debug + BaseEntityAttribute> EntityAttribute for T {}
+    /// set the value at the current epoch, which is the epoch of the running transaction if any
+    fn set_current_value(&self, value: DatabaseValue);
+}
 
 #[derive(Debug)]
 pub struct PhysicalAttribute {
-    attribute_name: String,
+    attribute_name: Symbol,
     current_epoch_ptr: Rc<EpochPtr>,
 
     initial_epoch_ptr: Rc<EpochPtr>,
 
     value_history: RefCell<Vec<AttributeValue<DatabaseValue>>>,
+
+    // set on foreign keys once their entity is added to a store, to register the written values
+    reverse_link: RefCell<Option<ReverseLink>>,
+    // set on indexed attributes once their entity is added to a store, see EntityStore::add_index()
+    index_link: RefCell<Option<IndexLink>>,
 }
 
 impl Display for PhysicalAttribute {
@@ -109,43 +185,223 @@ impl Display for PhysicalAttribute {
 }
 
 impl PhysicalAttribute {
-    fn new(attribute_name: String, current_epoch_ptr: Rc<EpochPtr>, initial_epoch_ptr: Rc<EpochPtr>) -> Self {
+    fn new(attribute_name: Symbol, current_epoch_ptr: Rc<EpochPtr>, initial_epoch_ptr: Rc<EpochPtr>) -> Self {
         PhysicalAttribute {
             attribute_name,
             current_epoch_ptr,
             initial_epoch_ptr,
             value_history: RefCell::new(vec!()),
+            reverse_link: RefCell::new(None),
+            index_link: RefCell::new(None),
+        }
+    }
+
+    /// register the values of this foreign key, present and future, in *link* reverse index
+    fn bind_reverse_link(&self, link: ReverseLink) {
+        for history in self.value_history.borrow().iter() {
+            link.register(&self.attribute_name, &history.value);
+        }
+        *self.reverse_link.borrow_mut() = Some(link);
+    }
+
+    /// register the values of this attribute, present and future, in *link* value index
+    fn bind_index_link(&self, link: IndexLink) {
+        for history in self.value_history.borrow().iter() {
+            link.register(&self.attribute_name, &history.value);
+        }
+        *self.index_link.borrow_mut() = Some(link);
+    }
+
+    /// register *value*, written in the history, in the indexes this attribute is bound to
+    fn register(&self, value: &DatabaseValue) {
+        if let Some(link) = self.reverse_link.borrow().as_ref() {
+            link.register(&self.attribute_name, value);
         }
+        if let Some(link) = self.index_link.borrow().as_ref() {
+            link.register(&self.attribute_name, value);
+        }
+    }
+
+
+    /// return an independent copy of this attribute and its history,
+    /// bound to the given epoch pointers
+    fn snapshot(&self, current_epoch_ptr: Rc<EpochPtr>, initial_epoch_ptr: Rc<EpochPtr>) -> Self {
+        PhysicalAttribute {
+            attribute_name: Arc::clone(&self.attribute_name),
+            current_epoch_ptr,
+            initial_epoch_ptr,
+            value_history: RefCell::new(self.value_history.borrow().iter().map(
+                |history| AttributeValue { epoch: history.epoch, value: history.value.detached(), branched: history.branched }
+            ).collect()),
+            reverse_link: RefCell::new(None),
+            index_link: RefCell::new(None),
+        }
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.value_history.borrow().len()
     }
 
+    /// approximate number of bytes used by this attribute and its history
+    pub fn approximate_size(&self) -> usize {
+        size_of::<PhysicalAttribute>() + self.history_size()
+    }
 
-    fn get_at_epoch(&self, epoch: Epoch) -> DatabaseValue {
-        println!("get at epoch {}", epoch);
+    /// approximate number of bytes used by the history of this attribute, see approximate_size()
+    pub fn history_size(&self) -> usize {
         let value_history = self.value_history.borrow();
-        for history in value_history.iter().rev() {
-            println!("h {history:?}");
-            if history.epoch <= epoch {
-                println!("return {}", history.value);
-                return history.value.clone();
+        value_history.capacity() * size_of::<AttributeValue<DatabaseValue>>()
+            + value_history.iter().map(|history| history.value.approximate_size() - size_of::<DatabaseValue>()).sum::<usize>()
+    }
+
+    /// drop the history entries written after *epoch*
+    pub fn truncate_after(&self, epoch: Epoch) {
+        let mut value_history = self.value_history.borrow_mut();
+        let position = PhysicalAttribute::position_after(&value_history, epoch);
+        value_history.truncate(position);
+    }
+
+    /// collapse the history entries written strictly between *after* and *before* into the
+    /// last of them. branched entries are kept. return the number of dropped entries
+    pub fn compact(&self, after: Epoch, before: Epoch) -> usize {
+        let mut value_history = self.value_history.borrow_mut();
+        let in_range: Vec<usize> = value_history.iter().enumerate()
+            .filter(|(_, history)| !history.branched && history.epoch > after && history.epoch < before)
+            .map(|(i, _)| i)
+            .collect();
+        if in_range.len() < 2 {
+            return 0;
+        }
+        let dropped = &in_range[..in_range.len() - 1];
+        let mut i = 0;
+        value_history.retain(|_| {
+            i += 1;
+            dropped.binary_search(&(i - 1)).is_err()
+        });
+        dropped.len()
+    }
+
+    /// replace the history of this attribute by the given (epoch, value) entries, ordered by epoch
+    fn restore_history(&self, history: Vec<(Epoch, DatabaseValue)>) {
+        *self.value_history.borrow_mut() = history.into_iter().map(|(epoch, value)| AttributeValue { epoch, value, branched: false }).collect();
+    }
+
+    /// replace the history of this attribute by a copy of the *other* one
+    pub fn copy_history_from(&self, other: &PhysicalAttribute) {
+        *self.value_history.borrow_mut() = other.value_history.borrow().clone();
+        for history in self.value_history.borrow().iter() {
+            self.register(&history.value);
+        }
+    }
+
+    /// apply *pk* to the foreign key values referencing the entity with *uuid*, in place so the
+    /// history is kept unchanged, and register them under it. return whether a value was
+    /// rewritten, the ones sharing the identifier of the entity having the pk already
+    pub fn apply_referenced_pk(&self, uuid: &Uuid, pk: PK) -> bool {
+        let mut rewritten = false;
+        for history in self.value_history.borrow().iter() {
+            if let DatabaseValue::ForeignKey(identifier) = &history.value {
+                if identifier.get_uuid() == uuid {
+                    if identifier.get_pk() != Some(pk) {
+                        identifier.set_applied_pk(pk);
+                        rewritten = true;
+                    }
+                    if let Some(link) = self.reverse_link.borrow().as_ref() {
+                        link.register(&self.attribute_name, &history.value);
+                    }
+                }
             }
         }
-        // return the initial value instead
-        let initial = value_history.first().unwrap();
-        return initial.value.clone();
+        rewritten
+    }
+
+    /// all the values taken by this attribute, ordered by epoch
+    pub fn history(&self) -> Vec<(Epoch, DatabaseValue)> {
+        self.value_history.borrow().iter().filter(|history| !history.branched).map(|history| (history.epoch, history.value.clone())).collect()
+    }
+
+    /// position of the first history entry written after *epoch*.
+    /// the history is kept ordered by epoch, so it can be binary searched
+    fn position_after(value_history: &[AttributeValue<DatabaseValue>], epoch: Epoch) -> usize {
+        value_history.partition_point(|history| history.epoch <= epoch)
+    }
+
+    /// the value at *epoch*, or MissingValue if none was written at or before it
+    pub fn try_get_at_epoch(&self, epoch: Epoch) -> Result<DatabaseValue, EntityError> {
+        let value_history = self.value_history.borrow();
+        let position = PhysicalAttribute::position_after(&value_history, epoch);
+        value_history[..position].iter().rev()
+            .find(|history| !history.branched)
+            .map(|history| history.value.clone())
+            .ok_or_else(|| EntityError::MissingValue(self.attribute_name.to_string(), epoch))
+    }
+
+    /// the value at *epoch*, or DatabaseValue::None if none was written at or before it
+    pub fn get_at_epoch(&self, epoch: Epoch) -> DatabaseValue {
+        self.try_get_at_epoch(epoch).unwrap_or(DatabaseValue::None)
+    }
+
+    /// call *f* with the value at *epoch* without cloning it, DatabaseValue::None if none was
+    /// written at or before it. *f* may not write this attribute
+    pub fn read_at_epoch<T>(&self, epoch: Epoch, f: impl FnOnce(&DatabaseValue) -> T) -> T {
+        match self.borrow_at_epoch(epoch) {
+            Some(value) => f(&value),
+            None => f(&DatabaseValue::None),
+        }
+    }
+
+    /// the value at *epoch* borrowed from the history, None if none was written at or before
+    /// it. the attribute may not be written while the value is borrowed
+    pub fn borrow_at_epoch(&self, epoch: Epoch) -> Option<Ref<'_, DatabaseValue>> {
+        Ref::filter_map(self.value_history.borrow(), |value_history| {
+            let position = PhysicalAttribute::position_after(value_history, epoch);
+            value_history[..position].iter().rev().find(|history| !history.branched).map(|history| &history.value)
+        }).ok()
+    }
+
+    /// call *f* with the current value, see read_at_epoch()
+    pub fn read_value<T>(&self, f: impl FnOnce(&DatabaseValue) -> T) -> T {
+        self.read_at_epoch(self.current_epoch_ptr.get_epoch(), f)
     }
 
     fn insert_at_epoch(&self, value: DatabaseValue, epoch: Epoch) {
+        self.register(&value);
         let mut value_history = self.value_history.borrow_mut();
+        let position = PhysicalAttribute::position_after(&value_history, epoch);
+        value_history.insert(position, AttributeValue { epoch, value, branched: false });
+    }
 
-        let history_value = AttributeValue { epoch, value };
-        for (i, hist) in value_history.iter().enumerate() {
-            if hist.epoch > epoch {
-                value_history.insert(i, history_value);
-                return;
-            }
-        }
+    /// the value in the branch forked at *base_epoch* with the write epoch *branch*:
+    /// the value written in the branch, or the one at *base_epoch*
+    pub fn get_in_branch(&self, branch: Epoch, base_epoch: Epoch) -> DatabaseValue {
+        let value_history = self.value_history.borrow();
+        let position = PhysicalAttribute::position_after(&value_history, branch);
+        value_history[..position].iter().rev()
+            .find(|history| history.branched && history.epoch == branch)
+            .map(|history| history.value.clone())
+            .unwrap_or_else(|| self.get_at_epoch(base_epoch))
+    }
+
+    /// write *value* in the branch with the write epoch *branch*
+    pub fn set_in_branch(&self, value: DatabaseValue, branch: Epoch) {
+        let mut value_history = self.value_history.borrow_mut();
+        let position = PhysicalAttribute::position_after(&value_history, branch);
+        value_history.insert(position, AttributeValue { epoch: branch, value, branched: true });
+    }
 
-        value_history.push(history_value);
+    /// remove the values written in the branch *branch*, and return the last of them
+    pub fn take_branch(&self, branch: Epoch) -> Option<DatabaseValue> {
+        let mut value_history = self.value_history.borrow_mut();
+        let mut taken = None;
+        value_history.retain(|history| {
+            if history.branched && history.epoch == branch {
+                taken = Some(history.value.clone());
+                false
+            } else {
+                true
+            }
+        });
+        taken
     }
 }
 
@@ -162,6 +418,10 @@ impl BaseEntityAttribute for PhysicalAttribute {
     fn set_value(&self, value: DatabaseValue, epoch: Epoch) {
         self.insert_at_epoch(value, epoch);
     }
+
+    fn set_current_value(&self, value: DatabaseValue) {
+        self.insert_at_epoch(value, self.current_epoch_ptr.get_epoch());
+    }
 }
 
 pub type PK = i64;
@@ -169,15 +429,16 @@ pub type PK = i64;
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct EntityIdentifier {
-    model: Model,
-    pk: Option<PK>,
+    model: Symbol,
+    // the pk is applied once the entity is persisted, shared by the clones so that they all see it
+    pk: Arc<Mutex<Option<PK>>>,
     uuid: Uuid
 }
 
 impl Display for EntityIdentifier {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let pk_str = if self.has_applied_pk() {
-            format!("pk={}", self.pk.unwrap())
+            format!("pk={}", self.get_pk().unwrap())
         } else {
             "not persisted".to_string()
         };
@@ -185,28 +446,63 @@ impl Display for EntityIdentifier {
     }
 }
 
+/// the identifiers are the same entity if they have the same model and pk once it's applied,
+/// the same uuid before
 impl PartialEq for EntityIdentifier {
     fn eq(&self, other: &Self) -> bool {
-        if self.uuid == other.uuid {
-            return true
+        match (self.get_pk(), other.get_pk()) {
+            (Some(pk), Some(other_pk)) => pk == other_pk && self.model == other.model,
+            (None, None) => self.uuid == other.uuid,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for EntityIdentifier {}
+
+/// consistent with the equality: hashed by model and pk once the pk is applied, by uuid before.
+/// the hash changes when the pk is applied
+impl Hash for EntityIdentifier {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.get_pk() {
+            Some(pk) => (&self.model, pk).hash(state),
+            None => self.uuid.hash(state),
         }
-        self.has_applied_pk() && other.has_applied_pk() && self.model == other.model && self.pk == other.pk
     }
 }
 
 impl EntityIdentifier {
+    /// the identifier with the given *uuid*, as serialized
+    pub fn restore(model: Model, pk: Option<PK>, uuid: Uuid) -> EntityIdentifier {
+        EntityIdentifier {
+            model: Symbol::from(model),
+            pk: Arc::new(Mutex::new(pk)),
+            uuid,
+        }
+    }
+
+    /// a new identifier of the interned *model*, applied *pk* if given, for the batches of
+    /// entities of a model
+    pub fn of_model(model: &Symbol, pk: Option<PK>) -> EntityIdentifier {
+        EntityIdentifier {
+            model: Arc::clone(model),
+            pk: Arc::new(Mutex::new(pk)),
+            uuid: Uuid::new_v4()
+        }
+    }
+
     pub fn new(model: Model) -> EntityIdentifier {
         EntityIdentifier {
-            model,
-            pk: None,
+            model: Symbol::from(model),
+            pk: Arc::new(Mutex::new(None)),
             uuid: Uuid::new_v4()
         }
     }
 
     pub fn new_persisted(model: Model, pk: PK) -> EntityIdentifier {
         EntityIdentifier {
-            model,
-            pk: Some(pk),
+            model: Symbol::from(model),
+            pk: Arc::new(Mutex::new(Some(pk))),
             uuid: Uuid::new_v4()
         }
     }
@@ -216,31 +512,71 @@ impl EntityIdentifier {
         &self.uuid
     }
 
-    pub fn get_model(&self) -> &Model {
+    pub fn get_model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn get_model_symbol(&self) -> &Symbol {
         &self.model
     }
 
+    /// the same identifier, its model name interned in *symbols*
+    pub fn interned(mut self, symbols: &Symbols) -> EntityIdentifier {
+        self.model = symbols.intern_symbol(&self.model);
+        self
+    }
+
+    /// a copy with its own pk, that applying the pk to this identifier or its clones doesn't reach
+    pub fn detached(&self) -> EntityIdentifier {
+        EntityIdentifier {
+            model: Arc::clone(&self.model),
+            pk: Arc::new(Mutex::new(self.get_pk())),
+            uuid: self.uuid,
+        }
+    }
+
+    fn get_pk(&self) -> Option<PK> {
+        // the lock is only held to read or write the pk, it can't be poisoned
+        *self.pk.lock().unwrap()
+    }
+
     pub fn has_applied_pk(&self) -> bool {
-        self.pk.is_some()
+        self.get_pk().is_some()
     }
 
-    pub fn get_applied_pk(&self) -> Result<&PK, EntityError> {
-        match &self.pk {
+    pub fn get_applied_pk(&self) -> Result<PK, EntityError> {
+        match self.get_pk() {
             None => Err(EntityError::UnpersistedEntity(self.clone())),
             Some(pk) => Ok(pk)
         }
 
     }
 
-    pub fn set_applied_pk(&mut self, pk: PK) {
-        self.pk = Some(pk)
+    pub fn set_applied_pk(&self, pk: PK) {
+        *self.pk.lock().unwrap() = Some(pk)
     }
 }
 
 
 pub struct Entity {
     identifier: EntityIdentifier,
-    physical_attributes: HashMap<String, Rc<PhysicalAttribute>>,
+    // the names are interned, shared by all the entities of the model
+    physical_attributes: HashMap<Symbol, Rc<PhysicalAttribute>>,
+    // the same attributes sorted by name, addressed by position by the compiled expressions
+    slots: Vec<Rc<PhysicalAttribute>>,
+    // target model of each foreign key attribute, None for generic ones
+    related_models: HashMap<Symbol, Option<Symbol>>,
+    on_delete: HashMap<Symbol, OnDelete>,
+    // foreign keys referencing a distinct entity for each entity, like django's OneToOneField
+    one_to_one: HashSet<Symbol>,
+    // the attributes declared with another field type than Any
+    field_types: HashMap<Symbol, FieldType>,
+    // the attributes declared with validators
+    validators: HashMap<Symbol, Vec<Validator>>,
+    // epoch the entity appeared at: the initial epoch for entities persisted in the database
+    created_epoch: Epoch,
+    // epoch the entity was deleted at, it stays visible at earlier epochs until garbage collected
+    deleted_epoch: Cell<Option<Epoch>>,
 }
 
 impl Debug for Entity {
@@ -249,59 +585,318 @@ impl Debug for Entity {
     }
 }
 
+/// what happens to the entities referencing an entity when it's deleted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnDelete {
+    /// delete them as well
+    Cascade,
+    /// empty their foreign key
+    SetNull,
+    /// refuse the deletion
+    Protect,
+    DoNothing,
+}
+
 #[derive(Clone, Debug)]
 pub enum AttributeKind {
     Physical,
+    /// reference to an entity of the given model, by identifier or by pk
+    ForeignKey(Model, OnDelete),
+    /// foreign key that no two entities may share the value of
+    OneToOne(Model, OnDelete),
+    /// reference to an entity of any model, by identifier (or model and pk).
+    /// it has no on_delete behavior, like django's GenericForeignKey
+    GenericForeignKey,
     // ManyToMany,
 }
 
+/// the kind, name and (epoch, value) history of an attribute, see Entity::restore()
+pub type AttributeHistory = (AttributeKind, String, Vec<(Epoch, DatabaseValue)>);
+
+/// the python type the values of an attribute are converted from and to, stored as a
+/// DatabaseValue: bools as numbers, dates and datetimes as iso formatted strings
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldType {
+    /// any value a DatabaseValue holds, unconverted
+    #[default]
+    Any,
+    Int,
+    Str,
+    Bool,
+    DateTime,
+    Date,
+}
+
+/// the check of a Validator::Custom, giving the reason the value is refused
+pub type ValidatorFn = dyn Fn(&DatabaseValue) -> Result<(), String> + Send + Sync;
+
+/// a check of the values written to an attribute, see Entity::validate()
+#[derive(Clone)]
+pub enum Validator {
+    /// the value must be one of these, like the choices of a django field
+    Choices(Vec<DatabaseValue>),
+    /// the value must not be None, like a django field with null=False. the entities created
+    /// without a value for the attribute can't be flushed, see Entity::missing_values()
+    NotNull,
+    /// the strings must have at most this number of characters, like a django CharField
+    MaxLength(usize),
+    /// the numbers must be greater than or equal to this bound, like django's MinValueValidator
+    MinValue(i64),
+    /// the numbers must be less than or equal to this bound, like django's MaxValueValidator
+    MaxValue(i64),
+    /// a function giving the reason the value is refused, like a python callable
+    Custom(Arc<ValidatorFn>),
+}
+
+impl Debug for Validator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Validator::Choices(choices) => f.debug_tuple("Choices").field(choices).finish(),
+            Validator::NotNull => f.write_str("NotNull"),
+            Validator::MaxLength(max_length) => f.debug_tuple("MaxLength").field(max_length).finish(),
+            Validator::MinValue(min_value) => f.debug_tuple("MinValue").field(min_value).finish(),
+            Validator::MaxValue(max_value) => f.debug_tuple("MaxValue").field(max_value).finish(),
+            Validator::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Validator {
+    /// the reason *value* is refused, if it is
+    pub fn validate(&self, value: &DatabaseValue) -> Result<(), String> {
+        match self {
+            Validator::Choices(choices) if !choices.contains(value) => Err(format!("{} is not a valid choice", value)),
+            Validator::Choices(_) => Ok(()),
+            Validator::NotNull if *value == DatabaseValue::None => Err("this field cannot be null".to_string()),
+            Validator::NotNull => Ok(()),
+            Validator::MaxLength(max_length) => match value {
+                DatabaseValue::String(string) if string.chars().count() > *max_length => Err(format!(
+                    "ensure this value has at most {} characters (it has {})", max_length, string.chars().count(),
+                )),
+                _ => Ok(()),
+            },
+            Validator::MinValue(min_value) => match value {
+                DatabaseValue::Number(number) if number < min_value => Err(format!("ensure this value is greater than or equal to {}", min_value)),
+                _ => Ok(()),
+            },
+            Validator::MaxValue(max_value) => match value {
+                DatabaseValue::Number(number) if number > max_value => Err(format!("ensure this value is less than or equal to {}", max_value)),
+                _ => Ok(()),
+            },
+            Validator::Custom(validate) => validate(value),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AttributeDescriptor {
     kind: AttributeKind,
-    name: String,
+    // interned once, the entities instantiated from a schema share the names of its descriptors
+    name: Symbol,
+    // the model referenced by the foreign keys, interned like the name
+    related_model: Option<Symbol>,
     initial: DatabaseValue,
+    field_type: FieldType,
+    validators: Vec<Validator>,
 }
 
 impl AttributeDescriptor {
     pub fn new(kind: AttributeKind, name: String, initial: DatabaseValue) -> Self {
+        let related_model = match &kind {
+            AttributeKind::ForeignKey(model, _) | AttributeKind::OneToOne(model, _) => Some(Symbol::from(model.as_str())),
+            AttributeKind::GenericForeignKey | AttributeKind::Physical => None,
+        };
         AttributeDescriptor {
             kind,
-            name,
-            initial
+            name: Symbol::from(name),
+            related_model,
+            initial,
+            field_type: FieldType::Any,
+            validators: vec![],
         }
     }
+
+    /// the same attribute, its names interned in *symbols*, shared by the entities of the store
+    pub fn interned(mut self, symbols: &Symbols) -> Self {
+        self.name = symbols.intern_symbol(&self.name);
+        self.related_model = self.related_model.map(|model| symbols.intern_symbol(&model));
+        self
+    }
+
+    /// the same attribute, its values checked by *validator* as well
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validators.push(validator);
+        self
+    }
+
+    /// check *value* with the validators of the attribute, in order
+    pub fn validate(&self, value: &DatabaseValue) -> Result<(), EntityError> {
+        validate(&self.name, &self.validators, value)
+    }
+
+    pub fn with_field_type(mut self, field_type: FieldType) -> Self {
+        self.field_type = field_type;
+        self
+    }
+
+    pub fn get_field_type(&self) -> FieldType {
+        self.field_type
+    }
+
+    /// the same attribute, with *initial* as initial value
+    pub fn with_initial(mut self, initial: DatabaseValue) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_kind(&self) -> &AttributeKind {
+        &self.kind
+    }
+
+    pub fn get_initial(&self) -> &DatabaseValue {
+        &self.initial
+    }
 }
 
-impl<'a> PartialEq for Entity {
-    fn eq(&self, other: &Self) -> bool {
-        self.identifier == other.identifier
+fn validate(attribute: &str, validators: &[Validator], value: &DatabaseValue) -> Result<(), EntityError> {
+    for validator in validators {
+        validator.validate(value).map_err(|message| EntityError::Validation(attribute.to_string(), message))?;
     }
+    Ok(())
+}
 
-    fn ne(&self, other: &Self) -> bool {
-        self.identifier != other.identifier
+impl PartialEq for Entity {
+    fn eq(&self, other: &Self) -> bool {
+        self.identifier == other.identifier
     }
-} 
+}
 
 impl<'a> Entity {
     pub fn new(identifier: EntityIdentifier, attributes: Vec<AttributeDescriptor>, initial_ptr: Rc<EpochPtr>, current_ptr: Rc<EpochPtr>) -> Self {
-        let mut physicals: HashMap<String, Rc<PhysicalAttribute>> = HashMap::new();
+        let mut physicals: HashMap<Symbol, Rc<PhysicalAttribute>> = HashMap::new();
+        let mut related_models: HashMap<Symbol, Option<Symbol>> = HashMap::new();
+        let mut on_delete: HashMap<Symbol, OnDelete> = HashMap::new();
+        let mut one_to_one: HashSet<Symbol> = HashSet::new();
+        let mut field_types: HashMap<Symbol, FieldType> = HashMap::new();
+        let mut validators: HashMap<Symbol, Vec<Validator>> = HashMap::new();
 
         for attribute in attributes {
+            let name = attribute.name;
             match attribute.kind {
-                // AttributeKind::ManyToMany => panic!("not yet implemented"),
-                AttributeKind::Physical => {
-                    let attr = PhysicalAttribute::new(attribute.name.to_string(), Rc::clone(&current_ptr), Rc::clone(&initial_ptr));
-                    attr.set_value(attribute.initial, initial_ptr.get_epoch());
-                    physicals.insert(attribute.name, Rc::new(attr));
-                }
+                AttributeKind::ForeignKey(_, policy) => {
+                    related_models.insert(Arc::clone(&name), attribute.related_model);
+                    on_delete.insert(Arc::clone(&name), policy);
+                },
+                AttributeKind::OneToOne(_, policy) => {
+                    related_models.insert(Arc::clone(&name), attribute.related_model);
+                    on_delete.insert(Arc::clone(&name), policy);
+                    one_to_one.insert(Arc::clone(&name));
+                },
+                AttributeKind::GenericForeignKey => {
+                    related_models.insert(Arc::clone(&name), None);
+                    on_delete.insert(Arc::clone(&name), OnDelete::DoNothing);
+                },
+                AttributeKind::Physical => {},
+            }
+            if attribute.field_type != FieldType::Any {
+                field_types.insert(Arc::clone(&name), attribute.field_type);
             }
+            if !attribute.validators.is_empty() {
+                validators.insert(Arc::clone(&name), attribute.validators);
+            }
+            // AttributeKind::ManyToMany => panic!("not yet implemented"),
+            // foreign keys values are stored like the physical ones
+            let attr = PhysicalAttribute::new(Arc::clone(&name), Rc::clone(&current_ptr), Rc::clone(&initial_ptr));
+            attr.set_value(attribute.initial, initial_ptr.get_epoch());
+            physicals.insert(name, Rc::new(attr));
         }
+        let created_epoch = if identifier.has_applied_pk() {
+            initial_ptr.get_epoch()
+        } else {
+            current_ptr.get_epoch()
+        };
         Entity {
             identifier,
+            slots: Entity::slots(&physicals),
             physical_attributes: physicals,
+            related_models,
+            on_delete,
+            one_to_one,
+            field_types,
+            validators,
+            created_epoch,
+            deleted_epoch: Cell::new(None),
+        }
+    }
+
+    /// the entity with the given (kind, name, history) *attributes*, created and deleted at the
+    /// given epochs, as serialized. the names are interned in *symbols*
+    pub fn restore(identifier: EntityIdentifier, attributes: Vec<AttributeHistory>, created_epoch: Epoch, deleted_epoch: Option<Epoch>, symbols: &Symbols, initial_ptr: Rc<EpochPtr>, current_ptr: Rc<EpochPtr>) -> Self {
+        let descriptors = attributes.iter().map(|(kind, name, _)| AttributeDescriptor::new(kind.clone(), name.clone(), DatabaseValue::None).interned(symbols)).collect();
+        let mut entity = Entity::new(identifier.interned(symbols), descriptors, initial_ptr, current_ptr);
+        for (_, name, history) in attributes {
+            entity.physical_attributes[name.as_str()].restore_history(history);
+        }
+        entity.created_epoch = created_epoch;
+        entity.deleted_epoch.set(deleted_epoch);
+        entity
+    }
+
+    /// return an independent copy of this entity (same identifier, copied histories)
+    /// bound to the given epoch pointers
+    pub fn snapshot(&self, initial_ptr: &Rc<EpochPtr>, current_ptr: &Rc<EpochPtr>) -> Entity {
+        let physical_attributes = self.physical_attributes.iter().map(
+            |(name, attr)| (Arc::clone(name), Rc::new(attr.snapshot(Rc::clone(current_ptr), Rc::clone(initial_ptr))))
+        ).collect();
+        Entity {
+            identifier: self.identifier.detached(),
+            slots: Entity::slots(&physical_attributes),
+            physical_attributes,
+            related_models: self.related_models.clone(),
+            on_delete: self.on_delete.clone(),
+            one_to_one: self.one_to_one.clone(),
+            field_types: self.field_types.clone(),
+            validators: self.validators.clone(),
+            created_epoch: self.created_epoch,
+            deleted_epoch: self.deleted_epoch.clone(),
+        }
+    }
+
+    /// replace the histories of all attributes shared with *other* by a copy of *other* ones
+    pub fn copy_history_from(&self, other: &Entity) {
+        for (name, attr) in self.physical_attributes.iter() {
+            if let Some(other_attr) = other.physical_attributes.get(&**name) {
+                attr.copy_history_from(other_attr);
+            }
         }
     }
 
+    fn slots(physical_attributes: &HashMap<Symbol, Rc<PhysicalAttribute>>) -> Vec<Rc<PhysicalAttribute>> {
+        let mut slots: Vec<Rc<PhysicalAttribute>> = physical_attributes.values().cloned().collect();
+        slots.sort_by(|left, right| left.attribute_name.cmp(&right.attribute_name));
+        slots
+    }
+
+    /// the slot of *attribute*, its position among the attributes sorted by name. the entities
+    /// with the same attributes have them at the same slots
+    pub fn slot_of(&self, attribute: &str) -> Option<usize> {
+        self.slots.binary_search_by(|attr| (*attr.attribute_name).cmp(attribute)).ok()
+    }
+
+    /// the attribute at *slot* if it is *attribute*, at once if they're the same interned name
+    pub fn get_slot(&self, slot: usize, attribute: &Symbol) -> Option<&PhysicalAttribute> {
+        self.slots.get(slot).map(Rc::as_ref).filter(|attr| attr.attribute_name == *attribute)
+    }
+
+    /// same as get(), borrowing the attribute from the entity
+    pub fn get_ref(&self, attribute: &str) -> Result<&PhysicalAttribute, EntityError> {
+        self.physical_attributes.get(attribute).map(Rc::as_ref).ok_or_else(|| EntityError::AttributeNotFound(attribute.to_string()))
+    }
+
     pub fn get<'b>(&'a self, attribute: &'b str) -> Result<Rc<PhysicalAttribute>, EntityError> {
         if let Some(attr) = self.physical_attributes.get(attribute) {
             Ok(Rc::clone(attr))
@@ -310,7 +905,247 @@ impl<'a> Entity {
         }
     }
 
-    pub fn get_identifier(&'a self) -> &EntityIdentifier {
+    /// resolve the entity referenced by the foreign key *attribute* through the *store* index.
+    /// return None if the foreign key is empty
+    pub fn get_related(&self, store: &EntityStore, attribute: &str) -> Result<Option<Rc<Entity>>, EntityError> {
+        let model = self.related_models.get(attribute).ok_or_else(|| EntityError::NotARelation(attribute.to_string()))?;
+        match self.get(attribute)?.get_value() {
+            DatabaseValue::ForeignKey(identifier) => store.get(&identifier).map(Some),
+            DatabaseValue::Number(pk) => match model {
+                Some(model) => store.get_persisted(model, pk).map(Some),
+                // a bare pk doesn't tell which model a generic foreign key references
+                None => Err(EntityError::NotARelation(attribute.to_string())),
+            },
+            DatabaseValue::None => Ok(None),
+            DatabaseValue::String(_) => Err(EntityError::NotARelation(attribute.to_string())),
+        }
+    }
+
+    /// validate *value* and write it in *attribute* at *epoch*, or at the current epoch if not
+    /// given. return the value it replaces
+    pub fn write(&self, attribute: &str, value: DatabaseValue, epoch: Option<Epoch>) -> Result<DatabaseValue, EntityError> {
+        let physical_attribute = self.get(attribute)?;
+        self.validate(attribute, &value)?;
+        Ok(match epoch {
+            Some(epoch) => {
+                let previous = physical_attribute.get_at_epoch(epoch);
+                physical_attribute.set_value(value, epoch);
+                previous
+            },
+            None => {
+                let previous = physical_attribute.get_value();
+                physical_attribute.set_current_value(value);
+                previous
+            },
+        })
+    }
+
+    /// register the foreign keys of this entity in *index*, see EntityStore::related()
+    pub fn bind_reverse_index(&self, index: &Rc<ReverseIndex>) {
+        for (name, model) in self.related_models.iter() {
+            if let Some(attr) = self.physical_attributes.get(name) {
+                attr.bind_reverse_link(ReverseLink::new(Rc::clone(index), Arc::clone(&self.identifier.model), *self.identifier.get_uuid(), model.clone()));
+            }
+        }
+    }
+
+    /// register the values of *attribute* in *index*, see EntityStore::add_index(). false if the
+    /// entity has no such physical attribute
+    pub fn bind_value_index(&self, index: &Rc<ValueIndex>, attribute: &str) -> bool {
+        let Some(attr) = self.physical_attributes.get(attribute) else { return false };
+        attr.bind_index_link(IndexLink::new(Rc::clone(index), Arc::clone(&self.identifier.model), *self.identifier.get_uuid()));
+        true
+    }
+
+    /// the model the foreign key *attribute* references, None for generic ones
+    pub fn get_related_model(&self, attribute: &str) -> Result<Option<&Symbol>, EntityError> {
+        self.related_models.get(attribute).map(Option::as_ref).ok_or_else(|| EntityError::NotARelation(attribute.to_string()))
+    }
+
+    /// the on_delete behavior of the foreign key *attribute*
+    pub fn get_on_delete(&self, attribute: &str) -> Result<OnDelete, EntityError> {
+        self.on_delete.get(attribute).copied().ok_or_else(|| EntityError::NotARelation(attribute.to_string()))
+    }
+
+    /// true if the foreign key *attribute* currently references *target*
+    pub fn references(&self, attribute: &str, target: &Entity) -> Result<bool, EntityError> {
+        let model = self.related_models.get(attribute).ok_or_else(|| EntityError::NotARelation(attribute.to_string()))?;
+        Ok(match self.get(attribute)?.get_value() {
+            DatabaseValue::ForeignKey(identifier) => identifier == target.identifier,
+            DatabaseValue::Number(pk) => model.as_deref() == Some(target.identifier.get_model()) && target.identifier.get_applied_pk() == Ok(pk),
+            _ => false,
+        })
+    }
+
+    /// the names of the foreign key attributes, sorted
+    pub fn relation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.related_models.keys().map(|name| name.to_string()).collect();
+        names.sort();
+        names
+    }
+
+    /// the kind *attribute* was declared with
+    pub fn get_kind(&self, attribute: &str) -> Result<AttributeKind, EntityError> {
+        self.get(attribute)?;
+        Ok(match self.related_models.get(attribute) {
+            None => AttributeKind::Physical,
+            Some(None) => AttributeKind::GenericForeignKey,
+            Some(Some(model)) if self.one_to_one.contains(attribute) => AttributeKind::OneToOne(model.to_string(), self.get_on_delete(attribute)?),
+            Some(Some(model)) => AttributeKind::ForeignKey(model.to_string(), self.get_on_delete(attribute)?),
+        })
+    }
+
+    /// true if *attribute* is a one to one foreign key
+    pub fn is_one_to_one(&self, attribute: &str) -> bool {
+        self.one_to_one.contains(attribute)
+    }
+
+    /// the field type *attribute* was declared with, Any by default
+    pub fn get_field_type(&self, attribute: &str) -> FieldType {
+        self.field_types.get(attribute).copied().unwrap_or_default()
+    }
+
+    /// check *value* with the validators *attribute* was declared with, before it's written
+    pub fn validate(&self, attribute: &str, value: &DatabaseValue) -> Result<(), EntityError> {
+        validate(attribute, self.validators.get(attribute).map(Vec::as_slice).unwrap_or_default(), value)
+    }
+
+    /// the names of the NotNull attributes holding None at the current epoch, ordered
+    pub fn missing_values(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.validators.iter()
+            .filter(|(name, validators)| validators.iter().any(|validator| matches!(validator, Validator::NotNull)) && self.physical_attributes[*name].get_value() == DatabaseValue::None)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// the messages of the validators refusing the current values of the attributes, see
+    /// EntityStore::clean()
+    pub fn clean_values(&self) -> MessageDict {
+        let mut messages = MessageDict::new();
+        for (name, validators) in self.validators.iter() {
+            let value = self.physical_attributes[name].get_value();
+            let refused: Vec<String> = validators.iter().filter_map(|validator| validator.validate(&value).err()).collect();
+            if !refused.is_empty() {
+                messages.insert(name.to_string(), refused);
+            }
+        }
+        messages
+    }
+
+    /// the entities of *model* whose foreign key *attribute* references this entity
+    pub fn related(&self, store: &EntityStore, model: &Model, attribute: &str) -> Vec<Rc<Entity>> {
+        store.related(self, model, attribute)
+    }
+
+    /// drop the history entries of all attributes written after *epoch*, and the deletion if it
+    /// happened after *epoch*
+    pub fn truncate_after(&self, epoch: Epoch) {
+        for attr in self.physical_attributes.values() {
+            attr.truncate_after(epoch);
+        }
+        if self.deleted_epoch.get().is_some_and(|deleted| deleted > epoch) {
+            self.deleted_epoch.set(None);
+        }
+    }
+
+    /// restore the values all attributes had at *epoch*, by writing them at the current epoch.
+    /// the history is kept so that the revert itself can be rolled back or undone
+    pub fn revert_to(&self, epoch: Epoch) {
+        for attr in self.physical_attributes.values() {
+            let value = attr.get_at_epoch(epoch);
+            if attr.read_value(|current| *current != value) {
+                attr.set_current_value(value);
+            }
+        }
+    }
+
+    /// remove the values written in the branch *branch*, returned by attribute name
+    pub fn take_branch(&self, branch: Epoch) -> Vec<(Symbol, DatabaseValue)> {
+        self.physical_attributes.iter()
+            .filter_map(|(name, attr)| attr.take_branch(branch).map(|value| (Symbol::clone(name), value)))
+            .collect()
+    }
+
+    /// remove the values written in the branch *branch*
+    pub fn discard_branch(&self, branch: Epoch) {
+        for attr in self.physical_attributes.values() {
+            attr.take_branch(branch);
+        }
+    }
+
+    /// collapse the history entries of all attributes written strictly between *after* and *before*
+    pub fn compact(&self, after: Epoch, before: Epoch) -> usize {
+        self.physical_attributes.values().map(|attr| attr.compact(after, before)).sum()
+    }
+
+    /// total number of history entries of all attributes
+    pub fn history_len(&self) -> usize {
+        self.physical_attributes.values().map(|attr| attr.history_len()).sum()
+    }
+
+    /// approximate number of bytes used by this entity, attributes included
+    pub fn approximate_size(&self) -> usize {
+        // the names are interned, they aren't counted
+        size_of::<Entity>()
+            + self.physical_attributes.values().map(|attr| size_of::<(Symbol, Rc<PhysicalAttribute>)>() + size_of::<Rc<PhysicalAttribute>>() + attr.approximate_size()).sum::<usize>()
+    }
+
+    /// approximate number of bytes used by the histories of the attributes, included in
+    /// approximate_size()
+    pub fn history_size(&self) -> usize {
+        self.physical_attributes.values().map(|attr| attr.history_size()).sum()
+    }
+
+    pub fn get_created_epoch(&self) -> Epoch {
+        self.created_epoch
+    }
+
+    pub fn get_deleted_epoch(&self) -> Option<Epoch> {
+        self.deleted_epoch.get()
+    }
+
+    /// mark the entity as deleted from *epoch* onward
+    pub fn delete_at(&self, epoch: Epoch) {
+        self.deleted_epoch.set(Some(epoch));
+    }
+
+    /// false if the entity was deleted at or before *epoch*
+    pub fn is_alive_at(&self, epoch: Epoch) -> bool {
+        self.deleted_epoch.get().is_none_or(|deleted| epoch < deleted)
+    }
+
+    /// names of the attributes whose value at *to* differs from their value at *from*, sorted
+    pub fn changed_attributes_between(&self, from: Epoch, to: Epoch) -> Vec<String> {
+        let mut changed: Vec<String> = self.physical_attributes.iter()
+            .filter(|(_, attr)| attr.read_at_epoch(from, |before| attr.read_at_epoch(to, |after| before != after)))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// names of the attributes whose current value differs from their initial value, sorted
+    pub fn changed_fields(&self) -> Vec<String> {
+        let mut changed: Vec<String> = self.physical_attributes.iter()
+            .filter(|(_, attr)| attr.get_initial() != attr.get_value())
+            .map(|(name, _)| name.to_string())
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// true if any attribute's current value differs from its initial value
+    pub fn is_dirty(&self) -> bool {
+        self.physical_attributes.values().any(|attr| attr.get_initial() != attr.get_value())
+    }
+
+    pub fn iter_attributes(&'a self) -> impl Iterator<Item=(&'a str, &'a Rc<PhysicalAttribute>)> {
+        self.physical_attributes.iter().map(|(name, attr)| (&**name, attr))
+    }
+
+    pub fn get_identifier(&'a self) -> &'a EntityIdentifier {
         &self.identifier
     }
 }
@@ -318,16 +1153,19 @@ impl<'a> Entity {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::rc::Rc;
-    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, EpochPtr, PhysicalAttribute};
+    use std::sync::Arc;
+    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, Epoch, EpochManager, EpochPtr, FieldType, OnDelete, PhysicalAttribute, Validator};
     use crate::errors::EntityError;
+    use crate::symbol::Symbol;
 
     #[test]
     fn get_ptr_slide() {
         let initial_ptr = Rc::new(EpochPtr::default());
         let current_ptr = Rc::new(EpochPtr::default());
         current_ptr.slide(2);
-        let attr: PhysicalAttribute = PhysicalAttribute::new("num".to_string(), Rc::clone(&current_ptr), initial_ptr);
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), Rc::clone(&current_ptr), initial_ptr);
         attr.set_value(DatabaseValue::Number(42), 0);
         attr.set_value(DatabaseValue::Number(52), 2);
 
@@ -342,13 +1180,139 @@ mod tests {
         assert_eq!(attr.get_value(), DatabaseValue::Number(42));
     }
 
+    #[test]
+    fn test_epoch_manager() {
+        let mut epoch_manager = EpochManager::new(1);
+        assert_eq!(epoch_manager.allocate(), 2);
+        assert_eq!(epoch_manager.allocate(), 3);
+        epoch_manager.advance_to(2);
+        assert_eq!(epoch_manager.allocate(), 4);
+        epoch_manager.advance_to(10);
+        assert_eq!(epoch_manager.allocate(), 11);
+    }
+
+    #[test]
+    fn test_set_current_value() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(3));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), Rc::clone(&current_ptr), initial_ptr);
+        attr.set_value(DatabaseValue::Number(42), 0);
+        attr.set_current_value(DatabaseValue::Number(52));
+
+        assert_eq!(attr.history(), vec![(0, DatabaseValue::Number(42)), (3, DatabaseValue::Number(52))]);
+    }
+
+    #[test]
+    fn test_history() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(5));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), current_ptr, initial_ptr);
+        attr.set_value(DatabaseValue::Number(42), 0);
+        attr.set_value(DatabaseValue::Number(62), 4);
+        attr.set_value(DatabaseValue::Number(52), 2);
+
+        assert_eq!(attr.history(), vec![(0, DatabaseValue::Number(42)), (2, DatabaseValue::Number(52)), (4, DatabaseValue::Number(62))]);
+        assert_eq!(attr.get_at_epoch(3), DatabaseValue::Number(52));
+        assert_eq!(attr.get_at_epoch(4), DatabaseValue::Number(62));
+    }
+
+    #[test]
+    fn test_history_ordering() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(100));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), current_ptr, initial_ptr);
+        for epoch in (0..100).rev().step_by(2) {
+            attr.set_value(DatabaseValue::Number(epoch), epoch);
+        }
+        attr.set_value(DatabaseValue::Number(-1), 51);
+
+        let epochs: Vec<Epoch> = attr.history().iter().map(|(epoch, _)| *epoch).collect();
+        assert!(epochs.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(attr.get_at_epoch(0), DatabaseValue::None);
+        assert_eq!(attr.get_at_epoch(50), DatabaseValue::Number(49));
+        assert_eq!(attr.get_at_epoch(51), DatabaseValue::Number(-1));
+        assert_eq!(attr.get_at_epoch(52), DatabaseValue::Number(-1));
+        assert_eq!(attr.get_at_epoch(53), DatabaseValue::Number(53));
+        assert_eq!(attr.get_value(), DatabaseValue::Number(99));
+
+        attr.truncate_after(51);
+        assert_eq!(attr.get_value(), DatabaseValue::Number(-1));
+    }
+
+    #[test]
+    fn test_empty_history() {
+        let initial_ptr = Rc::new(EpochPtr::new(2));
+        let current_ptr = Rc::new(EpochPtr::new(3));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), current_ptr, initial_ptr);
+        assert_eq!(attr.get_value(), DatabaseValue::None);
+        assert_eq!(attr.try_get_at_epoch(3), Err(EntityError::MissingValue("num".to_string(), 3)));
+
+        attr.set_value(DatabaseValue::Number(42), 2);
+        assert_eq!(attr.try_get_at_epoch(3), Ok(DatabaseValue::Number(42)));
+        assert_eq!(attr.get_at_epoch(1), DatabaseValue::None);
+    }
+
+    #[test]
+    fn test_read_value() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(2));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("title"), Rc::clone(&current_ptr), initial_ptr);
+        attr.set_value(DatabaseValue::String("dune".to_string()), 1);
+        attr.set_in_branch(DatabaseValue::String("draft".to_string()), 2);
+        assert!(attr.read_value(|value| *value == DatabaseValue::String("dune".to_string())));
+        assert_eq!(attr.read_at_epoch(0, |value| value.clone()), DatabaseValue::None);
+        attr.set_current_value(DatabaseValue::String("dune messiah".to_string()));
+        assert_eq!(attr.read_value(|value| format!("{:?}", value)), "String(\"dune messiah\")");
+        assert_eq!(attr.read_at_epoch(1, DatabaseValue::clone), DatabaseValue::String("dune".to_string()));
+    }
+
+    #[test]
+    fn test_branch() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), Rc::clone(&current_ptr), initial_ptr);
+        attr.set_value(DatabaseValue::Number(0), 0);
+        attr.set_value(DatabaseValue::Number(1), 1);
+        attr.set_in_branch(DatabaseValue::Number(20), 2);
+        current_ptr.slide(3);
+        attr.set_current_value(DatabaseValue::Number(3));
+
+        assert_eq!(attr.get_value(), DatabaseValue::Number(3));
+        assert_eq!(attr.get_at_epoch(2), DatabaseValue::Number(1));
+        assert_eq!(attr.history(), vec![(0, DatabaseValue::Number(0)), (1, DatabaseValue::Number(1)), (3, DatabaseValue::Number(3))]);
+        assert_eq!(attr.get_in_branch(2, 1), DatabaseValue::Number(20));
+        assert_eq!(attr.get_in_branch(4, 1), DatabaseValue::Number(1));
+        assert_eq!(attr.compact(0, 4), 1);
+        assert_eq!(attr.get_in_branch(2, 1), DatabaseValue::Number(20));
+
+        assert_eq!(attr.take_branch(2), Some(DatabaseValue::Number(20)));
+        assert_eq!(attr.take_branch(2), None);
+        assert_eq!(attr.history_len(), 2);
+    }
+
+    #[test]
+    fn test_compact() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(10));
+        let attr: PhysicalAttribute = PhysicalAttribute::new(Symbol::from("num"), current_ptr, initial_ptr);
+        for epoch in 0..6 {
+            attr.set_value(DatabaseValue::Number(epoch * 10), epoch);
+        }
+
+        assert_eq!(attr.compact(0, 4), 2);
+        assert_eq!(attr.history(), vec![(0, DatabaseValue::Number(0)), (3, DatabaseValue::Number(30)), (4, DatabaseValue::Number(40)), (5, DatabaseValue::Number(50))]);
+        assert_eq!(attr.get_initial(), DatabaseValue::Number(0));
+        assert_eq!(attr.get_value(), DatabaseValue::Number(50));
+        assert_eq!(attr.compact(0, 4), 0);
+    }
+
     #[test]
     fn test_entity() {
         let initial_ptr = Rc::new(EpochPtr::default());
         let current_ptr = Rc::new(EpochPtr::default());
         let entity = Entity::new(
             EntityIdentifier::new("User".to_string()),
-            vec![AttributeDescriptor { kind: AttributeKind::Physical, name: String::from("name"), initial: DatabaseValue::String("john".to_string()) }],
+            vec![AttributeDescriptor::new(AttributeKind::Physical, String::from("name"), DatabaseValue::String("john".to_string()))],
             initial_ptr,
             current_ptr,
         );
@@ -357,17 +1321,191 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_dirty_tracking() {
+        let initial_ptr = Rc::new(EpochPtr::new(0));
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let entity = Entity::new(
+            EntityIdentifier::new_persisted("User".to_string(), 1),
+            vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(20)),
+            ],
+            initial_ptr,
+            current_ptr,
+        );
+        assert!(!entity.is_dirty());
+        assert!(entity.changed_fields().is_empty());
+
+        entity.get("name").unwrap().set_current_value(DatabaseValue::String("john".to_string()));
+        assert!(!entity.is_dirty());
+
+        entity.get("age").unwrap().set_current_value(DatabaseValue::Number(21));
+        assert!(entity.is_dirty());
+        assert_eq!(entity.changed_fields(), vec!["age".to_string()]);
+    }
+
+    #[test]
+    fn test_revert_to() {
+        let initial_ptr = Rc::new(EpochPtr::new(0));
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let entity = Entity::new(
+            EntityIdentifier::new_persisted("User".to_string(), 1),
+            vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(20)),
+            ],
+            initial_ptr,
+            Rc::clone(&current_ptr),
+        );
+        entity.get("age").unwrap().set_current_value(DatabaseValue::Number(21));
+        current_ptr.slide(2);
+        entity.get("name").unwrap().set_current_value(DatabaseValue::String("jane".to_string()));
+        entity.get("age").unwrap().set_current_value(DatabaseValue::Number(22));
+
+        current_ptr.slide(3);
+        entity.revert_to(1);
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity.get("age").unwrap().get_value(), DatabaseValue::Number(21));
+        assert_eq!(entity.get("age").unwrap().get_at_epoch(2), DatabaseValue::Number(22));
+    }
+
     #[test]
     fn test_attr_not_found() {
         let initial_ptr = Rc::new(EpochPtr::default());
         let current_ptr = Rc::new(EpochPtr::default());
         let entity = Entity::new(
             EntityIdentifier::new("User".to_string()),
-            vec![AttributeDescriptor { kind: AttributeKind::Physical, name: String::from("name"), initial: DatabaseValue::String("john".to_string()) }],
+            vec![AttributeDescriptor::new(AttributeKind::Physical, String::from("name"), DatabaseValue::String("john".to_string()))],
             initial_ptr,
             current_ptr,
         );
         assert!(entity.get("oops").is_err());
         assert_eq!(entity.get("oops").unwrap_err(), EntityError::AttributeNotFound("oops".to_string()))
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_shared_names() {
+        let initial_ptr = Rc::new(EpochPtr::new(0));
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let descriptor = AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::DoNothing), "group".to_string(), DatabaseValue::None);
+        let entities: Vec<Entity> = (0..2).map(|_| Entity::new(
+            EntityIdentifier::new("User".to_string()), vec![descriptor.clone()], Rc::clone(&initial_ptr), Rc::clone(&current_ptr),
+        )).collect();
+        let names: Vec<&str> = entities.iter().flat_map(|entity| entity.iter_attributes().map(|(name, _)| name)).collect();
+        assert!(names.iter().all(|name| std::ptr::eq(name.as_ptr(), descriptor.get_name().as_ptr())));
+        assert_eq!(entities[0].get_related_model("group"), Ok(Some(&Symbol::from("Group"))));
+    }
+
+    #[test]
+    fn test_identifier_hash() {
+        let identifier = EntityIdentifier::new("User".to_string());
+        #[allow(clippy::mutable_key_type)]
+        let mut identifiers = HashSet::from([identifier.clone(), EntityIdentifier::new_persisted("User".to_string(), 1)]);
+        assert!(identifiers.contains(&identifier));
+        assert!(!identifiers.insert(EntityIdentifier::new_persisted("User".to_string(), 1)));
+        assert!(identifiers.insert(EntityIdentifier::new_persisted("Group".to_string(), 1)));
+    }
+
+    #[test]
+    fn test_identifier_pk() {
+        let identifier = EntityIdentifier::new("User".to_string());
+        let (clone, detached) = (identifier.clone(), identifier.detached());
+        assert!(clone == identifier && detached == identifier);
+        identifier.set_applied_pk(2);
+        // the clones share the pk, the detached copies keep their own
+        assert_eq!(clone.get_applied_pk(), Ok(2));
+        assert!(clone == EntityIdentifier::new_persisted("User".to_string(), 2));
+        assert!(!detached.has_applied_pk() && detached != identifier);
+        detached.set_applied_pk(3);
+        assert_eq!(identifier.get_applied_pk(), Ok(2));
+    }
+
+    #[test]
+    fn test_field_types() {
+        let initial_ptr = Rc::new(EpochPtr::new(0));
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let entity = Entity::new(
+            EntityIdentifier::new("User".to_string()),
+            vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::None),
+                AttributeDescriptor::new(AttributeKind::Physical, "active".to_string(), DatabaseValue::Number(1)).with_field_type(FieldType::Bool),
+            ],
+            Rc::clone(&initial_ptr),
+            Rc::clone(&current_ptr),
+        );
+
+        assert_eq!(entity.get_field_type("name"), FieldType::Any);
+        assert_eq!(entity.get_field_type("active"), FieldType::Bool);
+        assert_eq!(entity.snapshot(&initial_ptr, &current_ptr).get_field_type("active"), FieldType::Bool);
+    }
+
+    #[test]
+    fn test_validators() {
+        let initial_ptr = Rc::new(EpochPtr::new(0));
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let positive = Validator::Custom(Arc::new(|value| match value {
+            DatabaseValue::Number(number) if *number < 0 => Err("must be positive".to_string()),
+            _ => Ok(()),
+        }));
+        let status = AttributeDescriptor::new(AttributeKind::Physical, "status".to_string(), DatabaseValue::String("draft".to_string()))
+            .with_validator(Validator::Choices(vec![DatabaseValue::String("draft".to_string()), DatabaseValue::String("published".to_string())]));
+        let entity = Entity::new(
+            EntityIdentifier::new("Book".to_string()),
+            vec![
+                status,
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(0)).with_validator(positive),
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::None),
+            ],
+            Rc::clone(&initial_ptr),
+            Rc::clone(&current_ptr),
+        );
+
+        assert_eq!(entity.validate("status", &DatabaseValue::String("published".to_string())), Ok(()));
+        assert_eq!(entity.validate("status", &DatabaseValue::String("lost".to_string())), Err(EntityError::Validation("status".to_string(), "String(lost) is not a valid choice".to_string())));
+        assert_eq!(entity.validate("pages", &DatabaseValue::Number(-1)), Err(EntityError::Validation("pages".to_string(), "must be positive".to_string())));
+        assert_eq!(entity.validate("title", &DatabaseValue::Number(-1)), Ok(()));
+        assert!(entity.snapshot(&initial_ptr, &current_ptr).validate("pages", &DatabaseValue::Number(-1)).is_err());
+    }
+
+    #[test]
+    fn test_not_null() {
+        let entity = Entity::new(
+            EntityIdentifier::new("Book".to_string()),
+            vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::None).with_validator(Validator::NotNull),
+                AttributeDescriptor::new(AttributeKind::Physical, "isbn".to_string(), DatabaseValue::None).with_validator(Validator::NotNull),
+                AttributeDescriptor::new(AttributeKind::Physical, "notes".to_string(), DatabaseValue::None),
+            ],
+            Rc::new(EpochPtr::new(0)),
+            Rc::new(EpochPtr::new(1)),
+        );
+
+        assert_eq!(entity.missing_values(), vec!["isbn".to_string(), "title".to_string()]);
+        assert_eq!(entity.validate("title", &DatabaseValue::None), Err(EntityError::Validation("title".to_string(), "this field cannot be null".to_string())));
+        assert_eq!(entity.validate("notes", &DatabaseValue::None), Ok(()));
+        entity.get("title").unwrap().set_current_value(DatabaseValue::String("dune".to_string()));
+        assert_eq!(entity.missing_values(), vec!["isbn".to_string()]);
+    }
+
+    #[test]
+    fn test_max_length() {
+        let max_length = Validator::MaxLength(4);
+        assert_eq!(max_length.validate(&DatabaseValue::String("dune".to_string())), Ok(()));
+        assert_eq!(max_length.validate(&DatabaseValue::String("éèêë".to_string())), Ok(()));
+        assert_eq!(max_length.validate(&DatabaseValue::String("dunes".to_string())), Err("ensure this value has at most 4 characters (it has 5)".to_string()));
+        assert_eq!(max_length.validate(&DatabaseValue::Number(123456)), Ok(()));
+        assert_eq!(max_length.validate(&DatabaseValue::None), Ok(()));
+    }
+
+    #[test]
+    fn test_min_max_value() {
+        let (min_value, max_value) = (Validator::MinValue(1), Validator::MaxValue(5));
+        assert_eq!(min_value.validate(&DatabaseValue::Number(1)), Ok(()));
+        assert_eq!(min_value.validate(&DatabaseValue::Number(0)), Err("ensure this value is greater than or equal to 1".to_string()));
+        assert_eq!(max_value.validate(&DatabaseValue::Number(5)), Ok(()));
+        assert_eq!(max_value.validate(&DatabaseValue::Number(6)), Err("ensure this value is less than or equal to 5".to_string()));
+        assert_eq!(max_value.validate(&DatabaseValue::String("9".to_string())), Ok(()));
+        assert_eq!(min_value.validate(&DatabaseValue::None), Ok(()));
+    }
+}
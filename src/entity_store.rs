@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+use std::ops::Bound;
 use std::rc::Rc;
-use crate::entity::{AttributeDescriptor, Entity, EntityIdentifier, EpochPtr, Model, PK};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::entity::{AttributeDescriptor, AttributeHistory, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, Epoch, EpochManager, EpochPtr, MessageDict, Model, OnDelete, PK};
 use uuid::Uuid;
 use crate::errors::EntityError;
-use crate::expression::{FilterExpression, match_entity};
+use crate::symbol::{Symbol, Symbols};
+use crate::expression::{CompiledExpression, ExactExpression, FilterExpression, Lookup, match_entity};
 
 
 struct EntityIdentifierIndex {
-    entities_pk_index: HashMap<Model, HashMap<PK, Rc<Entity>>>,
+    entities_pk_index: HashMap<Symbol, HashMap<PK, Rc<Entity>>>,
     entities_uuid_index: HashMap<Uuid, Rc<Entity>>,
 }
 
@@ -26,203 +33,4109 @@ impl EntityIdentifierIndex {
             return Ok(Rc::clone(result));
         }
         if identifier.has_applied_pk() {
-            if let Some(result) = self.entities_pk_index.get(identifier.get_model()).and_then(|hashmap| hashmap.get(identifier.get_applied_pk().unwrap())) {
+            if let Some(result) = self.entities_pk_index.get(identifier.get_model()).and_then(|hashmap| hashmap.get(&identifier.get_applied_pk().unwrap())) {
                 return Ok(Rc::clone(result));
             }
         }
         Err(EntityError::EntityNotFound(identifier.clone()))
     }
 
+    fn reserve(&mut self, additional: usize) {
+        self.entities_uuid_index.reserve(additional);
+    }
+
+    /// register the entity under its new *pk*, dropping the previous pk entry if any
+    fn apply_pk(&mut self, entity: &Rc<Entity>, pk: PK) -> Result<(), EntityError> {
+        let identifier = entity.get_identifier();
+        let pk_index = self.entities_pk_index.entry(Arc::clone(identifier.get_model_symbol())).or_default();
+        if let Some(other) = pk_index.get(&pk) {
+            if other.get_identifier().get_uuid() != identifier.get_uuid() {
+                return Err(EntityError::DuplicatePk(other.get_identifier().clone()));
+            }
+        }
+        if let Ok(previous_pk) = identifier.get_applied_pk() {
+            pk_index.remove(&previous_pk);
+        }
+        identifier.set_applied_pk(pk);
+        pk_index.insert(pk, Rc::clone(entity));
+        Ok(())
+    }
+
+    fn remove(&mut self, entity: &Rc<Entity>) {
+        let identifier = entity.get_identifier();
+        self.entities_uuid_index.remove(identifier.get_uuid());
+        if let Ok(pk) = identifier.get_applied_pk() {
+            if let Some(pk_index) = self.entities_pk_index.get_mut(identifier.get_model()) {
+                pk_index.remove(&pk);
+            }
+        }
+    }
+
+    fn pk_index_len(&self) -> usize {
+        self.entities_pk_index.values().map(|pks| pks.len()).sum()
+    }
+
+    fn uuid_index_len(&self) -> usize {
+        self.entities_uuid_index.len()
+    }
+
+    /// the number of entities of *model* in the pk index
+    fn pk_index_len_of(&self, model: &str) -> usize {
+        self.entities_pk_index.get(model).map_or(0, |pks| pks.len())
+    }
+
+    fn clear(&mut self) {
+        self.entities_pk_index.clear();
+        self.entities_uuid_index.clear();
+    }
+
+    fn get_by_pk(&self, model: &str, pk: PK) -> Option<Rc<Entity>> {
+        self.entities_pk_index.get(model).and_then(|pks| pks.get(&pk)).map(Rc::clone)
+    }
+
     fn add(&mut self, entity: Rc<Entity>) {
         let identifier = entity.get_identifier();
-        self.entities_uuid_index.insert(identifier.get_uuid().clone(), Rc::clone(&entity));
+        self.entities_uuid_index.insert(*identifier.get_uuid(), Rc::clone(&entity));
         if identifier.has_applied_pk() {
-            self.entities_pk_index.entry(entity.get_identifier().get_model().clone()).or_insert_with(|| HashMap::new()).insert(identifier.get_applied_pk().unwrap().clone(), Rc::clone(&entity));
+            self.entities_pk_index.entry(Arc::clone(identifier.get_model_symbol())).or_default().insert(identifier.get_applied_pk().unwrap(), Rc::clone(&entity));
         }
 
     }
 }
 
-struct EntityStorage {
-    storage: HashMap<Model, Vec<Rc<Entity>>>,
+/// what a foreign key value points at
+#[derive(Debug, Eq, Hash, PartialEq)]
+enum RelationTarget {
+    Uuid(Uuid),
+    Pk(Symbol, PK),
 }
 
-impl EntityStorage {
-    fn add(&mut self, entity: Entity) -> Rc<Entity> {
-        let model = entity.get_identifier().get_model().clone();
-        let storage: &mut Vec<Rc<Entity>> = self.storage.entry(model).or_insert(vec![]);
-        let rc = Rc::new(entity);
-        let result = Rc::clone(&rc);
-        storage.push(rc);
-        result
+// uuids of the referencing entities with their registration sequence, by referenced entity
+type SourcesByTarget = HashMap<RelationTarget, HashMap<Uuid, u64>>;
+
+/// index of the entities referencing each entity through a foreign key, by source model and
+/// attribute. values written once are kept, so lookups must check the current values
+#[derive(Debug, Default)]
+pub struct ReverseIndex {
+    sources: RefCell<HashMap<Symbol, HashMap<Symbol, SourcesByTarget>>>,
+    // registration counter, to return the sources in a stable order
+    sequence: Cell<u64>,
+}
+
+impl ReverseIndex {
+    fn targets(target_model: Option<&Symbol>, value: &DatabaseValue) -> Vec<RelationTarget> {
+        match value {
+            DatabaseValue::ForeignKey(identifier) => {
+                let mut targets = vec![RelationTarget::Uuid(*identifier.get_uuid())];
+                if let Ok(pk) = identifier.get_applied_pk() {
+                    targets.push(RelationTarget::Pk(Arc::clone(identifier.get_model_symbol()), pk));
+                }
+                targets
+            },
+            DatabaseValue::Number(pk) => target_model.map(|model| RelationTarget::Pk(Arc::clone(model), *pk)).into_iter().collect(),
+            _ => vec![],
+        }
     }
 
-    fn new() -> Self {
-        EntityStorage {
-            storage: HashMap::new(),
+    fn register(&self, source_model: &Symbol, attribute: &Symbol, source: Uuid, target_model: Option<&Symbol>, value: &DatabaseValue) {
+        let targets = ReverseIndex::targets(target_model, value);
+        if targets.is_empty() {
+            return;
+        }
+        let mut sources = self.sources.borrow_mut();
+        let by_target = sources.entry(Arc::clone(source_model)).or_default().entry(Arc::clone(attribute)).or_default();
+        for target in targets {
+            let sequence = self.sequence.get();
+            by_target.entry(target).or_default().entry(source).or_insert_with(|| {
+                self.sequence.set(sequence + 1);
+                sequence
+            });
         }
     }
 
-    fn filter(&self, model: Model, filter_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
-        if let Some(storage) = self.storage.get(&model) {
-            let mut result = vec![];
-            for entity in storage {
-                if match_entity(filter_expression, entity)? {
-                    result.push(Rc::clone(entity))
-                }
+    /// uuids of the entities of *model* that referenced *target* with *attribute* at some epoch
+    fn candidates(&self, model: &str, attribute: &str, target: &Entity) -> Vec<Uuid> {
+        let identifier = target.get_identifier();
+        let mut keys = vec![RelationTarget::Uuid(*identifier.get_uuid())];
+        if let Ok(pk) = identifier.get_applied_pk() {
+            keys.push(RelationTarget::Pk(Arc::clone(identifier.get_model_symbol()), pk));
+        }
+        let sources = self.sources.borrow();
+        let Some(by_target) = sources.get(model).and_then(|by_attribute| by_attribute.get(attribute)) else { return vec![] };
+        let mut candidates: HashMap<Uuid, u64> = HashMap::new();
+        for key in keys {
+            for (uuid, sequence) in by_target.get(&key).into_iter().flatten() {
+                candidates.insert(*uuid, *sequence);
             }
-            Ok(result)
-        } else {
-            Ok(vec![])
         }
+        let mut candidates: Vec<(Uuid, u64)> = candidates.into_iter().collect();
+        candidates.sort_by_key(|(_, sequence)| *sequence);
+        candidates.into_iter().map(|(uuid, _)| uuid).collect()
+    }
+
+    /// the (model, attribute) foreign keys registered so far, sorted
+    fn relations(&self) -> Vec<(Model, String)> {
+        let mut relations: Vec<(Model, String)> = self.sources.borrow().iter()
+            .flat_map(|(model, by_attribute)| by_attribute.keys().map(|attribute| (model.to_string(), attribute.to_string())))
+            .collect();
+        relations.sort();
+        relations
+    }
+
+    fn clear(&self) {
+        self.sources.borrow_mut().clear();
     }
 }
 
+/// binding of a foreign key attribute to the reverse index of its store
+#[derive(Debug)]
+pub struct ReverseLink {
+    index: Rc<ReverseIndex>,
+    source_model: Symbol,
+    source: Uuid,
+    // None for generic foreign keys
+    target_model: Option<Symbol>,
+}
 
-pub struct EntityStore {
-    initial_ptr: Rc<EpochPtr>,
-    current_ptr: Rc<EpochPtr>,
-    entities: EntityStorage,
-    index: EntityIdentifierIndex,
+impl ReverseLink {
+    pub fn new(index: Rc<ReverseIndex>, source_model: Symbol, source: Uuid, target_model: Option<Symbol>) -> Self {
+        ReverseLink { index, source_model, source, target_model }
+    }
+
+    pub fn register(&self, attribute: &Symbol, value: &DatabaseValue) {
+        self.index.register(&self.source_model, attribute, self.source, self.target_model.as_ref(), value);
+    }
 }
 
+/// a value the value index holds: foreign keys are found through the reverse index instead.
+/// sorted indexes order None first, then the numbers, then the strings
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum IndexKey {
+    None,
+    Number(i64),
+    String(String),
+}
 
-impl<'a> EntityStore {
-    pub fn get(&self, identifier: &'a EntityIdentifier) -> Result<Rc<Entity>, EntityError> {
-        self.index.get(identifier)
+impl IndexKey {
+    fn of(value: &DatabaseValue) -> Option<IndexKey> {
+        match value {
+            DatabaseValue::String(value) => Some(IndexKey::String(value.clone())),
+            DatabaseValue::Number(value) => Some(IndexKey::Number(*value)),
+            DatabaseValue::None => Some(IndexKey::None),
+            DatabaseValue::ForeignKey(_) => None,
+        }
     }
 
-    pub fn filter(&self, model: Model, filter_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+    /// whether *value* is this value
+    fn holds(&self, value: &DatabaseValue) -> bool {
+        match (self, value) {
+            (IndexKey::String(key), DatabaseValue::String(value)) => key == value,
+            (IndexKey::Number(key), DatabaseValue::Number(value)) => key == value,
+            (IndexKey::None, DatabaseValue::None) => true,
+            _ => false,
+        }
+    }
 
-        self.entities.filter(model, filter_expression)
+    /// the keys passing the range *lookup*, which only compares values of the same type. None
+    /// if the lookup isn't a range over a number or a string
+    fn range(lookup: &Lookup) -> Option<(Bound<IndexKey>, Bound<IndexKey>)> {
+        let (value, included, upper) = match lookup {
+            Lookup::Gt(value) => (value, false, false),
+            Lookup::Gte(value) => (value, true, false),
+            Lookup::Lt(value) => (value, false, true),
+            Lookup::Lte(value) => (value, true, true),
+            _ => return None,
+        };
+        let (first, last) = match value {
+            DatabaseValue::Number(_) => (Bound::Excluded(IndexKey::None), Bound::Excluded(IndexKey::String(String::new()))),
+            DatabaseValue::String(_) => (Bound::Included(IndexKey::String(String::new())), Bound::Unbounded),
+            _ => return None,
+        };
+        let key = IndexKey::of(value)?;
+        let bound = if included { Bound::Included(key) } else { Bound::Excluded(key) };
+        Some(if upper { (first, bound) } else { (bound, last) })
     }
+}
 
+/// how the value index holds the values of an attribute, see EntityStore::add_index()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexKind {
+    /// for the exact and in lookups
+    Hash,
+    /// for the range lookups and the orderings too
+    Sorted,
+}
 
+/// the uuids of the entities holding each value at some epoch
+#[derive(Debug)]
+enum UuidsByValue {
+    Hash(HashMap<IndexKey, HashSet<Uuid>>),
+    Sorted(BTreeMap<IndexKey, HashSet<Uuid>>),
+}
 
-    pub fn add_entity(&'a mut self, entity: Entity) -> Rc<Entity> {
-        let identifier = entity.get_identifier();
-        let get_result = self.index.get(identifier);
-        match get_result {
-            Err(EntityError::EntityNotFound(_)) => {
-                // add the entity only if it's not already registered
-                let res = self.entities.add(entity);
-                self.index.add(Rc::clone(&res));
-                return res
+impl UuidsByValue {
+    fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => UuidsByValue::Hash(HashMap::new()),
+            IndexKind::Sorted => UuidsByValue::Sorted(BTreeMap::new()),
+        }
+    }
+
+    fn kind(&self) -> IndexKind {
+        match self {
+            UuidsByValue::Hash(_) => IndexKind::Hash,
+            UuidsByValue::Sorted(_) => IndexKind::Sorted,
+        }
+    }
+
+    fn insert(&mut self, key: IndexKey, uuid: Uuid) {
+        match self {
+            UuidsByValue::Hash(by_value) => by_value.entry(key).or_default().insert(uuid),
+            UuidsByValue::Sorted(by_value) => by_value.entry(key).or_default().insert(uuid),
+        };
+    }
+
+    fn get(&self, key: &IndexKey) -> Option<&HashSet<Uuid>> {
+        match self {
+            UuidsByValue::Hash(by_value) => by_value.get(key),
+            UuidsByValue::Sorted(by_value) => by_value.get(key),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            UuidsByValue::Hash(by_value) => by_value.clear(),
+            UuidsByValue::Sorted(by_value) => by_value.clear(),
+        }
+    }
+}
+
+/// hash index of the entities by the values of their indexed attributes, by model and attribute,
+/// see EntityStore::add_index(). values written once are kept, so lookups must check the values
+/// at the epoch they read
+#[derive(Debug, Default)]
+pub struct ValueIndex {
+    entries: RefCell<HashMap<Symbol, HashMap<Symbol, UuidsByValue>>>,
+    // the order the entities were bound in, which is the order of the storage
+    positions: RefCell<HashMap<Uuid, u64>>,
+    // by model and attribute, the entities lacking the attribute or that held a value without
+    // key, like the foreign keys, that the index misses
+    missed: RefCell<HashMap<Symbol, HashMap<Symbol, HashSet<Uuid>>>>,
+}
+
+impl ValueIndex {
+    fn kind(&self, model: &str, attribute: &str) -> Option<IndexKind> {
+        self.entries.borrow().get(model).and_then(|by_attribute| by_attribute.get(attribute)).map(UuidsByValue::kind)
+    }
+
+    /// the indexed (model, attribute) pairs with the kind of their index, sorted
+    fn indexes(&self) -> Vec<(Model, String, IndexKind)> {
+        let mut indexes: Vec<(Model, String, IndexKind)> = self.entries.borrow().iter().flat_map(
+            |(model, by_attribute)| by_attribute.iter().map(|(attribute, by_value)| (model.to_string(), attribute.to_string(), by_value.kind()))
+        ).collect();
+        indexes.sort_by(|left, right| (&left.0, &left.1).cmp(&(&right.0, &right.1)));
+        indexes
+    }
+
+    /// index the values of *attribute* of *model*, replacing its index if any, and register the
+    /// values of its *entities*
+    fn add(index: &Rc<ValueIndex>, model: &Symbol, attribute: &Symbol, kind: IndexKind, entities: &[Rc<Entity>]) {
+        index.entries.borrow_mut().entry(Arc::clone(model)).or_default().insert(Arc::clone(attribute), UuidsByValue::new(kind));
+        if let Some(missed) = index.missed.borrow_mut().get_mut(model) {
+            missed.remove(attribute);
+        }
+        for entity in entities {
+            index.position(entity);
+            if !entity.bind_value_index(index, attribute) {
+                index.miss(model, attribute, *entity.get_identifier().get_uuid());
+            }
+        }
+    }
+
+    /// register the values of the indexed attributes of *entity*, present and future
+    fn bind(index: &Rc<ValueIndex>, entity: &Entity) {
+        let model = entity.get_identifier().get_model_symbol();
+        let attributes: Vec<Symbol> = index.entries.borrow().get(model).map(|by_attribute| by_attribute.keys().cloned().collect()).unwrap_or_default();
+        if attributes.is_empty() {
+            return;
+        }
+        index.position(entity);
+        for attribute in attributes {
+            if !entity.bind_value_index(index, &attribute) {
+                index.miss(model, &attribute, *entity.get_identifier().get_uuid());
+            }
+        }
+    }
+
+    fn position(&self, entity: &Entity) {
+        let mut positions = self.positions.borrow_mut();
+        let position = positions.len() as u64;
+        positions.entry(*entity.get_identifier().get_uuid()).or_insert(position);
+    }
+
+    fn miss(&self, model: &Symbol, attribute: &Symbol, source: Uuid) {
+        self.missed.borrow_mut().entry(Arc::clone(model)).or_default().entry(Arc::clone(attribute)).or_default().insert(source);
+    }
+
+    /// true if every entity of *model* is found in the index of *attribute* under the values it
+    /// held, so that walk_sorted() gives all of them, but the missed ones for which *indexed*
+    /// is false, as they don't hold a missed value anymore
+    fn is_complete(&self, model: &str, attribute: &str, indexed: impl Fn(&Uuid) -> bool) -> bool {
+        self.missed.borrow().get(model).and_then(|by_attribute| by_attribute.get(attribute)).is_none_or(|missed| missed.iter().all(indexed))
+    }
+
+    /// forget the missed values of *entity*, removed from the store
+    fn forget(&self, entity: &Entity) {
+        let mut missed = self.missed.borrow_mut();
+        let Some(by_attribute) = missed.get_mut(entity.get_identifier().get_model()) else { return };
+        for missed in by_attribute.values_mut() {
+            missed.remove(entity.get_identifier().get_uuid());
+        }
+    }
+
+    fn register(&self, model: &Symbol, attribute: &Symbol, source: Uuid, value: &DatabaseValue) {
+        let Some(key) = IndexKey::of(value) else {
+            self.miss(model, attribute, source);
+            return;
+        };
+        if let Some(by_value) = self.entries.borrow_mut().get_mut(model).and_then(|by_attribute| by_attribute.get_mut(attribute)) {
+            by_value.insert(key, source);
+        }
+    }
+
+    /// uuids of the entities of *model* that held the values *filter_expression* looks up at
+    /// some epoch, in the order of the storage. None if no index applies to the expression
+    fn candidates(&self, model: &str, filter_expression: &FilterExpression) -> Option<Vec<Uuid>> {
+        let positions = self.positions.borrow();
+        let mut candidates: Vec<Uuid> = self.candidate_set(model, filter_expression)?.into_iter().collect();
+        candidates.sort_by_key(|uuid| positions.get(uuid).copied());
+        Some(candidates)
+    }
+
+    fn candidate_set(&self, model: &str, filter_expression: &FilterExpression) -> Option<HashSet<Uuid>> {
+        let lookup = |attribute: &str, values: Vec<&DatabaseValue>| {
+            let entries = self.entries.borrow();
+            let by_value = entries.get(model)?.get(attribute)?;
+            let mut candidates = HashSet::new();
+            for value in values {
+                candidates.extend(by_value.get(&IndexKey::of(value)?).into_iter().flatten());
+            }
+            Some(candidates)
+        };
+        match filter_expression {
+            FilterExpression::Exact(expression) => lookup(expression.get_attribute(), vec![expression.get_value()]),
+            FilterExpression::Lookup(expression) => match expression.get_lookup() {
+                Lookup::In(values) => lookup(expression.get_attribute(), values.iter().collect()),
+                lookup => {
+                    let range = IndexKey::range(lookup)?;
+                    let entries = self.entries.borrow();
+                    match entries.get(model)?.get(expression.get_attribute())? {
+                        UuidsByValue::Sorted(by_value) => Some(by_value.range(range).flat_map(|(_, uuids)| uuids).copied().collect()),
+                        UuidsByValue::Hash(_) => None,
+                    }
+                },
             },
-            Ok(entity) => entity,
-            Err(_) => panic!(),
+            // the entities must match every expression, so the smallest candidates are enough
+            FilterExpression::And(expressions) => expressions.iter()
+                .filter_map(|expression| self.candidate_set(model, expression))
+                .min_by_key(|candidates| candidates.len()),
         }
     }
 
-    pub fn new() -> EntityStore {
-        EntityStore {
-            initial_ptr: Rc::new(EpochPtr::default()),
-            current_ptr: Rc::new(EpochPtr::new(1)),
-            entities: EntityStorage::new(),
-            index: EntityIdentifierIndex::new(),
+    /// call *f* with each value of the sorted index of *attribute* of *model*, ascending or
+    /// *descending*, None last, and the uuids of the entities that held it in the order of the
+    /// storage. return false if the attribute has no sorted index
+    fn walk_sorted(&self, model: &str, attribute: &str, descending: bool, mut f: impl FnMut(&IndexKey, Vec<Uuid>)) -> bool {
+        let entries = self.entries.borrow();
+        let Some(UuidsByValue::Sorted(by_value)) = entries.get(model).and_then(|by_attribute| by_attribute.get(attribute)) else { return false };
+        let positions = self.positions.borrow();
+        let values: Box<dyn Iterator<Item=(&IndexKey, &HashSet<Uuid>)>> = match descending {
+            true => Box::new(by_value.iter().rev()),
+            false => Box::new(by_value.iter()),
+        };
+        let (nones, values): (Vec<_>, Vec<_>) = values.partition(|(key, _)| **key == IndexKey::None);
+        for (key, uuids) in values.into_iter().chain(nones) {
+            let mut uuids: Vec<Uuid> = uuids.iter().copied().collect();
+            uuids.sort_by_key(|uuid| positions.get(uuid).copied());
+            f(key, uuids);
         }
+        true
     }
 
-    pub fn instantiate_entity(&'a mut self, identifier: EntityIdentifier, attributes_descriptors: Vec<AttributeDescriptor>) -> Rc<Entity> {
-        let entity = Entity::new(identifier, attributes_descriptors, Rc::clone(&self.initial_ptr), Rc::clone(&self.current_ptr));
-        self.add_entity(entity)
+    /// forget the values and the entities, keeping the indexed attributes
+    fn clear(&self) {
+        for by_value in self.entries.borrow_mut().values_mut().flat_map(HashMap::values_mut) {
+            by_value.clear();
+        }
+        self.positions.borrow_mut().clear();
+        self.missed.borrow_mut().clear();
     }
 }
 
+/// binding of an indexed attribute to the value index of its store
+#[derive(Debug)]
+pub struct IndexLink {
+    index: Rc<ValueIndex>,
+    model: Symbol,
+    source: Uuid,
+}
 
-#[cfg(test)]
-mod test {
-    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, EntityIdentifier};
-    use crate::entity_store::EntityStore;
-    use crate::expression::{ExactExpression, FilterExpression};
+impl IndexLink {
+    pub fn new(index: Rc<ValueIndex>, model: Symbol, source: Uuid) -> Self {
+        IndexLink { index, model, source }
+    }
 
-    #[test]
+    pub fn register(&self, attribute: &Symbol, value: &DatabaseValue) {
+        self.index.register(&self.model, attribute, self.source, value);
+    }
+}
 
-    fn test_entity_store_add_and_get() {
-        let mut entity_store = EntityStore::new();
-        let identifier = EntityIdentifier::new("User".to_string());
-        let attributes_descriptors = vec!["name", "age"].iter().map(
-            |attr| AttributeDescriptor::new(AttributeKind::Physical, attr.to_string(), DatabaseValue::String(format!("default {}", attr)))
-        ).collect();
-        let entity = entity_store.instantiate_entity(identifier.clone(), attributes_descriptors);
+struct EntityStorage {
+    storage: HashMap<Symbol, Vec<Rc<Entity>>>,
+}
 
-        let entity_store = entity_store;
+impl EntityStorage {
+    fn add(&mut self, entity: Entity) -> Rc<Entity> {
+        let model = Arc::clone(entity.get_identifier().get_model_symbol());
+        let storage: &mut Vec<Rc<Entity>> = self.storage.entry(model).or_insert(vec![]);
+        let rc = Rc::new(entity);
+        let result = Rc::clone(&rc);
+        storage.push(rc);
+        result
+    }
 
+    fn reserve(&mut self, model: &Symbol, additional: usize) {
+        self.storage.entry(Arc::clone(model)).or_default().reserve(additional);
+    }
 
-        assert_eq!(entity.get("name").unwrap().get_initial(), DatabaseValue::String("default name".to_string()));
-        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("default name".to_string()));
+    fn new() -> Self {
+        EntityStorage {
+            storage: HashMap::new(),
+        }
+    }
 
-        assert_eq!(entity.get_identifier(), &identifier);
-        assert!(entity_store.get(entity.get_identifier()).is_ok());
-        assert_eq!(entity_store.get(entity.get_identifier()).unwrap(), entity);
-        assert_eq!(&entity_store.get(&identifier).unwrap(), &entity_store.get(&identifier.clone()).unwrap());
+    fn clear(&mut self) {
+        self.storage.clear();
     }
 
-    #[test]
-    fn test_entity_identifier_equality() {
-        let id1 = EntityIdentifier::new("User".to_string());
+    fn remove(&mut self, entity: &Rc<Entity>) {
+        if let Some(storage) = self.storage.get_mut(entity.get_identifier().get_model()) {
+            storage.retain(|stored| !Rc::ptr_eq(stored, entity));
+        }
+    }
 
-        let id2 = EntityIdentifier::new("Book".to_string());
-        let id3 = EntityIdentifier::new_persisted("User".to_string(), 1);
-        let id4 = EntityIdentifier::new_persisted("User".to_string(), 1);
-        let id5 = EntityIdentifier::new_persisted("User".to_string(), 2);
+    fn iter(&self) -> impl Iterator<Item=&Rc<Entity>> {
+        self.storage.values().flatten()
+    }
 
-        assert_eq!(id1, id1);
-        assert_ne!(id1, id2);
-        assert_ne!(id1, id3);
-        assert_ne!(id1, id4);
-        assert_ne!(id1, id5);
+    /// the entities of *model* not deleted at *epoch*
+    fn alive_at<'s>(&'s self, model: &str, epoch: Epoch) -> impl Iterator<Item=&'s Rc<Entity>> {
+        self.storage.get(model).into_iter().flatten().filter(move |entity| entity.is_alive_at(epoch))
+    }
 
-        assert_ne!(id2, id1);
-        assert_eq!(id2, id2);
-        assert_ne!(id2, id3);
-        assert_ne!(id2, id4);
-        assert_ne!(id2, id5);
+    /// the *entities* matching the *compiled* expression at *epoch*, evaluated as a batch, with
+    /// the number of scanned ones
+    fn matching<E: std::borrow::Borrow<Rc<Entity>>>(entities: &[E], compiled: &CompiledExpression, epoch: Epoch) -> Result<(Vec<Rc<Entity>>, usize), EntityError> {
+        let selected = compiled.match_batch(entities, epoch)?;
+        let result = entities.iter().zip(selected).filter(|(_, selected)| *selected).map(|(entity, _)| Rc::clone(entity.borrow())).collect();
+        Ok((result, entities.len()))
+    }
 
-        assert_ne!(id3, id1);
-        assert_ne!(id3, id2);
-        assert_eq!(id3, id3);
-        assert_eq!(id3, id4);
-        assert_ne!(id3, id5);
+    /// the entities matching the *compiled* expression at *epoch*, with the number of scanned ones
+    fn filter_at(&self, model: &Model, compiled: &CompiledExpression, epoch: Epoch) -> Result<(Vec<Rc<Entity>>, usize), EntityError> {
+        let entities: Vec<&Rc<Entity>> = self.alive_at(model, epoch).collect();
+        EntityStorage::matching(&entities, compiled, epoch)
+    }
 
-        assert_ne!(id4, id1);
-        assert_ne!(id4, id2);
-        assert_eq!(id4, id3);
-        assert_eq!(id4, id4);
-        assert_ne!(id4, id5);
+    fn all(&self, model: &Model, epoch: Epoch) -> Vec<Rc<Entity>> {
+        self.alive_at(model, epoch).cloned().collect()
+    }
 
-        assert_ne!(id5, id1);
-        assert_ne!(id5, id2);
-        assert_ne!(id5, id3);
-        assert_ne!(id5, id4);
-        assert_eq!(id5, id5);
+}
+
+
+/// what to do when both stores hold a different value for the same attribute of the same entity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    KeepOurs,
+    TakeTheirs,
+    Fail,
+}
+
+pub struct MergePolicy {
+    default: ConflictPolicy,
+    attributes: HashMap<String, ConflictPolicy>,
+}
+
+impl MergePolicy {
+    pub fn new(default: ConflictPolicy) -> Self {
+        MergePolicy {
+            default,
+            attributes: HashMap::new(),
+        }
     }
 
-    #[test]
-    fn test_entity_filter() {
+    /// override the default policy for the given attribute
+    pub fn with_attribute(mut self, attribute: String, policy: ConflictPolicy) -> Self {
+        self.attributes.insert(attribute, policy);
+        self
+    }
 
-        let mut entity_store = EntityStore::new();
-        let attributes_descriptors: Vec<AttributeDescriptor> = vec!["name", "age"].iter().map(
-            |attr| AttributeDescriptor::new(AttributeKind::Physical, attr.to_string(), DatabaseValue::String(format!("default {}", attr)))
-        ).collect();
+    fn get_policy(&self, attribute: &str) -> ConflictPolicy {
+        *self.attributes.get(attribute).unwrap_or(&self.default)
+    }
+}
 
-        for i in 1..100 {
-            let identifier = EntityIdentifier::new("User".to_string());
 
-            let entity = entity_store.instantiate_entity(identifier.clone(), attributes_descriptors.clone());
-            let name_attr = entity.get("name").unwrap();
-            name_attr.set_value(DatabaseValue::String(format!("user {}", i)), 1);
-            let age_attr = entity.get("age").unwrap();
-            age_attr.set_value(DatabaseValue::Number(i), 1);
+/// an entity created or modified since a given epoch
+#[derive(Debug, PartialEq)]
+pub struct EntityChange {
+    pub entity: Rc<Entity>,
+    pub created: bool,
+    /// names of the modified attributes, all of them for created entities
+    pub changed_attributes: Vec<String>,
+}
+
+/// the entities referenced through a foreign key by a batch of entities, see resolve_related()
+#[derive(Debug, Default, PartialEq)]
+pub struct RelatedBatch {
+    /// referenced entities found in the store, without duplicates
+    pub related: Vec<Rc<Entity>>,
+    /// pks of the referenced entities missing from the store, by model
+    pub missing: HashMap<Model, Vec<PK>>,
+}
+
+/// a many to many relation realized by the entities of an intermediate *through* model,
+/// holding foreign keys to both sides and their own attributes
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManyToMany {
+    pub through: Model,
+    /// foreign key of the through model to the entity the relation is declared on
+    pub source_attribute: String,
+    pub target_model: Model,
+    /// foreign key of the through model to the related entities
+    pub target_attribute: String,
+}
+
+/// the changes to persist to the database since the last flush, see changeset()
+#[derive(Debug, Default, PartialEq)]
+pub struct Changeset {
+    /// alive entities without pk, to insert, the referenced ones first
+    pub created: Vec<Rc<Entity>>,
+    /// alive persisted entities, with the names of their attributes modified since the last flush
+    pub updated: Vec<(Rc<Entity>, Vec<String>)>,
+    /// persisted entities deleted since the last flush, the referencing ones first
+    pub deleted: Vec<Rc<Entity>>,
+}
+
+/// the inconsistencies of the relations between the entities of a store, see check_integrity()
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// (entity, foreign key) referencing a deleted entity, or an unpersisted one unknown to the store
+    pub dangling: Vec<(Rc<Entity>, String)>,
+    /// (through entity, foreign key) of many to many relations referencing a deleted entity
+    pub deleted_memberships: Vec<(Rc<Entity>, String)>,
+    /// (one to one foreign key, referenced entity, referencing entities) shared by several entities
+    pub one_to_one_duplicates: Vec<(String, Rc<Entity>, Vec<Rc<Entity>>)>,
+}
+
+/// the declared attributes and constraints of a model, see register_model()
+#[derive(Clone, Debug, Default)]
+pub struct ModelSchema {
+    /// the attributes of the entities, initialized with their default value
+    pub attributes: Vec<AttributeDescriptor>,
+    /// sets of attributes whose values must be unique together
+    pub unique: Vec<Vec<String>>,
+}
+
+impl ModelSchema {
+    /// the attributes initialized with the given *values*, the others taking their default value.
+    /// fail if a value isn't for one of the attributes
+    fn initialize(&self, mut values: HashMap<String, DatabaseValue>) -> Result<Vec<AttributeDescriptor>, EntityError> {
+        let attributes = self.attributes.iter().map(|attribute| match values.remove(attribute.get_name()) {
+            Some(value) => attribute.clone().with_initial(value),
+            None => attribute.clone(),
+        }).collect();
+        match values.into_keys().next() {
+            Some(name) => Err(EntityError::AttributeNotFound(name)),
+            None => Ok(attributes),
         }
+    }
 
-        entity_store.current_ptr.slide(1);
-        let list = entity_store.filter("User".to_string(), &FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("user 4".to_string())))).unwrap();
-        assert_eq!(list.len(), 1);
-        let entity = list.first().unwrap();
-        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("user 4".to_string()));
+    fn is_relation(&self, name: &str) -> bool {
+        self.attributes.iter().any(|attribute| attribute.get_name() == name && !matches!(attribute.get_kind(), AttributeKind::Physical))
+    }
+}
+
+/// a constraint on the alive entities of a model, see set_constraint_check()
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Constraint {
+    /// the attributes must hold values unique together, None values excepted, see ModelSchema
+    Unique(Model, Vec<String>),
+    /// the foreign key must not reference a deleted entity or an unpersisted one unknown to the store
+    ForeignKey(Model, String),
+    /// the entities must match the filter expression of the check constraint of this name, see
+    /// add_check_constraint()
+    Check(Model, String),
+}
 
+impl Constraint {
+    fn involves(&self, attribute: &str) -> bool {
+        match self {
+            Constraint::Unique(_, attributes) => attributes.iter().any(|name| name == attribute),
+            Constraint::ForeignKey(_, name) => name == attribute,
+            // the expression may involve any attribute
+            Constraint::Check(_, _) => true,
+        }
     }
+}
+
+/// a function giving the messages of the rules over several attributes *entity* breaks, by
+/// attribute or under NON_FIELD_ERRORS, see EntityStore::add_entity_validator()
+pub type EntityValidatorFn = dyn Fn(&Entity) -> Result<(), MessageDict>;
+
+/// the key of the messages of the constraints over several attributes, see EntityStore::clean()
+pub const NON_FIELD_ERRORS: &str = "__all__";
+
+/// the entities by the values of the attributes of a unique constraint, see unique_key()
+type UniqueIndex = HashMap<Vec<String>, Vec<EntityIdentifier>>;
 
+/// values by attribute name, as given to upsert()
+pub type Values = Vec<(String, DatabaseValue)>;
 
+/// the values of the *attributes* of *entity* compared by unique constraints, None if one of
+/// them is None. the referenced entities are compared by pk once persisted, by uuid before
+fn unique_key(entity: &Entity, attributes: &[String]) -> Result<Option<Vec<String>>, EntityError> {
+    let mut key = Vec::with_capacity(attributes.len());
+    for attribute in attributes {
+        let part = entity.get(attribute)?.read_value(|value| match value {
+            DatabaseValue::None => None,
+            DatabaseValue::ForeignKey(identifier) => Some(match identifier.get_applied_pk() {
+                Ok(pk) => format!("{}:{}", identifier.get_model(), pk),
+                Err(_) => identifier.get_uuid().to_string(),
+            }),
+            value => Some(format!("{:?}", value)),
+        });
+        let Some(part) = part else { return Ok(None) };
+        key.push(part);
+    }
+    Ok(Some(key))
+}
+
+/// when a constraint is checked
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConstraintCheck {
+    /// on each write of its attributes
+    Immediate,
+    /// on flush only, the intermediate states may violate it
+    #[default]
+    Deferred,
+}
+
+/// a row loaded from the database, with the rows of its related entities, see hydrate()
+#[derive(Debug, Default)]
+pub struct HydrationRow {
+    pub pk: Option<PK>,
+    pub attributes: Vec<AttributeDescriptor>,
+    /// related rows, by foreign key attribute name, with the related model
+    pub related: Vec<(String, Model, HydrationRow)>,
+}
+
+/// the upper bounds of the buckets of StoreMetrics::scanned_per_filter, the last bucket holding
+/// the greater numbers
+pub const SCAN_BUCKETS: [usize; 4] = [10, 100, 1000, 10000];
+
+/// counters of the work done by the store since it was created or reset_metrics(), see
+/// EntityStore::metrics()
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreMetrics {
+    /// the executed filters
+    pub filters: usize,
+    /// the entities matched against the expressions of the filters
+    pub scanned: usize,
+    /// the number of filters by number of scanned entities, bucketed by SCAN_BUCKETS
+    pub scanned_per_filter: [usize; SCAN_BUCKETS.len() + 1],
+    /// the lookups by identifier finding the entity in the pk or uuid index
+    pub index_hits: usize,
+    pub index_misses: usize,
+    /// the related entities found in the store, and the ones to load, see record_related()
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// the values in the histories of the attributes when the metrics are taken
+    pub history_entries: usize,
+}
+
+impl StoreMetrics {
+    fn record_lookup(&mut self, hit: bool) {
+        match hit {
+            true => self.index_hits += 1,
+            false => self.index_misses += 1,
+        }
+    }
+
+    fn record_filter(&mut self, scanned: usize) {
+        self.filters += 1;
+        self.scanned += scanned;
+        let bucket = SCAN_BUCKETS.iter().position(|bound| scanned <= *bound).unwrap_or(SCAN_BUCKETS.len());
+        self.scanned_per_filter[bucket] += 1;
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct StoreStats {
+    pub entities_per_model: HashMap<Model, usize>,
+    pub history_entries: usize,
+    pub pk_index_size: usize,
+    pub uuid_index_size: usize,
+    /// rough estimation of the bytes used by the entities and indexes
+    pub approximate_memory: usize,
+}
+
+/// the approximate number of bytes used by the entities of a model, see
+/// EntityStore::memory_per_model()
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelMemory {
+    pub entities: usize,
+    /// the entities and their attributes, without the histories of the values
+    pub entity_bytes: usize,
+    /// the histories of the values of the attributes
+    pub history_bytes: usize,
+    /// the entries of the entities in the pk and uuid indexes
+    pub index_bytes: usize,
+}
+
+impl ModelMemory {
+    pub fn total(&self) -> usize {
+        self.entity_bytes + self.history_bytes + self.index_bytes
+    }
+}
+
+
+/// a write of an attribute recorded by the audit log, see EntityStore::enable_audit_log(). who
+/// made it and when are given by the caller, see EntityStore::set_audit_context()
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub identifier: EntityIdentifier,
+    pub attribute: String,
+    pub previous: DatabaseValue,
+    pub value: DatabaseValue,
+    pub epoch: Epoch,
+    pub who: Option<String>,
+    pub when: Option<String>,
+}
+
+/// a filter or count recorded by the query log, see EntityStore::enable_query_log()
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryLogEntry {
+    /// "filter", "filter_at" or "count"
+    pub operation: &'static str,
+    pub model: Model,
+    /// the filter expression as written by its Display, empty for count
+    pub expression: String,
+    pub duration: Duration,
+    /// the number of matched or counted entities
+    pub results: usize,
+}
+
+struct Savepoint {
+    epoch: Epoch,
+    write_epoch: Epoch,
+    // number of entities created in the transaction when the savepoint was taken
+    created: usize,
+}
+
+struct Transaction {
+    begin_epoch: Epoch,
+    created: Vec<Rc<Entity>>,
+    savepoints: Vec<Savepoint>,
+}
+
+pub struct EntityStore {
+    initial_ptr: Rc<EpochPtr>,
+    current_ptr: Rc<EpochPtr>,
+    epoch_manager: EpochManager,
+    entities: EntityStorage,
+    index: EntityIdentifierIndex,
+    transaction: Option<Transaction>,
+    // epochs the current pointer was at before each committed transaction, and after each undone one
+    undo_epochs: Vec<Epoch>,
+    redo_epochs: Vec<Epoch>,
+    // labelled epochs, see checkpoint()
+    checkpoints: HashMap<String, Epoch>,
+    // base epoch of each forked branch, by branch write epoch, see fork()
+    branches: HashMap<Epoch, Epoch>,
+    reverse_index: Rc<ReverseIndex>,
+    // the entities by the values of the indexed attributes, see add_index()
+    value_index: Rc<ValueIndex>,
+    // many to many relations by declaring model and name
+    many_to_many: HashMap<Model, HashMap<String, ManyToMany>>,
+    // attribute ordering the related entities, by referencing model and foreign key, see set_related_ordering()
+    related_orderings: HashMap<Model, HashMap<String, String>>,
+    // epoch of the last flush, see changeset()
+    flushed_epoch: Epoch,
+    // persisted entities deleted since the last flush, already garbage collected
+    collected_deletions: Vec<Rc<Entity>>,
+    // declared schemas by model
+    models: HashMap<Model, ModelSchema>,
+    // attribute incremented on each update, by model, see increment_version()
+    version_attributes: HashMap<Model, String>,
+    // the entities added since the last take_added(), when recorded, see record_added()
+    added: Option<Vec<Rc<Entity>>>,
+    // when the constraints are checked, deferred if missing
+    constraint_checks: HashMap<Constraint, ConstraintCheck>,
+    // the named filter expressions the entities must match, by model, see add_check_constraint()
+    check_constraints: HashMap<Model, Vec<(String, FilterExpression)>>,
+    // the validators of the entities as a whole, by model, see add_entity_validator()
+    entity_validators: HashMap<Model, Vec<Rc<EntityValidatorFn>>>,
+    // the epochs the current pointer was at before each enter_epoch(), innermost last
+    epoch_scopes: Vec<Epoch>,
+    // the recorded writes, None until enable_audit_log()
+    audit_log: RefCell<Option<Vec<AuditEntry>>>,
+    // who is writing and when, given to the recorded writes, see set_audit_context()
+    audit_context: (Option<String>, Option<String>),
+    // the counters of the work done, see metrics()
+    metrics: RefCell<StoreMetrics>,
+    // the last filters and counts, at most query_log_capacity, none recorded if 0
+    query_log: RefCell<VecDeque<QueryLogEntry>>,
+    query_log_capacity: usize,
+    // the names of the models and attributes, shared by the entities
+    symbols: Symbols,
+}
+
+
+impl<'a> EntityStore {
+    pub fn get(&self, identifier: &'a EntityIdentifier) -> Result<Rc<Entity>, EntityError> {
+        self.get_at(identifier, self.current_ptr.get_epoch())
+    }
+
+    /// same as get, failing only if the entity was deleted at or before *epoch*
+    pub fn get_at(&self, identifier: &'a EntityIdentifier, epoch: Epoch) -> Result<Rc<Entity>, EntityError> {
+        let entity = self.index.get(identifier);
+        self.metrics.borrow_mut().record_lookup(entity.is_ok());
+        let entity = entity?;
+        if entity.is_alive_at(epoch) {
+            Ok(entity)
+        } else {
+            Err(EntityError::EntityNotFound(identifier.clone()))
+        }
+    }
+
+    pub fn filter(&self, model: Model, filter_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+        self.filter_compiled(model, &filter_expression.compile())
+    }
+
+    /// same as filter, with an expression compiled once for the queries run again and again
+    pub fn filter_compiled(&self, model: Model, compiled: &CompiledExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let started = Instant::now();
+        let epoch = self.current_ptr.get_epoch();
+        let filter_expression = compiled.get_expression();
+        let (entities, scanned) = match self.indexed_candidates(&model, filter_expression, epoch) {
+            Some(candidates) => EntityStorage::matching(&candidates, compiled, epoch)?,
+            None => self.entities.filter_at(&model, compiled, epoch)?,
+        };
+        self.metrics.borrow_mut().record_filter(scanned);
+        self.record_query("filter", &model, Some(filter_expression), started, entities.len());
+        tracing::debug!(model, matched = entities.len(), "filter");
+        Ok(entities)
+    }
+
+    /// the entities of *model* alive at *epoch* that may match *filter_expression*, found through
+    /// the value index, None if no index applies to the expression
+    fn indexed_candidates(&self, model: &Model, filter_expression: &FilterExpression, epoch: Epoch) -> Option<Vec<Rc<Entity>>> {
+        let candidates = self.value_index.candidates(model, filter_expression)?;
+        Some(candidates.into_iter()
+            .filter_map(|uuid| self.index.entities_uuid_index.get(&uuid).map(Rc::clone))
+            .filter(|entity| entity.is_alive_at(epoch))
+            .collect())
+    }
+
+    /// index the values of *attribute* of the entities of *model*, so the filters on its exact
+    /// value or on a list of values look the entities up instead of scanning the whole model.
+    /// sorted indexes serve the range lookups and filter_ordered() too
+    pub fn add_index(&'a mut self, model: Model, attribute: String, kind: IndexKind) {
+        if self.value_index.kind(&model, &attribute) == Some(kind) {
+            return;
+        }
+        let entities = self.entities.storage.get(model.as_str()).map(Vec::as_slice).unwrap_or_default();
+        ValueIndex::add(&self.value_index, &self.symbols.intern(&model), &self.symbols.intern(&attribute), kind, entities);
+    }
+
+    /// the entities of *model* matching *filter_expression* ordered by their *ordering* attribute,
+    /// descending if prefixed with "-". the entities without value come last, the ones with equal
+    /// values keep the order of the storage. read in order from the sorted index of the attribute
+    /// if any and none of the entities lacks a value in it, instead of sorted
+    pub fn filter_ordered(&self, model: Model, compiled: &CompiledExpression, ordering: &str) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let (attribute, descending) = match ordering.strip_prefix('-') {
+            Some(attribute) => (attribute, true),
+            None => (ordering, false),
+        };
+        let epoch = self.current_ptr.get_epoch();
+        // the values held once are kept in the index, the entities the index missed are found in
+        // it if they are deleted or hold a value with a key now, the other ones must be sorted
+        let indexed = |uuid: &Uuid| self.index.entities_uuid_index.get(uuid).is_none_or(|entity| {
+            !entity.is_alive_at(epoch) || entity.get(attribute).is_ok_and(|attr| attr.read_value(|value| IndexKey::of(value).is_some()))
+        });
+        if self.value_index.is_complete(&model, attribute, indexed) {
+            let started = Instant::now();
+            let mut ordered = vec![];
+            let indexed = self.value_index.walk_sorted(&model, attribute, descending, |key, uuids| {
+                // the entities held the value at some epoch, the ones still holding it are in order
+                ordered.extend(uuids.iter().filter_map(|uuid| self.index.entities_uuid_index.get(uuid)).filter(|entity| {
+                    entity.is_alive_at(epoch) && entity.get(attribute).is_ok_and(|attr| attr.read_value(|value| key.holds(value)))
+                }).cloned());
+            });
+            if indexed {
+                let (entities, scanned) = EntityStorage::matching(&ordered, compiled, epoch)?;
+                self.metrics.borrow_mut().record_filter(scanned);
+                self.record_query("filter", &model, Some(compiled.get_expression()), started, entities.len());
+                tracing::debug!(model, matched = entities.len(), "filter");
+                return Ok(entities);
+            }
+        }
+        let entities = self.filter_compiled(model, compiled)?;
+        let mut attributes = Vec::with_capacity(entities.len());
+        for entity in entities {
+            attributes.push((entity.get(attribute)?, entity));
+        }
+        attributes.sort_by(|(left, _), (right, _)| left.read_value(|left| right.read_value(|right| compare_ordered(left, right, descending))));
+        Ok(attributes.into_iter().map(|(_, entity)| entity).collect())
+    }
+
+    /// the entity of *model* with the given *pk*, if it is not deleted
+    fn get_by_pk(&self, model: &str, pk: PK) -> Option<Rc<Entity>> {
+        self.index.get_by_pk(model, pk).filter(|entity| entity.is_alive_at(self.current_ptr.get_epoch()))
+    }
+
+    /// same as get, for the entity of *model* with the given *pk*, without building its identifier
+    pub fn get_persisted(&self, model: &str, pk: PK) -> Result<Rc<Entity>, EntityError> {
+        let entity = self.get_by_pk(model, pk);
+        self.metrics.borrow_mut().record_lookup(entity.is_some());
+        entity.ok_or_else(|| EntityError::EntityNotFound(EntityIdentifier::new_persisted(model.to_string(), pk)))
+    }
+
+    /// same as filter, matching the values the entities had at *epoch*
+    pub fn filter_at(&self, model: &Model, filter_expression: &FilterExpression, epoch: Epoch) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let started = Instant::now();
+        let compiled = filter_expression.compile();
+        let (entities, scanned) = match self.indexed_candidates(model, filter_expression, epoch) {
+            Some(candidates) => EntityStorage::matching(&candidates, &compiled, epoch)?,
+            None => self.entities.filter_at(model, &compiled, epoch)?,
+        };
+        self.metrics.borrow_mut().record_filter(scanned);
+        self.record_query("filter_at", model, Some(filter_expression), started, entities.len());
+        Ok(entities)
+    }
+
+    /// return the entities created after *epoch*, and the ones whose current values differ from
+    /// their values at *epoch*, with the changed attribute names
+    pub fn changes_since(&self, epoch: Epoch) -> Vec<EntityChange> {
+        let current_epoch = self.current_ptr.get_epoch();
+        let mut changes = vec![];
+        for entity in self.entities.iter() {
+            if entity.get_created_epoch() > epoch {
+                let mut changed_attributes: Vec<String> = entity.iter_attributes().map(|(name, _)| name.to_string()).collect();
+                changed_attributes.sort();
+                changes.push(EntityChange { entity: Rc::clone(entity), created: true, changed_attributes });
+            } else {
+                let changed_attributes = entity.changed_attributes_between(epoch, current_epoch);
+                if !changed_attributes.is_empty() {
+                    changes.push(EntityChange { entity: Rc::clone(entity), created: false, changed_attributes });
+                }
+            }
+        }
+        changes
+    }
+
+    /// return the entities of *model* having one of the given *pks*, by pk.
+    /// pks unknown to the store are absent of the result
+    pub fn in_bulk(&self, model: &Model, pks: &[PK]) -> HashMap<PK, Rc<Entity>> {
+        pks.iter().filter_map(|pk| self.get_by_pk(model, *pk).map(|entity| (*pk, entity))).collect()
+    }
+
+    /// return the entities of *model* whose *attribute* holds the pk of, or a reference to, a
+    /// *related_model* entity matching *related_expression*. related entities are resolved
+    /// through the index
+    pub fn filter_related(&self, model: Model, attribute: &str, related_model: &Model, related_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let mut result = vec![];
+        for entity in self.entities.all(&model, self.current_ptr.get_epoch()) {
+            let related = match entity.get(attribute)?.get_value() {
+                DatabaseValue::Number(pk) => self.get_by_pk(related_model, pk),
+                // generic foreign keys may reference other models
+                DatabaseValue::ForeignKey(identifier) if identifier.get_model() == related_model => self.get(&identifier).ok(),
+                _ => None,
+            };
+            if let Some(related) = related {
+                if match_entity(related_expression, &related)? {
+                    result.push(entity);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// return the entities of *model* whose foreign key *attribute* currently references *target*,
+    /// found through the reverse index instead of a scan of *model*
+    pub fn related(&self, target: &Entity, model: &Model, attribute: &str) -> Vec<Rc<Entity>> {
+        let epoch = self.current_ptr.get_epoch();
+        let mut related: Vec<Rc<Entity>> = self.reverse_index.candidates(model, attribute, target).into_iter()
+            .filter_map(|uuid| self.index.entities_uuid_index.get(&uuid).map(Rc::clone))
+            .filter(|entity| entity.is_alive_at(epoch) && entity.references(attribute, target).unwrap_or(false))
+            .collect();
+        if let Some(ordering) = self.get_related_ordering(model, attribute) {
+            let (ordering, descending) = match ordering.strip_prefix('-') {
+                Some(ordering) => (ordering, true),
+                None => (ordering.as_str(), false),
+            };
+            // the entities without value come last, the ones with equal values keep their order
+            related.sort_by(|left, right| match (left.get(ordering).ok(), right.get(ordering).ok()) {
+                (Some(left), Some(right)) => left.read_value(|left| right.read_value(|right| compare_ordered(left, right, descending))),
+                (left, right) => left.is_none().cmp(&right.is_none()),
+            });
+        }
+        related
+    }
+
+    /// order the entities of *model* returned by related() for their foreign key *attribute* by
+    /// their *ordering* attribute, descending if prefixed with "-" like django's Meta.ordering.
+    /// many to many relations are ordered by the through entities, see add_membership()
+    pub fn set_related_ordering(&'a mut self, model: Model, attribute: String, ordering: String) {
+        self.related_orderings.entry(model).or_default().insert(attribute, ordering);
+    }
+
+    fn get_related_ordering(&self, model: &str, attribute: &str) -> Option<&String> {
+        self.related_orderings.get(model).and_then(|orderings| orderings.get(attribute))
+    }
+
+    /// record the writes reported by record_write() from now on, in an append only log
+    pub fn enable_audit_log(&'a mut self) {
+        self.audit_log.get_mut().get_or_insert_with(Vec::new);
+    }
+
+    /// give *who* and *when* to the writes recorded from now on
+    pub fn set_audit_context(&'a mut self, who: Option<String>, when: Option<String>) {
+        self.audit_context = (who, when);
+    }
+
+    /// record the write of *attribute* of *entity* at *epoch*, which held *previous*, if the audit
+    /// log is enabled
+    pub fn record_write(&self, entity: &Entity, attribute: &str, previous: DatabaseValue, epoch: Epoch) -> Result<(), EntityError> {
+        let mut audit_log = self.audit_log.borrow_mut();
+        let Some(audit_log) = audit_log.as_mut() else { return Ok(()) };
+        let (who, when) = self.audit_context.clone();
+        audit_log.push(AuditEntry {
+            identifier: entity.get_identifier().clone(),
+            attribute: attribute.to_string(),
+            previous,
+            value: entity.get(attribute)?.get_at_epoch(epoch),
+            epoch,
+            who,
+            when,
+        });
+        Ok(())
+    }
+
+    /// the recorded writes in order, empty if the audit log isn't enabled
+    pub fn audit_log(&self) -> Vec<AuditEntry> {
+        self.audit_log.borrow().clone().unwrap_or_default()
+    }
+
+    /// write *value* in *attribute* of *entity* at the current epoch and record it in the audit
+    /// log. the writes made by the store itself, which are neither validated nor checked
+    pub fn set_attribute(&self, entity: &Entity, attribute: &str, value: DatabaseValue) -> Result<(), EntityError> {
+        let physical_attribute = entity.get(attribute)?;
+        let previous = physical_attribute.get_value();
+        physical_attribute.set_current_value(value);
+        self.record_write(entity, attribute, previous, self.current_ptr.get_epoch())
+    }
+
+    /// write *value* in *attribute* of *entity* at *epoch*, or at the current epoch if not given,
+    /// as the users do: the value is validated, the constraints checked immediately are checked,
+    /// the value being restored if one is violated, and the write is recorded in the audit log.
+    /// return the value it replaces
+    pub fn write_value(&self, entity: &Rc<Entity>, attribute: &str, value: DatabaseValue, epoch: Option<Epoch>) -> Result<DatabaseValue, EntityError> {
+        self.check_writable()?;
+        let previous = entity.write(attribute, value, epoch)?;
+        if let Err(error) = self.check_written(entity, attribute) {
+            let physical_attribute = entity.get(attribute)?;
+            match epoch {
+                Some(epoch) => physical_attribute.set_value(previous, epoch),
+                None => physical_attribute.set_current_value(previous),
+            }
+            return Err(error);
+        }
+        tracing::trace!(identifier = %entity.get_identifier(), attribute, "set_value");
+        self.record_write(entity, attribute, previous.clone(), epoch.unwrap_or_else(|| self.current_ptr.get_epoch()))?;
+        Ok(previous)
+    }
+
+    /// make the foreign key *attribute* of *entity* reference *target*, or empty it, written like
+    /// write_value(). return the value it replaces
+    pub fn set_related(&self, entity: &Rc<Entity>, attribute: &str, target: Option<&Entity>) -> Result<DatabaseValue, EntityError> {
+        entity.get_on_delete(attribute)?;
+        let value = target.map_or(DatabaseValue::None, |target| DatabaseValue::ForeignKey(target.get_identifier().clone()));
+        self.write_value(entity, attribute, value, None)
+    }
+
+    /// lock the updates of *model* optimistically with its *attribute* version, see increment_version()
+    pub fn set_version_attribute(&'a mut self, model: Model, attribute: String) {
+        self.version_attributes.insert(model, attribute);
+    }
+
+    pub fn get_version_attribute(&self, model: &str) -> Option<&String> {
+        self.version_attributes.get(model)
+    }
+
+    /// check *constraint* on each write of its attributes, or only on flush
+    pub fn set_constraint_check(&'a mut self, constraint: Constraint, check: ConstraintCheck) {
+        self.constraint_checks.insert(constraint, check);
+    }
+
+    pub fn get_constraint_check(&self, constraint: &Constraint) -> ConstraintCheck {
+        self.constraint_checks.get(constraint).copied().unwrap_or_default()
+    }
+
+    /// require the alive entities of *model* to match *expression*, checked like the other
+    /// constraints as Constraint::Check(model, name). replace the check constraint of this *name*
+    pub fn add_check_constraint(&'a mut self, model: Model, name: String, expression: FilterExpression) {
+        let check_constraints = self.check_constraints.entry(model).or_default();
+        match check_constraints.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, other)) => *other = expression,
+            None => check_constraints.push((name, expression)),
+        }
+    }
+
+    /// validate the entities of *model* as a whole with *validator*, on flush and by clean(), after
+    /// the validators of their attributes
+    pub fn add_entity_validator(&'a mut self, model: Model, validator: Rc<EntityValidatorFn>) {
+        self.entity_validators.entry(model).or_default().push(validator);
+    }
+
+    /// the messages of the entity validators of the model of *entity* refusing it, merged
+    fn validate_entity(&self, entity: &Entity) -> MessageDict {
+        let mut messages = MessageDict::new();
+        for validator in self.entity_validators.get(entity.get_identifier().get_model()).into_iter().flatten() {
+            if let Err(refused) = validator(entity) {
+                for (attribute, refused) in refused {
+                    messages.entry(attribute).or_default().extend(refused);
+                }
+            }
+        }
+        messages
+    }
+
+    /// the unique and check constraints of the model of *entity* and the ones of its foreign keys
+    fn constraints(&self, entity: &Entity) -> Vec<Constraint> {
+        let model = entity.get_identifier().get_model();
+        let mut constraints: Vec<Constraint> = self.models.get(model).map(
+            |schema| schema.unique.iter().map(|attributes| Constraint::Unique(model.to_string(), attributes.clone())).collect()
+        ).unwrap_or_default();
+        constraints.extend(entity.relation_names().into_iter().map(|attribute| Constraint::ForeignKey(model.to_string(), attribute)));
+        if let Some(check_constraints) = self.check_constraints.get(model) {
+            constraints.extend(check_constraints.iter().map(|(name, _)| Constraint::Check(model.to_string(), name.clone())));
+        }
+        constraints
+    }
+
+    /// check all the constraints of the alive *entities*, whatever when they are checked,
+    /// before flushing them. fail with the first violation
+    pub fn check_constraints(&self, entities: &[Rc<Entity>]) -> Result<(), EntityError> {
+        self.check_constraints_matching(entities, |_| true)
+    }
+
+    /// check that the entities to insert of *changeset* have their NotNull values, then the entity
+    /// validators and the constraints of the entities to insert and update
+    pub fn check_changeset(&self, changeset: &Changeset) -> Result<(), EntityError> {
+        for entity in changeset.created.iter() {
+            let missing = entity.missing_values();
+            if !missing.is_empty() {
+                return Err(EntityError::MissingValues(entity.get_identifier().clone(), missing));
+            }
+        }
+        let epoch = self.current_ptr.get_epoch();
+        let changed = changeset.created.iter().chain(changeset.updated.iter().map(|(entity, _)| entity));
+        for entity in changed.filter(|entity| entity.is_alive_at(epoch)) {
+            let messages = self.validate_entity(entity);
+            if !messages.is_empty() {
+                return Err(EntityError::Invalid(entity.get_identifier().clone(), messages));
+            }
+        }
+        self.check_constraints(&changeset.created)?;
+        self.check_constraints(&changeset.updated.iter().map(|(entity, _)| Rc::clone(entity)).collect::<Vec<Rc<Entity>>>())
+    }
+
+    /// check the constraints involving *attribute* checked immediately, after a write to it
+    pub fn check_written(&self, entity: &Rc<Entity>, attribute: &str) -> Result<(), EntityError> {
+        self.check_constraints_matching(std::slice::from_ref(entity), |constraint| {
+            constraint.involves(attribute) && self.get_constraint_check(constraint) == ConstraintCheck::Immediate
+        })
+    }
+
+    fn check_constraints_matching(&self, entities: &[Rc<Entity>], predicate: impl Fn(&Constraint) -> bool) -> Result<(), EntityError> {
+        let epoch = self.current_ptr.get_epoch();
+        // the composite key index of the alive entities, built once per unique constraint
+        let mut unique_indexes: HashMap<Constraint, UniqueIndex> = HashMap::new();
+        for entity in entities.iter().filter(|entity| entity.is_alive_at(epoch)) {
+            for constraint in self.constraints(entity).into_iter().filter(|constraint| predicate(constraint)) {
+                if let Some(violation) = self.violation(entity, constraint, &mut unique_indexes)? {
+                    return Err(violation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the ConstraintViolation error of *entity* if it violates *constraint*
+    fn violation(&self, entity: &Rc<Entity>, constraint: Constraint, unique_indexes: &mut HashMap<Constraint, UniqueIndex>) -> Result<Option<EntityError>, EntityError> {
+        match &constraint {
+            Constraint::Unique(model, attributes) => {
+                let Some(key) = unique_key(entity, attributes)? else { return Ok(None) };
+                if !unique_indexes.contains_key(&constraint) {
+                    unique_indexes.insert(constraint.clone(), self.unique_index(model, attributes)?);
+                }
+                let conflicting = unique_indexes[&constraint].get(&key).and_then(
+                    |identifiers| identifiers.iter().find(|identifier| identifier.get_uuid() != entity.get_identifier().get_uuid())
+                ).cloned();
+                if let Some(conflicting) = conflicting {
+                    return Ok(Some(EntityError::ConstraintViolation(entity.get_identifier().clone(), constraint, Some(Box::new(conflicting)))));
+                }
+            },
+            Constraint::ForeignKey(_, attribute) => if self.is_dangling(entity, attribute)? {
+                return Ok(Some(EntityError::ConstraintViolation(entity.get_identifier().clone(), constraint, None)));
+            },
+            Constraint::Check(model, name) => {
+                let (_, expression) = self.check_constraints[model].iter().find(|(other, _)| other == name).expect("listed by constraints()");
+                if !match_entity(expression, entity)? {
+                    return Ok(Some(EntityError::ConstraintViolation(entity.get_identifier().clone(), constraint, None)));
+                }
+            },
+        }
+        Ok(None)
+    }
+
+    /// the messages of everything wrong with *entity*, by attribute, like the message_dict of a
+    /// django ValidationError raised by full_clean(): the refused values, the missing ones, the
+    /// entity validators and the violated constraints, whatever when they are checked. the constraints over several
+    /// attributes are reported under NON_FIELD_ERRORS
+    pub fn clean(&self, entity: &Rc<Entity>) -> Result<MessageDict, EntityError> {
+        self.clean_with(entity, &mut HashMap::new())
+    }
+
+    fn clean_with(&self, entity: &Rc<Entity>, unique_indexes: &mut HashMap<Constraint, UniqueIndex>) -> Result<MessageDict, EntityError> {
+        let mut messages = entity.clean_values();
+        for (attribute, refused) in self.validate_entity(entity) {
+            messages.entry(attribute).or_default().extend(refused);
+        }
+        for constraint in self.constraints(entity) {
+            if self.violation(entity, constraint.clone(), unique_indexes)?.is_none() {
+                continue;
+            }
+            let (field, message) = match constraint {
+                Constraint::Unique(model, attributes) if attributes.len() == 1 => {
+                    (attributes[0].clone(), format!("{} with this {} already exists", model, attributes[0]))
+                },
+                Constraint::Unique(model, attributes) => {
+                    (NON_FIELD_ERRORS.to_string(), format!("{} with this {} already exists", model, attributes.join(" and ")))
+                },
+                Constraint::ForeignKey(_, attribute) => (attribute, "references a deleted or unknown entity".to_string()),
+                Constraint::Check(_, name) => (NON_FIELD_ERRORS.to_string(), format!("the check constraint {} is violated", name)),
+            };
+            messages.entry(field).or_default().push(message);
+        }
+        Ok(messages)
+    }
+
+    /// the messages of the alive entities of *model*, or of all models, that aren't valid, see
+    /// clean()
+    pub fn validate(&self, model: Option<&Model>) -> Result<Vec<(Rc<Entity>, MessageDict)>, EntityError> {
+        let entities = match model {
+            Some(model) => self.all(model),
+            None => self.alive_entities(),
+        };
+        let mut unique_indexes: HashMap<Constraint, UniqueIndex> = HashMap::new();
+        let mut invalid = vec![];
+        for entity in entities {
+            let messages = self.clean_with(&entity, &mut unique_indexes)?;
+            if !messages.is_empty() {
+                invalid.push((entity, messages));
+            }
+        }
+        Ok(invalid)
+    }
+
+    /// the identifiers of the alive entities of *model* by the values of their unique together
+    /// *attributes*, the entities holding a None value excepted
+    fn unique_index(&self, model: &Model, attributes: &[String]) -> Result<UniqueIndex, EntityError> {
+        let mut index: UniqueIndex = HashMap::new();
+        for entity in self.all(model) {
+            if let Some(key) = unique_key(&entity, attributes)? {
+                index.entry(key).or_default().push(entity.get_identifier().clone());
+            }
+        }
+        Ok(index)
+    }
+
+    /// increment the version of *entity* before saving its changes, if its model is versioned.
+    /// return the previous version, that the database row must still hold for the update to be
+    /// applied, a missing version counting as 0
+    pub fn increment_version(&self, entity: &Entity) -> Result<Option<PK>, EntityError> {
+        let Some(attribute) = self.get_version_attribute(entity.get_identifier().get_model()) else { return Ok(None) };
+        let version = match entity.get(attribute)?.get_value() {
+            DatabaseValue::Number(version) => version,
+            _ => 0,
+        };
+        self.set_attribute(entity, attribute, DatabaseValue::Number(version + 1))?;
+        Ok(Some(version))
+    }
+
+    /// declare the attributes and constraints of *model*
+    pub fn register_model(&'a mut self, model: Model, mut schema: ModelSchema) {
+        // interned once, the entities of the model share the names of the schema
+        schema.attributes = schema.attributes.into_iter().map(|descriptor| descriptor.interned(&self.symbols)).collect();
+        self.models.insert(model, schema);
+    }
+
+    pub fn get_model_schema(&self, model: &str) -> Result<&ModelSchema, EntityError> {
+        self.models.get(model).ok_or_else(|| EntityError::UnknownModel(model.to_string()))
+    }
+
+    /// instantiate an entity of a registered model with the given *values*, the other attributes
+    /// taking their default value
+    pub fn instantiate_model(&'a mut self, identifier: EntityIdentifier, values: HashMap<String, DatabaseValue>) -> Result<Rc<Entity>, EntityError> {
+        let schema = self.get_model_schema(identifier.get_model())?;
+        for attribute in schema.attributes.iter() {
+            if let Some(value) = values.get(attribute.get_name()) {
+                attribute.validate(value)?;
+            }
+        }
+        let attributes = schema.initialize(values)?;
+        Ok(self.instantiate_entity(identifier, attributes))
+    }
+
+    /// instantiate the persisted entities of *model* loaded from the database as (pk, values)
+    /// *rows*, their values set at the initial epoch, see model_attributes(). rows with a pk
+    /// already in the store resolve to the stored entity
+    pub fn load(&'a mut self, model: Model, rows: Vec<(PK, HashMap<String, DatabaseValue>)>) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let symbol = self.symbols.intern(&model);
+        self.entities.reserve(&symbol, rows.len());
+        self.index.reserve(rows.len());
+        let mut result = Vec::with_capacity(rows.len());
+        for (pk, values) in rows {
+            let attributes = self.model_attributes(&model, values)?;
+            result.push(self.instantiate_entity(EntityIdentifier::of_model(&symbol, Some(pk)), attributes));
+        }
+        Ok(result)
+    }
+
+    /// the attributes of an entity of *model* holding *values*. the attributes of registered
+    /// models are initialized like instantiate_model(), foreign keys accepting their django
+    /// "<name>_id" column name, the others are physical
+    pub fn model_attributes(&self, model: &Model, values: HashMap<String, DatabaseValue>) -> Result<Vec<AttributeDescriptor>, EntityError> {
+        match self.models.get(model) {
+            Some(schema) => schema.initialize(values.into_iter().map(|(name, value)| match name.strip_suffix("_id") {
+                Some(relation) if schema.is_relation(relation) && !schema.is_relation(&name) => (relation.to_string(), value),
+                _ => (name, value),
+            }).collect()),
+            None => Ok(values.into_iter().map(|(name, value)| AttributeDescriptor::new(AttributeKind::Physical, name, value)).collect()),
+        }
+    }
+
+    /// declare the many to many relation *name* of *model*
+    pub fn register_many_to_many(&'a mut self, model: Model, name: String, relation: ManyToMany) {
+        self.many_to_many.entry(model).or_default().insert(name, relation);
+    }
+
+    fn get_many_to_many(&self, source: &Entity, name: &str) -> Result<&ManyToMany, EntityError> {
+        self.many_to_many.get(source.get_identifier().get_model()).and_then(|relations| relations.get(name)).ok_or_else(|| EntityError::NotARelation(name.to_string()))
+    }
+
+    /// link *source* to *target* through the many to many relation *name*, by instantiating a
+    /// through entity with the given extra *attributes*. the through entity is deleted along
+    /// with either side. if the relation is ordered by an ascending position attribute missing
+    /// from *attributes*, the through entity is positioned after the others
+    pub fn add_membership(&'a mut self, source: &Entity, name: &str, target: &Entity, mut attributes: Vec<AttributeDescriptor>) -> Result<Rc<Entity>, EntityError> {
+        let relation = self.get_many_to_many(source, name)?.clone();
+        if let Some(position) = self.get_related_ordering(&relation.through, &relation.source_attribute) {
+            if !position.starts_with('-') && attributes.iter().all(|attribute| attribute.get_name() != position) {
+                let last = self.memberships(source, name)?.iter().filter_map(|membership| match membership.get(position).map(|attr| attr.get_value()) {
+                    Ok(DatabaseValue::Number(position)) => Some(position),
+                    _ => None,
+                }).max();
+                attributes.push(AttributeDescriptor::new(AttributeKind::Physical, position.clone(), DatabaseValue::Number(last.map_or(0, |last| last + 1))));
+            }
+        }
+        attributes.push(AttributeDescriptor::new(
+            AttributeKind::ForeignKey(source.get_identifier().get_model().to_string(), OnDelete::Cascade),
+            relation.source_attribute,
+            DatabaseValue::ForeignKey(source.get_identifier().clone()),
+        ));
+        attributes.push(AttributeDescriptor::new(
+            AttributeKind::ForeignKey(relation.target_model, OnDelete::Cascade),
+            relation.target_attribute,
+            DatabaseValue::ForeignKey(target.get_identifier().clone()),
+        ));
+        Ok(self.instantiate_entity(EntityIdentifier::new(relation.through), attributes))
+    }
+
+    /// the through entities of the many to many relation *name* of *source*
+    pub fn memberships(&self, source: &Entity, name: &str) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let relation = self.get_many_to_many(source, name)?;
+        Ok(self.related(source, &relation.through, &relation.source_attribute))
+    }
+
+    /// the entities related to *source* through the many to many relation *name*, whose
+    /// through entity matches *membership_expression*
+    pub fn many_to_many(&self, source: &Entity, name: &str, membership_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let relation = self.get_many_to_many(source, name)?;
+        let mut result = vec![];
+        for membership in self.memberships(source, name)? {
+            if match_entity(membership_expression, &membership)? {
+                result.extend(membership.get_related(self, &relation.target_attribute)?);
+            }
+        }
+        Ok(result)
+    }
+
+    /// unlink *source* and *target* by deleting the through entities between them.
+    /// return the number of removed memberships
+    pub fn remove_membership(&self, source: &Entity, name: &str, target: &Entity) -> Result<usize, EntityError> {
+        let relation = self.get_many_to_many(source, name)?;
+        let mut removed = 0;
+        for membership in self.memberships(source, name)? {
+            if membership.references(&relation.target_attribute, target)? {
+                self.delete(&membership)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// link *source* to exactly *targets* through the relation *name*: the memberships to other
+    /// entities are deleted and the missing ones created with the *attributes* given
+    pub fn set_many_to_many(&'a mut self, source: &Entity, name: &str, targets: &[Rc<Entity>], attributes: Vec<AttributeDescriptor>) -> Result<(), EntityError> {
+        let relation = self.get_many_to_many(source, name)?.clone();
+        let mut linked = vec![];
+        for membership in self.memberships(source, name)? {
+            match membership.get_related(self, &relation.target_attribute)? {
+                Some(target) if targets.contains(&target) => linked.push(target),
+                _ => { self.delete(&membership)?; },
+            }
+        }
+        for target in targets {
+            if !linked.contains(target) {
+                self.add_membership(source, name, target, attributes.clone())?;
+                linked.push(Rc::clone(target));
+            }
+        }
+        Ok(())
+    }
+
+    /// true if the foreign key *attribute* of *entity* can't be resolved to an alive entity.
+    /// pks of entities absent from the store are assumed to reference database rows
+    fn is_dangling(&self, entity: &Entity, attribute: &str) -> Result<bool, EntityError> {
+        match entity.get_related(self, attribute) {
+            Ok(_) => Ok(false),
+            Err(EntityError::EntityNotFound(identifier)) => Ok(!identifier.has_applied_pk() || self.index.get(&identifier).is_ok()),
+            Err(EntityError::NotARelation(_)) => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// check the relations of the alive entities: foreign keys that can't be resolved, many to many
+    /// memberships to deleted entities and one to one foreign keys shared by several entities
+    pub fn check_integrity(&self) -> Result<IntegrityReport, EntityError> {
+        let mut report = IntegrityReport::default();
+        let membership_attributes: HashSet<(&str, &str)> = self.many_to_many.values().flat_map(HashMap::values)
+            .flat_map(|relation| [(&*relation.through, &*relation.source_attribute), (&*relation.through, &*relation.target_attribute)])
+            .collect();
+        let mut one_to_one: Vec<(String, Rc<Entity>, Vec<Rc<Entity>>)> = vec![];
+        for entity in self.entities.iter().filter(|entity| entity.is_alive_at(self.current_ptr.get_epoch())) {
+            for attribute in entity.relation_names() {
+                if self.is_dangling(entity, &attribute)? {
+                    if membership_attributes.contains(&(entity.get_identifier().get_model(), &*attribute)) {
+                        report.deleted_memberships.push((Rc::clone(entity), attribute));
+                    } else {
+                        report.dangling.push((Rc::clone(entity), attribute));
+                    }
+                } else if entity.is_one_to_one(&attribute) {
+                    if let Some(target) = entity.get_related(self, &attribute)? {
+                        match one_to_one.iter_mut().find(|(name, other, _)| *name == attribute && *other == target) {
+                            Some((_, _, sources)) => sources.push(Rc::clone(entity)),
+                            None => one_to_one.push((attribute, target, vec![Rc::clone(entity)])),
+                        }
+                    }
+                }
+            }
+        }
+        report.one_to_one_duplicates = one_to_one.into_iter().filter(|(_, _, sources)| sources.len() > 1).collect();
+        Ok(report)
+    }
+
+    /// resolve the foreign key *attribute* of all *entities* in one pass, collecting the pks
+    /// of the referenced entities missing from the store so they can be loaded in batch
+    pub fn resolve_related(&self, entities: &[Rc<Entity>], attribute: &str) -> Result<RelatedBatch, EntityError> {
+        let mut batch = RelatedBatch::default();
+        let mut seen = HashSet::new();
+        for entity in entities {
+            match entity.get_related(self, attribute) {
+                Ok(Some(related)) => {
+                    if seen.insert(*related.get_identifier().get_uuid()) {
+                        batch.related.push(related);
+                    }
+                },
+                Ok(None) => {},
+                Err(EntityError::EntityNotFound(identifier)) if identifier.has_applied_pk() => {
+                    let pks = batch.missing.entry(identifier.get_model().to_string()).or_default();
+                    let pk = identifier.get_applied_pk()?;
+                    if !pks.contains(&pk) {
+                        pks.push(pk);
+                    }
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(batch)
+    }
+
+    /// return the entity of *model* with the greatest *attribute* value at the current epoch
+    pub fn latest(&self, model: &Model, attribute: &str) -> Result<Option<Rc<Entity>>, EntityError> {
+        self.find_extremum(model, attribute, Ordering::Greater)
+    }
+
+    /// return the entity of *model* with the smallest *attribute* value at the current epoch
+    pub fn earliest(&self, model: &Model, attribute: &str) -> Result<Option<Rc<Entity>>, EntityError> {
+        self.find_extremum(model, attribute, Ordering::Less)
+    }
+
+    /// return the first entity whose *attribute* value compares as *wanted* against all others.
+    /// entities without value are ignored
+    fn find_extremum(&self, model: &Model, attribute: &str, wanted: Ordering) -> Result<Option<Rc<Entity>>, EntityError> {
+        let mut best: Option<(DatabaseValue, Rc<Entity>)> = None;
+        for entity in self.entities.all(model, self.current_ptr.get_epoch()) {
+            let attr = entity.get(attribute)?;
+            let replace = attr.read_value(|value| match &best {
+                _ if *value == DatabaseValue::None => Ok(false),
+                None => Ok(true),
+                Some((best_value, _)) => Ok(value.partial_cmp(best_value).ok_or_else(|| EntityError::NotOrderable(attribute.to_string()))? == wanted),
+            })?;
+            if replace {
+                best = Some((attr.get_value(), entity));
+            }
+        }
+        Ok(best.map(|(_, entity)| entity))
+    }
+
+    /// return every entity of *model*, in insertion order
+    pub fn all(&self, model: &Model) -> Vec<Rc<Entity>> {
+        self.entities.all(model, self.current_ptr.get_epoch())
+    }
+
+    /// return every entity of every model
+    pub fn all_entities(&self) -> Vec<Rc<Entity>> {
+        self.entities.iter().cloned().collect()
+    }
+
+    /// the entities of every model not deleted at the current epoch
+    pub fn alive_entities(&self) -> Vec<Rc<Entity>> {
+        self.entities.iter().filter(|entity| entity.is_alive_at(self.current_ptr.get_epoch())).cloned().collect()
+    }
+
+    /// the number of entities of *model* not deleted at the current epoch, counted in the
+    /// storage of the model without collecting them
+    pub fn count(&self, model: &Model) -> usize {
+        let started = Instant::now();
+        let count = self.entities.alive_at(model, self.current_ptr.get_epoch()).count();
+        self.record_query("count", model, None, started, count);
+        count
+    }
+
+    /// the number of entities of every model not deleted at the current epoch, see count()
+    pub fn alive_count(&self) -> usize {
+        self.entities.iter().filter(|entity| entity.is_alive_at(self.current_ptr.get_epoch())).count()
+    }
+
+
+
+    pub fn add_entity(&'a mut self, entity: Entity) -> Rc<Entity> {
+        let identifier = entity.get_identifier();
+        let get_result = self.index.get(identifier);
+        match get_result {
+            Err(EntityError::EntityNotFound(_)) => {
+                // add the entity only if it's not already registered
+                let res = self.entities.add(entity);
+                self.index.add(Rc::clone(&res));
+                res.bind_reverse_index(&self.reverse_index);
+                ValueIndex::bind(&self.value_index, &res);
+                if let Some(added) = self.added.as_mut() {
+                    added.push(Rc::clone(&res));
+                }
+                if let Some(transaction) = self.transaction.as_mut() {
+                    transaction.created.push(Rc::clone(&res));
+                }
+                res
+            },
+            Ok(entity) => entity,
+            Err(_) => panic!(),
+        }
+    }
+
+    pub fn new() -> EntityStore {
+        EntityStore::new_at_epochs(0, 1)
+    }
+
+    /// record the entities added to the store from now on, to notify them, see take_added()
+    pub fn record_added(&'a mut self) {
+        self.added.get_or_insert_with(Vec::new);
+    }
+
+    /// the entities added since the last call, in order, if they are recorded
+    pub fn take_added(&'a mut self) -> Vec<Rc<Entity>> {
+        self.added.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// an empty store at the given epochs, to restore a serialized one. the epochs up to
+    /// *last_epoch* are considered allocated already
+    pub fn restore(initial: Epoch, current: Epoch, flushed: Epoch, last_epoch: Epoch) -> EntityStore {
+        let mut store = EntityStore::new_at_epochs(initial, current);
+        store.flushed_epoch = flushed;
+        store.epoch_manager.advance_to(last_epoch);
+        store
+    }
+
+    /// add the entity restored with its attribute histories, see Entity::restore(). a
+    /// *collected* one is a persisted deletion garbage collected before the last flush
+    pub fn restore_entity(&'a mut self, identifier: EntityIdentifier, attributes: Vec<AttributeHistory>, created_epoch: Epoch, deleted_epoch: Option<Epoch>, collected: bool) -> Rc<Entity> {
+        let entity = Entity::restore(identifier, attributes, created_epoch, deleted_epoch, &self.symbols, Rc::clone(&self.initial_ptr), Rc::clone(&self.current_ptr));
+        if collected {
+            let entity = Rc::new(entity);
+            self.collected_deletions.push(Rc::clone(&entity));
+            return entity;
+        }
+        self.add_entity(entity)
+    }
+
+    fn new_at_epochs(initial: Epoch, current: Epoch) -> EntityStore {
+        EntityStore {
+            initial_ptr: Rc::new(EpochPtr::new(initial)),
+            current_ptr: Rc::new(EpochPtr::new(current)),
+            epoch_manager: EpochManager::new(current),
+            entities: EntityStorage::new(),
+            index: EntityIdentifierIndex::new(),
+            transaction: None,
+            undo_epochs: vec![],
+            redo_epochs: vec![],
+            checkpoints: HashMap::new(),
+            branches: HashMap::new(),
+            reverse_index: Rc::new(ReverseIndex::default()),
+            value_index: Rc::new(ValueIndex::default()),
+            many_to_many: HashMap::new(),
+            related_orderings: HashMap::new(),
+            flushed_epoch: initial,
+            collected_deletions: vec![],
+            models: HashMap::new(),
+            version_attributes: HashMap::new(),
+            added: None,
+            constraint_checks: HashMap::new(),
+            check_constraints: HashMap::new(),
+            entity_validators: HashMap::new(),
+            epoch_scopes: vec![],
+            audit_log: RefCell::new(None),
+            audit_context: (None, None),
+            metrics: RefCell::new(StoreMetrics::default()),
+            query_log: RefCell::new(VecDeque::new()),
+            query_log_capacity: 0,
+            symbols: Symbols::default(),
+        }
+    }
+
+    /// start a transaction and return its write epoch.
+    /// the current pointer is moved to the write epoch, so the values written in the transaction
+    /// are read back until it is rolled back
+    pub fn begin(&'a mut self) -> Result<Epoch, EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        self.check_writable()?;
+        let begin_epoch = self.current_ptr.get_epoch();
+        if !self.redo_epochs.is_empty() {
+            // the undone transactions can't be redone once a new one starts
+            self.redo_epochs.clear();
+            for entity in self.entities.iter() {
+                entity.truncate_after(begin_epoch);
+            }
+            self.checkpoints.retain(|_, epoch| *epoch <= begin_epoch);
+            self.branches.retain(|branch, _| *branch <= begin_epoch);
+        }
+        self.transaction = Some(Transaction {
+            begin_epoch,
+            created: vec![],
+            savepoints: vec![],
+        });
+        let write_epoch = self.epoch_manager.allocate();
+        self.current_ptr.slide(write_epoch);
+        Ok(write_epoch)
+    }
+
+    /// keep all the changes made in the transaction
+    pub fn commit(&'a mut self) -> Result<(), EntityError> {
+        let transaction = self.transaction.take().ok_or(EntityError::NoActiveTransaction)?;
+        self.undo_epochs.push(transaction.begin_epoch);
+        Ok(())
+    }
+
+    /// move the current pointer back to before the last committed transaction.
+    /// return false if there is nothing to undo
+    pub fn undo(&'a mut self) -> Result<bool, EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        self.check_writable()?;
+        let Some(epoch) = self.undo_epochs.pop() else { return Ok(false) };
+        self.redo_epochs.push(self.current_ptr.get_epoch());
+        self.current_ptr.slide(epoch);
+        Ok(true)
+    }
+
+    /// move the current pointer forward to after the last undone transaction.
+    /// return false if there is nothing to redo
+    pub fn redo(&'a mut self) -> Result<bool, EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        self.check_writable()?;
+        let Some(epoch) = self.redo_epochs.pop() else { return Ok(false) };
+        self.undo_epochs.push(self.current_ptr.get_epoch());
+        self.current_ptr.slide(epoch);
+        Ok(true)
+    }
+
+    /// discard the values written after the transaction began and the entities it created,
+    /// and move the current pointer back to the begin epoch
+    pub fn rollback(&'a mut self) -> Result<(), EntityError> {
+        let transaction = self.transaction.take().ok_or(EntityError::NoActiveTransaction)?;
+        self.discard_after(transaction.begin_epoch, &transaction.created);
+        self.current_ptr.slide(transaction.begin_epoch);
+        Ok(())
+    }
+
+    /// mark the current state of the transaction and return a token to roll back to it.
+    /// following writes must be done at the current epoch, which is moved after the savepoint
+    pub fn savepoint(&'a mut self) -> Result<Epoch, EntityError> {
+        let transaction = self.transaction.as_mut().ok_or(EntityError::NoActiveTransaction)?;
+        let epoch = self.current_ptr.get_epoch();
+        let write_epoch = self.epoch_manager.allocate();
+        transaction.savepoints.push(Savepoint {
+            epoch,
+            write_epoch,
+            created: transaction.created.len(),
+        });
+        self.current_ptr.slide(write_epoch);
+        Ok(epoch)
+    }
+
+    /// discard the values and entities written since the savepoint *token* was taken.
+    /// the savepoint is kept, the ones taken after it are released
+    pub fn rollback_to(&'a mut self, token: Epoch) -> Result<(), EntityError> {
+        let transaction = self.transaction.as_mut().ok_or(EntityError::NoActiveTransaction)?;
+        let position = transaction.savepoints.iter().position(|savepoint| savepoint.epoch == token).ok_or(EntityError::UnknownSavepoint(token))?;
+        transaction.savepoints.truncate(position + 1);
+        let savepoint = &transaction.savepoints[position];
+        let write_epoch = savepoint.write_epoch;
+        let created = transaction.created.split_off(savepoint.created);
+        self.discard_after(token, &created);
+        self.current_ptr.slide(write_epoch);
+        Ok(())
+    }
+
+    /// drop the values written after *epoch* and the given *created* entities
+    fn discard_after(&'a mut self, epoch: Epoch, created: &[Rc<Entity>]) {
+        for entity in created {
+            self.entities.remove(entity);
+            self.index.remove(entity);
+            self.value_index.forget(entity);
+        }
+        for entity in self.entities.iter() {
+            entity.truncate_after(epoch);
+        }
+        self.checkpoints.retain(|_, checkpoint| *checkpoint <= epoch);
+        self.branches.retain(|branch, _| *branch <= epoch);
+    }
+
+    /// delete *entity* at the current epoch, along with the entities referencing it according to
+    /// the on_delete behavior of their foreign keys, and return all the deleted entities.
+    /// nothing is changed if a protecting foreign key refuses the deletion.
+    /// deleted entities are kept as tombstones, still visible at earlier epochs (undo, rollback,
+    /// views) until collect_garbage() removes them
+    pub fn delete(&self, entity: &Entity) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let epoch = self.current_ptr.get_epoch();
+        if !entity.is_alive_at(epoch) {
+            return Err(EntityError::EntityNotFound(entity.get_identifier().clone()));
+        }
+        let mut deleted = vec![self.index.get(entity.get_identifier())?];
+        let mut seen = HashSet::from([*entity.get_identifier().get_uuid()]);
+        let mut set_null = vec![];
+        let mut protected = vec![];
+        let mut i = 0;
+        while i < deleted.len() {
+            let target = Rc::clone(&deleted[i]);
+            i += 1;
+            for (model, attribute) in self.reverse_index.relations() {
+                for source in self.related(&target, &model, &attribute) {
+                    match source.get_on_delete(&attribute)? {
+                        OnDelete::Cascade => {
+                            if seen.insert(*source.get_identifier().get_uuid()) {
+                                deleted.push(source);
+                            }
+                        },
+                        OnDelete::SetNull => set_null.push((source, attribute.clone())),
+                        OnDelete::Protect => protected.push((Rc::clone(&target), source)),
+                        OnDelete::DoNothing => {},
+                    }
+                }
+            }
+        }
+        // protecting entities deleted as well don't prevent the deletion
+        if let Some((target, source)) = protected.into_iter().find(|(_, source)| !seen.contains(source.get_identifier().get_uuid())) {
+            return Err(EntityError::Protected(target.get_identifier().clone(), source.get_identifier().clone()));
+        }
+        for (source, attribute) in set_null {
+            if !seen.contains(source.get_identifier().get_uuid()) {
+                self.set_attribute(&source, &attribute, DatabaseValue::None)?;
+            }
+        }
+        for entity in deleted.iter() {
+            entity.delete_at(epoch);
+        }
+        Ok(deleted)
+    }
+
+    /// the entities to insert, update and delete in the database to persist the current state,
+    /// compared to the state at the last flush. see mark_flushed()
+    pub fn changeset(&self) -> Result<Changeset, EntityError> {
+        let epoch = self.current_ptr.get_epoch();
+        let mut changeset = Changeset { deleted: self.collected_deletions.clone(), ..Changeset::default() };
+        for entity in self.entities.iter() {
+            let persisted = entity.get_identifier().has_applied_pk();
+            if !entity.is_alive_at(epoch) {
+                if persisted && entity.get_deleted_epoch().is_some_and(|deleted| deleted > self.flushed_epoch) {
+                    changeset.deleted.push(Rc::clone(entity));
+                }
+            } else if !persisted {
+                changeset.created.push(Rc::clone(entity));
+            } else {
+                let changed_attributes = entity.changed_attributes_between(self.flushed_epoch, epoch);
+                if !changed_attributes.is_empty() {
+                    changeset.updated.push((Rc::clone(entity), changed_attributes));
+                }
+            }
+        }
+        changeset.created = dependency_order(changeset.created)?;
+        changeset.deleted = dependency_order(changeset.deleted)?.into_iter().rev().collect();
+        Ok(changeset)
+    }
+
+    /// record the current state as persisted: following changesets only contain the changes
+    /// made from now on, written at a new epoch. return the flushed epoch
+    pub fn mark_flushed(&'a mut self) -> Result<Epoch, EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        self.flushed_epoch = self.current_ptr.get_epoch();
+        self.collected_deletions.clear();
+        let write_epoch = self.epoch_manager.allocate();
+        self.current_ptr.slide(write_epoch);
+        Ok(self.flushed_epoch)
+    }
+
+    /// the epochs the store can still go back to or read at
+    fn pinned_epochs(&self) -> Vec<Epoch> {
+        let mut epochs: Vec<Epoch> = self.undo_epochs.iter().chain(self.redo_epochs.iter()).copied().collect();
+        epochs.extend(self.checkpoints.values());
+        epochs.extend(self.branches.values());
+        if let Some(transaction) = self.transaction.as_ref() {
+            epochs.push(transaction.begin_epoch);
+            epochs.extend(transaction.savepoints.iter().map(|savepoint| savepoint.epoch));
+        }
+        epochs
+    }
+
+    /// physically remove the deleted entities, with their history, that no undo, redo, checkpoint,
+    /// branch, transaction or savepoint can observe anymore. views returned by at() are not
+    /// tracked. the persisted entities stay in the changeset until flushed. return the number of
+    /// removed entities
+    pub fn collect_garbage(&'a mut self) -> usize {
+        let current_epoch = self.current_ptr.get_epoch();
+        let oldest_pinned = self.pinned_epochs().into_iter().min().unwrap_or(current_epoch).min(current_epoch);
+        let collectable: Vec<Rc<Entity>> = self.entities.iter()
+            .filter(|entity| entity.get_deleted_epoch().is_some_and(|deleted| deleted <= oldest_pinned))
+            .map(Rc::clone)
+            .collect();
+        for entity in collectable.iter() {
+            self.entities.remove(entity);
+            self.index.remove(entity);
+            self.value_index.forget(entity);
+            if entity.get_identifier().has_applied_pk() && entity.get_deleted_epoch().is_some_and(|deleted| deleted > self.flushed_epoch) {
+                self.collected_deletions.push(Rc::clone(entity));
+            }
+        }
+        collectable.len()
+    }
+
+    /// fork the epoch timeline at the current state and return the branch identifier.
+    /// values written in the branch are only visible through the branch, and values written
+    /// afterward outside of it are not visible from it, until it is merged or discarded.
+    /// the entities are shared, no copy is made
+    pub fn fork(&'a mut self) -> Epoch {
+        let base_epoch = self.current_ptr.get_epoch();
+        let branch = self.epoch_manager.allocate();
+        self.branches.insert(branch, base_epoch);
+        let write_epoch = self.epoch_manager.allocate();
+        self.current_ptr.slide(write_epoch);
+        branch
+    }
+
+    fn get_branch_base(&self, branch: Epoch) -> Result<Epoch, EntityError> {
+        self.branches.get(&branch).copied().ok_or(EntityError::UnknownBranch(branch))
+    }
+
+    /// the value of *attribute* of *entity* seen from *branch*
+    pub fn get_in_branch(&self, branch: Epoch, entity: &Entity, attribute: &str) -> Result<DatabaseValue, EntityError> {
+        let base_epoch = self.get_branch_base(branch)?;
+        Ok(entity.get(attribute)?.get_in_branch(branch, base_epoch))
+    }
+
+    /// write *value* to the *attribute* of *entity* in *branch* only. the value is validated, as
+    /// merge_branch() writes it unchecked
+    pub fn set_in_branch(&self, branch: Epoch, entity: &Entity, attribute: &str, value: DatabaseValue) -> Result<(), EntityError> {
+        self.check_writable()?;
+        self.get_branch_base(branch)?;
+        let physical_attribute = entity.get(attribute)?;
+        entity.validate(attribute, &value)?;
+        physical_attribute.set_in_branch(value, branch);
+        Ok(())
+    }
+
+    /// write the values of *branch* at the current epoch, overriding the ones written since it was forked
+    pub fn merge_branch(&'a mut self, branch: Epoch) -> Result<(), EntityError> {
+        self.get_branch_base(branch)?;
+        for entity in self.entities.iter() {
+            for (name, value) in entity.take_branch(branch) {
+                self.set_attribute(entity, &name, value)?;
+            }
+        }
+        self.branches.remove(&branch);
+        Ok(())
+    }
+
+    /// drop the values written in *branch*
+    pub fn discard_branch(&'a mut self, branch: Epoch) -> Result<(), EntityError> {
+        self.get_branch_base(branch)?;
+        for entity in self.entities.iter() {
+            entity.discard_branch(branch);
+        }
+        self.branches.remove(&branch);
+        Ok(())
+    }
+
+    /// label the current state with *label* and return its epoch. a previous checkpoint with the
+    /// same label is replaced. following writes must be done at the current epoch, which is moved
+    /// after the checkpoint
+    pub fn checkpoint(&'a mut self, label: &str) -> Epoch {
+        let epoch = self.current_ptr.get_epoch();
+        self.checkpoints.insert(label.to_string(), epoch);
+        let write_epoch = self.epoch_manager.allocate();
+        self.current_ptr.slide(write_epoch);
+        epoch
+    }
+
+    /// the epoch labelled with *label*
+    pub fn checkpoint_epoch(&self, label: &str) -> Result<Epoch, EntityError> {
+        self.checkpoints.get(label).copied().ok_or_else(|| EntityError::UnknownCheckpoint(label.to_string()))
+    }
+
+    /// the entities created or modified since the checkpoint *label*
+    pub fn changes_since_checkpoint(&self, label: &str) -> Result<Vec<EntityChange>, EntityError> {
+        Ok(self.changes_since(self.checkpoint_epoch(label)?))
+    }
+
+    /// discard the values and entities written since the checkpoint *label*.
+    /// the checkpoint is kept, the ones taken after it and the undo history after it are dropped
+    pub fn rollback_to_checkpoint(&'a mut self, label: &str) -> Result<(), EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        let epoch = self.checkpoint_epoch(label)?;
+        let created: Vec<Rc<Entity>> = self.entities.iter()
+            .filter(|entity| entity.get_created_epoch() > epoch)
+            .map(Rc::clone)
+            .collect();
+        self.discard_after(epoch, &created);
+        self.undo_epochs.retain(|undo_epoch| *undo_epoch <= epoch);
+        self.redo_epochs.clear();
+        let write_epoch = self.epoch_manager.allocate();
+        self.current_ptr.slide(write_epoch);
+        Ok(())
+    }
+
+    /// collapse, for every attribute, the values written after the initial epoch and before
+    /// *before_epoch* into the last of them, bounding the history length of long workflows.
+    /// values at the initial epoch and from *before_epoch* onward are untouched, but reading
+    /// at a compacted epoch (undo, savepoints) returns the collapsed value.
+    /// return the number of dropped history entries
+    pub fn compact(&self, before_epoch: Epoch) -> usize {
+        let initial_epoch = self.initial_ptr.get_epoch();
+        self.entities.iter().map(|entity| entity.compact(initial_epoch, before_epoch)).sum()
+    }
+
+    pub fn initial_epoch(&self) -> Epoch {
+        self.initial_ptr.get_epoch()
+    }
+
+    pub fn current_epoch(&self) -> Epoch {
+        self.current_ptr.get_epoch()
+    }
+
+    /// epoch of the last flush, see mark_flushed()
+    pub fn flushed_epoch(&self) -> Epoch {
+        self.flushed_epoch
+    }
+
+    /// the persisted entities deleted since the last flush that were garbage collected
+    pub fn collected_deletions(&self) -> &[Rc<Entity>] {
+        &self.collected_deletions
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// move the current pointer to *epoch* until leave_epoch(), so the values are read as they
+    /// were at *epoch*. no transaction may run meanwhile, the writes would land in the past
+    pub fn enter_epoch(&'a mut self, epoch: Epoch) -> Result<(), EntityError> {
+        if self.transaction.is_some() {
+            return Err(EntityError::TransactionAlreadyStarted);
+        }
+        self.epoch_scopes.push(self.current_ptr.get_epoch());
+        self.current_ptr.slide(epoch);
+        Ok(())
+    }
+
+    /// move the current pointer back to where it was before the last enter_epoch()
+    pub fn leave_epoch(&'a mut self) {
+        if let Some(epoch) = self.epoch_scopes.pop() {
+            self.current_ptr.slide(epoch);
+        }
+    }
+
+    /// fail if the current pointer was moved by enter_epoch(), values can't be written then
+    pub fn check_writable(&self) -> Result<(), EntityError> {
+        match self.epoch_scopes.is_empty() {
+            true => Ok(()),
+            false => Err(EntityError::ReadOnlyEpoch(self.current_ptr.get_epoch())),
+        }
+    }
+
+    /// the pk of the entity referenced by *identifier*, as applied to the stored entity
+    pub fn get_pk(&self, identifier: &EntityIdentifier) -> Result<PK, EntityError> {
+        match self.index.get(identifier) {
+            Ok(entity) => entity.get_identifier().get_applied_pk(),
+            Err(_) => identifier.get_applied_pk(),
+        }
+    }
+
+    /// apply the database *pk* to the entity once it has been persisted,
+    /// and index it so it can be retrieved by model+pk.
+    /// the foreign keys referencing it are given the pk as well, at every epoch
+    pub fn apply_pk(&'a mut self, identifier: &EntityIdentifier, pk: PK) -> Result<(), EntityError> {
+        let entity = self.index.get(identifier)?;
+        self.index.apply_pk(&entity, pk)?;
+        let uuid = entity.get_identifier().get_uuid();
+        for (model, attribute) in self.reverse_index.relations() {
+            for source in self.reverse_index.candidates(&model, &attribute, &entity) {
+                if let Some(source) = self.index.entities_uuid_index.get(&source) {
+                    source.get(&attribute)?.apply_referenced_pk(uuid, pk);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats {
+            pk_index_size: self.index.pk_index_len(),
+            uuid_index_size: self.index.uuid_index_len(),
+            ..StoreStats::default()
+        };
+        for (model, entities) in self.entities.storage.iter() {
+            stats.entities_per_model.insert(model.to_string(), entities.len());
+            for entity in entities {
+                stats.history_entries += entity.history_len();
+                stats.approximate_memory += entity.approximate_size();
+            }
+        }
+        stats.approximate_memory += (stats.pk_index_size + stats.uuid_index_size) * size_of::<(Uuid, Rc<Entity>)>();
+        stats
+    }
+
+    /// estimate the bytes used by the entities of each model, their histories and their index
+    /// entries, deleted entities included until garbage collected
+    pub fn memory_per_model(&self) -> HashMap<Model, ModelMemory> {
+        self.entities.storage.iter().map(|(model, entities)| {
+            let mut memory = ModelMemory { entities: entities.len(), ..ModelMemory::default() };
+            for entity in entities {
+                let history_bytes = entity.history_size();
+                memory.history_bytes += history_bytes;
+                memory.entity_bytes += entity.approximate_size() - history_bytes;
+            }
+            memory.index_bytes = entities.len() * size_of::<(Uuid, Rc<Entity>)>() + self.index.pk_index_len_of(model) * size_of::<(PK, Rc<Entity>)>();
+            (model.to_string(), memory)
+        }).collect()
+    }
+
+    /// the counters of the work done since the store was created or reset_metrics()
+    pub fn metrics(&self) -> StoreMetrics {
+        let mut metrics = self.metrics.borrow().clone();
+        metrics.history_entries = self.entities.iter().map(|entity| entity.history_len()).sum();
+        metrics
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.replace(StoreMetrics::default());
+    }
+
+    /// record the last *capacity* filters and counts with their duration, dropping the oldest
+    /// ones. a capacity of 0 stops the recording and clears the log
+    pub fn enable_query_log(&'a mut self, capacity: usize) {
+        self.query_log_capacity = capacity;
+        let query_log = self.query_log.get_mut();
+        let dropped = query_log.len().saturating_sub(capacity);
+        query_log.drain(..dropped);
+    }
+
+    /// the recorded filters and counts, oldest first
+    pub fn query_log(&self) -> Vec<QueryLogEntry> {
+        self.query_log.borrow().iter().cloned().collect()
+    }
+
+    pub fn clear_query_log(&self) {
+        self.query_log.borrow_mut().clear();
+    }
+
+    fn record_query(&self, operation: &'static str, model: &Model, expression: Option<&FilterExpression>, started: Instant, results: usize) {
+        if self.query_log_capacity == 0 {
+            return;
+        }
+        let mut query_log = self.query_log.borrow_mut();
+        if query_log.len() == self.query_log_capacity {
+            query_log.pop_front();
+        }
+        let expression = expression.map(|expression| expression.to_string()).unwrap_or_default();
+        query_log.push_back(QueryLogEntry { operation, model: model.clone(), expression, duration: started.elapsed(), results });
+    }
+
+    /// count the related entities *found* in the store and the *missing* ones, to load
+    pub fn record_related(&self, found: usize, missing: usize) {
+        let mut metrics = self.metrics.borrow_mut();
+        metrics.cache_hits += found;
+        metrics.cache_misses += missing;
+    }
+
+    /// drop all entities and indexes, and reset the epoch pointers to their initial state.
+    /// entities previously returned by the store are detached from it and keep their own epochs
+    pub fn clear(&'a mut self) {
+        self.entities.clear();
+        self.index.clear();
+        self.transaction = None;
+        self.undo_epochs.clear();
+        self.redo_epochs.clear();
+        self.checkpoints.clear();
+        self.branches.clear();
+        self.reverse_index.clear();
+        self.value_index.clear();
+        self.initial_ptr = Rc::new(EpochPtr::default());
+        self.current_ptr = Rc::new(EpochPtr::new(1));
+        self.epoch_manager = EpochManager::new(1);
+        self.flushed_epoch = self.initial_ptr.get_epoch();
+        self.collected_deletions.clear();
+        if let Some(added) = self.added.as_mut() {
+            added.clear();
+        }
+    }
+
+    /// add a copy of *entity* bound to this store epoch pointers
+    fn add_copy(&'a mut self, entity: &Entity) -> Rc<Entity> {
+        let copy = entity.snapshot(&self.initial_ptr, &self.current_ptr);
+        self.add_entity(copy)
+    }
+
+    pub fn instantiate_entity(&'a mut self, identifier: EntityIdentifier, attributes_descriptors: Vec<AttributeDescriptor>) -> Rc<Entity> {
+        tracing::trace!(%identifier, "instantiate");
+        // the entities of the store share the names
+        let attributes_descriptors = attributes_descriptors.into_iter().map(|descriptor| descriptor.interned(&self.symbols)).collect();
+        let entity = Entity::new(identifier.interned(&self.symbols), attributes_descriptors, Rc::clone(&self.initial_ptr), Rc::clone(&self.current_ptr));
+        self.add_entity(entity)
+    }
+
+    /// return an independent copy of the store: entities, histories, indexes and epoch pointers
+    /// are all copied, so changes made on the snapshot never reach the original store
+    pub fn snapshot(&self) -> EntityStore {
+        let mut snapshot = EntityStore::new_at_epochs(self.initial_ptr.get_epoch(), self.current_ptr.get_epoch());
+        snapshot.epoch_manager = self.epoch_manager.clone();
+        snapshot.many_to_many = self.many_to_many.clone();
+        snapshot.related_orderings = self.related_orderings.clone();
+        snapshot.flushed_epoch = self.flushed_epoch;
+        snapshot.models = self.models.clone();
+        snapshot.version_attributes = self.version_attributes.clone();
+        snapshot.added = self.added.as_ref().map(|_| vec![]);
+        snapshot.constraint_checks = self.constraint_checks.clone();
+        snapshot.check_constraints = self.check_constraints.clone();
+        snapshot.entity_validators = self.entity_validators.clone();
+        snapshot.audit_log = self.audit_log.clone();
+        snapshot.audit_context = self.audit_context.clone();
+        snapshot.symbols = self.symbols.clone();
+        for (model, attribute, kind) in self.value_index.indexes() {
+            ValueIndex::add(&snapshot.value_index, &snapshot.symbols.intern(&model), &snapshot.symbols.intern(&attribute), kind, &[]);
+        }
+        snapshot.collected_deletions = self.collected_deletions.iter().map(|entity| Rc::new(entity.snapshot(&snapshot.initial_ptr, &snapshot.current_ptr))).collect();
+        for entity in self.entities.iter() {
+            snapshot.add_copy(entity);
+        }
+        snapshot
+    }
+
+    /// return a store holding an independent copy of *entity* alone, with its history, at the
+    /// epochs of this store, and the copy
+    pub fn snapshot_entity(&self, entity: &Entity) -> (EntityStore, Rc<Entity>) {
+        let mut snapshot = EntityStore::new_at_epochs(self.initial_ptr.get_epoch(), self.current_ptr.get_epoch());
+        snapshot.epoch_manager = self.epoch_manager.clone();
+        snapshot.flushed_epoch = self.flushed_epoch;
+        let copy = snapshot.add_copy(entity);
+        (snapshot, copy)
+    }
+
+    /// find the entity of *model* whose *natural_key* attributes equal the given *values* and
+    /// write all *values* on it at the current epoch, like write_value(), or instantiate a new
+    /// entity with these values, see instantiate_values(). nothing is written if one of the
+    /// attributes is unknown, one of the values isn't valid or violates a constraint checked
+    /// immediately. return the entity and the values the written ones replaced, by attribute,
+    /// None if it was created
+    pub fn upsert(&'a mut self, model: Model, natural_key: &[String], values: Values) -> Result<(Rc<Entity>, Option<Values>), EntityError> {
+        let Some(entity) = self.find_natural_key(model.clone(), natural_key, &values)? else {
+            return Ok((self.instantiate_values(model, values)?, None));
+        };
+        for (name, value) in values.iter() {
+            entity.get(name)?;
+            entity.validate(name, value)?;
+        }
+        let logged = self.audit_log.borrow().as_ref().map(Vec::len);
+        let mut replaced = Vec::with_capacity(values.len());
+        for (name, value) in values {
+            match self.write_value(&entity, &name, value, None) {
+                Ok(previous) => replaced.push((name, previous)),
+                Err(error) => {
+                    // restore the values already written, and forget their writes
+                    for (name, previous) in replaced.into_iter().rev() {
+                        entity.get(&name)?.set_current_value(previous);
+                    }
+                    if let (Some(audit_log), Some(logged)) = (self.audit_log.borrow_mut().as_mut(), logged) {
+                        audit_log.truncate(logged);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok((entity, Some(replaced)))
+    }
+
+    /// the alive entity of *model* whose *natural_key* attributes equal the given *values*, see upsert()
+    pub fn find_natural_key(&self, model: Model, natural_key: &[String], values: &[(String, DatabaseValue)]) -> Result<Option<Rc<Entity>>, EntityError> {
+        let mut expressions = vec![];
+        for key in natural_key {
+            let (_, value) = values.iter().find(|(name, _)| name == key).ok_or_else(|| EntityError::AttributeNotFound(key.clone()))?;
+            expressions.push(FilterExpression::Exact(ExactExpression::new(key.clone(), value.clone())));
+        }
+        Ok(self.filter_compiled(model, &FilterExpression::And(expressions).compile())?.into_iter().next())
+    }
+
+    /// instantiate a new entity of *model* holding *values*, like instantiate_model() if the
+    /// model is registered, with physical attributes otherwise
+    pub fn instantiate_values(&'a mut self, model: Model, values: Values) -> Result<Rc<Entity>, EntityError> {
+        if self.models.contains_key(&model) {
+            return self.instantiate_model(EntityIdentifier::new(model), values.into_iter().collect());
+        }
+        let attributes_descriptors = values.into_iter().map(
+            |(name, value)| AttributeDescriptor::new(AttributeKind::Physical, name, value)
+        ).collect();
+        Ok(self.instantiate_entity(EntityIdentifier::new(model), attributes_descriptors))
+    }
+
+    /// import all entities of *other* into this store.
+    /// entities known by both stores (same uuid, or same model+pk) are reconciled attribute by
+    /// attribute using *policy*; nothing is changed if a conflict make the merge fail.
+    pub fn merge(&'a mut self, other: &EntityStore, policy: &MergePolicy) -> Result<(), EntityError> {
+        let mut taken = vec![];
+        let mut added = vec![];
+        for entity in other.entities.iter() {
+            match self.get(entity.get_identifier()) {
+                Ok(ours) => {
+                    for (name, theirs_attr) in entity.iter_attributes() {
+                        let Ok(ours_attr) = ours.get(name) else { continue };
+                        if ours_attr.get_value() == theirs_attr.get_value() {
+                            continue;
+                        }
+                        match policy.get_policy(name) {
+                            ConflictPolicy::KeepOurs => {},
+                            ConflictPolicy::TakeTheirs => taken.push((Rc::clone(&ours), name.to_string(), ours_attr, Rc::clone(theirs_attr))),
+                            ConflictPolicy::Fail => return Err(EntityError::MergeConflict(entity.get_identifier().clone(), name.to_string())),
+                        }
+                    }
+                },
+                Err(_) => added.push(entity),
+            }
+        }
+        let epoch = self.current_ptr.get_epoch();
+        for (ours, name, ours_attr, theirs_attr) in taken {
+            let previous = ours_attr.get_value();
+            ours_attr.copy_history_from(&theirs_attr);
+            self.record_write(&ours, &name, previous, epoch)?;
+        }
+        for entity in added {
+            self.add_copy(entity);
+        }
+        Ok(())
+    }
+
+    /// instantiate the entity of *model* described by *row* and, first, its related entities,
+    /// linking them through their foreign key attributes. rows with a pk already in the store
+    /// resolve to the stored entity
+    pub fn hydrate(&'a mut self, model: Model, row: HydrationRow) -> Rc<Entity> {
+        let mut attributes = row.attributes;
+        for (name, related_model, related_row) in row.related {
+            let related = self.hydrate(related_model.clone(), related_row);
+            let value = DatabaseValue::ForeignKey(related.get_identifier().clone());
+            attributes.push(AttributeDescriptor::new(AttributeKind::ForeignKey(related_model, OnDelete::DoNothing), name, value));
+        }
+        let identifier = match row.pk {
+            Some(pk) => EntityIdentifier::new_persisted(model, pk),
+            None => EntityIdentifier::new(model),
+        };
+        self.instantiate_entity(identifier, attributes)
+    }
+
+    /// instantiate one new entity of *model* per given list of attributes descriptors.
+    /// storage and indexes are grown once for the whole batch
+    pub fn instantiate_many(&'a mut self, model: Model, attributes_descriptors: Vec<Vec<AttributeDescriptor>>) -> Vec<Rc<Entity>> {
+        let symbol = self.symbols.intern(&model);
+        self.entities.reserve(&symbol, attributes_descriptors.len());
+        self.index.reserve(attributes_descriptors.len());
+        let mut result = Vec::with_capacity(attributes_descriptors.len());
+        for descriptors in attributes_descriptors {
+            result.push(self.instantiate_entity(EntityIdentifier::of_model(&symbol, None), descriptors));
+        }
+        result
+    }
+}
+
+
+/// the order of two values of an ordering attribute, *descending* or not, the missing values
+/// coming last
+fn compare_ordered(left: &DatabaseValue, right: &DatabaseValue, descending: bool) -> Ordering {
+    match (left, right) {
+        (DatabaseValue::None, DatabaseValue::None) => Ordering::Equal,
+        (DatabaseValue::None, _) => Ordering::Greater,
+        (_, DatabaseValue::None) => Ordering::Less,
+        (left, right) => {
+            let ordering = left.partial_cmp(right).unwrap_or(Ordering::Equal);
+            if descending { ordering.reverse() } else { ordering }
+        },
+    }
+}
+
+/// sort *entities* so that the ones referenced by others through a foreign key come before them,
+/// grouped by model as much as possible, and in their order otherwise. fail if some of them
+/// reference each other in a cycle
+fn dependency_order(entities: Vec<Rc<Entity>>) -> Result<Vec<Rc<Entity>>, EntityError> {
+    let mut positions: HashMap<RelationTarget, usize> = HashMap::new();
+    for (position, entity) in entities.iter().enumerate() {
+        let identifier = entity.get_identifier();
+        positions.insert(RelationTarget::Uuid(*identifier.get_uuid()), position);
+        if let Ok(pk) = identifier.get_applied_pk() {
+            positions.insert(RelationTarget::Pk(Arc::clone(identifier.get_model_symbol()), pk), position);
+        }
+    }
+    // positions of the entities referencing each entity, and number of entities each one references
+    let mut dependents: Vec<Vec<usize>> = vec![vec![]; entities.len()];
+    let mut dependencies: Vec<usize> = vec![0; entities.len()];
+    for (position, entity) in entities.iter().enumerate() {
+        let mut referenced = HashSet::new();
+        for attribute in entity.relation_names() {
+            let value = entity.get(&attribute)?.get_value();
+            let targets = ReverseIndex::targets(entity.get_related_model(&attribute)?, &value);
+            if let Some(target) = targets.iter().find_map(|target| positions.get(target)) {
+                if referenced.insert(*target) {
+                    dependents[*target].push(position);
+                    dependencies[position] += 1;
+                }
+            }
+        }
+    }
+    // the entities whose dependencies are sorted, by model in order of appearance
+    let mut models: Vec<&str> = vec![];
+    let mut ready: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (position, entity) in entities.iter().enumerate() {
+        let model = entity.get_identifier().get_model();
+        if !ready.contains_key(model) {
+            models.push(model);
+        }
+        let queue = ready.entry(model).or_default();
+        if dependencies[position] == 0 {
+            queue.push_back(position);
+        }
+    }
+    let mut order = vec![];
+    let mut model = models.first().copied();
+    while let Some(current) = model {
+        let Some(position) = ready.get_mut(current).and_then(VecDeque::pop_front) else {
+            model = models.iter().find(|model| !ready[*model].is_empty()).copied();
+            continue;
+        };
+        order.push(position);
+        for dependent in dependents[position].iter() {
+            dependencies[*dependent] -= 1;
+            if dependencies[*dependent] == 0 {
+                ready.get_mut(entities[*dependent].get_identifier().get_model()).unwrap().push_back(*dependent);
+            }
+        }
+    }
+    if order.len() < entities.len() {
+        let cycle = (0..entities.len()).filter(|position| dependencies[*position] > 0).map(|position| entities[position].get_identifier().clone()).collect();
+        return Err(EntityError::DependencyCycle(cycle));
+    }
+    Ok(order.into_iter().map(|position| Rc::clone(&entities[position])).collect())
+}
+
+/// a store layered on top of a parent store.
+/// entities of the parent are read through and copied into the overlay the first time they are
+/// accessed, so all writes are recorded locally until the overlay is committed into the parent
+/// or discarded.
+pub struct EntityStoreOverlay {
+    parent: Rc<RefCell<EntityStore>>,
+    local: EntityStore,
+}
+
+impl EntityStoreOverlay {
+    pub fn new(parent: Rc<RefCell<EntityStore>>) -> Self {
+        let local = {
+            let parent = parent.borrow();
+            let mut local = EntityStore::new_at_epochs(parent.initial_ptr.get_epoch(), parent.current_ptr.get_epoch());
+            local.epoch_manager = parent.epoch_manager.clone();
+            local
+        };
+        EntityStoreOverlay {
+            parent,
+            local,
+        }
+    }
+
+    pub fn get(&mut self, identifier: &EntityIdentifier) -> Result<Rc<Entity>, EntityError> {
+        match self.local.get(identifier) {
+            Err(EntityError::EntityNotFound(_)) => {
+                let entity = self.parent.borrow().get(identifier)?;
+                Ok(self.local.add_copy(&entity))
+            },
+            result => result,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn filter(&mut self, model: Model, filter_expression: &FilterExpression) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let mut result = self.local.filter(model.clone(), filter_expression)?;
+        let parent_result = self.parent.borrow().filter(model, filter_expression)?;
+        for entity in parent_result {
+            // entities already copied locally have been matched against their local values
+            if self.local.get(entity.get_identifier()).is_err() {
+                result.push(self.local.add_copy(&entity));
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn instantiate_entity(&mut self, identifier: EntityIdentifier, attributes_descriptors: Vec<AttributeDescriptor>) -> Result<Rc<Entity>, EntityError> {
+        match self.get(&identifier) {
+            Err(EntityError::EntityNotFound(_)) => Ok(self.local.instantiate_entity(identifier, attributes_descriptors)),
+            result => result,
+        }
+    }
+
+    /// apply all the local changes to the parent store
+    pub fn commit(self) {
+        let mut parent = self.parent.borrow_mut();
+        for entity in self.local.entities.iter() {
+            match parent.get(entity.get_identifier()) {
+                Ok(parent_entity) => parent_entity.copy_history_from(entity),
+                Err(_) => {
+                    parent.add_copy(entity);
+                },
+            }
+        }
+        parent.current_ptr.slide(self.local.current_ptr.get_epoch());
+        parent.epoch_manager.advance_to(self.local.epoch_manager.get_last_epoch());
+    }
+
+    /// drop all the local changes, leaving the parent store untouched
+    pub fn discard(self) {}
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, MessageDict, OnDelete, Validator, PK};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use crate::entity_store::{AuditEntry, Changeset, ConflictPolicy, Constraint, ConstraintCheck, EntityChange, EntityStore, EntityStoreOverlay, HydrationRow, IndexKind, ManyToMany, MergePolicy, ModelSchema, StoreMetrics, NON_FIELD_ERRORS};
+    use crate::errors::EntityError;
+    use crate::expression::{ExactExpression, FilterExpression, Lookup, LookupExpression};
+
+    #[test]
+
+    fn test_entity_store_add_and_get() {
+        let mut entity_store = EntityStore::new();
+        let identifier = EntityIdentifier::new("User".to_string());
+        let attributes_descriptors = ["name", "age"].iter().map(
+            |attr| AttributeDescriptor::new(AttributeKind::Physical, attr.to_string(), DatabaseValue::String(format!("default {}", attr)))
+        ).collect();
+        let entity = entity_store.instantiate_entity(identifier.clone(), attributes_descriptors);
+
+        let entity_store = entity_store;
+
+
+        assert_eq!(entity.get("name").unwrap().get_initial(), DatabaseValue::String("default name".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("default name".to_string()));
+
+        assert_eq!(entity.get_identifier(), &identifier);
+        assert!(entity_store.get(entity.get_identifier()).is_ok());
+        assert_eq!(entity_store.get(entity.get_identifier()).unwrap(), entity);
+        assert_eq!(&entity_store.get(&identifier).unwrap(), &entity_store.get(&identifier.clone()).unwrap());
+    }
+
+    #[test]
+    fn test_entity_identifier_equality() {
+        let id1 = EntityIdentifier::new("User".to_string());
+
+        let id2 = EntityIdentifier::new("Book".to_string());
+        let id3 = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let id4 = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let id5 = EntityIdentifier::new_persisted("User".to_string(), 2);
+
+        assert_eq!(id1, id1);
+        assert_ne!(id1, id2);
+        assert_ne!(id1, id3);
+        assert_ne!(id1, id4);
+        assert_ne!(id1, id5);
+
+        assert_ne!(id2, id1);
+        assert_eq!(id2, id2);
+        assert_ne!(id2, id3);
+        assert_ne!(id2, id4);
+        assert_ne!(id2, id5);
+
+        assert_ne!(id3, id1);
+        assert_ne!(id3, id2);
+        assert_eq!(id3, id3);
+        assert_eq!(id3, id4);
+        assert_ne!(id3, id5);
+
+        assert_ne!(id4, id1);
+        assert_ne!(id4, id2);
+        assert_eq!(id4, id3);
+        assert_eq!(id4, id4);
+        assert_ne!(id4, id5);
+
+        assert_ne!(id5, id1);
+        assert_ne!(id5, id2);
+        assert_ne!(id5, id3);
+        assert_ne!(id5, id4);
+        assert_eq!(id5, id5);
+    }
+
+    #[test]
+    fn test_entity_filter() {
+
+        let mut entity_store = EntityStore::new();
+        let attributes_descriptors: Vec<AttributeDescriptor> = ["name", "age"].iter().map(
+            |attr| AttributeDescriptor::new(AttributeKind::Physical, attr.to_string(), DatabaseValue::String(format!("default {}", attr)))
+        ).collect();
+
+        for i in 1..100 {
+            let identifier = EntityIdentifier::new("User".to_string());
+
+            let entity = entity_store.instantiate_entity(identifier.clone(), attributes_descriptors.clone());
+            let name_attr = entity.get("name").unwrap();
+            name_attr.set_value(DatabaseValue::String(format!("user {}", i)), 1);
+            let age_attr = entity.get("age").unwrap();
+            age_attr.set_value(DatabaseValue::Number(i), 1);
+        }
+
+        entity_store.current_ptr.slide(1);
+        let list = entity_store.filter("User".to_string(), &FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("user 4".to_string())))).unwrap();
+        assert_eq!(list.len(), 1);
+        let entity = list.first().unwrap();
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("user 4".to_string()));
+
+    }
+
+    #[test]
+    fn test_snapshot_is_independent() {
+        let mut entity_store = EntityStore::new();
+        let identifier = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let entity = entity_store.instantiate_entity(identifier.clone(), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string()))
+        ]);
+        entity.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 1);
+
+        let snapshot = entity_store.snapshot();
+        let copy = snapshot.get(&identifier).unwrap();
+        assert_eq!(copy.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(copy.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+
+        copy.get("name").unwrap().set_value(DatabaseValue::String("jane".to_string()), 1);
+        snapshot.current_ptr.slide(0);
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(copy.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        snapshot.current_ptr.slide(1);
+        assert_eq!(copy.get("name").unwrap().get_value(), DatabaseValue::String("jane".to_string()));
+        assert_eq!(snapshot.get(&EntityIdentifier::new_persisted("User".to_string(), 1)).unwrap(), copy);
+
+        let (entity_snapshot, entity_copy) = entity_store.snapshot_entity(&entity);
+        assert_eq!(entity_snapshot.alive_count(), 1);
+        entity_copy.get("name").unwrap().set_value(DatabaseValue::String("jane".to_string()), 1);
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(entity_copy.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+    }
+
+    fn name_descriptor(name: &str) -> Vec<AttributeDescriptor> {
+        vec![AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String(name.to_string()))]
+    }
+
+    #[test]
+    fn test_overlay_commit() {
+        let parent = Rc::new(RefCell::new(EntityStore::new()));
+        let identifier = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let entity = parent.borrow_mut().instantiate_entity(identifier.clone(), name_descriptor("john"));
+
+        let mut overlay = EntityStoreOverlay::new(Rc::clone(&parent));
+        let local = overlay.get(&identifier).unwrap();
+        local.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 1);
+        let created = overlay.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane")).unwrap();
+
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert!(parent.borrow().get(created.get_identifier()).is_err());
+        let found = overlay.filter("User".to_string(), &FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("doe".to_string())))).unwrap();
+        assert_eq!(found, vec![local]);
+
+        overlay.commit();
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+        assert!(parent.borrow().get(created.get_identifier()).is_ok());
+    }
+
+    #[test]
+    fn test_overlay_discard() {
+        let parent = Rc::new(RefCell::new(EntityStore::new()));
+        let identifier = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let entity = parent.borrow_mut().instantiate_entity(identifier.clone(), name_descriptor("john"));
+
+        let mut overlay = EntityStoreOverlay::new(Rc::clone(&parent));
+        overlay.get(&identifier).unwrap().get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 1);
+        let created = overlay.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("jane")).unwrap();
+        overlay.discard();
+
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert!(parent.borrow().get(created.get_identifier()).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut ours = EntityStore::new();
+        let mut theirs = EntityStore::new();
+        let descriptors = |name: &str, age: i64| vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String(name.to_string())),
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(age)),
+        ];
+        let shared = ours.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), descriptors("john", 20));
+        theirs.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), descriptors("doe", 30));
+        let new = theirs.instantiate_entity(EntityIdentifier::new("User".to_string()), descriptors("jane", 40));
+
+        let fail = MergePolicy::new(ConflictPolicy::Fail);
+        assert!(matches!(ours.merge(&theirs, &fail), Err(EntityError::MergeConflict(identifier, _)) if &identifier == shared.get_identifier()));
+        assert!(ours.get(new.get_identifier()).is_err());
+
+        let policy = MergePolicy::new(ConflictPolicy::KeepOurs).with_attribute("age".to_string(), ConflictPolicy::TakeTheirs);
+        ours.merge(&theirs, &policy).unwrap();
+        assert_eq!(shared.get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert_eq!(shared.get("age").unwrap().get_value(), DatabaseValue::Number(30));
+        let imported = ours.get(new.get_identifier()).unwrap();
+        assert_eq!(imported.get("name").unwrap().get_value(), DatabaseValue::String("jane".to_string()));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut entity_store = EntityStore::new();
+        let identifier = EntityIdentifier::new_persisted("User".to_string(), 1);
+        let entity = entity_store.instantiate_entity(identifier.clone(), name_descriptor("john"));
+        entity.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 2);
+        entity_store.current_ptr.slide(2);
+
+        entity_store.clear();
+        assert!(entity_store.get(&identifier).is_err());
+        assert_eq!(entity_store.filter("User".to_string(), &FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("doe".to_string())))).unwrap().len(), 0);
+        assert_eq!(entity_store.current_ptr.get_epoch(), 1);
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+
+        let entity = entity_store.instantiate_entity(identifier.clone(), name_descriptor("jane"));
+        assert_eq!(entity_store.get(&identifier).unwrap(), entity);
+    }
+
+    #[test]
+    fn test_all() {
+        let mut entity_store = EntityStore::new();
+        let users: Vec<_> = (0..3).map(|i| entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), i), name_descriptor("john"))).collect();
+        let book = entity_store.instantiate_entity(EntityIdentifier::new("Book".to_string()), name_descriptor("dune"));
+
+        assert_eq!(entity_store.all(&"User".to_string()), users);
+        assert_eq!(entity_store.all(&"Book".to_string()), vec![book]);
+        assert!(entity_store.all(&"Publisher".to_string()).is_empty());
+        assert_eq!(entity_store.all_entities().len(), 4);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("doe"));
+        entity_store.instantiate_entity(EntityIdentifier::new("Book".to_string()), name_descriptor("dune"));
+        user.get("name").unwrap().set_value(DatabaseValue::String("jane".to_string()), 1);
+
+        let stats = entity_store.stats();
+        assert_eq!(stats.entities_per_model.get("User"), Some(&2));
+        assert_eq!(stats.entities_per_model.get("Book"), Some(&1));
+        assert_eq!(stats.history_entries, 4);
+        assert_eq!(stats.pk_index_size, 1);
+        assert_eq!(stats.uuid_index_size, 3);
+        assert!(stats.approximate_memory > 0);
+    }
+
+    #[test]
+    fn test_memory_per_model() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("doe"));
+        entity_store.instantiate_entity(EntityIdentifier::new("Book".to_string()), name_descriptor("dune"));
+
+        let memory = entity_store.memory_per_model();
+        let users = memory["User"].clone();
+        assert_eq!((users.entities, memory["Book"].entities), (2, 1));
+        assert!(users.index_bytes > memory["Book"].index_bytes);
+        assert_eq!(users.total(), users.entity_bytes + users.history_bytes + users.index_bytes);
+
+        for epoch in 1..10 {
+            user.get("name").unwrap().set_value(DatabaseValue::String(format!("john {}", epoch)), epoch);
+        }
+        let memory = entity_store.memory_per_model();
+        assert!(memory["User"].history_bytes > users.history_bytes);
+        assert_eq!(memory["User"].entity_bytes, users.entity_bytes);
+    }
+
+    #[test]
+    fn test_apply_pk() {
+        let mut entity_store = EntityStore::new();
+        let identifier = EntityIdentifier::new("User".to_string());
+        let entity = entity_store.instantiate_entity(identifier.clone(), name_descriptor("john"));
+        let persisted = EntityIdentifier::new_persisted("User".to_string(), 42);
+        assert!(entity_store.get(&persisted).is_err());
+
+        entity_store.apply_pk(&identifier, 42).unwrap();
+        assert_eq!(entity.get_identifier().get_applied_pk(), Ok(42));
+        assert_eq!(entity_store.get(&persisted).unwrap(), entity);
+        assert_eq!(entity_store.stats().pk_index_size, 1);
+
+        entity_store.apply_pk(&identifier, 43).unwrap();
+        assert!(entity_store.get(&persisted).is_err());
+        assert_eq!(entity_store.get(&EntityIdentifier::new_persisted("User".to_string(), 43)).unwrap(), entity);
+
+        let other = EntityIdentifier::new("User".to_string());
+        entity_store.instantiate_entity(other.clone(), name_descriptor("doe"));
+        assert_eq!(entity_store.apply_pk(&other, 43), Err(EntityError::DuplicatePk(entity.get_identifier().clone())));
+        assert!(!other.has_applied_pk());
+    }
+
+    #[test]
+    fn test_apply_pk_to_foreign_keys() {
+        let mut entity_store = EntityStore::new();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new("Group".to_string()), name_descriptor("admins"));
+        let reference = DatabaseValue::ForeignKey(group.get_identifier().clone());
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::Cascade), "group".to_string(), DatabaseValue::None),
+        ]);
+        user.get("group").unwrap().set_current_value(reference);
+        let changeset = entity_store.changeset().unwrap();
+
+        entity_store.apply_pk(group.get_identifier(), 7).unwrap();
+        match user.get("group").unwrap().get_value() {
+            DatabaseValue::ForeignKey(identifier) => assert_eq!(identifier.get_applied_pk(), Ok(7)),
+            value => panic!("unexpected value {:?}", value),
+        }
+        assert_eq!(entity_store.related(&group, &"User".to_string(), "group"), vec![Rc::clone(&user)]);
+        assert_eq!(entity_store.get(&EntityIdentifier::new_persisted("Group".to_string(), 7)).unwrap(), group);
+        // the rewrite isn't a change of its own
+        assert_eq!(entity_store.changeset().unwrap().updated.len(), changeset.updated.len());
+    }
+
+    #[test]
+    fn test_upsert() {
+        let mut entity_store = EntityStore::new();
+        let natural_key = vec!["email".to_string()];
+        let values = |email: &str, name: &str| vec![
+            ("email".to_string(), DatabaseValue::String(email.to_string())),
+            ("name".to_string(), DatabaseValue::String(name.to_string())),
+        ];
+
+        let (john, replaced) = entity_store.upsert("User".to_string(), &natural_key, values("john@example.com", "john")).unwrap();
+        assert_eq!(replaced, None);
+        let (doe, replaced) = entity_store.upsert("User".to_string(), &natural_key, values("doe@example.com", "doe")).unwrap();
+        assert_eq!(replaced, None);
+        assert_ne!(john, doe);
+
+        let (updated, replaced) = entity_store.upsert("User".to_string(), &natural_key, values("john@example.com", "johnny")).unwrap();
+        assert_eq!(replaced, Some(values("john@example.com", "john")));
+        assert_eq!(updated, john);
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("johnny".to_string()));
+        assert_eq!(john.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity_store.all(&"User".to_string()).len(), 2);
+
+        assert_eq!(
+            entity_store.upsert("User".to_string(), &["pk".to_string()], values("x", "y")).unwrap_err(),
+            EntityError::AttributeNotFound("pk".to_string())
+        );
+
+        let mut unknown = values("john@example.com", "jon");
+        unknown.push(("age".to_string(), DatabaseValue::Number(3)));
+        assert_eq!(entity_store.upsert("User".to_string(), &natural_key, unknown), Err(EntityError::AttributeNotFound("age".to_string())));
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("johnny".to_string()));
+    }
+
+    #[test]
+    fn test_upsert_registered_model() {
+        let mut entity_store = EntityStore::new();
+        entity_store.register_model("Book".to_string(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String("".to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(0)).with_validator(Validator::MinValue(1)),
+            ],
+            unique: vec![],
+        });
+        let natural_key = vec!["title".to_string()];
+        let title = ("title".to_string(), DatabaseValue::String("dune".to_string()));
+
+        let (dune, replaced) = entity_store.upsert("Book".to_string(), &natural_key, vec![title.clone()]).unwrap();
+        assert_eq!(replaced, None);
+        assert_eq!(dune.get("pages").unwrap().get_value(), DatabaseValue::Number(0));
+        assert!(matches!(
+            entity_store.upsert("Book".to_string(), &natural_key, vec![title.clone(), ("pages".to_string(), DatabaseValue::Number(-1))]),
+            Err(EntityError::Validation(..))
+        ));
+        entity_store.upsert("Book".to_string(), &natural_key, vec![title.clone(), ("pages".to_string(), DatabaseValue::Number(412))]).unwrap();
+        assert_eq!(dune.get("pages").unwrap().get_value(), DatabaseValue::Number(412));
+        assert_eq!(entity_store.all(&"Book".to_string()).len(), 1);
+    }
+
+    #[test]
+    fn test_upsert_rollback() {
+        let mut entity_store = EntityStore::new();
+        let model = "Book".to_string();
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String("".to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(0)),
+                AttributeDescriptor::new(AttributeKind::Physical, "isbn".to_string(), DatabaseValue::String("".to_string())),
+            ],
+            unique: vec![vec!["isbn".to_string()]],
+        });
+        let unique = Constraint::Unique(model.clone(), vec!["isbn".to_string()]);
+        entity_store.set_constraint_check(unique.clone(), ConstraintCheck::Immediate);
+        entity_store.enable_audit_log();
+        let natural_key = vec!["title".to_string()];
+        let values = |title: &str, pages: i64, isbn: &str| vec![
+            ("title".to_string(), DatabaseValue::String(title.to_string())),
+            ("pages".to_string(), DatabaseValue::Number(pages)),
+            ("isbn".to_string(), DatabaseValue::String(isbn.to_string())),
+        ];
+        let (dune, _) = entity_store.upsert(model.clone(), &natural_key, values("dune", 412, "1")).unwrap();
+        let (messiah, _) = entity_store.upsert(model.clone(), &natural_key, values("dune messiah", 256, "2")).unwrap();
+        let logged = entity_store.audit_log().len();
+
+        // pages is written before isbn violates the constraint, and restored
+        assert_eq!(
+            entity_store.upsert(model.clone(), &natural_key, values("dune", 500, "2")),
+            Err(EntityError::ConstraintViolation(dune.get_identifier().clone(), unique, Some(Box::new(messiah.get_identifier().clone()))))
+        );
+        assert_eq!(dune.get("pages").unwrap().get_value(), DatabaseValue::Number(412));
+        assert_eq!(dune.get("isbn").unwrap().get_value(), DatabaseValue::String("1".to_string()));
+        assert_eq!(entity_store.audit_log().len(), logged);
+    }
+
+    #[test]
+    fn test_filter_related() {
+        let mut entity_store = EntityStore::new();
+        for (pk, country) in [(1, "FR"), (2, "US"), (3, "FR")] {
+            entity_store.instantiate_entity(EntityIdentifier::new_persisted("Customer".to_string(), pk), vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "country".to_string(), DatabaseValue::String(country.to_string()))
+            ]);
+        }
+        let orders: Vec<_> = [1, 2, 3, 4, 1].iter().map(|customer| entity_store.instantiate_entity(EntityIdentifier::new("Order".to_string()), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "customer".to_string(), DatabaseValue::Number(*customer))
+        ])).collect();
+
+        let french = FilterExpression::Exact(ExactExpression::new("country".to_string(), DatabaseValue::String("FR".to_string())));
+        let result = entity_store.filter_related("Order".to_string(), "customer", &"Customer".to_string(), &french).unwrap();
+        assert_eq!(result, vec![Rc::clone(&orders[0]), Rc::clone(&orders[2]), Rc::clone(&orders[4])]);
+
+        assert_eq!(
+            entity_store.filter_related("Order".to_string(), "client", &"Customer".to_string(), &french).unwrap_err(),
+            EntityError::AttributeNotFound("client".to_string())
+        );
+    }
+
+    #[test]
+    fn test_in_bulk() {
+        let mut entity_store = EntityStore::new();
+        let users: Vec<_> = (1..4).map(|pk| entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), pk), name_descriptor("john"))).collect();
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("Book".to_string(), 4), name_descriptor("dune"));
+
+        let result = entity_store.in_bulk(&"User".to_string(), &[1, 3, 4]);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result.get(&1), Some(&users[0]));
+        assert_eq!(result.get(&3), Some(&users[2]));
+        assert!(entity_store.in_bulk(&"Publisher".to_string(), &[1]).is_empty());
+    }
+
+    #[test]
+    fn test_latest_earliest() {
+        let mut entity_store = EntityStore::new();
+        let rows = [5, 12, 3, 12].iter().map(
+            |age| vec![AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(*age))]
+        ).collect();
+        let users = entity_store.instantiate_many("User".to_string(), rows);
+
+        assert_eq!(entity_store.latest(&"User".to_string(), "age").unwrap(), Some(Rc::clone(&users[1])));
+        assert_eq!(entity_store.earliest(&"User".to_string(), "age").unwrap(), Some(Rc::clone(&users[2])));
+        users[0].get("age").unwrap().set_value(DatabaseValue::Number(1), 1);
+        assert_eq!(entity_store.earliest(&"User".to_string(), "age").unwrap(), Some(Rc::clone(&users[0])));
+        assert_eq!(entity_store.latest(&"Book".to_string(), "age").unwrap(), None);
+
+        users[3].get("age").unwrap().set_value(DatabaseValue::String("old".to_string()), 1);
+        assert_eq!(entity_store.latest(&"User".to_string(), "age"), Err(EntityError::NotOrderable("age".to_string())));
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+
+        let epoch = entity_store.begin().unwrap();
+        assert_eq!(entity_store.begin(), Err(EntityError::TransactionAlreadyStarted));
+        entity.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), epoch);
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        entity_store.commit().unwrap();
+
+        assert!(!entity_store.in_transaction());
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity_store.commit(), Err(EntityError::NoActiveTransaction));
+        assert!(entity_store.begin().unwrap() > epoch);
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 1);
+
+        let epoch = entity_store.begin().unwrap();
+        entity.get("name").unwrap().set_value(DatabaseValue::String("jane".to_string()), epoch);
+        let created = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("max"));
+        entity_store.rollback().unwrap();
+
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("doe".to_string()));
+        assert!(entity_store.get(created.get_identifier()).is_err());
+        assert_eq!(entity_store.all(&"User".to_string()), vec![entity]);
+        assert_eq!(entity_store.rollback(), Err(EntityError::NoActiveTransaction));
+        // rolled back write epochs are never handed out again
+        assert!(entity_store.begin().unwrap() > epoch);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let set_name = |entity_store: &EntityStore, name: &str| entity.get("name").unwrap().set_value(DatabaseValue::String(name.to_string()), entity_store.current_epoch());
+        let name = || entity.get("name").unwrap().get_value();
+        assert_eq!(entity_store.savepoint(), Err(EntityError::NoActiveTransaction));
+
+        entity_store.begin().unwrap();
+        set_name(&entity_store, "doe");
+        let first = entity_store.savepoint().unwrap();
+        set_name(&entity_store, "jane");
+        let created = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("max"));
+        let second = entity_store.savepoint().unwrap();
+        set_name(&entity_store, "max");
+        assert_eq!(name(), DatabaseValue::String("max".to_string()));
+
+        entity_store.rollback_to(second).unwrap();
+        assert_eq!(name(), DatabaseValue::String("jane".to_string()));
+        assert!(entity_store.get(created.get_identifier()).is_ok());
+
+        entity_store.rollback_to(first).unwrap();
+        assert_eq!(name(), DatabaseValue::String("doe".to_string()));
+        assert!(entity_store.get(created.get_identifier()).is_err());
+        assert_eq!(entity_store.rollback_to(second), Err(EntityError::UnknownSavepoint(second)));
+
+        set_name(&entity_store, "joe");
+        entity_store.commit().unwrap();
+        assert_eq!(name(), DatabaseValue::String("joe".to_string()));
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let name = || entity.get("name").unwrap().get_value();
+        let string = |name: &str| DatabaseValue::String(name.to_string());
+        assert_eq!(entity_store.undo(), Ok(false));
+
+        for new_name in ["doe", "jane"] {
+            let epoch = entity_store.begin().unwrap();
+            entity.get("name").unwrap().set_value(string(new_name), epoch);
+            entity_store.commit().unwrap();
+        }
+
+        assert_eq!(entity_store.undo(), Ok(true));
+        assert_eq!(name(), string("doe"));
+        assert_eq!(entity_store.undo(), Ok(true));
+        assert_eq!(name(), string("john"));
+        assert_eq!(entity_store.undo(), Ok(false));
+        assert_eq!(entity_store.redo(), Ok(true));
+        assert_eq!(name(), string("doe"));
+
+        let epoch = entity_store.begin().unwrap();
+        assert_eq!(entity_store.undo(), Err(EntityError::TransactionAlreadyStarted));
+        assert_eq!(name(), string("doe"));
+        entity.get("name").unwrap().set_value(string("max"), epoch);
+        entity_store.commit().unwrap();
+        assert_eq!(entity_store.redo(), Ok(false));
+        assert_eq!(name(), string("max"));
+        assert_eq!(entity_store.undo(), Ok(true));
+        assert_eq!(name(), string("doe"));
+    }
+
+    #[test]
+    fn test_epoch_scopes() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let name = || entity.get("name").unwrap().get_value();
+        for new_name in ["doe", "jane"] {
+            let epoch = entity_store.begin().unwrap();
+            entity.get("name").unwrap().set_value(DatabaseValue::String(new_name.to_string()), epoch);
+            entity_store.commit().unwrap();
+        }
+        let current = entity_store.current_epoch();
+
+        entity_store.enter_epoch(1).unwrap();
+        assert_eq!(name(), DatabaseValue::String("john".to_string()));
+        entity_store.enter_epoch(current - 1).unwrap();
+        assert_eq!(name(), DatabaseValue::String("doe".to_string()));
+        assert_eq!(entity_store.begin(), Err(EntityError::ReadOnlyEpoch(current - 1)));
+        assert_eq!(entity_store.undo(), Err(EntityError::ReadOnlyEpoch(current - 1)));
+        entity_store.leave_epoch();
+        assert_eq!(entity_store.current_epoch(), 1);
+        entity_store.leave_epoch();
+        assert_eq!(entity_store.current_epoch(), current);
+        assert_eq!(name(), DatabaseValue::String("jane".to_string()));
+        assert_eq!(entity_store.check_writable(), Ok(()));
+
+        entity_store.begin().unwrap();
+        assert_eq!(entity_store.enter_epoch(1), Err(EntityError::TransactionAlreadyStarted));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut entity_store = EntityStore::new();
+        let entity = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        for epoch in 1..5 {
+            entity.get("name").unwrap().set_value(DatabaseValue::String(format!("name {}", epoch)), epoch);
+        }
+        entity_store.current_ptr.slide(4);
+
+        assert_eq!(entity_store.compact(4), 2);
+        assert_eq!(entity.get("name").unwrap().history_len(), 3);
+        assert_eq!(entity.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_value(), DatabaseValue::String("name 4".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_at_epoch(2), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity.get("name").unwrap().get_at_epoch(3), DatabaseValue::String("name 3".to_string()));
+    }
+
+    #[test]
+    fn test_filter_at() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let doe = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("doe"));
+        john.get("name").unwrap().set_value(DatabaseValue::String("doe".to_string()), 2);
+        let named_doe = FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("doe".to_string())));
+
+        assert_eq!(entity_store.filter_at(&"User".to_string(), &named_doe, 1).unwrap(), vec![Rc::clone(&doe)]);
+        assert_eq!(entity_store.filter_at(&"User".to_string(), &named_doe, 2).unwrap(), vec![Rc::clone(&john), Rc::clone(&doe)]);
+        let both = FilterExpression::And(vec![
+            named_doe,
+            FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("john".to_string()))),
+        ]);
+        assert!(entity_store.filter_at(&"User".to_string(), &both, 2).unwrap().is_empty());
+        assert_eq!(entity_store.current_epoch(), 1);
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let mut entity_store = EntityStore::new();
+        let descriptors = || vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(20)),
+        ];
+        let loaded = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), descriptors());
+        let untouched = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), descriptors());
+        assert!(entity_store.changes_since(0).is_empty());
+
+        let epoch = entity_store.begin().unwrap();
+        loaded.get("age").unwrap().set_current_value(DatabaseValue::Number(21));
+        untouched.get("age").unwrap().set_current_value(DatabaseValue::Number(20));
+        let created = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), descriptors());
+        entity_store.commit().unwrap();
+
+        let changes = entity_store.changes_since(0);
+        assert_eq!(changes, vec![
+            EntityChange { entity: Rc::clone(&loaded), created: false, changed_attributes: vec!["age".to_string()] },
+            EntityChange { entity: Rc::clone(&created), created: true, changed_attributes: vec!["age".to_string(), "name".to_string()] },
+        ]);
+        assert!(entity_store.changes_since(epoch).is_empty());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        john.get("name").unwrap().set_current_value(DatabaseValue::String("jane".to_string()));
+        let epoch = entity_store.checkpoint("loaded");
+        assert_eq!(entity_store.checkpoint_epoch("loaded"), Ok(epoch));
+        assert_eq!(entity_store.checkpoint_epoch("oops"), Err(EntityError::UnknownCheckpoint("oops".to_string())));
+
+        john.get("name").unwrap().set_current_value(DatabaseValue::String("doe".to_string()));
+        let created = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("bob"));
+        entity_store.checkpoint("validated");
+        let changes = entity_store.changes_since_checkpoint("loaded").unwrap();
+        assert_eq!(changes.len(), 2);
+
+        entity_store.rollback_to_checkpoint("loaded").unwrap();
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("jane".to_string()));
+        assert!(entity_store.get(created.get_identifier()).is_err());
+        assert!(entity_store.changes_since_checkpoint("loaded").unwrap().is_empty());
+        assert!(entity_store.checkpoint_epoch("validated").is_err());
+
+        entity_store.begin().unwrap();
+        assert_eq!(entity_store.rollback_to_checkpoint("loaded"), Err(EntityError::TransactionAlreadyStarted));
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let first = entity_store.fork();
+        let second = entity_store.fork();
+        entity_store.set_in_branch(first, &john, "name", DatabaseValue::String("first".to_string())).unwrap();
+        entity_store.set_in_branch(second, &john, "name", DatabaseValue::String("second".to_string())).unwrap();
+        john.get("name").unwrap().set_current_value(DatabaseValue::String("main".to_string()));
+
+        assert_eq!(entity_store.get_in_branch(first, &john, "name"), Ok(DatabaseValue::String("first".to_string())));
+        assert_eq!(entity_store.get_in_branch(second, &john, "name"), Ok(DatabaseValue::String("second".to_string())));
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("main".to_string()));
+
+        entity_store.discard_branch(second).unwrap();
+        assert_eq!(entity_store.get_in_branch(second, &john, "name"), Err(EntityError::UnknownBranch(second)));
+        entity_store.merge_branch(first).unwrap();
+        assert_eq!(john.get("name").unwrap().get_value(), DatabaseValue::String("first".to_string()));
+        assert_eq!(entity_store.merge_branch(first), Err(EntityError::UnknownBranch(first)));
+        assert_eq!(john.history_len(), 3);
+
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("jane".to_string())).with_validator(Validator::NotNull),
+        ]);
+        let third = entity_store.fork();
+        assert_eq!(entity_store.set_in_branch(third, &jane, "name", DatabaseValue::None), Err(EntityError::Validation("name".to_string(), "this field cannot be null".to_string())));
+        entity_store.enter_epoch(0).unwrap();
+        assert_eq!(entity_store.set_in_branch(third, &jane, "name", DatabaseValue::String("joan".to_string())), Err(EntityError::ReadOnlyEpoch(0)));
+        entity_store.leave_epoch();
+        entity_store.merge_branch(third).unwrap();
+        assert_eq!(jane.get("name").unwrap().get_value(), DatabaseValue::String("jane".to_string()));
+    }
+
+    #[test]
+    fn test_count() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 1), name_descriptor("admins"));
+        assert_eq!(entity_store.count(&"User".to_string()), 2);
+        assert_eq!(entity_store.count(&"Tag".to_string()), 0);
+        assert_eq!(entity_store.alive_count(), 3);
+
+        entity_store.delete(&john).unwrap();
+        assert_eq!(entity_store.count(&"User".to_string()), 1);
+        assert_eq!(entity_store.alive_count(), entity_store.alive_entities().len());
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+
+        let begin_epoch = entity_store.current_epoch();
+        entity_store.begin().unwrap();
+        entity_store.delete(&john).unwrap();
+        assert!(entity_store.get(john.get_identifier()).is_err());
+        assert_eq!(entity_store.delete(&john), Err(EntityError::EntityNotFound(john.get_identifier().clone())));
+        assert_eq!(entity_store.all(&"User".to_string()), vec![Rc::clone(&jane)]);
+        assert!(entity_store.get_at(john.get_identifier(), begin_epoch).is_ok());
+        entity_store.rollback().unwrap();
+        assert!(entity_store.get(john.get_identifier()).is_ok());
+
+        entity_store.begin().unwrap();
+        entity_store.delete(&john).unwrap();
+        entity_store.commit().unwrap();
+        // the undo history can still observe john
+        assert_eq!(entity_store.collect_garbage(), 0);
+        entity_store.undo().unwrap();
+        assert!(entity_store.get(john.get_identifier()).is_ok());
+        entity_store.redo().unwrap();
+        assert!(entity_store.get(john.get_identifier()).is_err());
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+        entity_store.checkpoint("loaded");
+        entity_store.delete(&john).unwrap();
+        assert_eq!(entity_store.collect_garbage(), 0);
+        assert_eq!(entity_store.stats().entities_per_model.get("User"), Some(&2));
+
+        entity_store.clear();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        entity_store.delete(&john).unwrap();
+        assert_eq!(entity_store.collect_garbage(), 1);
+        assert_eq!(entity_store.stats().entities_per_model.get("User"), Some(&0));
+        assert_eq!(entity_store.stats().pk_index_size, 0);
+    }
+
+    #[test]
+    fn test_get_related() {
+        let mut entity_store = EntityStore::new();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 7), name_descriptor("admins"));
+        let fk = |initial| vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::DoNothing), "group".to_string(), initial),
+        ];
+        let by_pk = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), fk(DatabaseValue::Number(7)));
+        let by_identifier = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), fk(DatabaseValue::ForeignKey(group.get_identifier().clone())));
+        let empty = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), fk(DatabaseValue::None));
+        let dangling = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), fk(DatabaseValue::Number(8)));
+
+        assert_eq!(by_pk.get_related(&entity_store, "group"), Ok(Some(Rc::clone(&group))));
+        assert_eq!(by_identifier.get_related(&entity_store, "group"), Ok(Some(Rc::clone(&group))));
+        assert_eq!(empty.get_related(&entity_store, "group"), Ok(None));
+        assert!(matches!(dangling.get_related(&entity_store, "group"), Err(EntityError::EntityNotFound(_))));
+        assert_eq!(by_pk.get_related(&entity_store, "name"), Err(EntityError::NotARelation("name".to_string())));
+    }
+
+    #[test]
+    fn test_related() {
+        let mut entity_store = EntityStore::new();
+        let admins = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 7), name_descriptor("admins"));
+        let staff = entity_store.instantiate_entity(EntityIdentifier::new("Group".to_string()), name_descriptor("staff"));
+        let user = |entity_store: &mut EntityStore, group| entity_store.instantiate_entity(
+            EntityIdentifier::new("User".to_string()),
+            vec![AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::DoNothing), "group".to_string(), group)],
+        );
+        let john = user(&mut entity_store, DatabaseValue::Number(7));
+        let jane = user(&mut entity_store, DatabaseValue::ForeignKey(admins.get_identifier().clone()));
+        let bob = user(&mut entity_store, DatabaseValue::None);
+
+        assert_eq!(admins.related(&entity_store, &"User".to_string(), "group"), vec![Rc::clone(&john), Rc::clone(&jane)]);
+        assert!(staff.related(&entity_store, &"User".to_string(), "group").is_empty());
+
+        bob.get("group").unwrap().set_current_value(DatabaseValue::ForeignKey(staff.get_identifier().clone()));
+        jane.get("group").unwrap().set_current_value(DatabaseValue::ForeignKey(staff.get_identifier().clone()));
+        assert_eq!(admins.related(&entity_store, &"User".to_string(), "group"), vec![Rc::clone(&john)]);
+        assert_eq!(entity_store.related(&staff, &"User".to_string(), "group"), vec![Rc::clone(&bob), Rc::clone(&jane)]);
+
+        entity_store.delete(&john).unwrap();
+        assert!(admins.related(&entity_store, &"User".to_string(), "group").is_empty());
+    }
+
+    #[test]
+    fn test_resolve_related() {
+        let mut entity_store = EntityStore::new();
+        let admins = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 7), name_descriptor("admins"));
+        let users: Vec<Rc<Entity>> = [7, 8, 7, 9, 8].into_iter().map(|pk| entity_store.instantiate_entity(
+            EntityIdentifier::new("User".to_string()),
+            vec![AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::DoNothing), "group".to_string(), DatabaseValue::Number(pk))],
+        )).collect();
+
+        let batch = entity_store.resolve_related(&users, "group").unwrap();
+        assert_eq!(batch.related, vec![admins]);
+        assert_eq!(batch.missing, HashMap::from([("Group".to_string(), vec![8, 9])]));
+        assert!(entity_store.resolve_related(&users, "name").is_err());
+    }
+
+    #[test]
+    fn test_hydrate() {
+        let mut entity_store = EntityStore::new();
+        let book = |pk, title: &str| HydrationRow {
+            pk: Some(pk),
+            attributes: name_descriptor(title),
+            related: vec![("author".to_string(), "Author".to_string(), HydrationRow {
+                pk: Some(3),
+                attributes: name_descriptor("tolkien"),
+                related: vec![],
+            })],
+        };
+        let first = entity_store.hydrate("Book".to_string(), book(1, "the hobbit"));
+        let second = entity_store.hydrate("Book".to_string(), book(2, "silmarillion"));
+
+        let author = first.get_related(&entity_store, "author").unwrap().unwrap();
+        assert_eq!(author.get("name").unwrap().get_value(), DatabaseValue::String("tolkien".to_string()));
+        assert_eq!(second.get_related(&entity_store, "author"), Ok(Some(Rc::clone(&author))));
+        assert_eq!(entity_store.all(&"Author".to_string()).len(), 1);
+        assert_eq!(author.related(&entity_store, &"Book".to_string(), "author"), vec![first, second]);
+    }
+
+    #[test]
+    fn test_on_delete() {
+        let mut entity_store = EntityStore::new();
+        let fk = |model: &str, on_delete, target: &Rc<Entity>| AttributeDescriptor::new(
+            AttributeKind::ForeignKey(model.to_string(), on_delete),
+            model.to_lowercase(),
+            DatabaseValue::ForeignKey(target.get_identifier().clone()),
+        );
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 1), name_descriptor("admins"));
+        let john = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), vec![fk("Group", OnDelete::Cascade, &group)]);
+        let post = entity_store.instantiate_entity(EntityIdentifier::new("Post".to_string()), vec![fk("User", OnDelete::Cascade, &john)]);
+        let invite = entity_store.instantiate_entity(EntityIdentifier::new("Invite".to_string()), vec![fk("Group", OnDelete::SetNull, &group)]);
+        let log = entity_store.instantiate_entity(EntityIdentifier::new("Log".to_string()), vec![fk("Group", OnDelete::DoNothing, &group)]);
+        let badge = entity_store.instantiate_entity(EntityIdentifier::new("Badge".to_string()), vec![fk("User", OnDelete::Protect, &john)]);
+
+        assert_eq!(entity_store.delete(&group), Err(EntityError::Protected(john.get_identifier().clone(), badge.get_identifier().clone())));
+        assert!(entity_store.get(group.get_identifier()).is_ok());
+        assert!(invite.get_related(&entity_store, "group").unwrap().is_some());
+
+        assert_eq!(entity_store.delete(&badge).unwrap(), vec![Rc::clone(&badge)]);
+        assert_eq!(entity_store.delete(&group).unwrap(), vec![Rc::clone(&group), Rc::clone(&john), Rc::clone(&post)]);
+        assert!(entity_store.get(post.get_identifier()).is_err());
+        assert_eq!(invite.get("group").unwrap().get_value(), DatabaseValue::None);
+        assert!(log.get("group").unwrap().get_value() != DatabaseValue::None);
+    }
+
+    #[test]
+    fn test_many_to_many_through() {
+        let mut entity_store = EntityStore::new();
+        entity_store.register_many_to_many("Group".to_string(), "members".to_string(), ManyToMany {
+            through: "Membership".to_string(),
+            source_attribute: "group".to_string(),
+            target_model: "User".to_string(),
+            target_attribute: "user".to_string(),
+        });
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 1), name_descriptor("admins"));
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+        let role = |role: &str| vec![AttributeDescriptor::new(AttributeKind::Physical, "role".to_string(), DatabaseValue::String(role.to_string()))];
+        entity_store.add_membership(&group, "members", &john, role("owner")).unwrap();
+        entity_store.add_membership(&group, "members", &jane, role("member")).unwrap();
+
+        assert_eq!(entity_store.many_to_many(&group, "members", &FilterExpression::And(vec![])), Ok(vec![Rc::clone(&john), Rc::clone(&jane)]));
+        let owners = FilterExpression::Exact(ExactExpression::new("role".to_string(), DatabaseValue::String("owner".to_string())));
+        assert_eq!(entity_store.many_to_many(&group, "members", &owners), Ok(vec![Rc::clone(&john)]));
+        assert_eq!(entity_store.memberships(&group, "members").unwrap().len(), 2);
+        assert!(entity_store.many_to_many(&john, "members", &owners).is_err());
+
+        assert_eq!(entity_store.remove_membership(&group, "members", &john), Ok(1));
+        assert_eq!(entity_store.many_to_many(&group, "members", &FilterExpression::And(vec![])), Ok(vec![Rc::clone(&jane)]));
+        entity_store.delete(&jane).unwrap();
+        assert!(entity_store.memberships(&group, "members").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_many_to_many() {
+        let mut entity_store = EntityStore::new();
+        entity_store.register_many_to_many("Post".to_string(), "tags".to_string(), ManyToMany {
+            through: "PostTag".to_string(),
+            source_attribute: "post".to_string(),
+            target_model: "Tag".to_string(),
+            target_attribute: "tag".to_string(),
+        });
+        let post = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Post".to_string(), 1), name_descriptor("hello"));
+        let tags: Vec<Rc<Entity>> = ["rust", "python", "django"].iter().enumerate().map(
+            |(pk, name)| entity_store.instantiate_entity(EntityIdentifier::new_persisted("Tag".to_string(), pk as i64), name_descriptor(name))
+        ).collect();
+        let all = FilterExpression::And(vec![]);
+        entity_store.add_membership(&post, "tags", &tags[0], vec![]).unwrap();
+        let kept = entity_store.add_membership(&post, "tags", &tags[1], vec![]).unwrap();
+
+        entity_store.set_many_to_many(&post, "tags", &[Rc::clone(&tags[1]), Rc::clone(&tags[2])], vec![]).unwrap();
+        assert_eq!(entity_store.many_to_many(&post, "tags", &all), Ok(vec![Rc::clone(&tags[1]), Rc::clone(&tags[2])]));
+        assert_eq!(entity_store.memberships(&post, "tags").unwrap()[0], kept);
+
+        entity_store.set_many_to_many(&post, "tags", &[], vec![]).unwrap();
+        assert_eq!(entity_store.many_to_many(&post, "tags", &all), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_related_ordering() {
+        let mut entity_store = EntityStore::new();
+        let post = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Post".to_string(), 1), name_descriptor("hello"));
+        let comment = |entity_store: &mut EntityStore, rank: DatabaseValue| entity_store.instantiate_entity(EntityIdentifier::new("Comment".to_string()), vec![
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Post".to_string(), OnDelete::Cascade), "post".to_string(), DatabaseValue::Number(1)),
+            AttributeDescriptor::new(AttributeKind::Physical, "rank".to_string(), rank),
+        ]);
+        let second = comment(&mut entity_store, DatabaseValue::Number(2));
+        let unranked = comment(&mut entity_store, DatabaseValue::None);
+        let first = comment(&mut entity_store, DatabaseValue::Number(1));
+        let model = "Comment".to_string();
+        assert_eq!(entity_store.related(&post, &model, "post"), vec![Rc::clone(&second), Rc::clone(&unranked), Rc::clone(&first)]);
+
+        entity_store.set_related_ordering(model.clone(), "post".to_string(), "rank".to_string());
+        assert_eq!(entity_store.related(&post, &model, "post"), vec![Rc::clone(&first), Rc::clone(&second), Rc::clone(&unranked)]);
+        entity_store.set_related_ordering(model.clone(), "post".to_string(), "-rank".to_string());
+        assert_eq!(entity_store.related(&post, &model, "post"), vec![Rc::clone(&second), Rc::clone(&first), Rc::clone(&unranked)]);
+
+        entity_store.register_many_to_many("Post".to_string(), "tags".to_string(), ManyToMany {
+            through: "PostTag".to_string(),
+            source_attribute: "post".to_string(),
+            target_model: "Tag".to_string(),
+            target_attribute: "tag".to_string(),
+        });
+        entity_store.set_related_ordering("PostTag".to_string(), "post".to_string(), "position".to_string());
+        let rust = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Tag".to_string(), 1), name_descriptor("rust"));
+        let python = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Tag".to_string(), 2), name_descriptor("python"));
+        entity_store.add_membership(&post, "tags", &rust, vec![]).unwrap();
+        let position = |position: i64| vec![AttributeDescriptor::new(AttributeKind::Physical, "position".to_string(), DatabaseValue::Number(position))];
+        entity_store.add_membership(&post, "tags", &python, position(-1)).unwrap();
+        let memberships = entity_store.memberships(&post, "tags").unwrap();
+        assert_eq!(memberships[1].get("position").unwrap().get_value(), DatabaseValue::Number(0));
+        assert_eq!(entity_store.many_to_many(&post, "tags", &FilterExpression::And(vec![])), Ok(vec![Rc::clone(&python), Rc::clone(&rust)]));
+    }
+
+    #[test]
+    fn test_changeset() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+        let created = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("doe"));
+        let discarded = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("foo"));
+        john.get("name").unwrap().set_current_value(DatabaseValue::String("johnny".to_string()));
+        entity_store.delete(&jane).unwrap();
+        entity_store.delete(&discarded).unwrap();
+
+        assert_eq!(entity_store.changeset(), Ok(Changeset {
+            created: vec![Rc::clone(&created)],
+            updated: vec![(Rc::clone(&john), vec!["name".to_string()])],
+            deleted: vec![Rc::clone(&jane)],
+        }));
+        assert!(entity_store.collect_garbage() > 0);
+        assert_eq!(entity_store.changeset().unwrap().deleted, vec![Rc::clone(&jane)]);
+
+        entity_store.apply_pk(created.get_identifier(), 3).unwrap();
+        entity_store.mark_flushed().unwrap();
+        assert_eq!(entity_store.changeset(), Ok(Changeset::default()));
+        created.get("name").unwrap().set_current_value(DatabaseValue::String("jon".to_string()));
+        assert_eq!(entity_store.changeset().unwrap().updated, vec![(Rc::clone(&created), vec!["name".to_string()])]);
+    }
+
+    #[test]
+    fn test_changeset_dependency_order() {
+        let mut entity_store = EntityStore::new();
+        let foreign_key = |model: &str, name: &str, target: DatabaseValue| AttributeDescriptor::new(AttributeKind::ForeignKey(model.to_string(), OnDelete::Cascade), name.to_string(), target);
+        let order = entity_store.instantiate_entity(EntityIdentifier::new("Order".to_string()), vec![foreign_key("Customer", "customer", DatabaseValue::None)]);
+        let customer = entity_store.instantiate_entity(EntityIdentifier::new("Customer".to_string()), vec![foreign_key("Company", "company", DatabaseValue::None)]);
+        let company = entity_store.instantiate_entity(EntityIdentifier::new("Company".to_string()), name_descriptor("acme"));
+        entity_store.set_related(&order, "customer", Some(&customer)).unwrap();
+        entity_store.set_related(&customer, "company", Some(&company)).unwrap();
+        let created: Vec<Rc<Entity>> = entity_store.changeset().unwrap().created;
+        let position = |entity: &Rc<Entity>| created.iter().position(|created| created == entity).unwrap();
+        assert!(position(&company) < position(&customer));
+        assert!(position(&customer) < position(&order));
+
+        for (pk, entity) in [&company, &customer, &order].into_iter().enumerate() {
+            entity_store.apply_pk(entity.get_identifier(), pk as i64).unwrap();
+        }
+        entity_store.mark_flushed().unwrap();
+        entity_store.delete(&company).unwrap();
+        assert_eq!(entity_store.changeset().unwrap().deleted, vec![Rc::clone(&order), Rc::clone(&customer), Rc::clone(&company)]);
+
+        let first = entity_store.instantiate_entity(EntityIdentifier::new("Node".to_string()), vec![foreign_key("Node", "next", DatabaseValue::None)]);
+        let second = entity_store.instantiate_entity(EntityIdentifier::new("Node".to_string()), vec![foreign_key("Node", "next", DatabaseValue::ForeignKey(first.get_identifier().clone()))]);
+        entity_store.set_related(&first, "next", Some(&second)).unwrap();
+        match entity_store.changeset() {
+            Err(EntityError::DependencyCycle(cycle)) => assert_eq!(cycle.len(), 2),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_changeset_grouped_by_model() {
+        let mut entity_store = EntityStore::new();
+        let mut companies = vec![];
+        let mut customers = vec![];
+        for name in ["acme", "initech"] {
+            let company = entity_store.instantiate_entity(EntityIdentifier::new("Company".to_string()), name_descriptor(name));
+            customers.push(entity_store.instantiate_entity(EntityIdentifier::new("Customer".to_string()), vec![
+                AttributeDescriptor::new(AttributeKind::ForeignKey("Company".to_string(), OnDelete::Cascade), "company".to_string(), DatabaseValue::ForeignKey(company.get_identifier().clone())),
+            ]));
+            companies.push(company);
+        }
+        companies.extend(customers);
+        assert_eq!(entity_store.changeset().unwrap().created, companies);
+    }
+
+    #[test]
+    fn test_generic_foreign_key() {
+        let mut entity_store = EntityStore::new();
+        let post = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Post".to_string(), 1), name_descriptor("hello"));
+        let photo = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Photo".to_string(), 1), name_descriptor("hello"));
+        let comment = |entity_store: &mut EntityStore, target: DatabaseValue| entity_store.instantiate_entity(
+            EntityIdentifier::new("Comment".to_string()),
+            vec![AttributeDescriptor::new(AttributeKind::GenericForeignKey, "content_object".to_string(), target)],
+        );
+        let on_post = comment(&mut entity_store, DatabaseValue::ForeignKey(EntityIdentifier::new_persisted("Post".to_string(), 1)));
+        let on_photo = comment(&mut entity_store, DatabaseValue::ForeignKey(photo.get_identifier().clone()));
+
+        assert_eq!(on_post.get_related(&entity_store, "content_object"), Ok(Some(Rc::clone(&post))));
+        assert_eq!(on_photo.get_related(&entity_store, "content_object"), Ok(Some(Rc::clone(&photo))));
+        assert_eq!(post.related(&entity_store, &"Comment".to_string(), "content_object"), vec![Rc::clone(&on_post)]);
+        let hello = FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("hello".to_string())));
+        assert_eq!(entity_store.filter_related("Comment".to_string(), "content_object", &"Photo".to_string(), &hello), Ok(vec![Rc::clone(&on_photo)]));
+
+        let bare_pk = comment(&mut entity_store, DatabaseValue::Number(1));
+        assert!(bare_pk.get_related(&entity_store, "content_object").is_err());
+        entity_store.delete(&post).unwrap();
+        assert!(entity_store.get(on_post.get_identifier()).is_ok());
+    }
+
+    #[test]
+    fn test_check_integrity() {
+        let mut entity_store = EntityStore::new();
+        entity_store.register_many_to_many("Group".to_string(), "members".to_string(), ManyToMany {
+            through: "Membership".to_string(),
+            source_attribute: "group".to_string(),
+            target_model: "User".to_string(),
+            target_attribute: "user".to_string(),
+        });
+        let relation = |kind: AttributeKind, name: &str, target: &Rc<Entity>| AttributeDescriptor::new(kind, name.to_string(), DatabaseValue::ForeignKey(target.get_identifier().clone()));
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 1), name_descriptor("admins"));
+        let john = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), name_descriptor("john"));
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), name_descriptor("jane"));
+        let membership = entity_store.instantiate_entity(EntityIdentifier::new("Membership".to_string()), vec![
+            relation(AttributeKind::ForeignKey("Group".to_string(), OnDelete::DoNothing), "group", &group),
+            relation(AttributeKind::ForeignKey("User".to_string(), OnDelete::DoNothing), "user", &jane),
+        ]);
+        let profile = |entity_store: &mut EntityStore, user: &Rc<Entity>| entity_store.instantiate_entity(
+            EntityIdentifier::new("Profile".to_string()),
+            vec![relation(AttributeKind::OneToOne("User".to_string(), OnDelete::Cascade), "user", user)],
+        );
+        let first = profile(&mut entity_store, &john);
+        let second = profile(&mut entity_store, &john);
+        profile(&mut entity_store, &jane);
+        let order = |entity_store: &mut EntityStore, customer: DatabaseValue| entity_store.instantiate_entity(
+            EntityIdentifier::new("Order".to_string()),
+            vec![AttributeDescriptor::new(AttributeKind::ForeignKey("User".to_string(), OnDelete::DoNothing), "customer".to_string(), customer)],
+        );
+        // a pk missing from the store may reference a database row
+        order(&mut entity_store, DatabaseValue::Number(42));
+        let unknown = order(&mut entity_store, DatabaseValue::ForeignKey(EntityIdentifier::new("User".to_string())));
+
+        let report = entity_store.check_integrity().unwrap();
+        assert_eq!(report.dangling, vec![(Rc::clone(&unknown), "customer".to_string())]);
+        assert!(report.deleted_memberships.is_empty());
+        assert_eq!(report.one_to_one_duplicates, vec![("user".to_string(), Rc::clone(&john), vec![Rc::clone(&first), Rc::clone(&second)])]);
+
+        entity_store.delete(&jane).unwrap();
+        entity_store.delete(&second).unwrap();
+        entity_store.delete(&unknown).unwrap();
+        let report = entity_store.check_integrity().unwrap();
+        assert_eq!(report.deleted_memberships, vec![(Rc::clone(&membership), "user".to_string())]);
+        assert!(report.dangling.is_empty());
+        assert!(report.one_to_one_duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_instantiate_many() {
+        let mut entity_store = EntityStore::new();
+        let rows: Vec<Vec<AttributeDescriptor>> = (0..10).map(
+            |i| vec![AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(i))]
+        ).collect();
+
+        let entities = entity_store.instantiate_many("User".to_string(), rows);
+        assert_eq!(entities.len(), 10);
+        assert_eq!(entities[3].get("age").unwrap().get_value(), DatabaseValue::Number(3));
+        for entity in entities.iter() {
+            assert_eq!(&entity_store.get(entity.get_identifier()).unwrap(), entity);
+        }
+        let list = entity_store.filter("User".to_string(), &FilterExpression::Exact(ExactExpression::new("age".to_string(), DatabaseValue::Number(7)))).unwrap();
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn test_instantiate_model() {
+        let mut entity_store = EntityStore::new();
+        let model = "Book".to_string();
+        assert_eq!(entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::new()).err(), Some(EntityError::UnknownModel(model.clone())));
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String("untitled".to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(0)),
+            ],
+            unique: vec![vec!["title".to_string()]],
+        });
+        assert_eq!(entity_store.get_model_schema(&model).unwrap().unique, vec![vec!["title".to_string()]]);
+
+        let book = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::from([
+            ("pages".to_string(), DatabaseValue::Number(42)),
+        ])).unwrap();
+        assert_eq!(book.get("title").unwrap().get_value(), DatabaseValue::String("untitled".to_string()));
+        assert_eq!(book.get("pages").unwrap().get_value(), DatabaseValue::Number(42));
+
+        let unknown = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::from([
+            ("author".to_string(), DatabaseValue::None),
+        ]));
+        assert_eq!(unknown.err(), Some(EntityError::AttributeNotFound("author".to_string())));
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        let mut entity_store = EntityStore::new();
+        let model = "Book".to_string();
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::None),
+                AttributeDescriptor::new(AttributeKind::ForeignKey("Author".to_string(), OnDelete::DoNothing), "author".to_string(), DatabaseValue::None),
+            ],
+            unique: vec![vec!["title".to_string()]],
+        });
+        let title = |title: &str| HashMap::from([("title".to_string(), DatabaseValue::String(title.to_string()))]);
+        let dune = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), title("dune")).unwrap();
+        let other = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), title("dune")).unwrap();
+        entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::new()).unwrap();
+        entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::new()).unwrap();
+
+        // deferred by default, the intermediate states may violate the constraints
+        let unique = Constraint::Unique(model.clone(), vec!["title".to_string()]);
+        assert_eq!(entity_store.get_constraint_check(&unique), ConstraintCheck::Deferred);
+        assert_eq!(entity_store.check_written(&other, "title"), Ok(()));
+        let changeset = entity_store.changeset().unwrap();
+        assert_eq!(entity_store.check_changeset(&changeset), Err(EntityError::ConstraintViolation(dune.get_identifier().clone(), unique.clone(), Some(Box::new(other.get_identifier().clone())))));
+
+        entity_store.set_constraint_check(unique.clone(), ConstraintCheck::Immediate);
+        assert_eq!(entity_store.check_written(&other, "title"), Err(EntityError::ConstraintViolation(other.get_identifier().clone(), unique, Some(Box::new(dune.get_identifier().clone())))));
+        other.get("title").unwrap().set_current_value(DatabaseValue::String("dune messiah".to_string()));
+        assert_eq!(entity_store.check_changeset(&entity_store.changeset().unwrap()), Ok(()));
+
+        let author = entity_store.instantiate_entity(EntityIdentifier::new("Author".to_string()), name_descriptor("frank"));
+        entity_store.set_related(&dune, "author", Some(&author)).unwrap();
+        entity_store.delete(&author).unwrap();
+        let foreign_key = Constraint::ForeignKey(model.clone(), "author".to_string());
+        assert_eq!(entity_store.check_constraints(&[Rc::clone(&dune)]), Err(EntityError::ConstraintViolation(dune.get_identifier().clone(), foreign_key, None)));
+        assert_eq!(entity_store.check_constraints(&[other]), Ok(()));
+    }
+
+    #[test]
+    fn test_check_constraint() {
+        let mut entity_store = EntityStore::new();
+        let model = "Book".to_string();
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(1))],
+            unique: vec![],
+        });
+        let positive = FilterExpression::Lookup(LookupExpression::new("pages".to_string(), Lookup::Gt(DatabaseValue::Number(0))));
+        entity_store.add_check_constraint(model.clone(), "positive_pages".to_string(), positive);
+        let book = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::new()).unwrap();
+        assert_eq!(entity_store.check_changeset(&entity_store.changeset().unwrap()), Ok(()));
+
+        book.get("pages").unwrap().set_current_value(DatabaseValue::Number(0));
+        let check = Constraint::Check(model.clone(), "positive_pages".to_string());
+        assert_eq!(entity_store.check_changeset(&entity_store.changeset().unwrap()), Err(EntityError::ConstraintViolation(book.get_identifier().clone(), check.clone(), None)));
+        assert_eq!(entity_store.check_written(&book, "pages"), Ok(()));
+        entity_store.set_constraint_check(check, ConstraintCheck::Immediate);
+        assert!(entity_store.check_written(&book, "pages").is_err());
+        let snapshot = entity_store.snapshot();
+        assert!(snapshot.check_constraints(&snapshot.all(&model)).is_err());
+    }
+
+    #[test]
+    fn test_clean() {
+        let mut entity_store = EntityStore::new();
+        let model = "Book".to_string();
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::None)
+                    .with_validator(Validator::NotNull)
+                    .with_validator(Validator::MaxLength(4)),
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(1)),
+            ],
+            unique: vec![vec!["title".to_string()]],
+        });
+        let positive = FilterExpression::Lookup(LookupExpression::new("pages".to_string(), Lookup::Gt(DatabaseValue::Number(0))));
+        entity_store.add_check_constraint(model.clone(), "positive_pages".to_string(), positive);
+        let dune = entity_store.instantiate_model(EntityIdentifier::new_persisted(model.clone(), 1), HashMap::from([("title".to_string(), DatabaseValue::String("dune".to_string()))])).unwrap();
+        let other = entity_store.instantiate_model(EntityIdentifier::new_persisted(model.clone(), 2), HashMap::new()).unwrap();
+        assert_eq!(entity_store.clean(&dune), Ok(MessageDict::new()));
+        assert_eq!(entity_store.clean(&other), Ok(MessageDict::from([("title".to_string(), vec!["this field cannot be null".to_string()])])));
+
+        other.get("title").unwrap().set_current_value(DatabaseValue::String("dune".to_string()));
+        other.get("pages").unwrap().set_current_value(DatabaseValue::Number(0));
+        dune.get("title").unwrap().set_current_value(DatabaseValue::String("dune!".to_string()));
+        assert_eq!(entity_store.clean(&other), Ok(MessageDict::from([
+            (NON_FIELD_ERRORS.to_string(), vec!["the check constraint positive_pages is violated".to_string()]),
+        ])));
+        other.get("title").unwrap().set_current_value(DatabaseValue::String("dune!".to_string()));
+        let invalid = entity_store.validate(Some(&model)).unwrap();
+        assert_eq!(invalid.iter().map(|(entity, _)| entity.get_identifier().clone()).collect::<Vec<_>>(), vec![dune.get_identifier().clone(), other.get_identifier().clone()]);
+        assert_eq!(invalid[0].1, MessageDict::from([("title".to_string(), vec![
+            "ensure this value has at most 4 characters (it has 5)".to_string(),
+            "Book with this title already exists".to_string(),
+        ])]));
+        assert_eq!(entity_store.validate(Some(&"Author".to_string())), Ok(vec![]));
+        assert_eq!(entity_store.validate(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_entity_validator() {
+        let mut entity_store = EntityStore::new();
+        let model = "Event".to_string();
+        entity_store.register_model(model.clone(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "start".to_string(), DatabaseValue::Number(0)),
+                AttributeDescriptor::new(AttributeKind::Physical, "end".to_string(), DatabaseValue::Number(0)),
+            ],
+            unique: vec![],
+        });
+        entity_store.add_entity_validator(model.clone(), Rc::new(|entity: &Entity| {
+            match entity.get("start").unwrap().get_value() <= entity.get("end").unwrap().get_value() {
+                true => Ok(()),
+                false => Err(MessageDict::from([("end".to_string(), vec!["ends before its start".to_string()])])),
+            }
+        }));
+        let event = entity_store.instantiate_model(EntityIdentifier::new(model.clone()), HashMap::from([("end".to_string(), DatabaseValue::Number(2))])).unwrap();
+        assert_eq!(entity_store.check_changeset(&entity_store.changeset().unwrap()), Ok(()));
+
+        event.get("start").unwrap().set_current_value(DatabaseValue::Number(3));
+        let messages = MessageDict::from([("end".to_string(), vec!["ends before its start".to_string()])]);
+        assert_eq!(entity_store.check_changeset(&entity_store.changeset().unwrap()), Err(EntityError::Invalid(event.get_identifier().clone(), messages.clone())));
+        assert_eq!(entity_store.clean(&event), Ok(messages));
+        assert_eq!(entity_store.snapshot().validate(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+        ]);
+        let epoch = entity_store.current_epoch();
+        user.get("name").unwrap().set_current_value(DatabaseValue::String("jane".to_string()));
+        assert_eq!(entity_store.record_write(&user, "name", DatabaseValue::String("john".to_string()), epoch), Ok(()));
+        assert!(entity_store.audit_log().is_empty());
+
+        entity_store.enable_audit_log();
+        entity_store.set_audit_context(Some("admin".to_string()), Some("2024-01-01T00:00:00".to_string()));
+        user.get("name").unwrap().set_current_value(DatabaseValue::String("doe".to_string()));
+        entity_store.record_write(&user, "name", DatabaseValue::String("jane".to_string()), epoch).unwrap();
+        assert_eq!(entity_store.audit_log(), vec![AuditEntry {
+            identifier: user.get_identifier().clone(),
+            attribute: "name".to_string(),
+            previous: DatabaseValue::String("jane".to_string()),
+            value: DatabaseValue::String("doe".to_string()),
+            epoch,
+            who: Some("admin".to_string()),
+            when: Some("2024-01-01T00:00:00".to_string()),
+        }]);
+        assert_eq!(entity_store.record_write(&user, "email", DatabaseValue::None, epoch), Err(EntityError::AttributeNotFound("email".to_string())));
+        assert_eq!(entity_store.snapshot().audit_log().len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log_store_writes() {
+        let mut entity_store = EntityStore::new();
+        entity_store.enable_audit_log();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 1), name_descriptor("admins"));
+        let invite = entity_store.instantiate_entity(EntityIdentifier::new("Invite".to_string()), vec![AttributeDescriptor::new(
+            AttributeKind::ForeignKey("Group".to_string(), OnDelete::SetNull),
+            "group".to_string(),
+            DatabaseValue::ForeignKey(group.get_identifier().clone()),
+        )]);
+        let epoch = entity_store.current_epoch();
+        entity_store.delete(&group).unwrap();
+        let natural_key = vec!["email".to_string()];
+        let values = |name: &str| vec![
+            ("email".to_string(), DatabaseValue::String("john@example.com".to_string())),
+            ("name".to_string(), DatabaseValue::String(name.to_string())),
+        ];
+        let (john, _) = entity_store.upsert("User".to_string(), &natural_key, values("john")).unwrap();
+        entity_store.upsert("User".to_string(), &natural_key, values("johnny")).unwrap();
+
+        let writes: Vec<_> = entity_store.audit_log().into_iter()
+            .map(|entry| (entry.identifier, entry.attribute, entry.previous, entry.value, entry.epoch))
+            .collect();
+        assert!(writes.contains(&(
+            invite.get_identifier().clone(),
+            "group".to_string(),
+            DatabaseValue::ForeignKey(group.get_identifier().clone()),
+            DatabaseValue::None,
+            epoch,
+        )));
+        assert!(writes.contains(&(
+            john.get_identifier().clone(),
+            "name".to_string(),
+            DatabaseValue::String("john".to_string()),
+            DatabaseValue::String("johnny".to_string()),
+            entity_store.current_epoch(),
+        )));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut entity_store = EntityStore::new();
+        let model = "User".to_string();
+        for pk in 0..12 {
+            entity_store.instantiate_entity(EntityIdentifier::new_persisted(model.clone(), pk), vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(pk)),
+            ]);
+        }
+        let expression = FilterExpression::Exact(ExactExpression::new("age".to_string(), DatabaseValue::Number(3)));
+        assert_eq!(entity_store.filter(model.clone(), &expression).unwrap().len(), 1);
+        entity_store.filter("Book".to_string(), &expression).unwrap();
+        entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 1)).unwrap();
+        assert!(entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 20)).is_err());
+        entity_store.record_related(2, 1);
+        assert_eq!(entity_store.metrics(), StoreMetrics {
+            filters: 2,
+            scanned: 12,
+            scanned_per_filter: [1, 1, 0, 0, 0],
+            index_hits: 1,
+            index_misses: 1,
+            cache_hits: 2,
+            cache_misses: 1,
+            history_entries: 12,
+        });
+
+        entity_store.reset_metrics();
+        assert_eq!(entity_store.metrics(), StoreMetrics { history_entries: 12, ..StoreMetrics::default() });
+    }
+
+    #[test]
+    fn test_add_index() {
+        let mut entity_store = EntityStore::new();
+        let model = "User".to_string();
+        let users: Vec<Rc<Entity>> = (0..10).map(|pk| entity_store.instantiate_entity(EntityIdentifier::new_persisted(model.clone(), pk), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(pk % 3)),
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String(format!("user {}", pk))),
+        ])).collect();
+        let age = |age| FilterExpression::Exact(ExactExpression::new("age".to_string(), DatabaseValue::Number(age)));
+        let pks = |entities: Vec<Rc<Entity>>| entities.iter().map(|entity| entity.get_identifier().get_applied_pk().unwrap()).collect::<Vec<PK>>();
+        entity_store.add_index(model.clone(), "age".to_string(), IndexKind::Hash);
+
+        entity_store.reset_metrics();
+        assert_eq!(pks(entity_store.filter(model.clone(), &age(1)).unwrap()), vec![1, 4, 7]);
+        assert_eq!(entity_store.metrics().scanned, 3);
+        let ages = FilterExpression::Lookup(LookupExpression::new("age".to_string(), Lookup::In(vec![DatabaseValue::Number(0), DatabaseValue::Number(2)])));
+        let name = FilterExpression::Exact(ExactExpression::new("name".to_string(), DatabaseValue::String("user 5".to_string())));
+        assert_eq!(pks(entity_store.filter(model.clone(), &FilterExpression::And(vec![name, ages])).unwrap()), vec![5]);
+        assert_eq!(entity_store.metrics().scanned, 10);
+
+        let epoch = entity_store.begin().unwrap();
+        users[4].get("age").unwrap().set_current_value(DatabaseValue::Number(2));
+        assert_eq!(pks(entity_store.filter_at(&model, &age(1), epoch - 1).unwrap()), vec![1, 4, 7]);
+        let added = entity_store.instantiate_entity(EntityIdentifier::new_persisted(model.clone(), 10), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(1)),
+        ]);
+        assert_eq!(pks(entity_store.filter(model.clone(), &age(1)).unwrap()), vec![1, 7, 10]);
+        entity_store.delete(&added).unwrap();
+        entity_store.rollback().unwrap();
+        assert_eq!(pks(entity_store.filter(model.clone(), &age(1)).unwrap()), vec![1, 4, 7]);
+        assert_eq!(pks(entity_store.snapshot().filter(model.clone(), &age(2)).unwrap()), vec![2, 5, 8]);
+    }
+
+    #[test]
+    fn test_sorted_index() {
+        let mut entity_store = EntityStore::new();
+        let model = "User".to_string();
+        let ages = [30, 10, 20, 10, 0, 40];
+        for (pk, age) in ages.iter().enumerate() {
+            let age = if *age == 0 { DatabaseValue::None } else { DatabaseValue::Number(*age) };
+            entity_store.instantiate_entity(EntityIdentifier::new_persisted(model.clone(), pk as PK), vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), age),
+            ]);
+        }
+        let pks = |entities: Vec<Rc<Entity>>| entities.iter().map(|entity| entity.get_identifier().get_applied_pk().unwrap()).collect::<Vec<PK>>();
+        let all = FilterExpression::And(vec![]).compile();
+        let unordered = (pks(entity_store.filter_ordered(model.clone(), &all, "age").unwrap()), pks(entity_store.filter_ordered(model.clone(), &all, "-age").unwrap()));
+        assert_eq!(unordered, (vec![1, 3, 2, 0, 5, 4], vec![5, 0, 2, 1, 3, 4]));
+
+        entity_store.add_index(model.clone(), "age".to_string(), IndexKind::Sorted);
+        entity_store.reset_metrics();
+        let older = FilterExpression::Lookup(LookupExpression::new("age".to_string(), Lookup::Gte(DatabaseValue::Number(20))));
+        assert_eq!(pks(entity_store.filter(model.clone(), &older).unwrap()), vec![0, 2, 5]);
+        assert_eq!(entity_store.metrics().scanned, 3);
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &all, "age").unwrap()), unordered.0);
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &all, "-age").unwrap()), unordered.1);
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &older.compile(), "-age").unwrap()), vec![5, 0, 2]);
+        // read from the index, the entity without age included
+        assert_eq!(entity_store.metrics().scanned, 3 + 6 + 6 + 6);
+
+        entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 5)).unwrap().get("age").unwrap().set_current_value(DatabaseValue::Number(5));
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &all, "age").unwrap()), vec![5, 1, 3, 2, 0, 4]);
+        // the index misses the foreign keys, the entities are sorted instead
+        let company = DatabaseValue::ForeignKey(EntityIdentifier::new_persisted("Company".to_string(), 1));
+        entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 1)).unwrap().get("age").unwrap().set_current_value(company);
+        entity_store.reset_metrics();
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &older.compile(), "-age").unwrap()), vec![0, 2]);
+        assert_eq!(entity_store.metrics().scanned, 3);
+
+        // read from the index again once the entity holds a value with a key, or is removed
+        let john = entity_store.get(&EntityIdentifier::new_persisted(model.clone(), 1)).unwrap();
+        john.get("age").unwrap().set_current_value(DatabaseValue::Number(15));
+        entity_store.reset_metrics();
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &older.compile(), "-age").unwrap()), vec![0, 2]);
+        assert_eq!(entity_store.metrics().scanned, 6);
+        john.get("age").unwrap().set_current_value(DatabaseValue::ForeignKey(EntityIdentifier::new_persisted("Company".to_string(), 1)));
+        entity_store.delete(&john).unwrap();
+        entity_store.reset_metrics();
+        assert_eq!(pks(entity_store.filter_ordered(model.clone(), &older.compile(), "-age").unwrap()), vec![0, 2]);
+        assert_eq!(entity_store.metrics().scanned, 5);
+        assert_eq!(entity_store.collect_garbage(), 1);
+        assert!(entity_store.value_index.missed.borrow()[model.as_str()]["age"].is_empty());
+        let name = DatabaseValue::String("john".to_string());
+        let younger = FilterExpression::Lookup(LookupExpression::new("age".to_string(), Lookup::Lt(name)));
+        assert!(entity_store.filter(model.clone(), &younger).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_query_log() {
+        let mut entity_store = EntityStore::new();
+        let model = "User".to_string();
+        for pk in 0..3 {
+            entity_store.instantiate_entity(EntityIdentifier::new_persisted(model.clone(), pk), vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(pk)),
+            ]);
+        }
+        let expression = FilterExpression::Lookup(LookupExpression::new("age".to_string(), Lookup::Gt(DatabaseValue::Number(0))));
+        entity_store.filter(model.clone(), &expression).unwrap();
+        assert!(entity_store.query_log().is_empty());
+
+        entity_store.enable_query_log(2);
+        entity_store.filter(model.clone(), &expression).unwrap();
+        entity_store.filter_at(&model, &expression, entity_store.current_epoch()).unwrap();
+        assert_eq!(entity_store.count(&model), 3);
+        let query_log = entity_store.query_log();
+        let queries: Vec<_> = query_log.iter().map(|entry| (entry.operation, entry.expression.as_str(), entry.results)).collect();
+        assert_eq!(queries, vec![("filter_at", "age__gt=0", 2), ("count", "", 3)]);
+        assert_eq!(query_log[1].model, model);
+
+        entity_store.enable_query_log(1);
+        assert_eq!(entity_store.query_log()[0].operation, "count");
+        entity_store.clear_query_log();
+        assert!(entity_store.query_log().is_empty());
+        entity_store.enable_query_log(0);
+        entity_store.count(&model);
+        assert!(entity_store.query_log().is_empty());
+    }
+
+    #[test]
+    fn test_increment_version() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::Physical, "version".to_string(), DatabaseValue::Number(3)),
+        ]);
+        assert_eq!(entity_store.increment_version(&user), Ok(None));
+
+        entity_store.set_version_attribute("User".to_string(), "version".to_string());
+        assert_eq!(entity_store.increment_version(&user), Ok(Some(3)));
+        assert_eq!(user.get("version").unwrap().get_value(), DatabaseValue::Number(4));
+        assert_eq!(user.get("version").unwrap().get_initial(), DatabaseValue::Number(3));
+
+        entity_store.set_version_attribute("User".to_string(), "revision".to_string());
+        assert_eq!(entity_store.increment_version(&user), Err(EntityError::AttributeNotFound("revision".to_string())));
+    }
+
+    #[test]
+    fn test_load() {
+        let mut entity_store = EntityStore::new();
+        let row = |pk: PK, values: Vec<(&str, DatabaseValue)>| (pk, values.into_iter().map(|(name, value)| (name.to_string(), value)).collect::<HashMap<String, DatabaseValue>>());
+        let authors = entity_store.load("Author".to_string(), vec![
+            row(1, vec![("name", DatabaseValue::String("john".to_string()))]),
+            row(2, vec![("name", DatabaseValue::String("jane".to_string()))]),
+        ]).unwrap();
+        assert_eq!(authors[1].get("name").unwrap().get_initial(), DatabaseValue::String("jane".to_string()));
+        assert!(entity_store.changeset().unwrap().created.is_empty());
+
+        entity_store.register_model("Book".to_string(), ModelSchema {
+            attributes: vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String("untitled".to_string())),
+                AttributeDescriptor::new(AttributeKind::ForeignKey("Author".to_string(), OnDelete::Cascade), "author".to_string(), DatabaseValue::None),
+            ],
+            unique: vec![],
+        });
+        let books = entity_store.load("Book".to_string(), vec![row(1, vec![("author_id", DatabaseValue::Number(2))])]).unwrap();
+        assert_eq!(books[0].get("title").unwrap().get_value(), DatabaseValue::String("untitled".to_string()));
+        assert_eq!(books[0].get_related(&entity_store, "author"), Ok(Some(Rc::clone(&authors[1]))));
+
+        let reloaded = entity_store.load("Author".to_string(), vec![row(1, vec![("name", DatabaseValue::String("doe".to_string()))])]).unwrap();
+        assert_eq!(reloaded, vec![Rc::clone(&authors[0])]);
+        assert_eq!(reloaded[0].get("name").unwrap().get_value(), DatabaseValue::String("john".to_string()));
+        assert_eq!(entity_store.load("Book".to_string(), vec![row(2, vec![("isbn", DatabaseValue::None)])]).err(), Some(EntityError::AttributeNotFound("isbn".to_string())));
+    }
+
+    #[test]
+    fn test_take_added() {
+        let mut entity_store = EntityStore::new();
+        let identifier = EntityIdentifier::new_persisted("User".to_string(), 1);
+        entity_store.instantiate_entity(identifier.clone(), name_descriptor("john"));
+        assert!(entity_store.take_added().is_empty());
+
+        entity_store.record_added();
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("jane"));
+        entity_store.instantiate_entity(identifier, name_descriptor("john"));
+        assert_eq!(entity_store.take_added(), vec![jane]);
+        assert!(entity_store.take_added().is_empty());
+    }
+
+    #[test]
+    fn test_store_symbols() {
+        let mut entity_store = EntityStore::new();
+        let john = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("john"));
+        let jane = entity_store.instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("jane"));
+        let names = |entity: &Entity| entity.iter_attributes().map(|(name, _)| name.as_ptr()).collect::<Vec<_>>();
+        assert_eq!(names(&john), names(&jane));
+        assert!(std::sync::Arc::ptr_eq(john.get_identifier().get_model_symbol(), jane.get_identifier().get_model_symbol()));
+        // the other stores have their own names
+        let other = EntityStore::new().instantiate_entity(EntityIdentifier::new("User".to_string()), name_descriptor("doe"));
+        assert_ne!(names(&other), names(&john));
+    }
 }
@@ -1,4 +1,5 @@
-use crate::entity::EntityIdentifier;
+use crate::entity::{EntityIdentifier, Epoch, MessageDict};
+use crate::entity_store::Constraint;
 
 #[derive(Debug)]
 #[derive(PartialEq)]
@@ -6,4 +7,40 @@ pub enum EntityError {
     AttributeNotFound(String),
     EntityNotFound(EntityIdentifier),
     UnpersistedEntity(EntityIdentifier),
+    MergeConflict(EntityIdentifier, String),
+    DuplicatePk(EntityIdentifier),
+    NotOrderable(String),
+    TransactionAlreadyStarted,
+    NoActiveTransaction,
+    UnknownSavepoint(Epoch),
+    UnknownCheckpoint(String),
+    MissingValue(String, Epoch),
+    UnknownBranch(Epoch),
+    NotARelation(String),
+    /// the entity can't be deleted, the second one references it with a protecting foreign key
+    Protected(EntityIdentifier, EntityIdentifier),
+    /// the entities reference each other, they can't be saved one after the other
+    DependencyCycle(Vec<EntityIdentifier>),
+    /// no table is mapped to the model, see sql::render()
+    NoTableMapping(String),
+    /// the model isn't registered, see EntityStore::register_model()
+    UnknownModel(String),
+    /// the entity was changed in the database since it was read, its version doesn't match
+    StaleEntity(EntityIdentifier),
+    /// the serialized store is malformed, see json::from_json()
+    InvalidDocument(String),
+    /// the CSV file can't be read or written, see csv_io
+    Csv(String),
+    /// the entity violates the constraint, see EntityStore::set_constraint_check(). unique ones
+    /// come with the entity holding the same values
+    ConstraintViolation(EntityIdentifier, Constraint, Option<Box<EntityIdentifier>>),
+    /// the store reads the values at a past epoch, see EntityStore::enter_epoch()
+    ReadOnlyEpoch(Epoch),
+    /// a validator of the attribute refused the value, with the reason, see Validator
+    Validation(String, String),
+    /// the entity can't be flushed without values for these NotNull attributes, see Validator
+    MissingValues(EntityIdentifier, Vec<String>),
+    /// an entity validator refused the entity, with the messages by attribute, see
+    /// EntityStore::add_entity_validator()
+    Invalid(EntityIdentifier, MessageDict),
 }
@@ -1,16 +1,99 @@
+use std::borrow::Borrow;
+use std::cell::{Cell, Ref};
+use std::fmt::{Display, Formatter};
 use std::rc::Rc;
-use crate::entity::{DatabaseValue, Entity, BaseEntityAttribute};
+use crate::entity::{DatabaseValue, Entity, Epoch, PhysicalAttribute};
 use crate::errors::EntityError;
+use crate::symbol::Symbol;
 
 
 pub fn match_entity(filter_expression: &FilterExpression, entity: &Rc<Entity>) -> Result<bool, EntityError> {
     match filter_expression {
         FilterExpression::Exact(expression) => expression.match_entity(entity),
+        FilterExpression::Lookup(expression) => expression.match_entity(entity),
+        FilterExpression::And(expressions) => {
+            for expression in expressions {
+                if !match_entity(expression, entity)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        },
+    }
+}
+
+/// the values of an attribute for a batch of entities, borrowed from their histories. None
+/// stands for the entities without value
+type Column<'e> = Vec<Option<Ref<'e, DatabaseValue>>>;
+
+/// a condition compiled to a test of the values of its attribute
+type Test = Box<dyn Fn(&DatabaseValue) -> bool>;
+
+/// an attribute read by a compiled expression, with the slot it was last found at
+struct Slot {
+    attribute: Symbol,
+    slot: Cell<usize>,
+}
 
+impl Slot {
+    /// the attribute of *entity*, at the known slot or looked up by name. the entities of a
+    /// model share their slots, so the lookup happens once
+    fn resolve<'e>(&self, entity: &'e Entity) -> Result<&'e PhysicalAttribute, EntityError> {
+        if let Some(attr) = entity.get_slot(self.slot.get(), &self.attribute) {
+            return Ok(attr);
+        }
+        let slot = entity.slot_of(&self.attribute).ok_or_else(|| EntityError::AttributeNotFound(self.attribute.to_string()))?;
+        self.slot.set(slot);
+        entity.get_ref(&self.attribute)
     }
 }
+
+/// a filter expression compiled to be evaluated again and again: the attributes it reads are
+/// resolved to the slots of the entities, its conditions to tests of the values. see
+/// FilterExpression::compile()
+pub struct CompiledExpression {
+    expression: FilterExpression,
+    slots: Vec<Slot>,
+    // the tests with the position of the slot of the values they test, in order
+    tests: Vec<(usize, Test)>,
+}
+
+impl CompiledExpression {
+    pub fn get_expression(&self) -> &FilterExpression {
+        &self.expression
+    }
+
+    /// which entities of the batch *entities* match the expression with their values at
+    /// *epoch*. the values of each attribute the expression reads are extracted once into a
+    /// column, then each test is evaluated over its column, for the entities the previous ones kept
+    pub fn match_batch<E: Borrow<Rc<Entity>>>(&self, entities: &[E], epoch: Epoch) -> Result<Vec<bool>, EntityError> {
+        let mut columns: Vec<Option<Column>> = self.slots.iter().map(|_| None).collect();
+        let mut selected = vec![true; entities.len()];
+        for (position, test) in self.tests.iter() {
+            if columns[*position].is_none() {
+                let slot = &self.slots[*position];
+                let mut column = Vec::with_capacity(entities.len());
+                for entity in entities {
+                    column.push(slot.resolve(entity.borrow())?.borrow_at_epoch(epoch));
+                }
+                columns[*position] = Some(column);
+            }
+            let values = columns[*position].iter().flatten().map(|value| value.as_deref().unwrap_or(&DatabaseValue::None));
+            for (selected, value) in selected.iter_mut().zip(values).filter(|(selected, _)| **selected) {
+                *selected = test(value);
+            }
+        }
+        Ok(selected)
+    }
+}
+
+#[derive(Clone)]
 pub enum FilterExpression {
     Exact(ExactExpression),
+    /// match if the value of an attribute passes a django style lookup
+    Lookup(LookupExpression),
+    /// match if all the expressions match
+    And(Vec<FilterExpression>),
 }
 
 type Attribute = String;
@@ -21,12 +104,46 @@ pub struct ExactExpression {
     value: DatabaseValue,
 }
 
+/// the django style lookups other than exact, see split_lookup()
+#[derive(Clone)]
+pub enum Lookup {
+    Gt(DatabaseValue),
+    Gte(DatabaseValue),
+    Lt(DatabaseValue),
+    Lte(DatabaseValue),
+    In(Vec<DatabaseValue>),
+    IsNull(bool),
+    Contains(String),
+    IContains(String),
+    StartsWith(String),
+    EndsWith(String),
+}
+
+/// the names of the lookups, the suffix of the keyword arguments after "__"
+pub const LOOKUPS: [&str; 11] = ["exact", "gt", "gte", "lt", "lte", "in", "isnull", "contains", "icontains", "startswith", "endswith"];
+
+/// split the keyword argument *key* into its attribute and lookup names: "age__gt" gives
+/// ("age", "gt") and "age" gives ("age", "exact"). unknown lookups are left in the attribute
+pub fn split_lookup(key: &str) -> (&str, &str) {
+    match key.rsplit_once("__") {
+        Some((attribute, lookup)) if LOOKUPS.contains(&lookup) => (attribute, lookup),
+        _ => (key, "exact"),
+    }
+}
+
+#[derive(Clone)]
+pub struct LookupExpression {
+    attribute: Attribute,
+    lookup: Lookup,
+}
+
 pub trait ExpressionTrait {
     fn match_entity(&self, entity: &Rc<Entity>) -> Result<bool, EntityError>;
 
     /// return if *other* in included in the actual expression
     /// it make sens to verify if our current expression
     /// is not a superset of the given *other*
+    #[allow(dead_code)]
     fn contains(&self, other: &FilterExpression) -> bool;
 }
 
@@ -39,7 +156,6 @@ impl From<ExactExpression> for FilterExpression {
 
 
 impl ExpressionTrait for ExactExpression {
-    #[allow(irrefutable_let_patterns)]
     fn contains(&self, other: &FilterExpression) -> bool {
         if let FilterExpression::Exact(other_eq) = other {
             self.attribute == other_eq.attribute && self.value == other_eq.value
@@ -49,7 +165,168 @@ impl ExpressionTrait for ExactExpression {
     }
 
     fn match_entity(&self, entity: &Rc<Entity>) -> Result<bool, EntityError>{
-        Ok(entity.get(&self.attribute[..])?.get_value() == self.value)
+        Ok(entity.get(&self.attribute[..])?.read_value(|value| *value == self.value))
+    }
+}
+
+impl ExpressionTrait for LookupExpression {
+    fn contains(&self, _other: &FilterExpression) -> bool {
+        false
+    }
+
+    fn match_entity(&self, entity: &Rc<Entity>) -> Result<bool, EntityError> {
+        Ok(entity.get(&self.attribute[..])?.read_value(|value| self.lookup.matches(value)))
+    }
+}
+
+impl Lookup {
+    /// whether *value* passes the lookup, values of another type never do but for isnull
+    pub fn matches(&self, value: &DatabaseValue) -> bool {
+        match (self, value) {
+            (Lookup::Gt(other), _) => value > other,
+            (Lookup::Gte(other), _) => value >= other,
+            (Lookup::Lt(other), _) => value < other,
+            (Lookup::Lte(other), _) => value <= other,
+            (Lookup::In(values), _) => values.contains(value),
+            (Lookup::IsNull(is_null), _) => (*value == DatabaseValue::None) == *is_null,
+            (Lookup::Contains(text), DatabaseValue::String(value)) => value.contains(text.as_str()),
+            (Lookup::IContains(text), DatabaseValue::String(value)) => value.to_lowercase().contains(&text.to_lowercase()),
+            (Lookup::StartsWith(text), DatabaseValue::String(value)) => value.starts_with(text.as_str()),
+            (Lookup::EndsWith(text), DatabaseValue::String(value)) => value.ends_with(text.as_str()),
+            _ => false,
+        }
+    }
+}
+
+/// a condition on the value of a single attribute, see FilterExpression::conditions()
+enum Condition<'x> {
+    Exact(&'x ExactExpression),
+    Lookup(&'x LookupExpression),
+}
+
+impl<'x> Condition<'x> {
+    fn attribute(&self) -> &'x str {
+        match self {
+            Condition::Exact(expression) => &expression.attribute,
+            Condition::Lookup(expression) => &expression.attribute,
+        }
+    }
+
+    /// the test of the values passing the condition, with its operand prepared once. the same
+    /// as Lookup::matches()
+    fn test(&self) -> Test {
+        let lookup = match self {
+            Condition::Exact(expression) => {
+                let expected = expression.value.clone();
+                return Box::new(move |value| *value == expected);
+            },
+            Condition::Lookup(expression) => expression.lookup.clone(),
+        };
+        match lookup {
+            Lookup::Gt(other) => Box::new(move |value| *value > other),
+            Lookup::Gte(other) => Box::new(move |value| *value >= other),
+            Lookup::Lt(other) => Box::new(move |value| *value < other),
+            Lookup::Lte(other) => Box::new(move |value| *value <= other),
+            Lookup::In(values) => Box::new(move |value| values.contains(value)),
+            Lookup::IsNull(is_null) => Box::new(move |value| (*value == DatabaseValue::None) == is_null),
+            Lookup::Contains(text) => Box::new(move |value| matches!(value, DatabaseValue::String(value) if value.contains(text.as_str()))),
+            Lookup::IContains(text) => {
+                let text = text.to_lowercase();
+                Box::new(move |value| matches!(value, DatabaseValue::String(value) if value.to_lowercase().contains(text.as_str())))
+            },
+            Lookup::StartsWith(text) => Box::new(move |value| matches!(value, DatabaseValue::String(value) if value.starts_with(text.as_str()))),
+            Lookup::EndsWith(text) => Box::new(move |value| matches!(value, DatabaseValue::String(value) if value.ends_with(text.as_str()))),
+        }
+    }
+}
+
+impl FilterExpression {
+    /// the conditions an entity must pass to match, the expressions of the And flattened
+    fn conditions(&self) -> Vec<Condition<'_>> {
+        match self {
+            FilterExpression::Exact(expression) => vec![Condition::Exact(expression)],
+            FilterExpression::Lookup(expression) => vec![Condition::Lookup(expression)],
+            FilterExpression::And(expressions) => expressions.iter().flat_map(FilterExpression::conditions).collect(),
+        }
+    }
+
+    /// compile the expression, to evaluate it on many entities or many times
+    pub fn compile(&self) -> CompiledExpression {
+        let mut slots: Vec<Slot> = vec![];
+        let mut tests = vec![];
+        for condition in self.conditions() {
+            let attribute = Symbol::from(condition.attribute());
+            let position = match slots.iter().position(|slot| slot.attribute == attribute) {
+                Some(position) => position,
+                None => {
+                    slots.push(Slot { attribute, slot: Cell::new(0) });
+                    slots.len() - 1
+                },
+            };
+            tests.push((position, condition.test()));
+        }
+        CompiledExpression { expression: self.clone(), slots, tests }
+    }
+}
+
+/// the expression written like the keyword arguments of filter(), the conditions of an And
+/// sorted so that the same filters are written the same: `pages__gt=0 AND title="dune"`
+impl Display for FilterExpression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterExpression::Exact(expression) => write!(f, "{}={}", expression.attribute, QueryValue(&expression.value)),
+            FilterExpression::Lookup(expression) => {
+                let (name, argument) = match &expression.lookup {
+                    Lookup::Gt(value) => ("gt", QueryValue(value).to_string()),
+                    Lookup::Gte(value) => ("gte", QueryValue(value).to_string()),
+                    Lookup::Lt(value) => ("lt", QueryValue(value).to_string()),
+                    Lookup::Lte(value) => ("lte", QueryValue(value).to_string()),
+                    Lookup::In(values) => ("in", format!("[{}]", values.iter().map(|value| QueryValue(value).to_string()).collect::<Vec<_>>().join(", "))),
+                    Lookup::IsNull(is_null) => ("isnull", if *is_null { "True" } else { "False" }.to_string()),
+                    Lookup::Contains(text) => ("contains", format!("{:?}", text)),
+                    Lookup::IContains(text) => ("icontains", format!("{:?}", text)),
+                    Lookup::StartsWith(text) => ("startswith", format!("{:?}", text)),
+                    Lookup::EndsWith(text) => ("endswith", format!("{:?}", text)),
+                };
+                write!(f, "{}__{}={}", expression.attribute, name, argument)
+            },
+            FilterExpression::And(expressions) => {
+                let mut conditions: Vec<String> = expressions.iter().map(|expression| expression.to_string()).collect();
+                conditions.sort();
+                f.write_str(&conditions.join(" AND "))
+            },
+        }
+    }
+}
+
+/// a value written as a python literal, the foreign keys as their identifier
+struct QueryValue<'v>(&'v DatabaseValue);
+
+impl Display for QueryValue<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            DatabaseValue::String(value) => write!(f, "{:?}", value),
+            DatabaseValue::Number(value) => write!(f, "{}", value),
+            DatabaseValue::None => f.write_str("None"),
+            DatabaseValue::ForeignKey(identifier) => write!(f, "<{}>", identifier),
+        }
+    }
+}
+
+impl LookupExpression {
+    pub fn new(attribute: Attribute, lookup: Lookup) -> Self {
+        LookupExpression {
+            attribute,
+            lookup,
+        }
+    }
+
+    pub fn get_attribute(&self) -> &str {
+        &self.attribute
+    }
+
+    pub fn get_lookup(&self) -> &Lookup {
+        &self.lookup
     }
 }
 
@@ -60,12 +337,22 @@ impl ExactExpression {
             value
         }
     }
+
+    pub fn get_attribute(&self) -> &str {
+        &self.attribute
+    }
+
+    pub fn get_value(&self) -> &DatabaseValue {
+        &self.value
+    }
 }
 
 #[cfg(test)]
 mod test  {
-    use crate::entity::DatabaseValue;
-    use crate::expression::{ExactExpression, FilterExpression, ExpressionTrait};
+    use std::rc::Rc;
+    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, EpochPtr};
+    use crate::errors::EntityError;
+    use crate::expression::{ExactExpression, FilterExpression, ExpressionTrait, Lookup, LookupExpression, split_lookup};
 
     #[test]
     fn test_equal_expression_include() {
@@ -101,4 +388,93 @@ mod test  {
 
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lookups() {
+        assert_eq!(split_lookup("age__gt"), ("age", "gt"));
+        assert_eq!(split_lookup("author__name__icontains"), ("author__name", "icontains"));
+        assert_eq!(split_lookup("age"), ("age", "exact"));
+        assert_eq!(split_lookup("age__around"), ("age__around", "exact"));
+
+        let age = DatabaseValue::Number(30);
+        assert!(Lookup::Gt(DatabaseValue::Number(29)).matches(&age));
+        assert!(!Lookup::Gt(DatabaseValue::Number(30)).matches(&age));
+        assert!(Lookup::Lte(DatabaseValue::Number(30)).matches(&age));
+        assert!(!Lookup::Lt(DatabaseValue::String("40".to_string())).matches(&age));
+        assert!(Lookup::In(vec![DatabaseValue::Number(1), DatabaseValue::Number(30)]).matches(&age));
+        assert!(Lookup::IsNull(false).matches(&age));
+        assert!(Lookup::IsNull(true).matches(&DatabaseValue::None));
+
+        let name = DatabaseValue::String("Darius".to_string());
+        assert!(Lookup::Contains("ari".to_string()).matches(&name));
+        assert!(!Lookup::Contains("dar".to_string()).matches(&name));
+        assert!(Lookup::IContains("dar".to_string()).matches(&name));
+        assert!(Lookup::StartsWith("Da".to_string()).matches(&name));
+        assert!(Lookup::EndsWith("us".to_string()).matches(&name));
+        assert!(!Lookup::Contains("3".to_string()).matches(&age));
+    }
+
+    #[test]
+    fn test_display() {
+        let title = FilterExpression::Exact(ExactExpression::new("title".to_string(), DatabaseValue::String("dune".to_string())));
+        let pages = FilterExpression::Lookup(LookupExpression::new("pages".to_string(), Lookup::Gt(DatabaseValue::Number(0))));
+        assert_eq!(title.to_string(), "title=\"dune\"");
+        assert_eq!(FilterExpression::And(vec![title.clone(), pages.clone()]).to_string(), "pages__gt=0 AND title=\"dune\"");
+        assert_eq!(FilterExpression::And(vec![pages, title]).to_string(), "pages__gt=0 AND title=\"dune\"");
+        let tags = LookupExpression::new("tag".to_string(), Lookup::In(vec![DatabaseValue::Number(1), DatabaseValue::None]));
+        assert_eq!(FilterExpression::Lookup(tags).to_string(), "tag__in=[1, None]");
+        assert_eq!(FilterExpression::And(vec![]).to_string(), "");
+    }
+
+    #[test]
+    fn test_match_batch() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::new(1));
+        let books: Vec<Rc<Entity>> = [("dune", 412), ("dune messiah", 256), ("emma", 0)].into_iter().map(|(title, pages)| {
+            let attributes = vec![
+                AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String(title.to_string())),
+                AttributeDescriptor::new(AttributeKind::Physical, "pages".to_string(), DatabaseValue::Number(pages)),
+            ];
+            Rc::new(Entity::new(EntityIdentifier::new("Book".to_string()), attributes, Rc::clone(&initial_ptr), Rc::clone(&current_ptr)))
+        }).collect();
+        books[2].get("pages").unwrap().set_current_value(DatabaseValue::Number(474));
+
+        let dune = FilterExpression::Lookup(LookupExpression::new("title".to_string(), Lookup::StartsWith("dune".to_string())));
+        let long = FilterExpression::Lookup(LookupExpression::new("pages".to_string(), Lookup::Gt(DatabaseValue::Number(300))));
+        let short = FilterExpression::Lookup(LookupExpression::new("pages".to_string(), Lookup::Lt(DatabaseValue::Number(500))));
+        assert_eq!(dune.compile().match_batch(&books, 1), Ok(vec![true, true, false]));
+        assert_eq!(FilterExpression::And(vec![long.clone(), short]).compile().match_batch(&books, 1), Ok(vec![true, false, true]));
+        assert_eq!(FilterExpression::And(vec![long.clone(), dune]).compile().match_batch(&books, 1), Ok(vec![true, false, false]));
+        assert_eq!(long.compile().match_batch(&books, 0), Ok(vec![true, false, false]));
+        assert_eq!(FilterExpression::And(vec![]).compile().match_batch(&books, 1), Ok(vec![true, true, true]));
+        let missing = FilterExpression::Exact(ExactExpression::new("author".to_string(), DatabaseValue::None));
+        assert_eq!(missing.compile().match_batch(&books, 1), Err(EntityError::AttributeNotFound("author".to_string())));
+    }
+
+    #[test]
+    fn test_compile() {
+        let initial_ptr = Rc::new(EpochPtr::default());
+        let current_ptr = Rc::new(EpochPtr::default());
+        let book = |title: &str, author: Option<&str>| {
+            let mut attributes = vec![AttributeDescriptor::new(AttributeKind::Physical, "title".to_string(), DatabaseValue::String(title.to_string()))];
+            if let Some(author) = author {
+                attributes.push(AttributeDescriptor::new(AttributeKind::Physical, "author".to_string(), DatabaseValue::String(author.to_string())));
+            }
+            Rc::new(Entity::new(EntityIdentifier::new("Book".to_string()), attributes, Rc::clone(&initial_ptr), Rc::clone(&current_ptr)))
+        };
+        let dune = FilterExpression::Lookup(LookupExpression::new("title".to_string(), Lookup::IContains("DUNE".to_string())));
+        let compiled = FilterExpression::And(vec![dune.clone(), dune]).compile();
+        assert_eq!(compiled.slots.len(), 1);
+        assert_eq!(compiled.tests.len(), 2);
+
+        // "title" moves from the first slot to the second one, and back
+        let books = vec![book("Dune", None), book("Emma", None)];
+        let authored = vec![book("Dune", Some("herbert")), book("Emma", Some("austen")), book("Children of dune", Some("herbert"))];
+        assert_eq!(compiled.match_batch(&books, 0), Ok(vec![true, false]));
+        assert_eq!(compiled.slots[0].slot.get(), 0);
+        assert_eq!(compiled.match_batch(&authored, 0), Ok(vec![true, false, true]));
+        assert_eq!(compiled.slots[0].slot.get(), 1);
+        assert_eq!(compiled.match_batch(&[&books[0], &authored[1]], 0), Ok(vec![true, false]));
+        assert_eq!(compiled.get_expression().to_string(), "title__icontains=\"DUNE\" AND title__icontains=\"DUNE\"");
+    }
+}
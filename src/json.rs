@@ -0,0 +1,367 @@
+use std::io::{Read, Write};
+use std::rc::Rc;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use crate::entity::{AttributeHistory, AttributeKind, DatabaseValue, Entity, EntityIdentifier, Epoch, OnDelete, PK};
+use crate::entity_store::EntityStore;
+use crate::errors::EntityError;
+
+/// the store as a JSON document, to hand a staged computation to another process or keep it for
+/// inspection. the entities are grouped by model, in insertion order:
+///
+/// ```json
+/// {
+///     "initial_epoch": 0, "current_epoch": 2, "flushed_epoch": 0,
+///     "entities": [{
+///         "model": "User", "pk": 1, "uuid": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+///         "created_epoch": 0, "deleted_epoch": null,
+///         "attributes": {
+///             "name": {"kind": "physical", "history": [[0, "john"], [2, "johnny"]]},
+///             "group": {
+///                 "kind": "foreign_key", "model": "Group", "on_delete": "CASCADE",
+///                 "history": [[0, {"model": "Group", "pk": null, "uuid": "..."}]]
+///             }
+///         }
+///     }],
+///     "collected_deletions": []
+/// }
+/// ```
+///
+/// kinds are "physical", "foreign_key", "one_to_one" and "generic_foreign_key". values written
+/// in branches are left out
+pub fn to_json(store: &EntityStore) -> Result<Value, EntityError> {
+    let mut entities = store.all_entities();
+    entities.sort_by(|left, right| left.get_identifier().get_model().cmp(right.get_identifier().get_model()));
+    Ok(json!({
+        "initial_epoch": store.initial_epoch(),
+        "current_epoch": store.current_epoch(),
+        "flushed_epoch": store.flushed_epoch(),
+        "entities": entities.iter().map(entity_to_json).collect::<Result<Vec<Value>, EntityError>>()?,
+        "collected_deletions": store.collected_deletions().iter().map(entity_to_json).collect::<Result<Vec<Value>, EntityError>>()?,
+    }))
+}
+
+/// the store described by a document produced by to_json(), the references between its
+/// entities preserved
+pub fn from_json(document: &Value) -> Result<EntityStore, EntityError> {
+    let entities = array(field(document, "entities")?)?;
+    let collected_deletions = array(field(document, "collected_deletions")?)?;
+    let current_epoch = epoch(field(document, "current_epoch")?)?;
+    let mut last_epoch = current_epoch;
+    let mut restored = vec![];
+    for (entity, collected) in entities.iter().map(|entity| (entity, false)).chain(collected_deletions.iter().map(|entity| (entity, true))) {
+        let identifier = identifier_from_json(entity)?;
+        let created_epoch = epoch(field(entity, "created_epoch")?)?;
+        let deleted_epoch = match field(entity, "deleted_epoch")? {
+            Value::Null => None,
+            value => Some(epoch(value)?),
+        };
+        let mut attributes: Vec<AttributeHistory> = vec![];
+        for (name, attribute) in field(entity, "attributes")?.as_object().ok_or_else(|| invalid("attributes", "an object"))? {
+            let mut history = vec![];
+            for entry in array(field(attribute, "history")?)? {
+                match array(entry)?.as_slice() {
+                    [entry_epoch, value] => history.push((epoch(entry_epoch)?, value_from_json(value)?)),
+                    _ => return Err(invalid("history", "[epoch, value] entries")),
+                }
+            }
+            last_epoch = history.iter().map(|(epoch, _)| *epoch).fold(last_epoch, Epoch::max);
+            attributes.push((kind_from_json(attribute)?, name.clone(), history));
+        }
+        last_epoch = last_epoch.max(created_epoch).max(deleted_epoch.unwrap_or(created_epoch));
+        restored.push((identifier, attributes, created_epoch, deleted_epoch, collected));
+    }
+    let mut store = EntityStore::restore(
+        epoch(field(document, "initial_epoch")?)?,
+        current_epoch,
+        epoch(field(document, "flushed_epoch")?)?,
+        last_epoch,
+    );
+    for (identifier, attributes, created_epoch, deleted_epoch, collected) in restored {
+        store.restore_entity(identifier, attributes, created_epoch, deleted_epoch, collected);
+    }
+    Ok(store)
+}
+
+/// the to_json() document of the store encoded with MessagePack, more compact than JSON
+pub fn to_msgpack(store: &EntityStore) -> Result<Vec<u8>, EntityError> {
+    rmp_serde::to_vec(&to_json(store)?).map_err(|error| EntityError::InvalidDocument(error.to_string()))
+}
+
+/// the store encoded by to_msgpack()
+pub fn from_msgpack(bytes: &[u8]) -> Result<EntityStore, EntityError> {
+    let document: Value = rmp_serde::from_slice(bytes).map_err(|error| EntityError::InvalidDocument(error.to_string()))?;
+    from_json(&document)
+}
+
+/// the to_msgpack() encoding of a store holding only *entity*, at the epochs of *store*, to
+/// hand a single entity to another process
+pub fn entity_to_msgpack(store: &EntityStore, entity: &Rc<Entity>) -> Result<Vec<u8>, EntityError> {
+    let document = json!({
+        "initial_epoch": store.initial_epoch(),
+        "current_epoch": store.current_epoch(),
+        "flushed_epoch": store.flushed_epoch(),
+        "entities": [entity_to_json(entity)?],
+        "collected_deletions": [],
+    });
+    rmp_serde::to_vec(&document).map_err(|error| EntityError::InvalidDocument(error.to_string()))
+}
+
+// written at the start of the snapshots, followed by their format version
+const SNAPSHOT_MAGIC: &[u8] = b"DLSTORE";
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// write a versioned binary snapshot of the store to *writer*: the to_msgpack() encoding,
+/// histories included, after a header identifying the format
+pub fn dump(store: &EntityStore, mut writer: impl Write) -> Result<(), EntityError> {
+    let bytes = to_msgpack(store)?;
+    writer.write_all(SNAPSHOT_MAGIC)
+        .and_then(|_| writer.write_all(&[SNAPSHOT_VERSION]))
+        .and_then(|_| writer.write_all(&bytes))
+        .and_then(|_| writer.flush())
+        .map_err(|error| EntityError::InvalidDocument(error.to_string()))
+}
+
+/// the store written by dump() to *reader*
+pub fn restore(mut reader: impl Read) -> Result<EntityStore, EntityError> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes).map_err(|error| EntityError::InvalidDocument(error.to_string()))?;
+    let Some(body) = bytes.strip_prefix(SNAPSHOT_MAGIC) else {
+        return Err(EntityError::InvalidDocument("not a store snapshot".to_string()));
+    };
+    match body.split_first() {
+        Some((&SNAPSHOT_VERSION, body)) => from_msgpack(body),
+        Some((version, _)) => Err(EntityError::InvalidDocument(format!("unsupported snapshot version {}", version))),
+        None => Err(EntityError::InvalidDocument("truncated snapshot".to_string())),
+    }
+}
+
+fn entity_to_json(entity: &Rc<Entity>) -> Result<Value, EntityError> {
+    let mut attributes = Map::new();
+    for (name, attribute) in entity.iter_attributes() {
+        let mut document = match entity.get_kind(name)? {
+            AttributeKind::Physical => json!({"kind": "physical"}),
+            AttributeKind::GenericForeignKey => json!({"kind": "generic_foreign_key"}),
+            AttributeKind::ForeignKey(model, on_delete) => json!({"kind": "foreign_key", "model": model, "on_delete": on_delete_name(on_delete)}),
+            AttributeKind::OneToOne(model, on_delete) => json!({"kind": "one_to_one", "model": model, "on_delete": on_delete_name(on_delete)}),
+        };
+        document["history"] = attribute.history().iter().map(|(epoch, value)| json!([epoch, value_to_json(value)])).collect();
+        attributes.insert(name.to_string(), document);
+    }
+    let mut document = identifier_to_json(entity.get_identifier());
+    document["created_epoch"] = json!(entity.get_created_epoch());
+    document["deleted_epoch"] = json!(entity.get_deleted_epoch());
+    document["attributes"] = Value::Object(attributes);
+    Ok(document)
+}
+
+fn identifier_to_json(identifier: &EntityIdentifier) -> Value {
+    json!({
+        "model": identifier.get_model(),
+        "pk": identifier.get_applied_pk().ok(),
+        "uuid": identifier.get_uuid().to_string(),
+    })
+}
+
+fn value_to_json(value: &DatabaseValue) -> Value {
+    match value {
+        DatabaseValue::String(value) => json!(value),
+        DatabaseValue::Number(value) => json!(value),
+        DatabaseValue::None => Value::Null,
+        DatabaseValue::ForeignKey(identifier) => identifier_to_json(identifier),
+    }
+}
+
+fn on_delete_name(on_delete: OnDelete) -> &'static str {
+    match on_delete {
+        OnDelete::Cascade => "CASCADE",
+        OnDelete::SetNull => "SET_NULL",
+        OnDelete::Protect => "PROTECT",
+        OnDelete::DoNothing => "DO_NOTHING",
+    }
+}
+
+fn on_delete_from_name(name: &str) -> Result<OnDelete, EntityError> {
+    match name {
+        "CASCADE" => Ok(OnDelete::Cascade),
+        "SET_NULL" => Ok(OnDelete::SetNull),
+        "PROTECT" => Ok(OnDelete::Protect),
+        "DO_NOTHING" => Ok(OnDelete::DoNothing),
+        _ => Err(invalid("on_delete", "an on_delete behavior")),
+    }
+}
+
+fn kind_from_json(attribute: &Value) -> Result<AttributeKind, EntityError> {
+    let relation = || -> Result<(String, OnDelete), EntityError> {
+        Ok((string(field(attribute, "model")?)?.to_string(), on_delete_from_name(string(field(attribute, "on_delete")?)?)?))
+    };
+    match string(field(attribute, "kind")?)? {
+        "physical" => Ok(AttributeKind::Physical),
+        "generic_foreign_key" => Ok(AttributeKind::GenericForeignKey),
+        "foreign_key" => relation().map(|(model, on_delete)| AttributeKind::ForeignKey(model, on_delete)),
+        "one_to_one" => relation().map(|(model, on_delete)| AttributeKind::OneToOne(model, on_delete)),
+        _ => Err(invalid("kind", "an attribute kind")),
+    }
+}
+
+fn identifier_from_json(document: &Value) -> Result<EntityIdentifier, EntityError> {
+    let pk: Option<PK> = match field(document, "pk")? {
+        Value::Null => None,
+        value => Some(value.as_i64().ok_or_else(|| invalid("pk", "an integer"))?),
+    };
+    let uuid = Uuid::parse_str(string(field(document, "uuid")?)?).map_err(|_| invalid("uuid", "a uuid"))?;
+    Ok(EntityIdentifier::restore(string(field(document, "model")?)?.to_string(), pk, uuid))
+}
+
+fn value_from_json(value: &Value) -> Result<DatabaseValue, EntityError> {
+    match value {
+        Value::String(value) => Ok(DatabaseValue::String(value.clone())),
+        Value::Number(value) => value.as_i64().map(DatabaseValue::Number).ok_or_else(|| invalid("value", "an integer")),
+        Value::Null => Ok(DatabaseValue::None),
+        Value::Object(_) => identifier_from_json(value).map(DatabaseValue::ForeignKey),
+        _ => Err(invalid("value", "a string, an integer, null or an identifier")),
+    }
+}
+
+fn field<'v>(document: &'v Value, name: &str) -> Result<&'v Value, EntityError> {
+    document.get(name).ok_or_else(|| EntityError::InvalidDocument(format!("missing \"{}\"", name)))
+}
+
+fn array(value: &Value) -> Result<&Vec<Value>, EntityError> {
+    value.as_array().ok_or_else(|| invalid("value", "an array"))
+}
+
+fn string(value: &Value) -> Result<&str, EntityError> {
+    value.as_str().ok_or_else(|| invalid("value", "a string"))
+}
+
+fn epoch(value: &Value) -> Result<Epoch, EntityError> {
+    value.as_i64().ok_or_else(|| invalid("epoch", "an integer"))
+}
+
+fn invalid(name: &str, expected: &str) -> EntityError {
+    EntityError::InvalidDocument(format!("{} must be {}", name, expected))
+}
+
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, EntityIdentifier, OnDelete};
+    use crate::entity_store::EntityStore;
+    use crate::errors::EntityError;
+    use crate::json::{dump, entity_to_msgpack, from_json, from_msgpack, restore, to_json, to_msgpack};
+
+    #[test]
+    fn test_to_json() {
+        let mut entity_store = EntityStore::new();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new("Group".to_string()), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("admins".to_string())),
+        ]);
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::Cascade), "group".to_string(), DatabaseValue::None),
+        ]);
+        user.get("group").unwrap().set_current_value(DatabaseValue::ForeignKey(group.get_identifier().clone()));
+        let group_uuid = group.get_identifier().get_uuid().to_string();
+
+        assert_eq!(to_json(&entity_store), Ok(json!({
+            "initial_epoch": 0,
+            "current_epoch": 1,
+            "flushed_epoch": 0,
+            "entities": [
+                {
+                    "model": "Group", "pk": null, "uuid": group_uuid, "created_epoch": 1, "deleted_epoch": null,
+                    "attributes": {"name": {"kind": "physical", "history": [[0, "admins"]]}},
+                },
+                {
+                    "model": "User", "pk": 1, "uuid": user.get_identifier().get_uuid().to_string(), "created_epoch": 0, "deleted_epoch": null,
+                    "attributes": {
+                        "name": {"kind": "physical", "history": [[0, "john"]]},
+                        "group": {
+                            "kind": "foreign_key", "model": "Group", "on_delete": "CASCADE",
+                            "history": [[0, null], [1, {"model": "Group", "pk": null, "uuid": group_uuid}]],
+                        },
+                    },
+                },
+            ],
+            "collected_deletions": [],
+        })));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let mut entity_store = EntityStore::new();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new("Group".to_string()), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("admins".to_string())),
+        ]);
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::OneToOne("Group".to_string(), OnDelete::Protect), "group".to_string(), DatabaseValue::None),
+        ]);
+        user.get("group").unwrap().set_current_value(DatabaseValue::ForeignKey(group.get_identifier().clone()));
+        user.get("name").unwrap().set_current_value(DatabaseValue::String("johnny".to_string()));
+        let document = to_json(&entity_store).unwrap();
+
+        let restored = from_json(&document).unwrap();
+        assert_eq!(to_json(&restored), Ok(document));
+        let restored_user = restored.get(user.get_identifier()).unwrap();
+        assert_eq!(restored_user.get("name").unwrap().get_initial(), DatabaseValue::String("john".to_string()));
+        assert_eq!(restored_user.get("name").unwrap().get_value(), DatabaseValue::String("johnny".to_string()));
+        let restored_group = restored.get(group.get_identifier()).unwrap();
+        assert_eq!(restored.related(&restored_group, &"User".to_string(), "group"), vec![restored_user]);
+        assert_eq!(restored.changeset().unwrap().created, vec![restored_group]);
+
+        assert_eq!(from_json(&json!({"entities": []})).err(), Some(EntityError::InvalidDocument("missing \"collected_deletions\"".to_string())));
+    }
+
+    #[test]
+    fn test_msgpack() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+            AttributeDescriptor::new(AttributeKind::GenericForeignKey, "target".to_string(), DatabaseValue::None),
+        ]);
+        user.get("name").unwrap().set_current_value(DatabaseValue::String("johnny".to_string()));
+        let bytes = to_msgpack(&entity_store).unwrap();
+        assert!(bytes.len() < serde_json::to_vec(&to_json(&entity_store).unwrap()).unwrap().len());
+
+        let restored = from_msgpack(&bytes).unwrap();
+        assert_eq!(to_json(&restored), to_json(&entity_store));
+        assert!(matches!(from_msgpack(&bytes[..10]), Err(EntityError::InvalidDocument(_))));
+    }
+
+    #[test]
+    fn test_entity_to_msgpack() {
+        let mut entity_store = EntityStore::new();
+        let user = entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+        ]);
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 2), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("doe".to_string())),
+        ]);
+        user.get("name").unwrap().set_current_value(DatabaseValue::String("johnny".to_string()));
+
+        let restored = from_msgpack(&entity_to_msgpack(&entity_store, &user).unwrap()).unwrap();
+        assert_eq!(restored.all_entities().len(), 1);
+        let restored_user = restored.get(user.get_identifier()).unwrap();
+        assert_eq!(restored_user.get("name").unwrap().history(), user.get("name").unwrap().history());
+        assert_eq!(restored.current_epoch(), entity_store.current_epoch());
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut entity_store = EntityStore::new();
+        entity_store.instantiate_entity(EntityIdentifier::new_persisted("User".to_string(), 1), vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("john".to_string())),
+        ]);
+        let mut bytes = vec![];
+        dump(&entity_store, &mut bytes).unwrap();
+        assert!(bytes.starts_with(b"DLSTORE\x01"));
+        assert_eq!(to_json(&restore(bytes.as_slice()).unwrap()), to_json(&entity_store));
+
+        bytes[7] = 2;
+        assert_eq!(restore(bytes.as_slice()).err(), Some(EntityError::InvalidDocument("unsupported snapshot version 2".to_string())));
+        assert_eq!(restore(&b"{}"[..]).err(), Some(EntityError::InvalidDocument("not a store snapshot".to_string())));
+    }
+}
@@ -1,34 +1,66 @@
+mod csv_io;
 mod entity;
 mod entity_store;
 mod errors;
 mod expression;
+mod json;
+mod logging;
+mod query;
+mod sql;
+mod symbol;
+mod sync_store;
 
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
+use std::sync::Arc;
+use uuid::Uuid;
 use pyo3::basic::CompareOp;
-use pyo3::exceptions::{PyException, PyNotImplementedError, PyValueError};
+use pyo3::exceptions::{PyException, PyIOError, PyKeyError, PyLookupError, PyRuntimeError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyLong, PyString};
-use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, Epoch, Model, PhysicalAttribute, PK};
-use crate::entity_store::EntityStore;
+use pyo3::types::{PyBool, PyBytes, PyDate, PyDateTime, PyDict, PyLong, PyString};
+use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, Entity, EntityIdentifier, Epoch, EpochPtr, FieldType, MessageDict, Model, OnDelete, PhysicalAttribute, Validator, PK};
+use crate::entity_store::{ConflictPolicy, Constraint, ConstraintCheck, EntityStore, EntityStoreOverlay, EntityValidatorFn, HydrationRow, IndexKind, ManyToMany, MergePolicy, ModelSchema, NON_FIELD_ERRORS, SCAN_BUCKETS};
 use crate::errors::EntityError;
-use crate::expression::{ExactExpression, FilterExpression};
+use crate::sql::TableMapping;
+use crate::sync_store::SyncEntityStore;
+use crate::expression::{CompiledExpression, ExactExpression, FilterExpression, Lookup, LookupExpression, split_lookup};
 
-pyo3::create_exception!(django_lightning_service, EntityNotFound, PyException);
+pyo3::create_exception!(django_lightning_service, EntityNotFound, PyLookupError);
+pyo3::create_exception!(django_lightning_service, AttributeNotFound, PyKeyError);
+pyo3::create_exception!(django_lightning_service, UnpersistedEntity, PyValueError);
+pyo3::create_exception!(django_lightning_service, ProtectedError, PyException);
+pyo3::create_exception!(django_lightning_service, DependencyCycleError, PyException);
+pyo3::create_exception!(django_lightning_service, StaleEntityError, PyException);
+pyo3::create_exception!(django_lightning_service, IntegrityError, PyException);
+pyo3::create_exception!(django_lightning_service, ValidationError, PyValueError);
 
 #[derive(Debug)]
 enum PyDatabaseValue {
     String(String),
     Number(i64),
-
+    None,
+    ForeignKey(EntityIdentifier),
 }
 
 impl<'source> FromPyObject<'source> for PyDatabaseValue {
     fn extract(ob: &'source PyAny) -> PyResult<Self> {
-        if let Ok(str) = ob.downcast::<PyString>() {
+        if ob.is_none() {
+            Ok(PyDatabaseValue::None)
+        } else if let Ok(str) = ob.downcast::<PyString>() {
             Ok(PyDatabaseValue::String(str.extract()?))
         } else if let Ok(int) = ob.downcast::<PyLong>() {
             Ok(PyDatabaseValue::Number(int.extract()?))
+        } else if let Ok(identifier) = ob.extract::<PyRef<PyEntityIdentifier>>() {
+            Ok(PyDatabaseValue::ForeignKey(identifier.entity_identifier.clone()))
+        } else if let Ok(entity) = ob.extract::<PyRef<PyEntity>>() {
+            Ok(PyDatabaseValue::ForeignKey(entity.entity.get_identifier().clone()))
+        } else if let Ok(value) = ob.extract::<PyRef<PyValue>>() {
+            Ok(value.value.clone().into())
+        } else if let Ok((model, pk)) = ob.extract::<(Model, PK)>() {
+            // (model, pk) pairs, as stored by generic foreign keys
+            Ok(PyDatabaseValue::ForeignKey(EntityIdentifier::new_persisted(model, pk)))
         } else {
             Err(PyValueError::new_err("cannot handle this type"))
         }
@@ -40,7 +72,9 @@ impl IntoPy<PyObject> for PyDatabaseValue {
     fn into_py(self, py: Python) -> PyObject {
         match self {
             PyDatabaseValue::String(val) => val.into_py(py),
-            PyDatabaseValue::Number(val) => val.into_py(py)
+            PyDatabaseValue::Number(val) => val.into_py(py),
+            PyDatabaseValue::None => py.None(),
+            PyDatabaseValue::ForeignKey(entity_identifier) => PyEntityIdentifier { entity_identifier }.into_py(py),
         }
     }
 }
@@ -49,11 +83,85 @@ impl IntoPy<PyObject> for PyDatabaseValue {
 fn to_python_error(entity_error: EntityError) -> PyErr {
 
     match entity_error {
-        EntityError::EntityNotFound(identifier) => PyException::new_err(format!("EntityNotFound({})", identifier)),
-        _ => PyException::new_err("oops")
+        EntityError::EntityNotFound(identifier) => EntityNotFound::new_err(format!("{} not found", identifier)),
+        EntityError::AttributeNotFound(name) => AttributeNotFound::new_err(format!("no attribute {}", name)),
+        EntityError::UnpersistedEntity(identifier) => UnpersistedEntity::new_err(format!("{} has no pk yet", identifier)),
+        EntityError::DuplicatePk(identifier) => IntegrityError::new_err(format!("another entity has the pk of {}", identifier)),
+        EntityError::Protected(identifier, protecting) => ProtectedError::new_err(format!("cannot delete {}, referenced by {}", identifier, protecting)),
+        EntityError::StaleEntity(identifier) => StaleEntityError::new_err(format!("{} was changed in the database since it was read", identifier)),
+        EntityError::ConstraintViolation(identifier, Constraint::Unique(_, attributes), conflicting) => unique_error(identifier, attributes, conflicting),
+        EntityError::ConstraintViolation(identifier, Constraint::ForeignKey(_, attribute), _) => IntegrityError::new_err(format!(
+            "the {} of {} references a deleted or unknown entity", attribute, identifier,
+        )),
+        EntityError::ConstraintViolation(identifier, Constraint::Check(_, name), _) => IntegrityError::new_err(format!(
+            "{} violates the check constraint {}", identifier, name,
+        )),
+        EntityError::Csv(reason) => PyIOError::new_err(format!("csv: {}", reason)),
+        EntityError::InvalidDocument(reason) => PyValueError::new_err(format!("invalid document: {}", reason)),
+        EntityError::DependencyCycle(identifiers) => DependencyCycleError::new_err(format!(
+            "cannot order the saving of {}, they reference each other",
+            identifiers.iter().map(|identifier| identifier.to_string()).collect::<Vec<String>>().join(", "),
+        )),
+        EntityError::ReadOnlyEpoch(epoch) => PyRuntimeError::new_err(format!("the store reads the values at epoch {}, they can't be written", epoch)),
+        EntityError::MergeConflict(identifier, attribute) => PyValueError::new_err(format!("the {} of {} was changed on both sides", attribute, identifier)),
+        EntityError::NotOrderable(attribute) => PyTypeError::new_err(format!("the values of {} can't be ordered", attribute)),
+        EntityError::TransactionAlreadyStarted => PyRuntimeError::new_err("a transaction is already running"),
+        EntityError::NoActiveTransaction => PyRuntimeError::new_err("no transaction is running"),
+        EntityError::UnknownSavepoint(epoch) => PyValueError::new_err(format!("unknown savepoint {}", epoch)),
+        EntityError::UnknownCheckpoint(label) => PyValueError::new_err(format!("unknown checkpoint {}", label)),
+        EntityError::UnknownBranch(epoch) => PyValueError::new_err(format!("unknown branch {}", epoch)),
+        EntityError::MissingValue(attribute, epoch) => PyValueError::new_err(format!("{} has no value at epoch {}", attribute, epoch)),
+        EntityError::NotARelation(attribute) => PyValueError::new_err(format!("{} isn't a relation", attribute)),
+        EntityError::NoTableMapping(model) => PyValueError::new_err(format!("no table is mapped to {}", model)),
+        EntityError::UnknownModel(model) => PyValueError::new_err(format!("{} isn't registered", model)),
+        EntityError::Validation(attribute, message) => validation_error(format!("{}: {}", attribute, message), MessageDict::from([(attribute, vec![message])])),
+        EntityError::MissingValues(identifier, attributes) => validation_error(
+            format!("{} has no value for {}", identifier, attributes.join(", ")),
+            attributes.into_iter().map(|attribute| (attribute, vec!["this field cannot be null".to_string()])).collect(),
+        ),
+        EntityError::Invalid(identifier, messages) => {
+            let text = messages.iter().map(|(attribute, messages)| format!("{}: {}", attribute, messages.join(", "))).collect::<Vec<String>>().join("; ");
+            validation_error(format!("{} isn't valid: {}", identifier, text), messages)
+        },
     }
 }
 
+/// the IntegrityError of the entity *identifier* holding the unique together *attributes* of the
+/// *conflicting* one, carrying its identifier as `conflicting`
+fn unique_error(identifier: EntityIdentifier, attributes: Vec<String>, conflicting: Option<Box<EntityIdentifier>>) -> PyErr {
+    let error = IntegrityError::new_err(format!(
+        "{} has the same {} as {}", identifier, attributes.join(", "),
+        conflicting.as_deref().map(repr_identifier).unwrap_or_else(|| "another entity".to_string()),
+    ));
+    Python::with_gil(|py| {
+        let conflicting = conflicting.map(|entity_identifier| PyEntityIdentifier { entity_identifier: *entity_identifier }.into_py(py));
+        if let Err(error) = error.value(py).setattr("conflicting", conflicting) {
+            return error;
+        }
+        error
+    })
+}
+
+/// the ValidationError of the *messages* of the offending attributes, carrying them as
+/// `message_dict`, their names as `attributes`, the first one as `attribute` with its first
+/// message as `message`
+fn validation_error(text: String, messages: MessageDict) -> PyErr {
+    Python::with_gil(|py| {
+        let error = ValidationError::new_err(text);
+        let value = error.value(py);
+        let first = messages.iter().next().map(|(attribute, messages)| (attribute.clone(), messages.first().cloned()));
+        let (attribute, message) = first.unzip();
+        let result = value.setattr("attribute", attribute)
+            .and_then(|_| value.setattr("attributes", messages.keys().cloned().collect::<Vec<String>>()))
+            .and_then(|_| value.setattr("message", message.flatten()))
+            .and_then(|_| value.setattr("message_dict", messages));
+        if let Err(error) = result {
+            return error;
+        }
+        error
+    })
+}
+
 impl From<EntityError> for pyo3::PyErr {
     fn from(value: EntityError) -> Self {
         to_python_error(value)
@@ -65,23 +173,25 @@ impl From<DatabaseValue> for PyDatabaseValue {
         match value {
             DatabaseValue::String(str) => PyDatabaseValue::String(str),
             DatabaseValue::Number(num) => PyDatabaseValue::Number(num),
-            DatabaseValue::None => PyDatabaseValue::String("".to_string()),
+            DatabaseValue::None => PyDatabaseValue::None,
+            DatabaseValue::ForeignKey(identifier) => PyDatabaseValue::ForeignKey(identifier),
         }
     }
 }
 
-impl Into<DatabaseValue> for PyDatabaseValue {
-    fn into(self) -> DatabaseValue {
-        match self {
+impl From<PyDatabaseValue> for DatabaseValue {
+    fn from(value: PyDatabaseValue) -> Self {
+        match value {
             PyDatabaseValue::String(str) => DatabaseValue::String(str),
             PyDatabaseValue::Number(num) => DatabaseValue::Number(num),
-            // PyDatabaseValue::None => DatabaseValue::None,
+            PyDatabaseValue::None => DatabaseValue::None,
+            PyDatabaseValue::ForeignKey(identifier) => DatabaseValue::ForeignKey(identifier),
         }
     }
 }
 
 
-#[pyclass(unsendable)]
+#[pyclass(module = "django_lightning_service")]
 struct PyEntityIdentifier {
     entity_identifier: EntityIdentifier,
 }
@@ -109,17 +219,575 @@ impl PyEntityIdentifier {
     fn get_uuid(&self) -> String {
         self.entity_identifier.get_uuid().to_string()
     }
-    fn get_model(&self) -> &Model {
+    fn get_model(&self) -> &str {
         self.entity_identifier.get_model()
     }
-    fn get_applied_pk(&self) -> PK {
-        *self.entity_identifier.get_applied_pk().unwrap()
+    /// the pk, fail with UnpersistedEntity if the entity has none yet
+    fn get_applied_pk(&self) -> Result<PK, EntityError> {
+        self.entity_identifier.get_applied_pk()
+    }
+
+    /// the identifier with the given *uuid*, as pickled
+    #[staticmethod]
+    #[pyo3(signature = (model, pk, uuid))]
+    fn restore(model: Model, pk: Option<PK>, uuid: &str) -> PyResult<Self> {
+        let uuid = Uuid::parse_str(uuid).map_err(|error| PyValueError::new_err(format!("invalid uuid: {}", error)))?;
+        Ok(PyEntityIdentifier { entity_identifier: EntityIdentifier::restore(model, pk, uuid) })
+    }
+
+    /// pickle the model, pk and uuid, unsaved identifiers stay equal once unpickled
+    fn __reduce__(&self, py: Python) -> PyResult<(PyObject, IdentifierParts)> {
+        let restore = py.get_type::<PyEntityIdentifier>().getattr("restore")?;
+        let identifier = &self.entity_identifier;
+        Ok((restore.into(), (identifier.get_model().to_string(), identifier.get_applied_pk().ok(), identifier.get_uuid().to_string())))
+    }
+
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyObject {
+        let py = other.py();
+        match other.extract::<PyRef<PyEntityIdentifier>>() {
+            Ok(other) => compare_identifiers(py, &self.entity_identifier, &other.entity_identifier, op),
+            Err(_) => py.NotImplemented(),
+        }
+    }
+
+    /// consistent with the equality, it changes when the pk is applied
+    fn __hash__(&self) -> u64 {
+        hash_identifier(&self.entity_identifier)
+    }
+
+    fn __str__(&self) -> String {
+        self.entity_identifier.to_string()
+    }
+
+    fn __repr__(slf: &PyCell<Self>) -> PyResult<String> {
+        Ok(format!("<{} {}>", slf.get_type().name()?, repr_identifier(&slf.borrow().entity_identifier)))
+    }
+}
+
+/// the model of *identifier* with its pk, or its uuid until it's persisted
+fn repr_identifier(identifier: &EntityIdentifier) -> String {
+    match identifier.get_applied_pk() {
+        Ok(pk) => format!("{} pk={}", identifier.get_model(), pk),
+        Err(_) => format!("{} uuid={}", identifier.get_model(), identifier.get_uuid()),
+    }
+}
+
+/// the report of PyEntityStore.dump_debug()
+fn dump_debug(entity_store: &EntityStore) -> String {
+    let epoch = entity_store.current_epoch();
+    let mut entities = entity_store.all_entities();
+    entities.sort_by(|left, right| left.get_identifier().get_model().cmp(right.get_identifier().get_model()));
+    let mut lines = vec![format!(
+        "store at epoch {} (initial {}, flushed {}): {} entities",
+        epoch, entity_store.initial_epoch(), entity_store.flushed_epoch(), entities.len(),
+    )];
+    for entity in entities.iter() {
+        let persisted = entity.get_identifier().has_applied_pk();
+        let state = match (entity.is_alive_at(epoch), persisted) {
+            (false, _) => ", deleted",
+            (true, false) => ", new",
+            (true, true) if entity.is_dirty() => ", modified",
+            (true, true) => "",
+        };
+        lines.push(format!("{}{}", repr_identifier(entity.get_identifier()), state));
+        let mut attributes: Vec<(&str, &Rc<PhysicalAttribute>)> = entity.iter_attributes().collect();
+        attributes.sort_by_key(|(name, _)| *name);
+        for (name, attribute) in attributes {
+            let (initial, value) = (attribute.get_initial(), attribute.get_value());
+            lines.push(match persisted && initial != value {
+                true => format!("    {}: {} -> {}", name, repr_value(&initial), repr_value(&value)),
+                false => format!("    {}: {}", name, repr_value(&value)),
+            });
+        }
+    }
+    match entity_store.changeset() {
+        Ok(changeset) if changeset.created.is_empty() && changeset.updated.is_empty() && changeset.deleted.is_empty() => {
+            lines.push("pending changes: none".to_string());
+        },
+        Ok(changeset) => {
+            lines.push("pending changes:".to_string());
+            lines.extend(changeset.created.iter().map(|entity| format!("    insert {}", repr_identifier(entity.get_identifier()))));
+            lines.extend(changeset.updated.iter().map(|(entity, attributes)| format!("    update {}: {}", repr_identifier(entity.get_identifier()), attributes.join(", "))));
+            lines.extend(changeset.deleted.iter().map(|entity| format!("    delete {}", repr_identifier(entity.get_identifier()))));
+        },
+        Err(error) => lines.push(format!("pending changes: {:?}", error)),
+    }
+    lines.join("\n")
+}
+
+/// *value* as written in the reprs, strings quoted and foreign keys as the referenced identifier
+fn repr_value(value: &DatabaseValue) -> String {
+    match value {
+        DatabaseValue::String(value) => format!("{:?}", value),
+        DatabaseValue::Number(value) => value.to_string(),
+        DatabaseValue::None => "None".to_string(),
+        DatabaseValue::ForeignKey(identifier) => identifier.to_string(),
+    }
+}
+
+/// the result of the equality comparisons of identifiers, NotImplemented for the orderings
+fn compare_identifiers(py: Python, left: &EntityIdentifier, right: &EntityIdentifier, op: CompareOp) -> PyObject {
+    match op {
+        CompareOp::Eq => (left == right).into_py(py),
+        CompareOp::Ne => (left != right).into_py(py),
+        _ => py.NotImplemented(),
+    }
+}
+
+fn hash_identifier(identifier: &EntityIdentifier) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    identifier.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// the state the calls work on with the GIL released, see without_gil(). it holds no reference
+/// bound to the GIL
+trait StoreState {}
+
+impl StoreState for RefCell<EntityStore> {}
+
+impl StoreState for PyQuery {}
+
+/// run *f* on *state* with the GIL released, so that the other python threads keep running
+/// meanwhile. *f* captures Send data only, so no python object bound to the GIL: the calls
+/// reaching python, like the validators, run with the GIL held instead
+fn without_gil<S: StoreState, T>(py: Python, state: &S, f: impl FnOnce(&S) -> T + Send) -> T {
+    struct CallingThread<T>(T);
+    // SAFETY: allow_threads runs its closure on the calling thread, so the state and the result
+    // never leave it. the pyclasses holding the state are unsendable: no other python thread
+    // reaches it while the GIL is released
+    unsafe impl<T> Send for CallingThread<T> {}
+    let state = CallingThread(state);
+    py.allow_threads(move || {
+        let state = state;
+        CallingThread(f(state.0))
+    }).0
+}
+
+/// parse "physical", "generic_foreign_key", "foreign_key:<related model>[:<on_delete>]"
+/// or "one_to_one:<related model>[:<on_delete>]"
+fn parse_attribute_kind(kind: &str) -> PyResult<AttributeKind> {
+    if let Some(relation) = kind.strip_prefix("foreign_key:") {
+        let (model, on_delete) = parse_relation(relation)?;
+        return Ok(AttributeKind::ForeignKey(model, on_delete));
+    }
+    if let Some(relation) = kind.strip_prefix("one_to_one:") {
+        let (model, on_delete) = parse_relation(relation)?;
+        return Ok(AttributeKind::OneToOne(model, on_delete));
+    }
+    match kind {
+        "Physical" | "physical" => Ok(AttributeKind::Physical),
+        "generic_foreign_key" => Ok(AttributeKind::GenericForeignKey),
+        _ => Err(PyValueError::new_err(format!("unknown attribute kind: {}", kind))),
+    }
+}
+
+/// the string parse_attribute_kind() parses back to *kind*
+fn format_attribute_kind(kind: &AttributeKind) -> String {
+    match kind {
+        AttributeKind::Physical => "physical".to_string(),
+        AttributeKind::GenericForeignKey => "generic_foreign_key".to_string(),
+        AttributeKind::ForeignKey(model, on_delete) => format!("foreign_key:{}:{}", model, format_on_delete(on_delete)),
+        AttributeKind::OneToOne(model, on_delete) => format!("one_to_one:{}:{}", model, format_on_delete(on_delete)),
+    }
+}
+
+/// parse "any", "int", "str", "bool", "datetime" or "date"
+fn parse_field_type(field_type: &str) -> PyResult<FieldType> {
+    match field_type {
+        "any" => Ok(FieldType::Any),
+        "int" => Ok(FieldType::Int),
+        "str" => Ok(FieldType::Str),
+        "bool" => Ok(FieldType::Bool),
+        "datetime" => Ok(FieldType::DateTime),
+        "date" => Ok(FieldType::Date),
+        _ => Err(PyValueError::new_err(format!("unknown field type: {}", field_type))),
+    }
+}
+
+fn format_field_type(field_type: FieldType) -> &'static str {
+    match field_type {
+        FieldType::Any => "any",
+        FieldType::Int => "int",
+        FieldType::Str => "str",
+        FieldType::Bool => "bool",
+        FieldType::DateTime => "datetime",
+        FieldType::Date => "date",
+    }
+}
+
+/// the value stored for the python *value* of an attribute of *field_type*, fail with a
+/// TypeError if it isn't of this type. None is accepted by all of them
+fn to_database_value(field_type: FieldType, value: &PyAny) -> PyResult<PyDatabaseValue> {
+    let expected = match field_type {
+        _ if value.is_none() => return Ok(PyDatabaseValue::None),
+        FieldType::Any => return value.extract(),
+        FieldType::Int if value.is_instance_of::<PyLong>() && !value.is_instance_of::<PyBool>() => return value.extract(),
+        FieldType::Str if value.is_instance_of::<PyString>() => return value.extract(),
+        FieldType::Bool if value.is_instance_of::<PyBool>() => return Ok(PyDatabaseValue::Number(value.is_true()? as i64)),
+        FieldType::DateTime if value.is_instance_of::<PyDateTime>() => return Ok(PyDatabaseValue::String(value.call_method0("isoformat")?.extract()?)),
+        FieldType::Date if value.is_instance_of::<PyDate>() && !value.is_instance_of::<PyDateTime>() => return Ok(PyDatabaseValue::String(value.call_method0("isoformat")?.extract()?)),
+        _ => format_field_type(field_type),
+    };
+    Err(PyTypeError::new_err(format!("expected a {} value, got {}", expected, value.repr()?)))
+}
+
+/// the python value of the stored *value* of an attribute of *field_type*
+fn from_database_value(py: Python, field_type: FieldType, value: DatabaseValue) -> PyResult<PyObject> {
+    match (field_type, value) {
+        (FieldType::Bool, DatabaseValue::Number(number)) => Ok((number != 0).into_py(py)),
+        (FieldType::DateTime, DatabaseValue::String(text)) => Ok(py.import("datetime")?.getattr("datetime")?.call_method1("fromisoformat", (text,))?.into()),
+        (FieldType::Date, DatabaseValue::String(text)) => Ok(py.import("datetime")?.getattr("date")?.call_method1("fromisoformat", (text,))?.into()),
+        (_, value) => Ok(PyDatabaseValue::from(value).into_py(py)),
+    }
+}
+
+fn parse_relation(relation: &str) -> PyResult<(Model, OnDelete)> {
+    Ok(match relation.split_once(':') {
+        Some((model, on_delete)) => (model.to_string(), parse_on_delete(on_delete)?),
+        None => (relation.to_string(), OnDelete::DoNothing),
+    })
+}
+
+fn parse_on_delete(on_delete: &str) -> PyResult<OnDelete> {
+    match on_delete {
+        "CASCADE" | "cascade" => Ok(OnDelete::Cascade),
+        "SET_NULL" | "set_null" => Ok(OnDelete::SetNull),
+        "PROTECT" | "protect" => Ok(OnDelete::Protect),
+        "DO_NOTHING" | "do_nothing" => Ok(OnDelete::DoNothing),
+        _ => Err(PyValueError::new_err(format!("unknown on_delete behavior: {}", on_delete))),
+    }
+}
+
+fn format_on_delete(on_delete: &OnDelete) -> &'static str {
+    match on_delete {
+        OnDelete::Cascade => "CASCADE",
+        OnDelete::SetNull => "SET_NULL",
+        OnDelete::Protect => "PROTECT",
+        OnDelete::DoNothing => "DO_NOTHING",
+    }
+}
+
+fn parse_constraint_check(check: &str) -> PyResult<ConstraintCheck> {
+    match check {
+        "immediate" => Ok(ConstraintCheck::Immediate),
+        "deferred" => Ok(ConstraintCheck::Deferred),
+        _ => Err(PyValueError::new_err(format!("unknown constraint check: {}", check))),
+    }
+}
+
+fn parse_conflict_policy(policy: &str) -> PyResult<ConflictPolicy> {
+    match policy {
+        "ours" => Ok(ConflictPolicy::KeepOurs),
+        "theirs" => Ok(ConflictPolicy::TakeTheirs),
+        "fail" => Ok(ConflictPolicy::Fail),
+        _ => Err(PyValueError::new_err(format!("unknown conflict policy: {}", policy))),
     }
 }
 
+/// an attribute of the entities given to instantiate_entity(). its *field_type* ("int", "str",
+/// "bool", "datetime" or "date") converts the values set and got through PyAttribute, any value
+/// is stored as given by default. the values set are checked by the *validators*, called with
+/// the value and raising an exception to refuse it, must be one of the *choices* if given and
+/// must not be None unless *null*, nor strings longer than *max_length*, nor numbers out of the
+/// *min_value* and *max_value* bounds, a ValidationError is raised otherwise. the entities
+/// missing a value for a *null* False attribute can't be flushed
 #[pyclass(unsendable)]
+#[derive(Clone)]
+struct PyAttributeDescriptor {
+    attribute_descriptor: AttributeDescriptor,
+}
+
+#[pymethods]
+impl PyAttributeDescriptor {
+    #[new]
+    #[pyo3(signature = (name, kind, initial, field_type="any", validators=vec![], choices=None, null=true, max_length=None, min_value=None, max_value=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(name: String, kind: &str, initial: &PyAny, field_type: &str, validators: Vec<PyObject>, choices: Option<Vec<&PyAny>>, null: bool, max_length: Option<usize>, min_value: Option<i64>, max_value: Option<i64>) -> PyResult<Self> {
+        let field_type = parse_field_type(field_type)?;
+        let initial = to_database_value(field_type, initial)?;
+        let mut attribute_descriptor = AttributeDescriptor::new(parse_attribute_kind(kind)?, name, initial.into()).with_field_type(field_type);
+        if !null {
+            attribute_descriptor = attribute_descriptor.with_validator(Validator::NotNull);
+        }
+        if let Some(max_length) = max_length {
+            attribute_descriptor = attribute_descriptor.with_validator(Validator::MaxLength(max_length));
+        }
+        if let Some(min_value) = min_value {
+            attribute_descriptor = attribute_descriptor.with_validator(Validator::MinValue(min_value));
+        }
+        if let Some(max_value) = max_value {
+            attribute_descriptor = attribute_descriptor.with_validator(Validator::MaxValue(max_value));
+        }
+        if let Some(choices) = choices {
+            let choices = choices.into_iter().map(|choice| Ok(to_database_value(field_type, choice)?.into())).collect::<PyResult<Vec<DatabaseValue>>>()?;
+            attribute_descriptor = attribute_descriptor.with_validator(Validator::Choices(choices));
+        }
+        for validator in validators {
+            attribute_descriptor = attribute_descriptor.with_validator(python_validator(field_type, validator));
+        }
+        Ok(PyAttributeDescriptor { attribute_descriptor })
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        self.attribute_descriptor.get_name()
+    }
+}
+
+/// the validator calling *validator* with the values converted to *field_type*, refusing them
+/// if it raises an exception, its message giving the reason
+fn python_validator(field_type: FieldType, validator: PyObject) -> Validator {
+    Validator::Custom(Arc::new(move |value| Python::with_gil(|py| {
+        let value = from_database_value(py, field_type, value.clone()).map_err(|error| error.value(py).to_string())?;
+        validator.call1(py, (value,)).map(|_| ()).map_err(|error| error.value(py).to_string())
+    })))
+}
+
+/// the entity validator calling *validator* with the values of the entity by attribute name,
+/// converted to their field type, refusing it if it raises an exception: the message_dict of a
+/// ValidationError, or its message under "__all__"
+fn python_entity_validator(validator: PyObject) -> Rc<EntityValidatorFn> {
+    Rc::new(move |entity| Python::with_gil(|py| {
+        let values = PyDict::new(py);
+        for (name, attribute) in entity.iter_attributes() {
+            let value = from_database_value(py, entity.get_field_type(name), attribute.get_value()).map_err(|error| refused_entity(py, error))?;
+            values.set_item(name, value).map_err(|error| refused_entity(py, error))?;
+        }
+        validator.call1(py, (values,)).map(|_| ()).map_err(|error| refused_entity(py, error))
+    }))
+}
+
+/// the messages of the exception raised by an entity validator, see python_entity_validator()
+fn refused_entity(py: Python, error: PyErr) -> MessageDict {
+    let value = error.value(py);
+    if value.is_instance_of::<ValidationError>() {
+        if let Ok(messages) = value.getattr("message_dict").and_then(|messages| messages.extract::<MessageDict>()) {
+            return messages;
+        }
+    }
+    MessageDict::from([(NON_FIELD_ERRORS.to_string(), vec![value.to_string()])])
+}
+
+/// an attribute descriptor given by python, either as a PyAttributeDescriptor
+/// or as a `(name, kind, initial)` or `(name, kind, initial, field_type)` tuple
+struct PyAttributeDescriptorArg(AttributeDescriptor);
+
+impl<'source> FromPyObject<'source> for PyAttributeDescriptorArg {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        if let Ok(descriptor) = ob.extract::<PyAttributeDescriptor>() {
+            Ok(PyAttributeDescriptorArg(descriptor.attribute_descriptor))
+        } else if let Ok((name, kind, initial, field_type)) = ob.extract::<(String, &str, &PyAny, &str)>() {
+            Ok(PyAttributeDescriptorArg(PyAttributeDescriptor::new(name, kind, initial, field_type, vec![], None, true, None, None, None)?.attribute_descriptor))
+        } else {
+            let (name, kind, initial): (String, &str, PyDatabaseValue) = ob.extract()?;
+            Ok(PyAttributeDescriptorArg(AttributeDescriptor::new(parse_attribute_kind(kind)?, name, initial.into())))
+        }
+    }
+}
+
+/// a sql statement and the parameters of each of its executions, see sql_statements()
+type PyStatement = (String, Vec<Vec<PyDatabaseValue>>);
+
+/// the model, pk and uuid of an identifier, see PyEntityIdentifier.restore()
+type IdentifierParts = (Model, Option<PK>, String);
+
+/// the callables saving the entities of a model to the database, see flush()
+struct Persistence {
+    // called with the entity, returns its pk
+    insert: Option<PyObject>,
+    // called with the entity and the names of its modified attributes
+    update: Option<PyObject>,
+    // called with the entity
+    delete: Option<PyObject>,
+    // called instead of insert with a list of at most batch_size entities, returns their pks
+    bulk_insert: Option<PyObject>,
+    batch_size: usize,
+}
+
+impl Persistence {
+    fn clone_ref(&self, py: Python) -> Persistence {
+        let clone = |callback: &Option<PyObject>| callback.as_ref().map(|callback| callback.clone_ref(py));
+        Persistence {
+            insert: clone(&self.insert),
+            update: clone(&self.update),
+            delete: clone(&self.delete),
+            bulk_insert: clone(&self.bulk_insert),
+            batch_size: self.batch_size,
+        }
+    }
+}
+
+#[pyclass(unsendable, weakref, module = "django_lightning_service")]
 struct PyEntityStore {
     entity_store: Rc<RefCell<EntityStore>>,
+    // called with (model, pk) to fetch the related entities missing from the store, see get_related()
+    loader: Option<PyObject>,
+    // called with (model, pks) by prefetch()
+    batch_loader: Option<PyObject>,
+    // persistence callbacks by model
+    persistence: HashMap<Model, Persistence>,
+    // database tables by model, see sql_statements()
+    tables: HashMap<Model, TableMapping>,
+    // the callables connected to each event, see connect()
+    hooks: Hooks,
+    // called with flush_now() by flush() instead of flushing, see set_on_commit()
+    on_commit: Option<PyObject>,
+}
+
+/// the events hooks can be connected to, see PyEntityStore.connect()
+const HOOK_EVENTS: [&str; 4] = ["created", "changed", "deleted", "flushed"];
+
+/// the events of the lifecycle of the entities and the store, see PyEntityStore.subscribe()
+const LIFECYCLE_EVENTS: [&str; 4] = ["entity_created", "attribute_changed", "entity_deleted", "epoch_advanced"];
+
+/// the callables connected to each event, shared by the store and its entities
+type Hooks = Rc<RefCell<HashMap<String, Vec<PyObject>>>>;
+
+/// call the callables connected to *event* with *entity* and the names of its *fields*
+fn notify(py: Python, hooks: &Hooks, event: &str, entity: &PyEntity, fields: Vec<String>) -> PyResult<()> {
+    // the callables aren't borrowed while they run, so they can connect others
+    let callbacks: Vec<PyObject> = hooks.borrow().get(event).map(
+        |callbacks| callbacks.iter().map(|callback| callback.clone_ref(py)).collect()
+    ).unwrap_or_default();
+    for callback in callbacks {
+        callback.call1(py, (entity.clone(), fields.clone()))?;
+    }
+    Ok(())
+}
+
+/// whether callables are subscribed to *event*, so its payload is worth building
+fn subscribed(hooks: &Hooks, event: &str) -> bool {
+    hooks.borrow().get(event).is_some_and(|callbacks| !callbacks.is_empty())
+}
+
+/// call the callables subscribed to *event* with its *payload*, given the event name as "event"
+fn publish(py: Python, hooks: &Hooks, event: &str, payload: &PyDict) -> PyResult<()> {
+    payload.set_item("event", event)?;
+    let callbacks: Vec<PyObject> = hooks.borrow().get(event).map(
+        |callbacks| callbacks.iter().map(|callback| callback.clone_ref(py)).collect()
+    ).unwrap_or_default();
+    for callback in callbacks {
+        callback.call1(py, (payload,))?;
+    }
+    Ok(())
+}
+
+/// call the watches of the attributes whose current value moved with the epoch, and publish
+/// "epoch_advanced" if the current epoch moved from *previous* to *epoch*
+fn publish_epoch(py: Python, hooks: &Hooks, previous: Epoch, epoch: Epoch) -> PyResult<()> {
+    if previous == epoch {
+        return Ok(());
+    }
+    fire_watches(py, hooks)?;
+    if !subscribed(hooks, "epoch_advanced") {
+        return Ok(());
+    }
+    let payload = PyDict::new(py);
+    payload.set_item("previous", previous)?;
+    payload.set_item("epoch", epoch)?;
+    publish(py, hooks, "epoch_advanced", payload)
+}
+
+/// an attribute watched by a callable, kept in the hooks under "watch", see PyEntityStore.watch()
+#[pyclass(unsendable)]
+struct PyWatch {
+    entity: PyEntity,
+    attribute: String,
+    callback: PyObject,
+    // the current value when the callable was last called, or the watch started
+    value: RefCell<DatabaseValue>,
+}
+
+/// call the watches whose attribute current value changed since they were last called
+fn fire_watches(py: Python, hooks: &Hooks) -> PyResult<()> {
+    let watches: Vec<PyObject> = hooks.borrow().get("watch").map(
+        |watches| watches.iter().map(|watch| watch.clone_ref(py)).collect()
+    ).unwrap_or_default();
+    for watch in watches {
+        let watch: PyRef<PyWatch> = watch.extract(py)?;
+        let attribute = watch.entity.entity.get(&watch.attribute)?;
+        // most writes leave the watched values as they were, the value is only cloned once changed
+        if attribute.read_value(|value| *value != *watch.value.borrow()) {
+            let value = attribute.get_value();
+            let previous = watch.value.replace(value.clone());
+            let field_type = watch.entity.entity.get_field_type(&watch.attribute);
+            let (previous, value) = (from_database_value(py, field_type, previous)?, from_database_value(py, field_type, value)?);
+            watch.callback.call1(py, (watch.entity.clone(), &watch.attribute, previous, value))?;
+        }
+    }
+    Ok(())
+}
+
+/// notify the "created" hooks and publish "entity_created" for the entities added to
+/// *entity_store* since the last call
+fn notify_added(py: Python, entity_store: &Rc<RefCell<EntityStore>>, hooks: &Hooks) -> PyResult<()> {
+    let added = entity_store.borrow_mut().take_added();
+    for entity in added {
+        let mut fields: Vec<String> = entity.iter_attributes().map(|(name, _)| name.to_string()).collect();
+        fields.sort();
+        let entity = PyEntity { entity, entity_store: Some(Rc::clone(entity_store)), hooks: Some(Rc::clone(hooks)) };
+        notify(py, hooks, "created", &entity, fields)?;
+        if subscribed(hooks, "entity_created") {
+            let payload = PyDict::new(py);
+            payload.set_item("identifier", entity.get_identifier().into_py(py))?;
+            payload.set_item("values", entity.to_dict(py, None)?)?;
+            payload.set_item("entity", entity.into_py(py))?;
+            publish(py, hooks, "entity_created", payload)?;
+        }
+    }
+    Ok(())
+}
+
+impl PyEntityStore {
+    fn wrap(&self, entity: Rc<Entity>) -> PyEntity {
+        PyEntity { entity, entity_store: Some(Rc::clone(&self.entity_store)), hooks: Some(Rc::clone(&self.hooks)) }
+    }
+
+    /// notify the "created" hooks of the entities added since the last call
+    fn notify_added(&self, py: Python) -> PyResult<()> {
+        notify_added(py, &self.entity_store, &self.hooks)
+    }
+
+    /// run *f* with the store, then publish "epoch_advanced" if it moved the current epoch
+    fn moving_epoch<T>(&self, py: Python, f: impl FnOnce(&mut EntityStore) -> Result<T, EntityError>) -> PyResult<T> {
+        let previous = self.entity_store.borrow().current_epoch();
+        let result = f(&mut self.entity_store.borrow_mut())?;
+        let epoch = self.entity_store.borrow().current_epoch();
+        publish_epoch(py, &self.hooks, previous, epoch)?;
+        Ok(result)
+    }
+
+    /// the persistence callback of the model of *entity* selected by *callback*
+    fn persistence_callback(&self, entity: &Entity, operation: &str, callback: fn(&Persistence) -> &Option<PyObject>) -> PyResult<&PyObject> {
+        let model = entity.get_identifier().get_model();
+        self.persistence.get(model).and_then(|persistence| callback(persistence).as_ref())
+            .ok_or_else(|| PyException::new_err(format!("no {} persistence callback registered for {}", operation, model)))
+    }
+
+    /// fetch the entities of *model* with the given *pks* with the loaders and instantiate them.
+    /// the pks the loaders return nothing for are skipped
+    fn fetch(&self, py: Python, model: Model, pks: Vec<PK>) -> PyResult<Vec<Rc<Entity>>> {
+        // the store isn't borrowed while the loaders run, so they can use it
+        let rows: Vec<(PK, Vec<PyAttributeDescriptorArg>)> = if let Some(batch_loader) = self.batch_loader.as_ref() {
+            let mut rows: HashMap<PK, Vec<PyAttributeDescriptorArg>> = batch_loader.call1(py, (model.clone(), pks.clone()))?.extract(py)?;
+            pks.into_iter().filter_map(|pk| rows.remove(&pk).map(|row| (pk, row))).collect()
+        } else if let Some(loader) = self.loader.as_ref() {
+            let mut rows = vec![];
+            for pk in pks {
+                let row: Option<Vec<PyAttributeDescriptorArg>> = loader.call1(py, (model.clone(), pk))?.extract(py)?;
+                rows.extend(row.map(|row| (pk, row)));
+            }
+            rows
+        } else {
+            vec![]
+        };
+        let mut entity_store = self.entity_store.borrow_mut();
+        Ok(rows.into_iter().map(|(pk, row)| entity_store.instantiate_entity(
+            EntityIdentifier::new_persisted(model.clone(), pk),
+            row.into_iter().map(|attr| attr.0).collect(),
+        )).collect())
+    }
 }
 
 #[pymethods]
@@ -127,110 +795,2032 @@ impl PyEntityStore {
     #[new]
     fn new() -> Self {
         PyEntityStore {
-            entity_store: Rc::new(RefCell::new(EntityStore::new()))
+            entity_store: Rc::new(RefCell::new(EntityStore::new())),
+            loader: None,
+            batch_loader: None,
+            persistence: HashMap::new(),
+            tables: HashMap::new(),
+            hooks: Rc::new(RefCell::new(HashMap::new())),
+            on_commit: None,
+        }
+    }
+
+    /// call *callback* on *event* with the entity and the list of the names of the concerned
+    /// attributes: "created" when an entity is added to the store (all its attributes),
+    /// "changed" when an attribute value is set, "deleted" when an entity is deleted (none) and
+    /// "flushed" for each entity saved by flush() (all the attributes of the inserted ones, the
+    /// modified ones of the updated ones, none for the deleted ones)
+    pub fn connect(&self, event: &str, callback: PyObject) -> PyResult<()> {
+        if !HOOK_EVENTS.contains(&event) {
+            return Err(PyValueError::new_err(format!("unknown event \"{}\", expected one of {}", event, HOOK_EVENTS.join(", "))));
+        }
+        if event == "created" {
+            self.entity_store.borrow_mut().record_added();
+        }
+        self.hooks.borrow_mut().entry(event.to_string()).or_default().push(callback);
+        Ok(())
+    }
+
+    /// call *callback* on the lifecycle *event* with a dict describing it, its name as "event":
+    /// "entity_created" with the "identifier", "entity" and "values" of an entity added to the
+    /// store, "attribute_changed" with the "identifier", "entity", "attribute", "previous" and
+    /// "value" of a value set, at "epoch", "entity_deleted" with the "identifier" and "entity" of
+    /// a deleted entity, at "epoch", and "epoch_advanced" with the "previous" and new "epoch" when
+    /// the current epoch moves. disconnect() stops it
+    pub fn subscribe(&self, event: &str, callback: PyObject) -> PyResult<()> {
+        if !LIFECYCLE_EVENTS.contains(&event) {
+            return Err(PyValueError::new_err(format!("unknown event \"{}\", expected one of {}", event, LIFECYCLE_EVENTS.join(", "))));
+        }
+        if event == "entity_created" {
+            self.entity_store.borrow_mut().record_added();
+        }
+        self.hooks.borrow_mut().entry(event.to_string()).or_default().push(callback);
+        Ok(())
+    }
+
+    /// stop calling *callback* on *event*, return whether it was connected
+    pub fn disconnect(&self, py: Python, event: &str, callback: PyObject) -> PyResult<bool> {
+        let mut hooks = self.hooks.borrow_mut();
+        let Some(callbacks) = hooks.get_mut(event) else {
+            return Ok(false);
+        };
+        for (index, connected) in callbacks.iter().enumerate() {
+            if connected.as_ref(py).eq(callback.as_ref(py))? {
+                callbacks.remove(index);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// call *callback* with the entity, the attribute and the previous and new values each time
+    /// the current value of the *attribute* of the entity *identifier* changes, whether it is set
+    /// or the current epoch moves, by a rollback or an undo. unwatch() stops it
+    pub fn watch(&self, py: Python, identifier: &PyEntityIdentifier, attribute: String, callback: PyObject) -> PyResult<()> {
+        let entity = self.get(identifier)?;
+        let value = entity.entity.get(&attribute)?.get_value();
+        let watch = PyWatch { entity, attribute, callback, value: RefCell::new(value) };
+        self.hooks.borrow_mut().entry("watch".to_string()).or_default().push(watch.into_py(py));
+        Ok(())
+    }
+
+    /// stop calling *callback* on the changes of the *attribute* of the entity *identifier*,
+    /// return whether it was watching them
+    pub fn unwatch(&self, py: Python, identifier: &PyEntityIdentifier, attribute: &str, callback: PyObject) -> PyResult<bool> {
+        let mut hooks = self.hooks.borrow_mut();
+        let Some(watches) = hooks.get_mut("watch") else {
+            return Ok(false);
+        };
+        for (index, watch) in watches.iter().enumerate() {
+            let watch: PyRef<PyWatch> = watch.extract(py)?;
+            if *watch.entity.entity.get_identifier() == identifier.entity_identifier && watch.attribute == attribute
+                && watch.callback.as_ref(py).eq(callback.as_ref(py))? {
+                drop(watch);
+                watches.remove(index);
+                return Ok(true);
+            }
         }
+        Ok(false)
     }
 
     fn get(&self, identifier: &PyEntityIdentifier) -> Result<PyEntity, EntityError> {
-        self.entity_store.borrow().get(&identifier.entity_identifier).and_then(|entity| Ok(PyEntity {entity}))
+        self.entity_store.borrow().get(&identifier.entity_identifier).map(|entity| self.wrap(entity))
+    }
+
+    /// iterate over the entities of every model not deleted
+    fn __iter__(&self) -> PyEntityIterator {
+        let entities: Vec<PyEntity> = self.entity_store.borrow().alive_entities().into_iter().map(|entity| self.wrap(entity)).collect();
+        PyEntityIterator { entities: entities.into_iter() }
+    }
+
+    fn __len__(&self) -> usize {
+        self.entity_store.borrow().alive_count()
+    }
+
+    /// the number of entities of *model* not deleted, without evaluating a filter
+    fn count(&self, model: Model) -> usize {
+        self.entity_store.borrow().count(&model)
+    }
+
+    /// whether the entity or identifier *item* is in the store and not deleted
+    fn __contains__(&self, item: &PyAny) -> bool {
+        let identifier = match (item.extract::<PyRef<PyEntity>>(), item.extract::<PyRef<PyEntityIdentifier>>()) {
+            (Ok(entity), _) => entity.entity.get_identifier().clone(),
+            (_, Ok(identifier)) => identifier.entity_identifier.clone(),
+            _ => return false,
+        };
+        self.entity_store.borrow().get(&identifier).is_ok()
+    }
+
+    /// the entities of *model* matching the keyword arguments, as a query evaluated on each use
+    #[pyo3(signature = (model, **kwargs))]
+    fn query(&self, model: Model, kwargs: Option<&PyDict>) -> PyResult<PyQuery> {
+        Ok(PyQuery {
+            entity_store: Rc::clone(&self.entity_store),
+            hooks: Rc::clone(&self.hooks),
+            model,
+            expression: Rc::new(kwargs_expression(kwargs)?.compile()),
+            ordering: None,
+        })
+    }
+
+    /// the current epoch and the number of entities of each model
+    fn __repr__(slf: &PyCell<Self>) -> PyResult<String> {
+        let stats = slf.borrow().entity_store.borrow().stats();
+        let mut counts: Vec<(Model, usize)> = stats.entities_per_model.into_iter().collect();
+        counts.sort();
+        let counts = if counts.is_empty() {
+            "empty".to_string()
+        } else {
+            counts.iter().map(|(model, count)| format!("{}={}", model, count)).collect::<Vec<String>>().join(", ")
+        };
+        Ok(format!("<{} epoch {}: {}>", slf.get_type().name()?, slf.borrow().entity_store.borrow().current_epoch(), counts))
+    }
+
+    /// the entities of *model* matching the keyword arguments, exact values or django style
+    /// lookups: `filter("User", name="darius", age__gt=30)`
+    #[pyo3(signature = (model, **kwargs))]
+    pub fn filter(&self, py: Python, model: Model, kwargs: Option<&PyDict>) -> PyResult<Vec<PyEntity>> {
+        let expression = kwargs_expression(kwargs)?;
+        let entities = without_gil(py, &*self.entity_store, move |entity_store| entity_store.borrow().filter(model, &expression))?;
+        Ok(entities.into_iter().map(|entity| self.wrap(entity)).collect())
     }
 
-    pub fn filter(&self, model: Model) -> Result<Vec<PyEntity>, PyErr> {
-        let expression = ExactExpression::new("name".to_string(), DatabaseValue::String("darius".to_string()));
-        let result = self.entity_store.borrow().filter(model, &FilterExpression::Exact(expression));
-        match result {
-            Ok(entities) => Ok(entities.iter().map(|entity| PyEntity { entity: Rc::clone(entity) }).collect()),
-            Err(err) => Err(err.into())
+    /// the *attributes* of the entities of *model* matching the keyword arguments, as a dict of
+    /// columns, ready for `pandas.DataFrame()`. "pk" is the pk of the entities and foreign keys
+    /// hold the pk they reference. the attributes default to "pk" and the registered ones, or the
+    /// ones of the first entity
+    #[pyo3(signature = (model, attributes=None, **kwargs))]
+    pub fn columns<'py>(&self, py: Python<'py>, model: Model, attributes: Option<Vec<String>>, kwargs: Option<&PyDict>) -> PyResult<&'py PyDict> {
+        let entity_store = self.entity_store.borrow();
+        let entities = entity_store.filter(model.clone(), &kwargs_expression(kwargs)?)?;
+        let attributes = attributes.unwrap_or_else(|| default_columns(&entity_store, &model, &entities));
+        let columns = query::columns(&entity_store, &entities, &attributes)?;
+        let result = PyDict::new(py);
+        for (attribute, values) in attributes.into_iter().zip(columns) {
+            let values: Vec<PyDatabaseValue> = values.into_iter().map(PyDatabaseValue::from).collect();
+            result.set_item(attribute, values.into_py(py))?;
         }
+        Ok(result)
     }
 
-    pub fn instantiate_entity(&mut self, identifier: &PyEntityIdentifier) -> PyEntity {
-        let attributes_descriptors: Vec<AttributeDescriptor> = vec!["name", "age"].iter().map(
-            |attr| AttributeDescriptor::new(AttributeKind::Physical, attr.to_string(), DatabaseValue::String(format!("default {}", attr)))
-        ).collect();
-        let entity = self.entity_store.borrow_mut().instantiate_entity(identifier.entity_identifier.clone(), attributes_descriptors);
-        PyEntity {entity}
+    /// instantiate an entity of *model* per record of the CSV file at *path*: persisted ones
+    /// for the records with a *pk* column value, new ones otherwise. *column_mapping* gives the
+    /// attribute of each column to load, all columns are loaded as is if None. empty cells are
+    /// None, integers numbers and anything else strings. return the number of loaded records
+    #[pyo3(signature = (model, path, column_mapping=None, pk="id"))]
+    pub fn load_csv(&self, py: Python, model: Model, path: &str, column_mapping: Option<HashMap<String, String>>, pk: &str) -> PyResult<usize> {
+        let file = std::fs::File::open(path)?;
+        let count = without_gil(py, &*self.entity_store, |entity_store| csv_io::load_csv(&mut entity_store.borrow_mut(), &model, std::io::BufReader::new(file), column_mapping.as_ref(), pk))?;
+        self.notify_added(py)?;
+        Ok(count)
+    }
 
+    /// write the columns() of the entities of *model* matching the keyword arguments to the CSV
+    /// file at *path*. return the number of written records
+    #[pyo3(signature = (model, path, attributes=None, **kwargs))]
+    pub fn to_csv(&self, model: Model, path: &str, attributes: Option<Vec<String>>, kwargs: Option<&PyDict>) -> PyResult<usize> {
+        let entity_store = self.entity_store.borrow();
+        let entities = entity_store.filter(model.clone(), &kwargs_expression(kwargs)?)?;
+        let attributes = attributes.unwrap_or_else(|| default_columns(&entity_store, &model, &entities));
+        let file = std::fs::File::create(path)?;
+        Ok(csv_io::write_csv(&entity_store, &entities, &attributes, file)?)
     }
-}
 
-#[pyclass(unsendable)]
-struct PyEntity {
-    entity: Rc<Entity>,
-}
+    /// the columns() as a pyarrow RecordBatch
+    #[pyo3(signature = (model, attributes=None, **kwargs))]
+    pub fn to_arrow<'py>(&self, py: Python<'py>, model: Model, attributes: Option<Vec<String>>, kwargs: Option<&PyDict>) -> PyResult<&'py PyAny> {
+        let columns = self.columns(py, model, attributes, kwargs)?;
+        py.import("pyarrow")?.getattr("RecordBatch")?.call_method1("from_pydict", (columns,))
+    }
 
-#[pymethods]
-impl PyEntity {
-    fn get(&self, attr: &str) -> Result<PyAttribute, EntityError> {
+    /// return the entity of *model* with the greatest *attribute* value, if any
+    pub fn latest(&self, model: Model, attribute: &str) -> Result<Option<PyEntity>, EntityError> {
+        Ok(self.entity_store.borrow().latest(&model, attribute)?.map(|entity| self.wrap(entity)))
+    }
+
+    /// return the entity of *model* with the smallest *attribute* value, if any
+    pub fn earliest(&self, model: Model, attribute: &str) -> Result<Option<PyEntity>, EntityError> {
+        Ok(self.entity_store.borrow().earliest(&model, attribute)?.map(|entity| self.wrap(entity)))
+    }
+
+    /// return all entities of *model*, or of every model if none is given
+    #[pyo3(signature = (model=None))]
+    pub fn all(&self, model: Option<Model>) -> Vec<PyEntity> {
+        let entity_store = self.entity_store.borrow();
+        let entities = match model {
+            Some(model) => entity_store.all(&model),
+            None => entity_store.all_entities(),
+        };
+        entities.into_iter().map(|entity| self.wrap(entity)).collect()
+    }
+
+    /// return the `(entity, created, changed_attributes)` of the entities created or modified since *epoch*
+    pub fn changes_since(&self, epoch: Epoch) -> Vec<(PyEntity, bool, Vec<String>)> {
+        self.entity_store.borrow().changes_since(epoch).into_iter().map(
+            |change| (self.wrap(change.entity), change.created, change.changed_attributes)
+        ).collect()
+    }
+
+    /// return a dict of pk -> entity for the entities of *model* having one of the given *pks*
+    pub fn in_bulk(&self, model: Model, pks: Vec<PK>) -> HashMap<PK, PyEntity> {
+        self.entity_store.borrow().in_bulk(&model, &pks).into_iter().map(|(pk, entity)| (pk, self.wrap(entity))).collect()
+    }
+
+    /// return the entities of *model* whose *attribute* holds the pk of a *related_model*
+    /// entity having *related_attribute* equal to *value*
+    pub fn filter_related(&self, py: Python, model: Model, attribute: &str, related_model: Model, related_attribute: String, value: PyDatabaseValue) -> Result<Vec<PyEntity>, EntityError> {
+        let expression = FilterExpression::Exact(ExactExpression::new(related_attribute, value.into()));
+        let entities = without_gil(py, &*self.entity_store, move |entity_store| entity_store.borrow().filter_related(model, attribute, &related_model, &expression))?;
+        Ok(entities.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
 
-        self.entity.get(attr).and_then(|attr| Ok(PyAttribute {
-            attribute: attr.into()
-        }))
+    pub fn instantiate_entity(&mut self, py: Python, identifier: &PyEntityIdentifier, attributes: Vec<PyAttributeDescriptorArg>) -> PyResult<PyEntity> {
+        let attributes_descriptors: Vec<AttributeDescriptor> = attributes.into_iter().map(|attr| attr.0).collect();
+        let entity = self.entity_store.borrow_mut().instantiate_entity(identifier.entity_identifier.clone(), attributes_descriptors);
+        self.notify_added(py)?;
+        Ok(self.wrap(entity))
     }
 
-    fn __richcmp__(&self, other: &Self, op: CompareOp) -> PyResult<bool> {
-        match op {
-            CompareOp::Eq => Ok(self.entity.get_identifier() == other.entity.get_identifier()),
-            _ => Err(PyNotImplementedError::new_err("cannot compare it")),
+    pub fn snapshot(&self, py: Python) -> PyEntityStore {
+        PyEntityStore {
+            entity_store: Rc::new(RefCell::new(self.entity_store.borrow().snapshot())),
+            loader: self.loader.as_ref().map(|loader| loader.clone_ref(py)),
+            batch_loader: self.batch_loader.as_ref().map(|loader| loader.clone_ref(py)),
+            persistence: self.persistence.iter().map(|(model, persistence)| (model.clone(), persistence.clone_ref(py))).collect(),
+            tables: self.tables.clone(),
+            // the watches stay on the entities of this store
+            hooks: Rc::new(RefCell::new(self.hooks.borrow().iter().filter(|(event, _)| *event != "watch").map(
+                |(event, callbacks)| (event.clone(), callbacks.iter().map(|callback| callback.clone_ref(py)).collect())
+            ).collect())),
+            on_commit: self.on_commit.as_ref().map(|on_commit| on_commit.clone_ref(py)),
         }
     }
 
-}
+    /// set the pk of a persisted entity, making it reachable by model+pk
+    pub fn apply_pk(&self, identifier: &PyEntityIdentifier, pk: PK) -> Result<(), EntityError> {
+        self.entity_store.borrow_mut().apply_pk(&identifier.entity_identifier, pk)
+    }
+
+    /// return counters about the store content, as a dict
+    pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let stats = self.entity_store.borrow().stats();
+        let result = PyDict::new(py);
+        result.set_item("entities_per_model", stats.entities_per_model)?;
+        result.set_item("history_entries", stats.history_entries)?;
+        result.set_item("pk_index_size", stats.pk_index_size)?;
+        result.set_item("uuid_index_size", stats.uuid_index_size)?;
+        result.set_item("approximate_memory", stats.approximate_memory)?;
+        Ok(result)
+    }
 
+    /// estimate the bytes used by each model, as a dict of dicts by model with the number of
+    /// "entities" and the bytes of the "entities" themselves ("entity_bytes"), of the histories of
+    /// their values ("history_bytes") and of their index entries ("index_bytes"), and the "total" of the three
+    pub fn memory_report<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        for (model, memory) in self.entity_store.borrow().memory_per_model() {
+            let report = PyDict::new(py);
+            report.set_item("entities", memory.entities)?;
+            report.set_item("entity_bytes", memory.entity_bytes)?;
+            report.set_item("history_bytes", memory.history_bytes)?;
+            report.set_item("index_bytes", memory.index_bytes)?;
+            report.set_item("total", memory.total())?;
+            result.set_item(model, report)?;
+        }
+        Ok(result)
+    }
 
-#[pyclass(unsendable)]
-struct PyAttribute {
-    attribute: Rc<PhysicalAttribute>,
-}
+    /// a human readable report of the store: its epochs, every entity with its state and
+    /// its values, the initial one of the modified values, then the changes the next flush would save
+    pub fn dump_debug(&self) -> String {
+        dump_debug(&self.entity_store.borrow())
+    }
 
+    /// the counters of the work done by the store since it was created or reset_metrics(), as a
+    /// dict: the executed "filters", the entities they "scanned" and the "scanned_per_filter"
+    /// histogram as (upper bound, count) buckets, the last bound None, the lookups by identifier
+    /// finding the entity in the index ("index_hits") or not ("index_misses"), the related
+    /// entities found in the store ("cache_hits") or to load ("cache_misses") by get_related() and
+    /// prefetch(), and the "history_entries" of the attributes
+    pub fn metrics<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let metrics = self.entity_store.borrow().metrics();
+        let bounds = SCAN_BUCKETS.iter().map(|bound| Some(*bound)).chain([None]);
+        let result = PyDict::new(py);
+        result.set_item("filters", metrics.filters)?;
+        result.set_item("scanned", metrics.scanned)?;
+        result.set_item("scanned_per_filter", bounds.zip(metrics.scanned_per_filter).collect::<Vec<(Option<usize>, usize)>>())?;
+        result.set_item("index_hits", metrics.index_hits)?;
+        result.set_item("index_misses", metrics.index_misses)?;
+        result.set_item("cache_hits", metrics.cache_hits)?;
+        result.set_item("cache_misses", metrics.cache_misses)?;
+        result.set_item("history_entries", metrics.history_entries)?;
+        Ok(result)
+    }
 
-#[pymethods]
-impl PyAttribute {
-    #[getter]
-    fn initial(&self) -> PyDatabaseValue {
-        self.attribute.get_initial().into()
+    pub fn reset_metrics(&self) {
+        self.entity_store.borrow().reset_metrics();
     }
 
-    #[getter]
-    fn value(&self) -> PyDatabaseValue {
-        println!("got value {:?}", <DatabaseValue as Into<PyDatabaseValue>>::into(self.attribute.get_value()));
-        self.attribute.get_value().into()
+    /// record the last *capacity* filters and counts, like django's connection.queries, see
+    /// queries(). a capacity of 0 stops the recording
+    #[pyo3(signature = (capacity=1000))]
+    pub fn enable_query_log(&self, capacity: usize) {
+        self.entity_store.borrow_mut().enable_query_log(capacity);
+    }
+
+    /// the recorded filters and counts, oldest first, as dicts with the "operation", the "model",
+    /// the normalized "expression", the number of "results" and the "duration" in seconds
+    pub fn queries<'py>(&self, py: Python<'py>) -> PyResult<Vec<&'py PyDict>> {
+        self.entity_store.borrow().query_log().into_iter().map(|entry| {
+            let result = PyDict::new(py);
+            result.set_item("operation", entry.operation)?;
+            result.set_item("model", entry.model)?;
+            result.set_item("expression", entry.expression)?;
+            result.set_item("duration", entry.duration.as_secs_f64())?;
+            result.set_item("results", entry.results)?;
+            Ok(result)
+        }).collect()
     }
 
-    fn set_value(&self, value: PyDatabaseValue, epoch: Epoch) {
-        self.attribute.set_value(value.into(), epoch);
+    pub fn clear_query_log(&self) {
+        self.entity_store.borrow().clear_query_log();
     }
 
+    /// check the relations between the entities, returning a dict with the "dangling" foreign keys
+    /// and "deleted_memberships" as (entity, attribute) lists, and the "one_to_one_duplicates" as
+    /// (attribute, referenced entity, referencing entities) list. all lists are empty if consistent
+    pub fn check_integrity<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let report = self.entity_store.borrow().check_integrity()?;
+        let links = |links: Vec<(Rc<Entity>, String)>| -> Vec<(PyEntity, String)> {
+            links.into_iter().map(|(entity, attribute)| (self.wrap(entity), attribute)).collect()
+        };
+        let result = PyDict::new(py);
+        result.set_item("dangling", links(report.dangling).into_py(py))?;
+        result.set_item("deleted_memberships", links(report.deleted_memberships).into_py(py))?;
+        let duplicates: Vec<(String, PyEntity, Vec<PyEntity>)> = report.one_to_one_duplicates.into_iter().map(
+            |(attribute, target, sources)| (attribute, self.wrap(target), sources.into_iter().map(|entity| self.wrap(entity)).collect())
+        ).collect();
+        result.set_item("one_to_one_duplicates", duplicates.into_py(py))?;
+        Ok(result)
+    }
 
+    /// return a read-only view of the store as it was at *epoch*
+    pub fn at(&self, epoch: Epoch) -> PyEntityStoreView {
+        PyEntityStoreView {
+            entity_store: Rc::clone(&self.entity_store),
+            epoch_ptr: Rc::new(EpochPtr::new(epoch)),
+        }
+    }
 
-    fn __str__(slf: &PyCell<Self>) -> PyResult<String> {
-        let attr = &slf.borrow().attribute;
-        Ok(attr.to_string())
+    /// return a context manager reading the values as they were at *epoch* in the enclosed block,
+    /// through the entities of the store. nothing can be written meanwhile
+    pub fn at_epoch(&self, epoch: Epoch) -> PyEpochScope {
+        PyEpochScope {
+            entity_store: Rc::clone(&self.entity_store),
+            epoch,
+        }
     }
-    fn __repr__(slf: &PyCell<Self>) -> PyResult<String> {
 
-        let class_name: String = slf.get_type().name()?.to_string();
+    /// return a context manager running the enclosed block in a transaction,
+    /// committed on success and rolled back on exception
+    pub fn transaction(&self) -> PyTransaction {
+        PyTransaction {
+            entity_store: Rc::clone(&self.entity_store),
+            hooks: Rc::clone(&self.hooks),
+        }
+    }
 
-        Ok(format!("<{} {}>", class_name, slf.borrow().attribute.to_string()))
+    /// start a transaction and return the epoch its values must be written at
+    pub fn begin(&self, py: Python) -> PyResult<Epoch> {
+        self.moving_epoch(py, |entity_store| entity_store.begin())
     }
-}
 
-#[pyfunction]
-fn create_database_value(type_: &str) -> PyResult<PyDatabaseValue> {
+    pub fn commit(&self, py: Python) -> PyResult<()> {
+        self.moving_epoch(py, |entity_store| entity_store.commit())
+    }
 
-    if type_ == "Number" {
-        Ok(PyDatabaseValue::Number(42))
-    } else if type_ == "String" {
-        Ok(PyDatabaseValue::String("world".to_string()))
-    } else {
-        Err(PyValueError::new_err(format!("unknown type: {}", type_)))
+    /// discard the values and entities written since begin()
+    pub fn rollback(&self, py: Python) -> PyResult<()> {
+        self.moving_epoch(py, |entity_store| entity_store.rollback())
     }
-}
 
+    /// go back to before the last committed transaction, return False if there is nothing to undo
+    pub fn undo(&self, py: Python) -> PyResult<bool> {
+        self.moving_epoch(py, |entity_store| entity_store.undo())
+    }
+
+    /// go forward to after the last undone transaction, return False if there is nothing to redo
+    pub fn redo(&self, py: Python) -> PyResult<bool> {
+        self.moving_epoch(py, |entity_store| entity_store.redo())
+    }
+
+    /// mark the state of the running transaction and return a token for rollback_to()
+    pub fn savepoint(&self, py: Python) -> PyResult<Epoch> {
+        self.moving_epoch(py, |entity_store| entity_store.savepoint())
+    }
+
+    /// discard the values and entities written since the savepoint *token*
+    pub fn rollback_to(&self, py: Python, token: Epoch) -> PyResult<()> {
+        self.moving_epoch(py, |entity_store| entity_store.rollback_to(token))
+    }
+
+    /// label the current state with *label* and return its epoch
+    pub fn checkpoint(&self, py: Python, label: &str) -> PyResult<Epoch> {
+        self.moving_epoch(py, |entity_store| Ok(entity_store.checkpoint(label)))
+    }
+
+    /// the epoch labelled with *label*
+    pub fn checkpoint_epoch(&self, label: &str) -> Result<Epoch, EntityError> {
+        self.entity_store.borrow().checkpoint_epoch(label)
+    }
+
+    /// discard the values and entities written since the checkpoint *label*
+    pub fn rollback_to_checkpoint(&self, py: Python, label: &str) -> PyResult<()> {
+        self.moving_epoch(py, |entity_store| entity_store.rollback_to_checkpoint(label))
+    }
+
+    /// same as changes_since(), from the checkpoint *label*
+    pub fn changes_since_checkpoint(&self, label: &str) -> Result<Vec<(PyEntity, bool, Vec<String>)>, EntityError> {
+        Ok(self.entity_store.borrow().changes_since_checkpoint(label)?.into_iter().map(
+            |change| (self.wrap(change.entity), change.created, change.changed_attributes)
+        ).collect())
+    }
+
+    /// the entity referenced by the foreign key *attribute* of *entity*, None if it's empty.
+    /// if it's missing from the store, it's fetched with the loader and instantiated
+    pub fn get_related(&self, py: Python, entity: PyRef<PyEntity>, attribute: &str) -> PyResult<Option<PyEntity>> {
+        let related = entity.entity.get_related(&self.entity_store.borrow(), attribute);
+        match &related {
+            Ok(Some(_)) => self.entity_store.borrow().record_related(1, 0),
+            Err(EntityError::EntityNotFound(_)) => self.entity_store.borrow().record_related(0, 1),
+            _ => {},
+        }
+        match related {
+            Err(EntityError::EntityNotFound(identifier)) if identifier.has_applied_pk() && self.loader.is_some() => {
+                let model = identifier.get_model().to_string();
+                let pk = identifier.get_applied_pk()?;
+                let fetched = self.fetch(py, model, vec![pk])?.pop();
+                self.notify_added(py)?;
+                match fetched {
+                    Some(entity) => Ok(Some(self.wrap(entity))),
+                    None => Err(EntityError::EntityNotFound(identifier).into()),
+                }
+            },
+            related => Ok(related?.map(|entity| self.wrap(entity))),
+        }
+    }
+
+    /// set the callable fetching the related entities missing from the store: called with the
+    /// model and pk, it returns the attributes as given to instantiate_entity(), or None.
+    /// *batch_loader*, if given, is called instead by prefetch() with the model and a list of pks,
+    /// and returns a dict of pk -> attributes
+    #[pyo3(signature = (loader, batch_loader=None))]
+    pub fn set_loader(&mut self, loader: Option<PyObject>, batch_loader: Option<PyObject>) {
+        self.loader = loader;
+        self.batch_loader = batch_loader;
+    }
+
+    /// set the callables persisting the entities of *model* on flush(): *insert* is called with
+    /// each new entity and returns its pk, *update* with each modified entity and the names of
+    /// its modified attributes, *delete* with each deleted entity. *bulk_insert*, if given, is
+    /// called instead of *insert* with lists of at most *batch_size* new entities, like django's
+    /// bulk_create(), and returns their pks in the same order
+    #[pyo3(signature = (model, insert=None, update=None, delete=None, bulk_insert=None, batch_size=100))]
+    pub fn register_persistence(&mut self, model: Model, insert: Option<PyObject>, update: Option<PyObject>, delete: Option<PyObject>, bulk_insert: Option<PyObject>, batch_size: usize) {
+        self.persistence.insert(model, Persistence { insert, update, delete, bulk_insert, batch_size: batch_size.max(1) });
+    }
+
+    /// defer the flushes to the commit of the enclosing database transaction: flush() passes
+    /// flush_now() to *on_commit*, like django's `transaction.on_commit`, instead of saving the
+    /// changes, so nothing is written if the transaction rolls back. None flushes immediately again
+    #[pyo3(signature = (on_commit))]
+    pub fn set_on_commit(&mut self, on_commit: Option<PyObject>) {
+        self.on_commit = on_commit;
+    }
+
+    /// flush_now(), or queue it with the on_commit callable if set, see set_on_commit(). return
+    /// the counts of flush_now(), None if queued
+    pub fn flush<'py>(slf: &'py PyCell<Self>, py: Python<'py>) -> PyResult<Option<&'py PyDict>> {
+        let on_commit = slf.borrow().on_commit.as_ref().map(|on_commit| on_commit.clone_ref(py));
+        match on_commit {
+            // queued again on each flush: the callables of a rolled back transaction are dropped
+            // and the next commit flushes all the changes made since the last flush anyway
+            Some(on_commit) => {
+                on_commit.call1(py, (slf.getattr("flush_now")?,))?;
+                Ok(None)
+            },
+            None => slf.borrow().flush_now(py).map(Some),
+        }
+    }
+
+    /// save the changes made since the last flush with the persistence callbacks: the new entities
+    /// are inserted and given the returned pks, the referenced ones first, then the modified ones
+    /// updated and the deleted ones deleted, the referencing ones first. if a callback fails, the
+    /// flush can be retried. return the number of entities inserted, updated and deleted.
+    /// versioned entities are updated with their version incremented, see set_version_attribute()
+    pub fn flush_now<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        if self.entity_store.borrow().in_transaction() {
+            return Err(EntityError::TransactionAlreadyStarted.into());
+        }
+        let start = std::time::Instant::now();
+        // the check runs the validators, some of them written in python
+        let changeset = {
+            let entity_store = self.entity_store.borrow();
+            let changeset = entity_store.changeset()?;
+            entity_store.check_changeset(&changeset).map(|_| changeset)?
+        };
+        let mut flushed = vec![];
+        // fail before saving anything if a callback is missing
+        for entity in changeset.created.iter() {
+            self.persistence_callback(entity, "insert", |persistence| if persistence.bulk_insert.is_some() { &persistence.bulk_insert } else { &persistence.insert })?;
+        }
+        for (entity, _) in changeset.updated.iter() {
+            self.persistence_callback(entity, "update", |persistence| &persistence.update)?;
+        }
+        for entity in changeset.deleted.iter() {
+            self.persistence_callback(entity, "delete", |persistence| &persistence.delete)?;
+        }
+        // the store isn't borrowed while the callbacks run, so they can use it
+        let mut created = changeset.created.as_slice();
+        while let Some(first) = created.first() {
+            let model = first.get_identifier().get_model();
+            let persistence = &self.persistence[model];
+            // the consecutive new entities of a model are inserted in batches
+            let batch_len = match persistence.bulk_insert {
+                Some(_) => created.iter().take(persistence.batch_size).take_while(|entity| entity.get_identifier().get_model() == model).count(),
+                None => 1,
+            };
+            let (batch, rest) = created.split_at(batch_len);
+            let pks: Vec<PK> = match (persistence.bulk_insert.as_ref(), persistence.insert.as_ref()) {
+                (Some(bulk_insert), _) => {
+                    let entities: Vec<PyEntity> = batch.iter().map(|entity| self.wrap(Rc::clone(entity))).collect();
+                    bulk_insert.call1(py, (entities,))?.extract(py)?
+                },
+                (None, Some(insert)) => vec![insert.call1(py, (self.wrap(Rc::clone(first)),))?.extract(py)?],
+                (None, None) => unreachable!("checked above"),
+            };
+            if pks.len() != batch.len() {
+                return Err(PyValueError::new_err(format!("{} pks returned for {} inserted {} entities", pks.len(), batch.len(), model)));
+            }
+            for (entity, pk) in batch.iter().zip(pks) {
+                self.entity_store.borrow_mut().apply_pk(entity.get_identifier(), pk)?;
+                let mut fields: Vec<String> = entity.iter_attributes().map(|(name, _)| name.to_string()).collect();
+                fields.sort();
+                flushed.push((Rc::clone(entity), fields));
+            }
+            created = rest;
+        }
+        for (entity, changed_attributes) in changeset.updated.iter() {
+            let update = self.persistence_callback(entity, "update", |persistence| &persistence.update)?;
+            let mut changed_attributes = changed_attributes.clone();
+            let version_attribute = self.entity_store.borrow().get_version_attribute(entity.get_identifier().get_model()).cloned();
+            let version = self.entity_store.borrow().increment_version(entity)?;
+            if let Some(attribute) = version_attribute.as_ref().filter(|attribute| !changed_attributes.contains(attribute)) {
+                changed_attributes.push(attribute.clone());
+            }
+            // the update of a versioned entity returns the number of rows it updated, 0 meaning
+            // the row changed since it was read
+            let updated: Option<usize> = update.call1(py, (self.wrap(Rc::clone(entity)), changed_attributes.clone()))?.extract(py)?;
+            if let (Some(attribute), Some(version), Some(0)) = (version_attribute, version, updated) {
+                self.entity_store.borrow().set_attribute(entity, &attribute, DatabaseValue::Number(version))?;
+                return Err(EntityError::StaleEntity(entity.get_identifier().clone()).into());
+            }
+            flushed.push((Rc::clone(entity), changed_attributes));
+        }
+        for entity in changeset.deleted.iter() {
+            let delete = self.persistence_callback(entity, "delete", |persistence| &persistence.delete)?;
+            delete.call1(py, (self.wrap(Rc::clone(entity)),))?;
+            flushed.push((Rc::clone(entity), vec![]));
+        }
+        self.moving_epoch(py, |entity_store| entity_store.mark_flushed())?;
+        for (entity, fields) in flushed {
+            notify(py, &self.hooks, "flushed", &self.wrap(entity), fields)?;
+        }
+        tracing::info!(inserted = changeset.created.len(), updated = changeset.updated.len(), deleted = changeset.deleted.len(), elapsed = ?start.elapsed(), "flush");
+        let result = PyDict::new(py);
+        result.set_item("inserted", changeset.created.len())?;
+        result.set_item("updated", changeset.updated.len())?;
+        result.set_item("deleted", changeset.deleted.len())?;
+        Ok(result)
+    }
+
+    /// map *model* to the database *table*, *columns* giving the column of each attribute to write
+    /// in order. names are written as is in the statements, quoted if needed
+    #[pyo3(signature = (model, table, columns, pk_column="id".to_string()))]
+    pub fn register_table(&mut self, model: Model, table: String, columns: &PyDict, pk_column: String) -> PyResult<()> {
+        let columns = columns.iter().map(|(attribute, column)| Ok((attribute.extract()?, column.extract()?))).collect::<PyResult<Vec<(String, String)>>>()?;
+        self.tables.insert(model, TableMapping { table, pk_column, columns });
+        Ok(())
+    }
+
+    /// the store as a JSON document: the entities with their identifiers and attribute
+    /// histories, and the epoch pointers. *indent* pretty prints it
+    #[pyo3(signature = (indent=false))]
+    pub fn to_json(&self, indent: bool) -> PyResult<String> {
+        let document = json::to_json(&self.entity_store.borrow())?;
+        let result = if indent { serde_json::to_string_pretty(&document) } else { serde_json::to_string(&document) };
+        result.map_err(|error| PyValueError::new_err(error.to_string()))
+    }
+
+    /// the store described by a document returned by to_json(). the callbacks and table
+    /// mappings aren't serialized, they must be registered again
+    #[staticmethod]
+    pub fn from_json(document: &str) -> PyResult<PyEntityStore> {
+        let document: serde_json::Value = serde_json::from_str(document).map_err(|error| PyValueError::new_err(error.to_string()))?;
+        Ok(PyEntityStore {
+            entity_store: Rc::new(RefCell::new(json::from_json(&document)?)),
+            ..PyEntityStore::new()
+        })
+    }
+
+    /// the store as to_json() document encoded with MessagePack
+    pub fn to_msgpack<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        Ok(PyBytes::new(py, &json::to_msgpack(&self.entity_store.borrow())?))
+    }
+
+    /// the store encoded by to_msgpack(). like with from_json(), the callbacks and table
+    /// mappings must be registered again
+    #[staticmethod]
+    pub fn from_msgpack(bytes: &[u8]) -> PyResult<PyEntityStore> {
+        Ok(PyEntityStore {
+            entity_store: Rc::new(RefCell::new(json::from_msgpack(bytes)?)),
+            ..PyEntityStore::new()
+        })
+    }
+
+    /// write a versioned binary snapshot of the store, histories included, to the file at
+    /// *path*. the file is replaced once fully written, so a crash never leaves it truncated
+    pub fn dump(&self, path: &str) -> PyResult<()> {
+        let partial = format!("{}.partial", path);
+        json::dump(&self.entity_store.borrow(), std::io::BufWriter::new(std::fs::File::create(&partial)?))?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// the store written by dump() to the file at *path*. like with from_json(), the callbacks
+    /// and table mappings must be registered again
+    #[staticmethod]
+    pub fn restore(path: &str) -> PyResult<PyEntityStore> {
+        let file = std::fs::File::open(path)?;
+        Ok(PyEntityStore {
+            entity_store: Rc::new(RefCell::new(json::restore(std::io::BufReader::new(file))?)),
+            ..PyEntityStore::new()
+        })
+    }
+
+    /// the entity of the store with the identifier of *entity*, like an unpickled one. it's
+    /// fetched with the loader if it's missing
+    pub fn attach(&self, py: Python, entity: PyRef<PyEntity>) -> PyResult<PyEntity> {
+        let identifier = entity.entity.get_identifier();
+        let found = self.entity_store.borrow().get(identifier);
+        match found {
+            Err(EntityError::EntityNotFound(_)) if identifier.has_applied_pk() && (self.loader.is_some() || self.batch_loader.is_some()) => {
+                let fetched = self.fetch(py, identifier.get_model().to_string(), vec![identifier.get_applied_pk()?])?.pop();
+                self.notify_added(py)?;
+                match fetched {
+                    Some(fetched) => Ok(self.wrap(fetched)),
+                    None => Err(EntityError::EntityNotFound(identifier.clone()).into()),
+                }
+            },
+            found => Ok(self.wrap(found?)),
+        }
+    }
+
+    /// the snapshot() of the store
+    pub fn __deepcopy__(&self, py: Python, _memo: &PyAny) -> PyEntityStore {
+        self.snapshot(py)
+    }
+
+    /// pickle the store as its to_msgpack() encoding
+    pub fn __reduce__(&self, py: Python) -> PyResult<(PyObject, (PyObject,))> {
+        let from_msgpack = py.get_type::<PyEntityStore>().getattr("from_msgpack")?;
+        Ok((from_msgpack.into(), (self.to_msgpack(py)?.into(),)))
+    }
+
+    /// the changes made since the last flush as `(sql, params)` statements, to run with
+    /// cursor.executemany() before calling mark_flushed(). the new entities referenced by others
+    /// must be given a pk first
+    pub fn sql_statements(&self) -> Result<Vec<PyStatement>, EntityError> {
+        let entity_store = self.entity_store.borrow();
+        let changeset = entity_store.changeset()?;
+        entity_store.check_changeset(&changeset)?;
+        let statements = sql::render(&entity_store, &changeset, &self.tables)?;
+        Ok(statements.into_iter().map(|statement| (
+            statement.sql,
+            statement.params.into_iter().map(|params| params.into_iter().map(PyDatabaseValue::from).collect()).collect(),
+        )).collect())
+    }
+
+    /// record the current state as persisted, following flushes only save the changes made from now on
+    pub fn mark_flushed(&self, py: Python) -> PyResult<Epoch> {
+        self.moving_epoch(py, |entity_store| entity_store.mark_flushed())
+    }
+
+    /// resolve the foreign keys listed in *lookups* ("author", "author__publisher") for all
+    /// *entities* level by level, loading the missing related entities in batch
+    pub fn prefetch(&self, py: Python, entities: Vec<PyRef<PyEntity>>, lookups: Vec<String>) -> PyResult<()> {
+        let entities = to_entities(entities);
+        for lookup in lookups {
+            let mut level = entities.clone();
+            for attribute in lookup.split("__") {
+                let batch = self.entity_store.borrow().resolve_related(&level, attribute)?;
+                let missing = batch.missing.values().map(Vec::len).sum();
+                self.entity_store.borrow().record_related(batch.related.len(), missing);
+                level = batch.related;
+                for (model, pks) in batch.missing {
+                    level.extend(self.fetch(py, model, pks)?);
+                }
+            }
+        }
+        self.notify_added(py)
+    }
+
+    /// declare the attributes of *model*, given like to instantiate_entity() with their default
+    /// value, and the sets of attributes whose values must be *unique* together
+    #[pyo3(signature = (model, attributes, unique=vec![]))]
+    pub fn register_model(&self, model: Model, attributes: Vec<PyAttributeDescriptorArg>, unique: Vec<Vec<String>>) {
+        let attributes = attributes.into_iter().map(|attr| attr.0).collect();
+        self.entity_store.borrow_mut().register_model(model, ModelSchema { attributes, unique });
+    }
+
+    /// the schema of *model* as a dict with the `(name, kind, default)` "attributes", the
+    /// "unique" attribute sets and the "field_types" of the typed attributes by name
+    pub fn model_schema<'py>(&self, py: Python<'py>, model: Model) -> PyResult<&'py PyDict> {
+        let entity_store = self.entity_store.borrow();
+        let schema = entity_store.get_model_schema(&model)?;
+        let attributes: Vec<(String, String, PyDatabaseValue)> = schema.attributes.iter().map(
+            |attribute| (attribute.get_name().to_string(), format_attribute_kind(attribute.get_kind()), attribute.get_initial().clone().into())
+        ).collect();
+        let result = PyDict::new(py);
+        result.set_item("attributes", attributes.into_py(py))?;
+        result.set_item("unique", schema.unique.clone())?;
+        let field_types: HashMap<&str, &str> = schema.attributes.iter().filter(|attribute| attribute.get_field_type() != FieldType::Any).map(
+            |attribute| (attribute.get_name(), format_field_type(attribute.get_field_type()))
+        ).collect();
+        result.set_item("field_types", field_types)?;
+        Ok(result)
+    }
+
+    /// instantiate an entity of the registered *model* with the keyword arguments as values,
+    /// the other attributes taking their default value. it's persisted if *pk* is given
+    #[pyo3(signature = (model, pk=None, **values))]
+    pub fn instantiate_model(&self, py: Python, model: Model, pk: Option<PK>, values: Option<HashMap<String, &PyAny>>) -> PyResult<PyEntity> {
+        let values = {
+            let entity_store = self.entity_store.borrow();
+            let schema = entity_store.get_model_schema(&model)?;
+            values.unwrap_or_default().into_iter().map(|(name, value)| {
+                let field_type = schema.attributes.iter().find(|attribute| attribute.get_name() == name).map(AttributeDescriptor::get_field_type).unwrap_or_default();
+                Ok((name, to_database_value(field_type, value)?.into()))
+            }).collect::<PyResult<HashMap<String, DatabaseValue>>>()?
+        };
+        let identifier = match pk {
+            Some(pk) => EntityIdentifier::new_persisted(model, pk),
+            None => EntityIdentifier::new(model),
+        };
+        let entity = self.entity_store.borrow_mut().instantiate_model(identifier, values)?;
+        self.notify_added(py)?;
+        Ok(self.wrap(entity))
+    }
+
+    /// declare the many to many relation *name* of *model*, realized by the *through* model
+    /// entities holding the *source_attribute* and *target_attribute* foreign keys
+    pub fn register_many_to_many(&self, model: Model, name: String, through: Model, source_attribute: String, target_model: Model, target_attribute: String) {
+        self.entity_store.borrow_mut().register_many_to_many(model, name, ManyToMany { through, source_attribute, target_model, target_attribute });
+    }
+
+    /// check the *constraint* ("unique", "foreign_key" or "check") of *model* on the given
+    /// *attributes*, or the check constraint named by the only one, on each write of their values
+    /// ("immediate") or on flush only ("deferred", the default), letting imports go through
+    /// inconsistent intermediate states. violations raise IntegrityError
+    pub fn set_constraint_check(&self, model: Model, constraint: &str, attributes: Vec<String>, check: &str) -> PyResult<()> {
+        let constraint = match (constraint, attributes.as_slice()) {
+            ("unique", _) => Constraint::Unique(model, attributes),
+            ("foreign_key", [attribute]) => Constraint::ForeignKey(model, attribute.clone()),
+            ("foreign_key", _) => return Err(PyValueError::new_err("a foreign key constraint is on a single attribute")),
+            ("check", [name]) => Constraint::Check(model, name.clone()),
+            ("check", _) => return Err(PyValueError::new_err("a check constraint is given by its name")),
+            _ => return Err(PyValueError::new_err(format!("unknown constraint: {}", constraint))),
+        };
+        self.entity_store.borrow_mut().set_constraint_check(constraint, parse_constraint_check(check)?);
+        Ok(())
+    }
+
+    /// the messages of the alive entities of *model*, or of every model, that aren't valid, by
+    /// identifier: `{identifier: {attribute: [message, ...]}}`, see PyEntity.full_clean()
+    #[pyo3(signature = (model=None))]
+    pub fn validate<'py>(&self, py: Python<'py>, model: Option<Model>) -> PyResult<&'py PyDict> {
+        let invalid = self.entity_store.borrow().validate(model.as_ref())?;
+        let result = PyDict::new(py);
+        for (entity, messages) in invalid {
+            result.set_item(PyEntityIdentifier { entity_identifier: entity.get_identifier().clone() }.into_py(py), messages)?;
+        }
+        Ok(result)
+    }
+
+    /// validate the entities of *model* as a whole, for the rules over several attributes:
+    /// *validator* is called with the dict of their values on flush and by validate(), raising
+    /// an exception to refuse them. its message is given under "__all__", unless it's a
+    /// ValidationError carrying the messages by attribute as `message_dict`
+    pub fn add_entity_validator(&self, model: Model, validator: PyObject) {
+        self.entity_store.borrow_mut().add_entity_validator(model, python_entity_validator(validator));
+    }
+
+    /// record the values set from now on in an append only audit log, see audit_log()
+    pub fn enable_audit_log(&self) {
+        self.entity_store.borrow_mut().enable_audit_log();
+    }
+
+    /// give *who* and *when*, a datetime, to the writes recorded from now on
+    #[pyo3(signature = (who=None, when=None))]
+    pub fn set_audit_context(&self, who: Option<String>, when: Option<&PyAny>) -> PyResult<()> {
+        let when = match when.map(|when| to_database_value(FieldType::DateTime, when)).transpose()? {
+            Some(PyDatabaseValue::String(when)) => Some(when),
+            _ => None,
+        };
+        self.entity_store.borrow_mut().set_audit_context(who, when);
+        Ok(())
+    }
+
+    /// the recorded writes in order, of the entity *identifier* and its *attribute* if given, as
+    /// dicts with the "identifier", "attribute", "previous" and new "value", the "epoch" of the
+    /// write, and "who" made it "when", see set_audit_context()
+    #[pyo3(signature = (identifier=None, attribute=None))]
+    pub fn audit_log<'py>(&self, py: Python<'py>, identifier: Option<PyRef<PyEntityIdentifier>>, attribute: Option<&str>) -> PyResult<Vec<&'py PyDict>> {
+        let entries = self.entity_store.borrow().audit_log().into_iter().filter(|entry| {
+            identifier.as_ref().is_none_or(|identifier| entry.identifier == identifier.entity_identifier)
+                && attribute.is_none_or(|attribute| entry.attribute == attribute)
+        });
+        entries.map(|entry| {
+            let result = PyDict::new(py);
+            result.set_item("identifier", PyEntityIdentifier { entity_identifier: entry.identifier.clone() }.into_py(py))?;
+            result.set_item("attribute", &entry.attribute)?;
+            result.set_item("previous", PyDatabaseValue::from(entry.previous.clone()).into_py(py))?;
+            result.set_item("value", PyDatabaseValue::from(entry.value.clone()).into_py(py))?;
+            result.set_item("epoch", entry.epoch)?;
+            result.set_item("who", &entry.who)?;
+            result.set_item("when", from_database_value(py, FieldType::DateTime, entry.when.clone().map_or(DatabaseValue::None, DatabaseValue::String))?)?;
+            Ok(result)
+        }).collect()
+    }
+
+    /// write the audit log as CSV to *path*, with the model, pk and uuid of the written entities,
+    /// return the number of written records
+    pub fn export_audit_log(&self, path: &str) -> PyResult<usize> {
+        let file = std::fs::File::create(path)?;
+        Ok(csv_io::write_audit_log(&self.entity_store.borrow().audit_log(), file)?)
+    }
+
+    /// require the entities of *model* to match the keyword arguments, exact values or django
+    /// style lookups like filter(), checked like the other constraints under *name*:
+    /// `add_check_constraint("Book", "positive_pages", pages__gt=0)`. replace the check
+    /// constraint of this *name*
+    #[pyo3(signature = (model, name, **kwargs))]
+    pub fn add_check_constraint(&self, model: Model, name: String, kwargs: Option<&PyDict>) -> PyResult<()> {
+        let expression = kwargs_expression(kwargs)?;
+        self.entity_store.borrow_mut().add_check_constraint(model, name, expression);
+        Ok(())
+    }
+
+    /// lock the updates of *model* optimistically with its *attribute* version: flush()
+    /// increments it and the update callback must only update the row still holding the
+    /// previous version, returning the number of updated rows. StaleEntityError is raised if none
+    pub fn set_version_attribute(&self, model: Model, attribute: String) {
+        self.entity_store.borrow_mut().set_version_attribute(model, attribute);
+    }
+
+    /// order the entities of *model* referencing an entity with *attribute* by their *ordering*
+    /// attribute, descending if prefixed with "-". many to many relations are ordered by the
+    /// attribute of their through model, given a position when missing
+    pub fn set_related_ordering(&self, model: Model, attribute: String, ordering: String) {
+        self.entity_store.borrow_mut().set_related_ordering(model, attribute, ordering);
+    }
+
+    /// index the values of *attribute* of the entities of *model*, so the filters on its exact
+    /// value or on a list of values (`__in`) look the entities up instead of scanning the model.
+    /// *sorted* indexes serve the range lookups (`__gt`, `__lte`...) and PyQuery.order_by() too
+    #[pyo3(signature = (model, attribute, sorted=false))]
+    pub fn add_index(&self, model: Model, attribute: String, sorted: bool) {
+        let kind = if sorted { IndexKind::Sorted } else { IndexKind::Hash };
+        self.entity_store.borrow_mut().add_index(model, attribute, kind);
+    }
+
+    /// link *source* to *target* through the relation *name*, the keyword arguments are the
+    /// attributes of the created through entity
+    #[pyo3(signature = (source, name, target, **attributes))]
+    pub fn add_membership(&self, py: Python, source: PyRef<PyEntity>, name: &str, target: PyRef<PyEntity>, attributes: Option<HashMap<String, PyDatabaseValue>>) -> PyResult<PyEntity> {
+        let entity = self.entity_store.borrow_mut().add_membership(&source.entity, name, &target.entity, physical_attributes(attributes))?;
+        self.notify_added(py)?;
+        Ok(self.wrap(entity))
+    }
+
+    /// delete the through entities linking *source* and *target*, return their number
+    pub fn remove_membership(&self, source: PyRef<PyEntity>, name: &str, target: PyRef<PyEntity>) -> Result<usize, EntityError> {
+        self.entity_store.borrow().remove_membership(&source.entity, name, &target.entity)
+    }
+
+    /// the through entities of the relation *name* of *source*
+    pub fn memberships(&self, source: PyRef<PyEntity>, name: &str) -> Result<Vec<PyEntity>, EntityError> {
+        Ok(self.entity_store.borrow().memberships(&source.entity, name)?.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
+
+    /// the entities related to *source* through the relation *name*, the keyword arguments
+    /// filter on the attributes of the through entities
+    #[pyo3(signature = (source, name, **kwargs))]
+    pub fn many_to_many(&self, source: PyRef<PyEntity>, name: &str, kwargs: Option<&PyDict>) -> PyResult<Vec<PyEntity>> {
+        let expression = kwargs_expression(kwargs)?;
+        Ok(self.entity_store.borrow().many_to_many(&source.entity, name, &expression)?.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
+
+    /// the entities of *model* whose foreign key *attribute* references *entity*
+    pub fn related(&self, entity: PyRef<PyEntity>, model: Model, attribute: &str) -> Vec<PyEntity> {
+        entity.entity.related(&self.entity_store.borrow(), &model, attribute).into_iter().map(|entity| self.wrap(entity)).collect()
+    }
+
+    /// delete *entity* at the current epoch, with the entities referencing it according to the
+    /// on_delete behavior of their foreign keys. return the number of deleted entities
+    pub fn delete(&self, py: Python, entity: PyRef<PyEntity>) -> PyResult<usize> {
+        let deleted = self.entity_store.borrow().delete(&entity.entity)?;
+        let epoch = self.entity_store.borrow().current_epoch();
+        for entity in deleted.iter() {
+            notify(py, &self.hooks, "deleted", &self.wrap(Rc::clone(entity)), vec![])?;
+            if subscribed(&self.hooks, "entity_deleted") {
+                let payload = PyDict::new(py);
+                payload.set_item("identifier", PyEntityIdentifier { entity_identifier: entity.get_identifier().clone() }.into_py(py))?;
+                payload.set_item("entity", self.wrap(Rc::clone(entity)).into_py(py))?;
+                payload.set_item("epoch", epoch)?;
+                publish(py, &self.hooks, "entity_deleted", payload)?;
+            }
+        }
+        Ok(deleted.len())
+    }
+
+    /// remove the deleted entities no undo, checkpoint, branch or transaction can observe anymore,
+    /// return their number
+    pub fn collect_garbage(&self) -> usize {
+        self.entity_store.borrow_mut().collect_garbage()
+    }
+
+    /// fork the epoch timeline, see PyBranch
+    pub fn fork(&self) -> PyBranch {
+        let branch = self.entity_store.borrow_mut().fork();
+        PyBranch {
+            entity_store: Rc::clone(&self.entity_store),
+            branch,
+        }
+    }
+
+    /// collapse the values written before *before_epoch* (initial values excepted),
+    /// return the number of dropped history entries
+    pub fn compact(&self, before_epoch: Epoch) -> usize {
+        self.entity_store.borrow().compact(before_epoch)
+    }
+
+    /// the epoch values must be written at to be read back
+    pub fn current_epoch(&self) -> Epoch {
+        self.entity_store.borrow().current_epoch()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.entity_store.borrow().in_transaction()
+    }
+
+    /// drop all the entities and reset the epochs, so the store can be reused
+    pub fn clear(&self) {
+        self.entity_store.borrow_mut().clear();
+    }
+
+    /// import the entities of *other*, resolving differing attribute values with *policy*
+    /// ("ours", "theirs" or "fail"), optionally overridden per attribute name
+    #[pyo3(signature = (other, policy="fail", attributes_policy=None))]
+    pub fn merge(&self, other: &PyEntityStore, policy: &str, attributes_policy: Option<HashMap<String, String>>) -> PyResult<()> {
+        if Rc::ptr_eq(&self.entity_store, &other.entity_store) {
+            return Ok(());
+        }
+        let mut merge_policy = MergePolicy::new(parse_conflict_policy(policy)?);
+        for (attribute, attribute_policy) in attributes_policy.unwrap_or_default() {
+            merge_policy = merge_policy.with_attribute(attribute, parse_conflict_policy(&attribute_policy)?);
+        }
+        Ok(self.entity_store.borrow_mut().merge(&other.entity_store.borrow(), &merge_policy)?)
+    }
+
+    /// update the entity of *model* matching the *natural_key* attributes of *values*, like
+    /// PyEntity.update(), or create it, like instantiate_model() for the registered models. return
+    /// the entity and whether it was created
+    pub fn upsert(&self, py: Python, model: Model, natural_key: Vec<String>, values: HashMap<String, PyDatabaseValue>) -> PyResult<(PyEntity, bool)> {
+        let values = values.into_iter().map(|(name, value)| (name, value.into())).collect();
+        let (entity, replaced) = self.entity_store.borrow_mut().upsert(model, &natural_key, values)?;
+        let entity = self.wrap(entity);
+        let Some(replaced) = replaced else {
+            self.notify_added(py)?;
+            return Ok((entity, true));
+        };
+        for (name, previous) in replaced {
+            entity.get(&name)?.notify_written(py, previous, None)?;
+        }
+        Ok((entity, false))
+    }
+
+    /// return a child store reading through this one, whose changes are applied on commit()
+    pub fn child(&self) -> PyEntityStoreOverlay {
+        PyEntityStoreOverlay {
+            overlay: Some(EntityStoreOverlay::new(Rc::clone(&self.entity_store)))
+        }
+    }
+
+    /// instantiate the entities of *model* loaded from the database as *rows*, along with the
+    /// related entities given as nested dicts. *related* maps the foreign key lookups
+    /// ("author", "author__publisher") to their related model
+    #[pyo3(signature = (model, rows, related=None))]
+    pub fn hydrate(&mut self, py: Python, model: Model, rows: Vec<&PyDict>, related: Option<HashMap<String, Model>>) -> PyResult<Vec<PyEntity>> {
+        let related = related.unwrap_or_default();
+        let rows = rows.into_iter().map(|row| hydration_row(row, "", &related)).collect::<PyResult<Vec<HydrationRow>>>()?;
+        let entities = without_gil(py, &*self.entity_store, |entity_store| {
+            let mut entity_store = entity_store.borrow_mut();
+            rows.into_iter().map(|row| entity_store.hydrate(model.clone(), row)).collect::<Vec<Rc<Entity>>>()
+        });
+        self.notify_added(py)?;
+        Ok(entities.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
+
+    /// instantiate the persisted entities of *model* from the database *rows*, like the
+    /// QuerySet.values() ones, their *pk* key giving the identifier. registered models get their
+    /// declared attributes, see register_model()
+    #[pyo3(signature = (model, rows, pk="id"))]
+    pub fn load(&self, py: Python, model: Model, rows: Vec<HashMap<String, PyDatabaseValue>>, pk: &str) -> PyResult<Vec<PyEntity>> {
+        let rows = rows.into_iter().map(|mut row| match row.remove(pk) {
+            Some(PyDatabaseValue::Number(value)) => Ok((value, row.into_iter().map(|(name, value)| (name, value.into())).collect())),
+            _ => Err(PyValueError::new_err(format!("row without an integer \"{}\"", pk))),
+        }).collect::<PyResult<Vec<(PK, HashMap<String, DatabaseValue>)>>>()?;
+        let entities = without_gil(py, &*self.entity_store, |entity_store| entity_store.borrow_mut().load(model, rows))?;
+        self.notify_added(py)?;
+        Ok(entities.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
+
+    pub fn instantiate_many(&mut self, py: Python, model: Model, rows: Vec<HashMap<String, PyDatabaseValue>>) -> PyResult<Vec<PyEntity>> {
+        let attributes_descriptors: Vec<Vec<AttributeDescriptor>> = rows.into_iter().map(
+            |row| row.into_iter().map(
+                |(name, initial)| AttributeDescriptor::new(AttributeKind::Physical, name, initial.into())
+            ).collect()
+        ).collect();
+        let entities = without_gil(py, &*self.entity_store, |entity_store| entity_store.borrow_mut().instantiate_many(model, attributes_descriptors));
+        self.notify_added(py)?;
+        Ok(entities.into_iter().map(|entity| self.wrap(entity)).collect())
+    }
+}
+
+/// build the hydration row of a dict: "pk" gives the identifier, the keys listed in *related*
+/// (by lookup path from the top-level row, "author__publisher") are foreign keys, holding a pk or
+/// the dict of the related row, the other keys are physical attributes
+fn hydration_row(row: &PyDict, path: &str, related: &HashMap<String, Model>) -> PyResult<HydrationRow> {
+    let mut result = HydrationRow::default();
+    for (key, value) in row.iter() {
+        let name: String = key.extract()?;
+        let lookup = if path.is_empty() { name.clone() } else { format!("{}__{}", path, name) };
+        if name == "pk" {
+            result.pk = value.extract()?;
+        } else if let Some(related_model) = related.get(&lookup) {
+            if let Ok(related_row) = value.downcast::<PyDict>() {
+                result.related.push((name, related_model.clone(), hydration_row(related_row, &lookup, related)?));
+            } else {
+                let value: PyDatabaseValue = value.extract()?;
+                result.attributes.push(AttributeDescriptor::new(AttributeKind::ForeignKey(related_model.clone(), OnDelete::DoNothing), name, value.into()));
+            }
+        } else {
+            let value: PyDatabaseValue = value.extract()?;
+            result.attributes.push(AttributeDescriptor::new(AttributeKind::Physical, name, value.into()));
+        }
+    }
+    Ok(result)
+}
+
+/// physical attribute descriptors initialized with the given values
+fn physical_attributes(values: Option<HashMap<String, PyDatabaseValue>>) -> Vec<AttributeDescriptor> {
+    values.unwrap_or_default().into_iter().map(
+        |(name, initial)| AttributeDescriptor::new(AttributeKind::Physical, name, initial.into())
+    ).collect()
+}
+
+/// "pk" and the attributes of *model* when registered, or the ones of the first of *entities*
+fn default_columns(entity_store: &EntityStore, model: &Model, entities: &[Rc<Entity>]) -> Vec<String> {
+    let mut names: Vec<String> = match entity_store.get_model_schema(model) {
+        Ok(schema) => schema.attributes.iter().map(|attribute| attribute.get_name().to_string()).collect(),
+        Err(_) => entities.first().map(|entity| entity.iter_attributes().map(|(name, _)| name.to_string()).collect()).unwrap_or_default(),
+    };
+    names.sort();
+    names.insert(0, "pk".to_string());
+    names
+}
+
+/// build the expression matching entities whose attributes equal all the given keyword arguments
+fn kwargs_expression(kwargs: Option<&PyDict>) -> PyResult<FilterExpression> {
+    let mut expressions = vec![];
+    if let Some(kwargs) = kwargs {
+        for (key, value) in kwargs.iter() {
+            expressions.push(lookup_expression(key.extract()?, value)?);
+        }
+    }
+    Ok(FilterExpression::And(expressions))
+}
+
+/// the expression of the django style keyword argument *key*, like "age__gt", with its *value*
+fn lookup_expression(key: &str, value: &PyAny) -> PyResult<FilterExpression> {
+    let (attribute, lookup) = split_lookup(key);
+    let database_value = || -> PyResult<DatabaseValue> { Ok(value.extract::<PyDatabaseValue>()?.into()) };
+    let lookup = match lookup {
+        "exact" => return Ok(FilterExpression::Exact(ExactExpression::new(attribute.to_string(), database_value()?))),
+        "gt" => Lookup::Gt(database_value()?),
+        "gte" => Lookup::Gte(database_value()?),
+        "lt" => Lookup::Lt(database_value()?),
+        "lte" => Lookup::Lte(database_value()?),
+        "in" => Lookup::In(value.iter()?.map(|item| Ok(item?.extract::<PyDatabaseValue>()?.into())).collect::<PyResult<_>>()?),
+        "isnull" => Lookup::IsNull(value.extract()?),
+        "contains" => Lookup::Contains(value.extract()?),
+        "icontains" => Lookup::IContains(value.extract()?),
+        "startswith" => Lookup::StartsWith(value.extract()?),
+        "endswith" => Lookup::EndsWith(value.extract()?),
+        _ => unreachable!("split_lookup only returns known lookups"),
+    };
+    Ok(FilterExpression::Lookup(LookupExpression::new(attribute.to_string(), lookup)))
+}
+
+/// the entities of a model matching a filter, like a django QuerySet. it's evaluated again on
+/// each use, see PyEntityStore.query()
+#[pyclass(unsendable)]
+struct PyQuery {
+    entity_store: Rc<RefCell<EntityStore>>,
+    hooks: Hooks,
+    model: Model,
+    // compiled once, the query being evaluated on each use
+    expression: Rc<CompiledExpression>,
+    // the attribute ordering the entities, see order_by()
+    ordering: Option<String>,
+}
+
+impl PyQuery {
+    fn evaluate(&self, py: Python) -> Result<Vec<Rc<Entity>>, EntityError> {
+        without_gil(py, self, |query| match &query.ordering {
+            Some(ordering) => query.entity_store.borrow().filter_ordered(query.model.clone(), &query.expression, ordering),
+            None => query.entity_store.borrow().filter_compiled(query.model.clone(), &query.expression),
+        })
+    }
+}
+
+#[pymethods]
+impl PyQuery {
+    fn all(&self, py: Python) -> Result<Vec<PyEntity>, EntityError> {
+        Ok(self.evaluate(py)?.into_iter().map(|entity| PyEntity {
+            entity,
+            entity_store: Some(Rc::clone(&self.entity_store)),
+            hooks: Some(Rc::clone(&self.hooks)),
+        }).collect())
+    }
+
+    /// the entities of this query also matching the keyword arguments
+    #[pyo3(signature = (**kwargs))]
+    fn filter(&self, kwargs: Option<&PyDict>) -> PyResult<PyQuery> {
+        Ok(PyQuery {
+            entity_store: Rc::clone(&self.entity_store),
+            hooks: Rc::clone(&self.hooks),
+            model: self.model.clone(),
+            expression: Rc::new(FilterExpression::And(vec![self.expression.get_expression().clone(), kwargs_expression(kwargs)?]).compile()),
+            ordering: self.ordering.clone(),
+        })
+    }
+
+    /// the entities of this query ordered by their *ordering* attribute, descending if prefixed
+    /// with "-", the entities without value last
+    fn order_by(&self, ordering: String) -> PyQuery {
+        PyQuery {
+            entity_store: Rc::clone(&self.entity_store),
+            hooks: Rc::clone(&self.hooks),
+            model: self.model.clone(),
+            expression: Rc::clone(&self.expression),
+            ordering: Some(ordering),
+        }
+    }
+
+    fn __iter__(&self, py: Python) -> Result<PyEntityIterator, EntityError> {
+        Ok(PyEntityIterator { entities: self.all(py)?.into_iter() })
+    }
+
+    fn __len__(&self, py: Python) -> Result<usize, EntityError> {
+        Ok(self.evaluate(py)?.len())
+    }
+
+    fn __contains__(&self, item: &PyAny) -> Result<bool, EntityError> {
+        match item.extract::<PyRef<PyEntity>>() {
+            Ok(entity) => Ok(self.evaluate(item.py())?.contains(&entity.entity)),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// the iterator over the entities of a store or a query
+#[pyclass(unsendable)]
+struct PyEntityIterator {
+    entities: std::vec::IntoIter<PyEntity>,
+}
+
+#[pymethods]
+impl PyEntityIterator {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> Option<PyEntity> {
+        self.entities.next()
+    }
+}
+
+/// a read-only view of a store, answering with the values as they were at its epoch
+#[pyclass(unsendable)]
+struct PyEntityStoreView {
+    entity_store: Rc<RefCell<EntityStore>>,
+    epoch_ptr: Rc<EpochPtr>,
+}
+
+#[pymethods]
+impl PyEntityStoreView {
+    #[getter]
+    fn epoch(&self) -> Epoch {
+        self.epoch_ptr.get_epoch()
+    }
+
+    fn get(&self, identifier: &PyEntityIdentifier) -> Result<PyEntityView, EntityError> {
+        let entity = self.entity_store.borrow().get_at(&identifier.entity_identifier, self.epoch_ptr.get_epoch())?;
+        Ok(PyEntityView { entity, epoch_ptr: Rc::clone(&self.epoch_ptr) })
+    }
+
+    #[pyo3(signature = (model, **kwargs))]
+    fn filter(&self, py: Python, model: Model, kwargs: Option<&PyDict>) -> PyResult<Vec<PyEntityView>> {
+        let expression = kwargs_expression(kwargs)?;
+        let epoch = self.epoch_ptr.get_epoch();
+        let entities = without_gil(py, &*self.entity_store, move |entity_store| entity_store.borrow().filter_at(&model, &expression, epoch))?;
+        Ok(entities.into_iter().map(|entity| PyEntityView { entity, epoch_ptr: Rc::clone(&self.epoch_ptr) }).collect())
+    }
+}
+
+#[pyclass(unsendable)]
+struct PyEntityView {
+    entity: Rc<Entity>,
+    epoch_ptr: Rc<EpochPtr>,
+}
+
+#[pymethods]
+impl PyEntityView {
+    fn get(&self, attr: &str) -> Result<PyAttributeView, EntityError> {
+        self.entity.get(attr).map(|attribute| PyAttributeView {
+            attribute,
+            field_type: self.entity.get_field_type(attr),
+            epoch_ptr: Rc::clone(&self.epoch_ptr),
+        })
+    }
+
+    /// equal when they have the same identifier, other comparisons and types are NotImplemented
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyObject {
+        let py = other.py();
+        match other.extract::<PyRef<PyEntityView>>() {
+            Ok(other) => compare_identifiers(py, self.entity.get_identifier(), other.entity.get_identifier(), op),
+            Err(_) => py.NotImplemented(),
+        }
+    }
+
+    /// the hash of the identifier, consistent with the equality
+    fn __hash__(&self) -> u64 {
+        hash_identifier(self.entity.get_identifier())
+    }
+}
+
+#[pyclass(unsendable)]
+struct PyAttributeView {
+    attribute: Rc<PhysicalAttribute>,
+    field_type: FieldType,
+    epoch_ptr: Rc<EpochPtr>,
+}
+
+#[pymethods]
+impl PyAttributeView {
+    #[getter]
+    fn initial(&self, py: Python) -> PyResult<PyObject> {
+        from_database_value(py, self.field_type, self.attribute.get_initial())
+    }
+
+    #[getter]
+    fn value(&self, py: Python) -> PyResult<PyObject> {
+        from_database_value(py, self.field_type, self.attribute.get_at_epoch(self.epoch_ptr.get_epoch()))
+    }
+}
+
+#[pyclass(unsendable)]
+struct PyTransaction {
+    entity_store: Rc<RefCell<EntityStore>>,
+    hooks: Hooks,
+}
+
+#[pymethods]
+impl PyTransaction {
+    fn __enter__(&self, py: Python) -> PyResult<Epoch> {
+        let previous = self.entity_store.borrow().current_epoch();
+        let epoch = self.entity_store.borrow_mut().begin()?;
+        publish_epoch(py, &self.hooks, previous, epoch)?;
+        Ok(epoch)
+    }
+
+    fn __exit__(&self, py: Python, exc_type: Option<&PyAny>, _exc_value: Option<&PyAny>, _traceback: Option<&PyAny>) -> PyResult<bool> {
+        let previous = self.entity_store.borrow().current_epoch();
+        if exc_type.is_none() {
+            self.entity_store.borrow_mut().commit()?;
+        } else {
+            self.entity_store.borrow_mut().rollback()?;
+        }
+        publish_epoch(py, &self.hooks, previous, self.entity_store.borrow().current_epoch())?;
+        // never swallow the exception
+        Ok(false)
+    }
+}
+
+/// the block reading the store at an epoch, see PyEntityStore.at_epoch()
+#[pyclass(unsendable)]
+struct PyEpochScope {
+    entity_store: Rc<RefCell<EntityStore>>,
+    epoch: Epoch,
+}
+
+#[pymethods]
+impl PyEpochScope {
+    fn __enter__(&self) -> Result<Epoch, EntityError> {
+        self.entity_store.borrow_mut().enter_epoch(self.epoch)?;
+        Ok(self.epoch)
+    }
+
+    fn __exit__(&self, _exc_type: Option<&PyAny>, _exc_value: Option<&PyAny>, _traceback: Option<&PyAny>) -> bool {
+        self.entity_store.borrow_mut().leave_epoch();
+        false
+    }
+}
+
+/// a fork of the store epoch timeline: values set through it are only visible through it
+/// until merge() writes them to the store or discard() drops them
+#[pyclass(unsendable)]
+struct PyBranch {
+    entity_store: Rc<RefCell<EntityStore>>,
+    branch: Epoch,
+}
+
+#[pymethods]
+impl PyBranch {
+    #[getter]
+    fn epoch(&self) -> Epoch {
+        self.branch
+    }
+
+    fn get(&self, entity: PyRef<PyEntity>, attribute: &str) -> Result<PyDatabaseValue, EntityError> {
+        Ok(self.entity_store.borrow().get_in_branch(self.branch, &entity.entity, attribute)?.into())
+    }
+
+    fn set(&self, entity: PyRef<PyEntity>, attribute: &str, value: PyDatabaseValue) -> Result<(), EntityError> {
+        self.entity_store.borrow().set_in_branch(self.branch, &entity.entity, attribute, value.into())
+    }
+
+    fn merge(&self) -> Result<(), EntityError> {
+        self.entity_store.borrow_mut().merge_branch(self.branch)
+    }
+
+    fn discard(&self) -> Result<(), EntityError> {
+        self.entity_store.borrow_mut().discard_branch(self.branch)
+    }
+}
+
+#[pyclass(unsendable)]
+struct PyEntityStoreOverlay {
+    overlay: Option<EntityStoreOverlay>,
+}
+
+impl PyEntityStoreOverlay {
+    fn get_overlay(&mut self) -> PyResult<&mut EntityStoreOverlay> {
+        self.overlay.as_mut().ok_or_else(|| PyException::new_err("this store overlay is already committed or discarded"))
+    }
+}
+
+#[pymethods]
+impl PyEntityStoreOverlay {
+    fn get(&mut self, identifier: &PyEntityIdentifier) -> PyResult<PyEntity> {
+        Ok(self.get_overlay()?.get(&identifier.entity_identifier).map(|entity| PyEntity {entity, entity_store: None, hooks: None})?)
+    }
+
+    pub fn instantiate_entity(&mut self, identifier: &PyEntityIdentifier, attributes: Vec<PyAttributeDescriptorArg>) -> PyResult<PyEntity> {
+        let attributes_descriptors: Vec<AttributeDescriptor> = attributes.into_iter().map(|attr| attr.0).collect();
+        let entity = self.get_overlay()?.instantiate_entity(identifier.entity_identifier.clone(), attributes_descriptors)?;
+        Ok(PyEntity {entity, entity_store: None, hooks: None})
+    }
+
+    fn commit(&mut self) -> PyResult<()> {
+        self.get_overlay()?;
+        self.overlay.take().unwrap().commit();
+        Ok(())
+    }
+
+    fn discard(&mut self) -> PyResult<()> {
+        self.get_overlay()?;
+        self.overlay.take().unwrap().discard();
+        Ok(())
+    }
+}
+
+/// an entity of a store. it can be subclassed in python to add domain methods, the instances
+/// being created from the store and the identifier of the entity:
+///
+///     class User(PyEntity):
+///         def full_name(self):
+///             return f"{self['first_name']} {self['last_name']}"
+///
+///     User(store, PyEntityIdentifier("User", 1)).full_name()
+#[pyclass(unsendable, subclass, weakref, module = "django_lightning_service")]
+#[derive(Clone)]
+struct PyEntity {
+    entity: Rc<Entity>,
+    // the store the entity belongs to, None for the entities of overlays
+    entity_store: Option<Rc<RefCell<EntityStore>>>,
+    // the hooks of the store, notified of the changes of the attributes
+    hooks: Option<Hooks>,
+}
+
+#[pymethods]
+impl PyEntity {
+    #[new]
+    fn new(entity_store: PyRef<PyEntityStore>, identifier: PyRef<PyEntityIdentifier>) -> Result<Self, EntityError> {
+        entity_store.get(&identifier)
+    }
+
+    fn get(&self, attr: &str) -> Result<PyAttribute, EntityError> {
+
+        self.entity.get(attr).map(|attribute| PyAttribute {
+            attribute,
+            name: attr.to_string(),
+            field_type: self.entity.get_field_type(attr),
+            owner: self.clone(),
+        })
+    }
+
+    fn get_identifier(&self) -> PyEntityIdentifier {
+        PyEntityIdentifier { entity_identifier: self.entity.get_identifier().clone() }
+    }
+
+    /// run the validators of the attributes and check the constraints of the entity, whatever
+    /// when they are checked, raise a ValidationError with the messages of every offending
+    /// attribute as `message_dict`, the constraints over several attributes under "__all__".
+    /// see PyEntityStore.validate()
+    fn full_clean(&self) -> Result<(), EntityError> {
+        let messages = match self.entity_store.as_ref() {
+            Some(entity_store) => entity_store.borrow().clean(&self.entity)?,
+            None => self.entity.clean_values(),
+        };
+        if messages.is_empty() {
+            return Ok(());
+        }
+        Err(EntityError::Invalid(self.entity.get_identifier().clone(), messages))
+    }
+
+    /// pickle a copy of the entity and its history, unpickled detached from any store and read
+    /// only, see detached(). PyEntityStore.attach() gives back the entity of a store
+    fn __reduce__(&self, py: Python) -> PyResult<(PyObject, (PyObject, PyEntityIdentifier))> {
+        let bytes = match self.entity_store.as_ref() {
+            Some(entity_store) => json::entity_to_msgpack(&entity_store.borrow(), &self.entity)?,
+            None => json::entity_to_msgpack(&EntityStore::new(), &self.entity)?,
+        };
+        let detached = py.get_type::<PyEntity>().getattr("detached")?;
+        Ok((detached.into(), (PyBytes::new(py, &bytes).into(), self.get_identifier())))
+    }
+
+    /// the same entity, through a new handle
+    fn __copy__(&self) -> PyEntity {
+        self.clone()
+    }
+
+    /// a copy of the entity and its history in a store of its own, changed independently of
+    /// the entity
+    fn __deepcopy__(&self, _memo: &PyAny) -> PyEntity {
+        let (entity_store, entity) = match self.entity_store.as_ref() {
+            Some(entity_store) => entity_store.borrow().snapshot_entity(&self.entity),
+            None => EntityStore::new().snapshot_entity(&self.entity),
+        };
+        PyEntity { entity, entity_store: Some(Rc::new(RefCell::new(entity_store))), hooks: None }
+    }
+
+    /// the read-only copy of the entity *identifier* pickled in *bytes*, in a store of its own
+    #[staticmethod]
+    fn detached(bytes: &[u8], identifier: PyRef<PyEntityIdentifier>) -> Result<PyEntity, EntityError> {
+        let mut entity_store = json::from_msgpack(bytes)?;
+        let entity = entity_store.get(&identifier.entity_identifier)?;
+        let current_epoch = entity_store.current_epoch();
+        entity_store.enter_epoch(current_epoch)?;
+        Ok(PyEntity { entity, entity_store: Some(Rc::new(RefCell::new(entity_store))), hooks: None })
+    }
+
+    /// the current value of the attribute *name*, like `get(name).value`
+    fn __getitem__(&self, py: Python, name: &str) -> PyResult<PyObject> {
+        self.get(name)?.value(py)
+    }
+
+    /// set the current value of the attribute *name*, like `get(name).set_value(value)`
+    fn __setitem__(&self, py: Python, name: &str, value: &PyAny) -> PyResult<()> {
+        self.get(name)?.set_value(py, value, None)
+    }
+
+    /// the values of the attributes by name, at *epoch* or the current ones, ordered by name
+    #[pyo3(signature = (epoch=None))]
+    fn to_dict<'py>(&self, py: Python<'py>, epoch: Option<Epoch>) -> PyResult<&'py PyDict> {
+        let mut attributes: Vec<(&str, &Rc<PhysicalAttribute>)> = self.entity.iter_attributes().collect();
+        attributes.sort_by_key(|(name, _)| *name);
+        let result = PyDict::new(py);
+        for (name, attribute) in attributes {
+            let value = match epoch {
+                Some(epoch) => attribute.get_at_epoch(epoch),
+                None => attribute.get_value(),
+            };
+            result.set_item(name, from_database_value(py, self.entity.get_field_type(name), value)?)?;
+        }
+        Ok(result)
+    }
+
+    /// set the current values of the attributes given by name in *values*, like
+    /// PyAttribute.set_value(). nothing is set if one of them is unknown
+    fn update(&self, py: Python, values: HashMap<String, &PyAny>) -> PyResult<()> {
+        let attributes = values.into_iter().map(|(name, value)| Ok((self.get(&name)?, value))).collect::<Result<Vec<(PyAttribute, &PyAny)>, EntityError>>()?;
+        for (attribute, value) in attributes {
+            attribute.set_value(py, value, None)?;
+        }
+        Ok(())
+    }
+
+    /// true if any attribute was modified since the initial epoch
+    fn is_dirty(&self) -> bool {
+        self.entity.is_dirty()
+    }
+
+    /// names of the attributes modified since the initial epoch
+    fn changed_fields(&self) -> Vec<String> {
+        self.entity.changed_fields()
+    }
+
+    /// restore the values the attributes had at *epoch*
+    fn revert_to(&self, epoch: Epoch) {
+        self.entity.revert_to(epoch)
+    }
+
+    /// the manager of the entities related through the many to many relation *name*, or, if
+    /// *attribute* is given, of the entities of the model *name* referencing this one with it
+    #[pyo3(signature = (name, attribute=None))]
+    fn rel(&self, name: String, attribute: Option<String>) -> PyResult<PyRelatedManager> {
+        let entity_store = self.entity_store.as_ref().ok_or_else(|| PyException::new_err("this entity doesn't belong to a store"))?;
+        let related_set = match attribute {
+            Some(attribute) => RelatedSet::ReverseForeignKey(name, attribute),
+            None => {
+                // fail early on unknown relations
+                entity_store.borrow().memberships(&self.entity, &name)?;
+                RelatedSet::ManyToMany(name)
+            },
+        };
+        Ok(PyRelatedManager {
+            entity_store: Rc::clone(entity_store),
+            hooks: self.hooks.clone().unwrap_or_default(),
+            entity: Rc::clone(&self.entity),
+            related_set,
+        })
+    }
+
+    /// equal when they have the same identifier, other comparisons and types are NotImplemented
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyObject {
+        let py = other.py();
+        match other.extract::<PyRef<PyEntity>>() {
+            Ok(other) => compare_identifiers(py, self.entity.get_identifier(), other.entity.get_identifier(), op),
+            Err(_) => py.NotImplemented(),
+        }
+    }
+
+    /// the hash of the identifier, consistent with the equality
+    fn __hash__(&self) -> u64 {
+        hash_identifier(self.entity.get_identifier())
+    }
+
+    fn __str__(&self) -> String {
+        self.entity.get_identifier().to_string()
+    }
+
+    /// the identifier, whether the entity is dirty and its current values, ordered by name
+    fn __repr__(slf: &PyCell<Self>) -> PyResult<String> {
+        let entity = &slf.borrow().entity;
+        let mut attributes: Vec<(&str, &Rc<PhysicalAttribute>)> = entity.iter_attributes().collect();
+        attributes.sort_by_key(|(name, _)| *name);
+        Ok(format!(
+            "<{} {}{}: {}>",
+            // the name of the python subclasses, name() gives their qualified name
+            slf.get_type().getattr("__name__")?,
+            repr_identifier(entity.get_identifier()),
+            if entity.is_dirty() { " dirty" } else { "" },
+            attributes.iter().map(|(name, attribute)| format!("{}={}", name, repr_value(&attribute.get_value()))).collect::<Vec<String>>().join(", "),
+        ))
+    }
+}
+
+/// the entities a PyRelatedManager manages
+enum RelatedSet {
+    /// related through the many to many relation of the given name
+    ManyToMany(String),
+    /// of the given model, referencing the entity with the given foreign key
+    ReverseForeignKey(Model, String),
+}
+
+/// the entities related to an entity through a many to many relation or a reverse foreign key,
+/// with the surface of django's RelatedManager. see PyEntity.rel()
+#[pyclass(unsendable)]
+struct PyRelatedManager {
+    entity_store: Rc<RefCell<EntityStore>>,
+    hooks: Hooks,
+    entity: Rc<Entity>,
+    related_set: RelatedSet,
+}
+
+impl PyRelatedManager {
+    fn related(&self) -> Result<Vec<Rc<Entity>>, EntityError> {
+        let entity_store = self.entity_store.borrow();
+        match &self.related_set {
+            RelatedSet::ManyToMany(name) => entity_store.many_to_many(&self.entity, name, &FilterExpression::And(vec![])),
+            RelatedSet::ReverseForeignKey(model, attribute) => Ok(entity_store.related(&self.entity, model, attribute)),
+        }
+    }
+
+    /// make the foreign key *attribute* of *entity* reference *target*, or empty it, written and
+    /// notified like PyAttribute.set_value()
+    fn set_related(&self, py: Python, entity: Rc<Entity>, attribute: &str, target: Option<&Entity>) -> PyResult<()> {
+        let previous = self.entity_store.borrow().set_related(&entity, attribute, target)?;
+        let entity = PyEntity { entity, entity_store: Some(Rc::clone(&self.entity_store)), hooks: Some(Rc::clone(&self.hooks)) };
+        entity.get(attribute)?.notify_written(py, previous, None)
+    }
+}
+
+#[pymethods]
+impl PyRelatedManager {
+    fn all(&self) -> Result<Vec<PyEntity>, EntityError> {
+        Ok(self.related()?.into_iter().map(|entity| PyEntity { entity, entity_store: Some(Rc::clone(&self.entity_store)), hooks: Some(Rc::clone(&self.hooks)) }).collect())
+    }
+
+    /// relate the *entities* not related yet. *through_defaults* are the attributes of the
+    /// created through entities
+    #[pyo3(signature = (*entities, through_defaults=None))]
+    fn add(&self, py: Python, entities: Vec<PyRef<PyEntity>>, through_defaults: Option<HashMap<String, PyDatabaseValue>>) -> PyResult<()> {
+        let related = self.related()?;
+        let attributes = physical_attributes(through_defaults);
+        for entity in to_entities(entities).into_iter().filter(|entity| !related.contains(entity)) {
+            match &self.related_set {
+                RelatedSet::ManyToMany(name) => { self.entity_store.borrow_mut().add_membership(&self.entity, name, &entity, attributes.clone())?; },
+                RelatedSet::ReverseForeignKey(_, attribute) => self.set_related(py, entity, attribute, Some(&self.entity))?,
+            }
+        }
+        notify_added(py, &self.entity_store, &self.hooks)
+    }
+
+    /// unrelate the *entities*, by deleting their through entities or emptying their foreign key
+    #[pyo3(signature = (*entities))]
+    fn remove(&self, py: Python, entities: Vec<PyRef<PyEntity>>) -> PyResult<()> {
+        for entity in to_entities(entities) {
+            match &self.related_set {
+                RelatedSet::ManyToMany(name) => { self.entity_store.borrow().remove_membership(&self.entity, name, &entity)?; },
+                RelatedSet::ReverseForeignKey(_, attribute) => {
+                    if entity.references(attribute, &self.entity)? {
+                        self.set_related(py, entity, attribute, None)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// relate exactly the *entities*, see add() and remove()
+    #[pyo3(signature = (entities, through_defaults=None))]
+    fn set(&self, py: Python, entities: Vec<PyRef<PyEntity>>, through_defaults: Option<HashMap<String, PyDatabaseValue>>) -> PyResult<()> {
+        let entities = to_entities(entities);
+        match &self.related_set {
+            RelatedSet::ManyToMany(name) => {
+                self.entity_store.borrow_mut().set_many_to_many(&self.entity, name, &entities, physical_attributes(through_defaults))?;
+                notify_added(py, &self.entity_store, &self.hooks)
+            },
+            RelatedSet::ReverseForeignKey(_, attribute) => {
+                for related in self.related()?.into_iter().filter(|related| !entities.contains(related)) {
+                    self.set_related(py, related, attribute, None)?;
+                }
+                for entity in entities {
+                    self.set_related(py, entity, attribute, Some(&self.entity))?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// unrelate all the entities
+    fn clear(&self, py: Python) -> PyResult<()> {
+        self.set(py, vec![], None)
+    }
+}
+
+
+#[pyclass(unsendable)]
+struct PyAttribute {
+    attribute: Rc<PhysicalAttribute>,
+    name: String,
+    // the values set and got are converted from and to this type
+    field_type: FieldType,
+    // the entity holding the attribute, passed to the "changed" hooks
+    owner: PyEntity,
+}
+
+
+#[pymethods]
+impl PyAttribute {
+    #[getter]
+    fn initial(&self, py: Python) -> PyResult<PyObject> {
+        from_database_value(py, self.field_type, self.attribute.get_initial())
+    }
+
+    #[getter]
+    fn value(&self, py: Python) -> PyResult<PyObject> {
+        from_database_value(py, self.field_type, self.attribute.get_value())
+    }
+
+    /// set the value at the current epoch of the store, which is the write epoch of the running
+    /// transaction if any, like set_value() without epoch
+    #[setter(value)]
+    fn set_current_value(&self, py: Python, value: &PyAny) -> PyResult<()> {
+        self.set_value(py, value, None)
+    }
+
+    /// set the value at *epoch*, or at the current epoch of the store if not given.
+    /// fail in a PyEntityStore.at_epoch() block, with TypeError if it isn't of the field type of
+    /// the attribute, and with IntegrityError, the value left unchanged, if it violates a
+    /// constraint checked immediately, see PyEntityStore.set_constraint_check()
+    #[pyo3(signature = (value, epoch=None))]
+    fn set_value(&self, py: Python, value: &PyAny, epoch: Option<Epoch>) -> PyResult<()> {
+        let value: DatabaseValue = to_database_value(self.field_type, value)?.into();
+        let previous = match self.owner.entity_store.as_ref() {
+            Some(entity_store) => entity_store.borrow().write_value(&self.owner.entity, &self.name, value, epoch)?,
+            None => self.owner.entity.write(&self.name, value, epoch)?,
+        };
+        self.notify_written(py, previous, epoch)
+    }
+
+    /// return the `(epoch, value)` pairs this attribute went through, ordered by epoch
+    fn history(&self, py: Python) -> PyResult<Vec<(Epoch, PyObject)>> {
+        self.attribute.history().into_iter().map(|(epoch, value)| Ok((epoch, from_database_value(py, self.field_type, value)?))).collect()
+    }
+
+    /// return the value of this attribute as it was at *epoch*
+    fn value_at(&self, py: Python, epoch: Epoch) -> PyResult<PyObject> {
+        from_database_value(py, self.field_type, self.attribute.get_at_epoch(epoch))
+    }
+
+
+
+    fn __str__(slf: &PyCell<Self>) -> PyResult<String> {
+        let attr = &slf.borrow().attribute;
+        Ok(attr.to_string())
+    }
+    fn __repr__(slf: &PyCell<Self>) -> PyResult<String> {
+
+        let class_name: String = slf.get_type().name()?.to_string();
+
+        Ok(format!("<{} {}>", class_name, slf.borrow().attribute))
+    }
+}
+
+impl PyAttribute {
+    /// notify the hooks of the write of the value at *epoch*, which replaced *previous*
+    fn notify_written(&self, py: Python, previous: DatabaseValue, epoch: Option<Epoch>) -> PyResult<()> {
+        let Some(hooks) = self.owner.hooks.as_ref() else { return Ok(()) };
+        notify(py, hooks, "changed", &self.owner, vec![self.name.clone()])?;
+        if subscribed(hooks, "attribute_changed") {
+            let epoch = match (epoch, self.owner.entity_store.as_ref()) {
+                (Some(epoch), _) => Some(epoch),
+                (None, entity_store) => entity_store.map(|entity_store| entity_store.borrow().current_epoch()),
+            };
+            let payload = PyDict::new(py);
+            payload.set_item("identifier", self.owner.get_identifier().into_py(py))?;
+            payload.set_item("entity", self.owner.clone().into_py(py))?;
+            payload.set_item("attribute", &self.name)?;
+            payload.set_item("previous", from_database_value(py, self.field_type, previous)?)?;
+            payload.set_item("value", self.value(py)?)?;
+            payload.set_item("epoch", epoch)?;
+            publish(py, hooks, "attribute_changed", payload)?;
+        }
+        fire_watches(py, hooks)
+    }
+}
+
+/// a store usable from several threads, like the thread pools of a django server. a limited key/value
+/// facade: unlike PyEntityStore, it answers with identifiers and values rather than entities, the values
+/// being given and returned as stored, whatever their field type, and it has no relations, transactions,
+/// epochs nor hooks. the calls are serialized, without the GIL
+#[pyclass(module = "django_lightning_service")]
+struct PySyncEntityStore {
+    entity_store: SyncEntityStore,
+}
+
+#[pymethods]
+impl PySyncEntityStore {
+    #[new]
+    fn new() -> PySyncEntityStore {
+        PySyncEntityStore { entity_store: SyncEntityStore::new() }
+    }
+
+    /// declare the attributes of *model*, see PyEntityStore.register_model()
+    #[pyo3(signature = (model, attributes, unique=vec![]))]
+    fn register_model(&self, py: Python, model: Model, attributes: Vec<PyAttributeDescriptorArg>, unique: Vec<Vec<String>>) {
+        let attributes = attributes.into_iter().map(|attr| attr.0).collect();
+        py.allow_threads(|| self.entity_store.register_model(model, ModelSchema { attributes, unique }))
+    }
+
+    /// instantiate an entity of the registered *model* with the given values, return its identifier
+    #[pyo3(signature = (model, pk=None, **values))]
+    fn instantiate_model(&self, py: Python, model: Model, pk: Option<PK>, values: Option<HashMap<String, PyDatabaseValue>>) -> Result<PyEntityIdentifier, EntityError> {
+        let values = values.unwrap_or_default().into_iter().map(|(name, value)| (name, value.into())).collect();
+        let identifier = match pk {
+            Some(pk) => EntityIdentifier::new_persisted(model, pk),
+            None => EntityIdentifier::new(model),
+        };
+        let entity_identifier = py.allow_threads(|| self.entity_store.instantiate_model(identifier, values))?;
+        Ok(PyEntityIdentifier { entity_identifier })
+    }
+
+    /// the current value of *attribute*
+    fn get(&self, py: Python, identifier: &PyEntityIdentifier, attribute: &str) -> Result<PyDatabaseValue, EntityError> {
+        let identifier = identifier.entity_identifier.clone();
+        Ok(py.allow_threads(move || self.entity_store.get_value(identifier, attribute))?.into())
+    }
+
+    /// set the current value of *attribute*
+    fn set(&self, py: Python, identifier: &PyEntityIdentifier, attribute: &str, value: PyDatabaseValue) -> Result<(), EntityError> {
+        let identifier = identifier.entity_identifier.clone();
+        py.allow_threads(move || self.entity_store.set_value(identifier, attribute, value.into()))
+    }
+
+    /// the current values of the attributes of an entity, by name
+    fn values(&self, py: Python, identifier: &PyEntityIdentifier) -> Result<HashMap<String, PyDatabaseValue>, EntityError> {
+        let identifier = identifier.entity_identifier.clone();
+        let values = py.allow_threads(move || self.entity_store.values(identifier))?;
+        Ok(values.into_iter().map(|(name, value)| (name, value.into())).collect())
+    }
+
+    /// the identifiers of the entities of *model* matching the keyword arguments, see
+    /// PyEntityStore.filter()
+    #[pyo3(signature = (model, **kwargs))]
+    fn filter(&self, py: Python, model: Model, kwargs: Option<&PyDict>) -> PyResult<Vec<PyEntityIdentifier>> {
+        let expression = kwargs_expression(kwargs)?;
+        let identifiers = py.allow_threads(|| self.entity_store.filter(model, expression))?;
+        Ok(identifiers.into_iter().map(|entity_identifier| PyEntityIdentifier { entity_identifier }).collect())
+    }
+
+    /// delete an entity with the ones referencing it, return the number of deleted entities
+    fn delete(&self, py: Python, identifier: &PyEntityIdentifier) -> Result<usize, EntityError> {
+        let identifier = identifier.entity_identifier.clone();
+        py.allow_threads(move || self.entity_store.delete(identifier))
+    }
+
+    /// the number of alive entities of *model*
+    fn count(&self, py: Python, model: Model) -> usize {
+        py.allow_threads(|| self.entity_store.count(&model))
+    }
+
+    fn __len__(&self, py: Python) -> usize {
+        py.allow_threads(|| self.entity_store.alive_count())
+    }
+}
+
+/// a value as stored by the attributes, built with Number(), String() or Null(). it's accepted
+/// anywhere a value is, like the int, str and None it stands for
+#[pyclass(name = "DatabaseValue", module = "django_lightning_service")]
+#[derive(Clone)]
+struct PyValue {
+    value: DatabaseValue,
+}
+
+#[pymethods]
+impl PyValue {
+    /// the kind of the value: number, string, null or foreign_key
+    #[getter]
+    fn kind(&self) -> &'static str {
+        match self.value {
+            DatabaseValue::Number(_) => "number",
+            DatabaseValue::String(_) => "string",
+            DatabaseValue::None => "null",
+            DatabaseValue::ForeignKey(_) => "foreign_key",
+        }
+    }
+
+    /// the python value: an int, a str, None or an identifier
+    #[getter]
+    fn value(&self) -> PyDatabaseValue {
+        self.value.clone().into()
+    }
+
+    /// equal to the values of the same kind holding the same value
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyObject {
+        let py = other.py();
+        match (other.extract::<PyRef<PyValue>>(), op) {
+            (Ok(other), CompareOp::Eq) => (self.value == other.value).into_py(py),
+            (Ok(other), CompareOp::Ne) => (self.value != other.value).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::mem::discriminant(&self.value).hash(&mut hasher);
+        match &self.value {
+            DatabaseValue::Number(number) => number.hash(&mut hasher),
+            DatabaseValue::String(string) => string.hash(&mut hasher),
+            DatabaseValue::None => {}
+            DatabaseValue::ForeignKey(identifier) => identifier.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    fn __repr__(&self) -> String {
+        match &self.value {
+            DatabaseValue::Number(number) => format!("Number({})", number),
+            DatabaseValue::String(string) => format!("String({:?})", string),
+            DatabaseValue::None => "Null()".to_string(),
+            DatabaseValue::ForeignKey(identifier) => format!("ForeignKey({})", repr_identifier(identifier)),
+        }
+    }
+}
+
+/// the number *value*, see DatabaseValue
+#[pyfunction]
+#[pyo3(name = "Number")]
+fn number(value: i64) -> PyValue {
+    PyValue { value: DatabaseValue::Number(value) }
+}
+
+/// the string *value*, see DatabaseValue
+#[pyfunction]
+#[pyo3(name = "String")]
+fn string(value: String) -> PyValue {
+    PyValue { value: DatabaseValue::String(value) }
+}
+
+/// the missing value, see DatabaseValue
+#[pyfunction]
+#[pyo3(name = "Null")]
+fn null() -> PyValue {
+    PyValue { value: DatabaseValue::None }
+}
+
+
+fn to_entities(entities: Vec<PyRef<PyEntity>>) -> Vec<Rc<Entity>> {
+    entities.iter().map(|entity| Rc::clone(&entity.entity)).collect()
+}
+
+type SetOperation = fn(&[Rc<Entity>], &[Rc<Entity>]) -> Vec<Rc<Entity>>;
+
+/// wrap the result of a set operation on *left* and *right* entities, keeping their store
+fn from_set_operation(left: &[PyRef<PyEntity>], right: &[PyRef<PyEntity>], operation: SetOperation) -> Vec<PyEntity> {
+    let owners: HashMap<*const Entity, &PyEntity> = left.iter().chain(right.iter()).map(
+        |entity| (Rc::as_ptr(&entity.entity), &**entity)
+    ).collect();
+    let left: Vec<Rc<Entity>> = left.iter().map(|entity| Rc::clone(&entity.entity)).collect();
+    let right: Vec<Rc<Entity>> = right.iter().map(|entity| Rc::clone(&entity.entity)).collect();
+    operation(&left, &right).into_iter().map(|entity| {
+        let owner = owners[&Rc::as_ptr(&entity)];
+        PyEntity { entity, entity_store: owner.entity_store.clone(), hooks: owner.hooks.clone() }
+    }).collect()
+}
+
+/// entities present in *left* or *right*, without duplicates
+#[pyfunction]
+fn union(left: Vec<PyRef<PyEntity>>, right: Vec<PyRef<PyEntity>>) -> Vec<PyEntity> {
+    from_set_operation(&left, &right, query::union)
+}
+
+/// entities present in both *left* and *right*, without duplicates
+#[pyfunction]
+fn intersection(left: Vec<PyRef<PyEntity>>, right: Vec<PyRef<PyEntity>>) -> Vec<PyEntity> {
+    from_set_operation(&left, &right, query::intersection)
+}
+
+/// entities present in *left* but not in *right*, without duplicates
+#[pyfunction]
+fn difference(left: Vec<PyRef<PyEntity>>, right: Vec<PyRef<PyEntity>>) -> Vec<PyEntity> {
+    from_set_operation(&left, &right, query::difference)
+}
 
+/// forward the logs of the store to python's logging, to the loggers named after the rust
+/// modules like "django_lightning_service.entity_store". *filter* selects them with the syntax
+/// of RUST_LOG, like "django_lightning_service=debug", and defaults to RUST_LOG, then "warn".
+/// the filter can be changed by calling it again
 #[pyfunction]
-fn repr_database_value(value: PyDatabaseValue)  -> PyResult<String> {
-    Ok(Into::<DatabaseValue>::into(value).to_string())
+#[pyo3(signature = (filter=None))]
+fn setup_logging(filter: Option<&str>) -> PyResult<()> {
+    logging::setup(filter).map_err(PyValueError::new_err)
 }
 
 
@@ -238,11 +2828,37 @@ fn repr_database_value(value: PyDatabaseValue)  -> PyResult<String> {
 #[pymodule]
 fn django_lightning_service(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyEntityStore>()?;
+    m.add_class::<PyEntityStoreOverlay>()?;
+    m.add_class::<PyTransaction>()?;
+    m.add_class::<PyEpochScope>()?;
+    m.add_class::<PyBranch>()?;
+    m.add_class::<PyEntityStoreView>()?;
+    m.add_class::<PyEntityView>()?;
+    m.add_class::<PyAttributeView>()?;
     m.add_class::<PyAttribute>()?;
     m.add_class::<PyEntity>()?;
+    m.add_class::<PyRelatedManager>()?;
+    m.add_class::<PyQuery>()?;
+    m.add_class::<PyEntityIterator>()?;
     m.add_class::<PyEntityIdentifier>()?;
-    m.add_function(wrap_pyfunction!(create_database_value, m)?).unwrap();
-    m.add_function(wrap_pyfunction!(repr_database_value, m)?).unwrap();
+    m.add_class::<PyAttributeDescriptor>()?;
+    m.add_class::<PySyncEntityStore>()?;
+    m.add_class::<PyValue>()?;
+    m.add_function(wrap_pyfunction!(number, m)?)?;
+    m.add_function(wrap_pyfunction!(string, m)?)?;
+    m.add_function(wrap_pyfunction!(null, m)?)?;
+    m.add_function(wrap_pyfunction!(union, m)?).unwrap();
+    m.add_function(wrap_pyfunction!(intersection, m)?).unwrap();
+    m.add_function(wrap_pyfunction!(difference, m)?).unwrap();
+    m.add_function(wrap_pyfunction!(setup_logging, m)?)?;
     m.add("CustomError", py.get_type::<EntityNotFound>())?;
+    m.add("EntityNotFound", py.get_type::<EntityNotFound>())?;
+    m.add("AttributeNotFound", py.get_type::<AttributeNotFound>())?;
+    m.add("UnpersistedEntity", py.get_type::<UnpersistedEntity>())?;
+    m.add("ProtectedError", py.get_type::<ProtectedError>())?;
+    m.add("DependencyCycleError", py.get_type::<DependencyCycleError>())?;
+    m.add("StaleEntityError", py.get_type::<StaleEntityError>())?;
+    m.add("IntegrityError", py.get_type::<IntegrityError>())?;
+    m.add("ValidationError", py.get_type::<ValidationError>())?;
     Ok(())
 }
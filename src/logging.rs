@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use std::sync::OnceLock;
+use pyo3::prelude::*;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+
+/// the handle changing the filter of the installed subscriber, see setup()
+static FILTER: OnceLock<Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// forward the tracing events to python's logging, to the logger named after the module
+/// emitting them: "django_lightning_service.entity_store" for the store
+struct PythonLayer;
+
+impl<S: Subscriber> Layer<S> for PythonLayer {
+    fn on_event(&self, event: &Event<'_>, _context: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let logger = event.metadata().target().replace("::", ".");
+        let level = python_level(event.metadata().level());
+        Python::with_gil(|py| {
+            let result = py.import("logging")
+                .and_then(|logging| logging.call_method1("getLogger", (logger,)))
+                .and_then(|logger| logger.call_method1("log", (level, message.text)));
+            // a failing handler can't fail the operation it logs
+            if let Err(error) = result {
+                error.print(py);
+            }
+        });
+    }
+}
+
+/// the message of an event followed by its other fields, as `key=value`
+#[derive(Default)]
+struct Message {
+    text: String,
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.text.is_empty() {
+            self.text.push(' ');
+        }
+        let _ = match field.name() {
+            "message" => write!(self.text, "{:?}", value),
+            name => write!(self.text, "{}={:?}", name, value),
+        };
+    }
+}
+
+/// the level of python's logging matching *level*, TRACE being below DEBUG
+fn python_level(level: &Level) -> i32 {
+    match *level {
+        Level::TRACE => 5,
+        Level::DEBUG => 10,
+        Level::INFO => 20,
+        Level::WARN => 30,
+        Level::ERROR => 40,
+    }
+}
+
+/// forward the events enabled by *filter*, with the syntax of RUST_LOG, to python's logging. the
+/// filter defaults to the RUST_LOG environment variable, then to "warn". the subscriber is
+/// installed by the first call, the next ones only change the filter
+pub fn setup(filter: Option<&str>) -> Result<(), String> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter).map_err(|error| error.to_string())?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+    };
+    if let Some(handle) = FILTER.get() {
+        return handle.reload(filter).map_err(|error| error.to_string());
+    }
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+    tracing_subscriber::registry().with(filter).with(PythonLayer).try_init().map_err(|error| error.to_string())?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+    use crate::logging::{python_level, Message};
+
+    /// collect the messages of the events, as PythonLayer gives them to python
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Messages {
+        fn on_event(&self, event: &Event<'_>, _context: Context<'_, S>) {
+            let mut message = Message::default();
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.text);
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let messages = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(Messages(Arc::clone(&messages)));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(model = "Book", matched = 2, "filter");
+            tracing::info!(inserted = 1);
+        });
+        assert_eq!(*messages.lock().unwrap(), vec!["filter model=\"Book\" matched=2".to_string(), "inserted=1".to_string()]);
+        assert_eq!(python_level(&Level::WARN), 30);
+    }
+}
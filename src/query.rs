@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+use uuid::Uuid;
+use crate::entity::{BaseEntityAttribute, DatabaseValue, Entity};
+use crate::entity_store::EntityStore;
+use crate::errors::EntityError;
+
+
+fn uuids(entities: &[Rc<Entity>]) -> HashSet<Uuid> {
+    entities.iter().map(|entity| *entity.get_identifier().get_uuid()).collect()
+}
+
+/// keep the first occurrence of each entity, in order
+fn dedup<'a>(entities: impl Iterator<Item=&'a Rc<Entity>>) -> Vec<Rc<Entity>> {
+    let mut seen = HashSet::new();
+    entities.filter(|entity| seen.insert(*entity.get_identifier().get_uuid())).map(Rc::clone).collect()
+}
+
+/// entities present in *left* or *right*
+pub fn union(left: &[Rc<Entity>], right: &[Rc<Entity>]) -> Vec<Rc<Entity>> {
+    dedup(left.iter().chain(right.iter()))
+}
+
+/// entities present in both *left* and *right*
+pub fn intersection(left: &[Rc<Entity>], right: &[Rc<Entity>]) -> Vec<Rc<Entity>> {
+    let right = uuids(right);
+    dedup(left.iter().filter(|entity| right.contains(entity.get_identifier().get_uuid())))
+}
+
+/// entities present in *left* but not in *right*
+pub fn difference(left: &[Rc<Entity>], right: &[Rc<Entity>]) -> Vec<Rc<Entity>> {
+    let right = uuids(right);
+    dedup(left.iter().filter(|entity| !right.contains(entity.get_identifier().get_uuid())))
+}
+
+/// the values of the *attributes* of *entities*, column by column, to export them without
+/// wrapping each entity. "pk" gives the pk of the entities, and foreign keys the pk of the
+/// entity they reference. both are None for the entities not persisted yet
+pub fn columns(store: &EntityStore, entities: &[Rc<Entity>], attributes: &[String]) -> Result<Vec<Vec<DatabaseValue>>, EntityError> {
+    let pk = |result: Result<i64, EntityError>| result.map_or(DatabaseValue::None, DatabaseValue::Number);
+    attributes.iter().map(|attribute| entities.iter().map(|entity| Ok(match attribute.as_str() {
+        "pk" => pk(entity.get_identifier().get_applied_pk()),
+        _ => match entity.get(attribute)?.get_value() {
+            DatabaseValue::ForeignKey(identifier) => pk(store.get_pk(&identifier)),
+            value => value,
+        },
+    })).collect()).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use crate::entity::{AttributeDescriptor, AttributeKind, DatabaseValue, Entity, EntityIdentifier, EpochPtr, OnDelete};
+    use crate::entity_store::EntityStore;
+    use crate::errors::EntityError;
+    use crate::query::{columns, difference, intersection, union};
+
+    #[test]
+    fn test_set_operations() {
+        let entities: Vec<Rc<Entity>> = (0..4).map(|_| Rc::new(Entity::new(
+            EntityIdentifier::new("User".to_string()),
+            vec![],
+            Rc::new(EpochPtr::default()),
+            Rc::new(EpochPtr::default()),
+        ))).collect();
+        let e = |i: usize| Rc::clone(&entities[i]);
+        let left = vec![e(0), e(1), e(2), e(1)];
+        let right = vec![e(2), e(3), e(0)];
+
+        assert_eq!(union(&left, &right), vec![e(0), e(1), e(2), e(3)]);
+        assert_eq!(intersection(&left, &right), vec![e(0), e(2)]);
+        assert_eq!(difference(&left, &right), vec![e(1)]);
+        assert_eq!(difference(&right, &left), vec![e(3)]);
+        assert!(intersection(&left, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_columns() {
+        let mut entity_store = EntityStore::new();
+        let group = entity_store.instantiate_entity(EntityIdentifier::new_persisted("Group".to_string(), 7), vec![]);
+        let user = |entity_store: &mut EntityStore, identifier: EntityIdentifier, name: &str, group: DatabaseValue| entity_store.instantiate_entity(identifier, vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String(name.to_string())),
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::Cascade), "group".to_string(), group),
+        ]);
+        let users = vec![
+            user(&mut entity_store, EntityIdentifier::new_persisted("User".to_string(), 1), "john", DatabaseValue::ForeignKey(group.get_identifier().clone())),
+            user(&mut entity_store, EntityIdentifier::new("User".to_string()), "doe", DatabaseValue::None),
+        ];
+        let attributes = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(columns(&entity_store, &users, &attributes(&["pk", "name", "group"])), Ok(vec![
+            vec![DatabaseValue::Number(1), DatabaseValue::None],
+            vec![DatabaseValue::String("john".to_string()), DatabaseValue::String("doe".to_string())],
+            vec![DatabaseValue::Number(7), DatabaseValue::None],
+        ]));
+        assert_eq!(columns(&entity_store, &users, &attributes(&["age"])), Err(EntityError::AttributeNotFound("age".to_string())));
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::entity::{BaseEntityAttribute, DatabaseValue, Entity, Model};
+use crate::entity_store::{Changeset, EntityStore};
+use crate::errors::EntityError;
+
+/// how the entities of a model are stored in the database. names are written as is in the
+/// statements, so they must be quoted if needed
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableMapping {
+    pub table: String,
+    pub pk_column: String,
+    /// (attribute, column) pairs, in column order. other attributes are not written
+    pub columns: Vec<(String, String)>,
+}
+
+impl TableMapping {
+    /// the mapped (attribute, column) pairs among *attributes*
+    fn mapped<'a>(&'a self, attributes: &'a [String]) -> impl Iterator<Item=&'a (String, String)> {
+        self.columns.iter().filter(move |(attribute, _)| attributes.contains(attribute))
+    }
+}
+
+/// a parameterized statement with "%s" placeholders, and the parameters of each execution,
+/// as expected by cursor.executemany()
+#[derive(Debug, PartialEq)]
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<Vec<DatabaseValue>>,
+}
+
+/// the statements persisting *changeset*: the inserts, updates then deletes, in the changeset
+/// order. consecutive entities of a model writing the same columns share a statement.
+/// foreign keys are written as the pk of the referenced entity, which must be persisted already
+pub fn render(store: &EntityStore, changeset: &Changeset, tables: &HashMap<Model, TableMapping>) -> Result<Vec<Statement>, EntityError> {
+    // statements with the operation, model and columns they were rendered for
+    let mut statements: Vec<(String, Statement)> = vec![];
+    for entity in changeset.created.iter() {
+        let table = get_table(entity, tables)?;
+        let attributes: Vec<String> = entity.iter_attributes().map(|(name, _)| name.to_string()).collect();
+        let columns: Vec<&(String, String)> = table.mapped(&attributes).collect();
+        let names: Vec<&str> = columns.iter().map(|(_, column)| column.as_str()).collect();
+        push(&mut statements, entity, format!("insert {:?}", names), values(store, entity, &columns)?, || format!(
+            "INSERT INTO {} ({}) VALUES ({})", table.table, names.join(", "), vec!["%s"; names.len()].join(", "),
+        ));
+    }
+    for (entity, changed_attributes) in changeset.updated.iter() {
+        let table = get_table(entity, tables)?;
+        let columns: Vec<&(String, String)> = table.mapped(changed_attributes).collect();
+        if columns.is_empty() {
+            continue;
+        }
+        let names: Vec<&str> = columns.iter().map(|(_, column)| column.as_str()).collect();
+        let mut params = values(store, entity, &columns)?;
+        params.push(DatabaseValue::Number(entity.get_identifier().get_applied_pk()?));
+        push(&mut statements, entity, format!("update {:?}", names), params, || format!(
+            "UPDATE {} SET {} WHERE {} = %s",
+            table.table,
+            names.iter().map(|column| format!("{} = %s", column)).collect::<Vec<String>>().join(", "),
+            table.pk_column,
+        ));
+    }
+    for entity in changeset.deleted.iter() {
+        let table = get_table(entity, tables)?;
+        let params = vec![DatabaseValue::Number(entity.get_identifier().get_applied_pk()?)];
+        push(&mut statements, entity, "delete".to_string(), params, || format!("DELETE FROM {} WHERE {} = %s", table.table, table.pk_column));
+    }
+    Ok(statements.into_iter().map(|(_, statement)| statement).collect())
+}
+
+/// add the *params* of *entity* to the last statement if it was rendered for the same model and
+/// *operation*, or a new statement rendered by *sql*
+fn push(statements: &mut Vec<(String, Statement)>, entity: &Entity, operation: String, params: Vec<DatabaseValue>, sql: impl FnOnce() -> String) {
+    let key = format!("{} {}", entity.get_identifier().get_model(), operation);
+    match statements.last_mut() {
+        Some((last_key, statement)) if *last_key == key => statement.params.push(params),
+        _ => statements.push((key, Statement { sql: sql(), params: vec![params] })),
+    }
+}
+
+fn get_table<'a>(entity: &Rc<Entity>, tables: &'a HashMap<Model, TableMapping>) -> Result<&'a TableMapping, EntityError> {
+    let model = entity.get_identifier().get_model();
+    tables.get(model).ok_or_else(|| EntityError::NoTableMapping(model.to_string()))
+}
+
+/// the current values of the *columns* attributes of *entity*, foreign keys replaced by pks
+fn values(store: &EntityStore, entity: &Entity, columns: &[&(String, String)]) -> Result<Vec<DatabaseValue>, EntityError> {
+    columns.iter().map(|(attribute, _)| match entity.get(attribute)?.get_value() {
+        DatabaseValue::ForeignKey(identifier) => Ok(DatabaseValue::Number(store.get_pk(&identifier)?)),
+        value => Ok(value),
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::entity::{AttributeDescriptor, AttributeKind, BaseEntityAttribute, DatabaseValue, EntityIdentifier, OnDelete};
+    use crate::entity_store::EntityStore;
+    use crate::errors::EntityError;
+    use crate::sql::{render, Statement, TableMapping};
+
+    #[test]
+    fn test_render() {
+        let mut entity_store = EntityStore::new();
+        let tables = HashMap::from([
+            ("User".to_string(), TableMapping {
+                table: "auth_user".to_string(),
+                pk_column: "id".to_string(),
+                columns: vec![("name".to_string(), "username".to_string()), ("group".to_string(), "group_id".to_string())],
+            }),
+        ]);
+        let user = |entity_store: &mut EntityStore, identifier: EntityIdentifier, name: &str| entity_store.instantiate_entity(identifier, vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String(name.to_string())),
+            AttributeDescriptor::new(AttributeKind::ForeignKey("Group".to_string(), OnDelete::Cascade), "group".to_string(), DatabaseValue::ForeignKey(EntityIdentifier::new_persisted("Group".to_string(), 7))),
+            AttributeDescriptor::new(AttributeKind::Physical, "cache".to_string(), DatabaseValue::None),
+        ]);
+        let john = user(&mut entity_store, EntityIdentifier::new_persisted("User".to_string(), 1), "john");
+        let jane = user(&mut entity_store, EntityIdentifier::new_persisted("User".to_string(), 2), "jane");
+        user(&mut entity_store, EntityIdentifier::new("User".to_string()), "doe");
+        user(&mut entity_store, EntityIdentifier::new("User".to_string()), "foo");
+        john.get("name").unwrap().set_current_value(DatabaseValue::String("johnny".to_string()));
+        john.get("cache").unwrap().set_current_value(DatabaseValue::Number(1));
+        jane.get("cache").unwrap().set_current_value(DatabaseValue::Number(1));
+        entity_store.delete(&jane).unwrap();
+
+        let changeset = entity_store.changeset().unwrap();
+        let name = |name: &str| DatabaseValue::String(name.to_string());
+        assert_eq!(render(&entity_store, &changeset, &tables), Ok(vec![
+            Statement {
+                sql: "INSERT INTO auth_user (username, group_id) VALUES (%s, %s)".to_string(),
+                params: vec![vec![name("doe"), DatabaseValue::Number(7)], vec![name("foo"), DatabaseValue::Number(7)]],
+            },
+            Statement {
+                sql: "UPDATE auth_user SET username = %s WHERE id = %s".to_string(),
+                params: vec![vec![name("johnny"), DatabaseValue::Number(1)]],
+            },
+            Statement {
+                sql: "DELETE FROM auth_user WHERE id = %s".to_string(),
+                params: vec![vec![DatabaseValue::Number(2)]],
+            },
+        ]));
+        assert_eq!(render(&entity_store, &changeset, &HashMap::new()), Err(EntityError::NoTableMapping("User".to_string())));
+    }
+}
@@ -0,0 +1,52 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+
+/// an interned name, of a model or an attribute: the entities share a single copy of each name,
+/// cloned without allocating. atomically counted, the entities of a SyncEntityStore move
+/// between threads
+pub type Symbol = Arc<str>;
+
+/// the interned names of a store, dropped with it
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    symbols: RefCell<HashSet<Symbol>>,
+}
+
+impl Symbols {
+    /// the shared copy of *name*, added to the interned names on its first use
+    pub fn intern(&self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.borrow().get(name) {
+            return Arc::clone(symbol);
+        }
+        let symbol: Symbol = Arc::from(name);
+        self.symbols.borrow_mut().insert(Arc::clone(&symbol));
+        symbol
+    }
+
+    /// the shared copy of *symbol*, which is added to the interned names if it's the first one
+    pub fn intern_symbol(&self, symbol: &Symbol) -> Symbol {
+        if let Some(symbol) = self.symbols.borrow().get(symbol) {
+            return Arc::clone(symbol);
+        }
+        self.symbols.borrow_mut().insert(Arc::clone(symbol));
+        Arc::clone(symbol)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::symbol::{Symbol, Symbols};
+
+    #[test]
+    fn test_intern() {
+        let symbols = Symbols::default();
+        let name = symbols.intern("title");
+        assert_eq!(&*name, "title");
+        assert!(Arc::ptr_eq(&name, &symbols.intern(&String::from("title"))));
+        assert!(Arc::ptr_eq(&name, &symbols.intern_symbol(&Symbol::from("title"))));
+        assert!(!Arc::ptr_eq(&name, &symbols.intern("pages")));
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use crate::entity::{BaseEntityAttribute, DatabaseValue, EntityIdentifier, Model};
+use crate::entity_store::{EntityStore, ModelSchema};
+use crate::errors::EntityError;
+use crate::expression::FilterExpression;
+
+
+/// an entity store shared between threads, a key/value facade over EntityStore: the calls are
+/// serialized and answer with identifiers and values, the entities themselves never leave the
+/// store. relations, transactions, epochs and hooks are left to EntityStore, run through with()
+pub struct SyncEntityStore {
+    entity_store: Mutex<EntityStore>,
+}
+
+// SAFETY: the entities, their attributes, the indexes and the epoch pointers are reference
+// counted without synchronization, and the entity validators aren't Send. sharing the store is
+// sound under these rules, which every method must keep:
+// - the store is only reached under the lock, so its Rc and RefCell are never used concurrently;
+// - nothing of the store leaves the lock: with() only returns Send results, which an Rc or a
+//   reference into the store isn't, and the methods answer with identifiers and values, which
+//   own their data (the interned names are Arc);
+// - nothing thread bound enters the store: with() only runs Send closures, so what they capture
+//   and give to the store is Send, and the validators are Send + Sync (see ValidatorFn). the
+//   python ones take the GIL when called.
+// a thread panicking with the lock leaves the store usable: the RefCell borrows are released
+// while unwinding
+unsafe impl Send for SyncEntityStore {}
+unsafe impl Sync for SyncEntityStore {}
+
+impl Default for SyncEntityStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncEntityStore {
+    pub fn new() -> SyncEntityStore {
+        SyncEntityStore { entity_store: Mutex::new(EntityStore::new()) }
+    }
+
+    /// run *f* with the store locked. a thread panicking with the lock leaves the store as is
+    pub fn with<T: Send, F: FnOnce(&mut EntityStore) -> T + Send>(&self, f: F) -> T {
+        f(&mut self.entity_store.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn register_model(&self, model: Model, schema: ModelSchema) {
+        self.with(|entity_store| entity_store.register_model(model, schema))
+    }
+
+    /// instantiate an entity of a registered model, see EntityStore::instantiate_model()
+    pub fn instantiate_model(&self, identifier: EntityIdentifier, values: HashMap<String, DatabaseValue>) -> Result<EntityIdentifier, EntityError> {
+        self.with(|entity_store| Ok(entity_store.instantiate_model(identifier, values)?.get_identifier().clone()))
+    }
+
+    pub fn get_value(&self, identifier: EntityIdentifier, attribute: &str) -> Result<DatabaseValue, EntityError> {
+        self.with(move |entity_store| Ok(entity_store.get(&identifier)?.get(attribute)?.get_value()))
+    }
+
+    /// set the current value of *attribute*, left unchanged if it breaks an immediate constraint
+    pub fn set_value(&self, identifier: EntityIdentifier, attribute: &str, value: DatabaseValue) -> Result<(), EntityError> {
+        self.with(move |entity_store| {
+            entity_store.write_value(&entity_store.get(&identifier)?, attribute, value, None)?;
+            Ok(())
+        })
+    }
+
+    /// the current values of the attributes of an entity
+    pub fn values(&self, identifier: EntityIdentifier) -> Result<HashMap<String, DatabaseValue>, EntityError> {
+        self.with(move |entity_store| {
+            let entity = entity_store.get(&identifier)?;
+            let values = entity.iter_attributes().map(|(name, attribute)| (name.to_string(), attribute.get_value())).collect();
+            Ok(values)
+        })
+    }
+
+    /// the identifiers of the entities of *model* matching *filter_expression*
+    pub fn filter(&self, model: Model, filter_expression: FilterExpression) -> Result<Vec<EntityIdentifier>, EntityError> {
+        self.with(move |entity_store| {
+            let entities = entity_store.filter(model, &filter_expression)?;
+            Ok(entities.iter().map(|entity| entity.get_identifier().clone()).collect())
+        })
+    }
+
+    /// delete an entity with its cascade, return the number of deleted entities
+    pub fn delete(&self, identifier: EntityIdentifier) -> Result<usize, EntityError> {
+        self.with(move |entity_store| Ok(entity_store.delete(&*entity_store.get(&identifier)?)?.len()))
+    }
+
+    pub fn count(&self, model: &Model) -> usize {
+        self.with(|entity_store| entity_store.count(model))
+    }
+
+    pub fn alive_count(&self) -> usize {
+        self.with(|entity_store| entity_store.alive_count())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use crate::entity::{AttributeDescriptor, AttributeKind, DatabaseValue, EntityIdentifier, Validator};
+    use crate::entity_store::{Constraint, ConstraintCheck, ModelSchema};
+    use crate::errors::EntityError;
+    use crate::expression::{ExactExpression, FilterExpression};
+    use crate::sync_store::SyncEntityStore;
+
+    #[test]
+    fn test_sync_store() {
+        let entity_store = Arc::new(SyncEntityStore::new());
+        let model = "User".to_string();
+        let attributes = vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "name".to_string(), DatabaseValue::String("".to_string())),
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(0)),
+        ];
+        entity_store.register_model(model.clone(), ModelSchema { attributes, unique: vec![] });
+
+        let threads: Vec<_> = (0..4).map(|pk| {
+            let entity_store = Arc::clone(&entity_store);
+            let model = model.clone();
+            std::thread::spawn(move || {
+                let values = HashMap::from([("name".to_string(), DatabaseValue::String(format!("user {}", pk)))]);
+                let identifier = entity_store.instantiate_model(EntityIdentifier::new_persisted(model, pk), values).unwrap();
+                entity_store.set_value(identifier, "age", DatabaseValue::Number(pk * 10)).unwrap();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(entity_store.count(&model), 4);
+        let identifier = EntityIdentifier::new_persisted(model.clone(), 2);
+        assert_eq!(entity_store.get_value(identifier.clone(), "age"), Ok(DatabaseValue::Number(20)));
+        assert_eq!(entity_store.values(identifier.clone()).unwrap()["name"], DatabaseValue::String("user 2".to_string()));
+        let expression = FilterExpression::Exact(ExactExpression::new("age".to_string(), DatabaseValue::Number(30)));
+        assert_eq!(entity_store.filter(model.clone(), expression), Ok(vec![EntityIdentifier::new_persisted(model.clone(), 3)]));
+        assert!(matches!(entity_store.get_value(identifier.clone(), "email"), Err(EntityError::AttributeNotFound(_))));
+
+        assert_eq!(entity_store.delete(identifier), Ok(1));
+        assert_eq!(entity_store.count(&model), 3);
+        assert_eq!(entity_store.alive_count(), 3);
+    }
+
+    #[test]
+    fn test_sync_store_writes() {
+        let entity_store = Arc::new(SyncEntityStore::new());
+        let model = "User".to_string();
+        let attributes = vec![
+            AttributeDescriptor::new(AttributeKind::Physical, "email".to_string(), DatabaseValue::None),
+            AttributeDescriptor::new(AttributeKind::Physical, "age".to_string(), DatabaseValue::Number(0))
+                .with_validator(Validator::MinValue(0)),
+        ];
+        entity_store.register_model(model.clone(), ModelSchema { attributes, unique: vec![vec!["email".to_string()]] });
+        entity_store.with(|entity_store| {
+            entity_store.set_constraint_check(Constraint::Unique("User".to_string(), vec!["email".to_string()]), ConstraintCheck::Immediate);
+            entity_store.enable_audit_log();
+        });
+        let identifiers: Vec<_> = (0..4).map(|pk| entity_store.instantiate_model(EntityIdentifier::new_persisted(model.clone(), pk), HashMap::new()).unwrap()).collect();
+
+        // every thread writes the same emails: one write of each is refused, the value left unchanged
+        let threads: Vec<_> = identifiers.iter().cloned().map(|identifier| {
+            let entity_store = Arc::clone(&entity_store);
+            std::thread::spawn(move || (0..2).filter(|email| {
+                entity_store.set_value(identifier.clone(), "email", DatabaseValue::String(format!("{}@example.com", email))).is_ok()
+            }).count())
+        }).collect();
+        let written: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert!(written >= 2);
+        assert_eq!(entity_store.with(|entity_store| entity_store.audit_log().len()), written);
+        let emails: Vec<_> = identifiers.iter().filter_map(|identifier| match entity_store.get_value(identifier.clone(), "email") {
+            Ok(DatabaseValue::String(email)) => Some(email),
+            _ => None,
+        }).collect();
+        assert_eq!(emails.iter().collect::<HashSet<_>>().len(), emails.len());
+
+        assert!(matches!(entity_store.set_value(identifiers[0].clone(), "age", DatabaseValue::Number(-1)), Err(EntityError::Validation(..))));
+        assert_eq!(entity_store.get_value(identifiers[0].clone(), "age"), Ok(DatabaseValue::Number(0)));
+
+        // a thread panicking with the lock leaves the store usable
+        let panicking = Arc::clone(&entity_store);
+        assert!(std::thread::spawn(move || panicking.with(|entity_store| {
+            let _entity = entity_store.get(&EntityIdentifier::new_persisted("User".to_string(), 1)).unwrap();
+            panic!("the lock is poisoned");
+        })).join().is_err());
+        assert_eq!(entity_store.set_value(identifiers[1].clone(), "age", DatabaseValue::Number(30)), Ok(()));
+        assert_eq!(entity_store.count(&model), 4);
+    }
+}